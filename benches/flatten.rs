@@ -0,0 +1,43 @@
+//! how long flattening takes for commands with lots of arguments, run with `cargo bench`, like
+//! the `parse` benchmark each case is run a fixed number of times and the mean time is printed,
+//! only the flattening is timed, the command is parsed beforehand
+//!
+//! ```text
+//! cargo bench --bench flatten [FILTER]
+//! ```
+
+use std::{hint::black_box, time::Instant};
+
+use cs128h_project::{ast::Main, env, evaluator::Evaluator, parse};
+
+fn bench(name: &str, iterations: u32, input: &str) {
+    let filter = std::env::args().skip(1).find(|x| !x.starts_with('-'));
+    if filter.is_some_and(|x| !name.contains(&x)) {
+        return;
+    }
+    let asts: Vec<Main> = (0..iterations)
+        .map(|_| parse(input).expect("benchmark input must parse"))
+        .collect();
+    let mut evaluator = Evaluator::new();
+    let start = Instant::now();
+    for ast in asts {
+        black_box(evaluator.flatten(black_box(ast)).expect("nothing can fail"));
+    }
+    println!(
+        "{:<24} {:>12.3?}/iter ({} bytes)",
+        name,
+        start.elapsed() / iterations,
+        input.len()
+    );
+}
+
+fn main() {
+    env::set("a".into(), "some value".into()).expect("valid name");
+    env::set("b".into(), "another".into()).expect("valid name");
+    let words = |word: &str| format!("cmd {}", vec![word; 1_000].join(" "));
+    bench("1000 plain words", 100, &words("word"));
+    bench("1000 single quoted", 100, &words("'a word'"));
+    bench("1000 variables", 100, &words("$a"));
+    bench("1000 mixed words", 100, &words("x${a}y$b"));
+    bench("1000 double quoted", 100, &words("\"x $a y $b z\""));
+}
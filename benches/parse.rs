@@ -0,0 +1,41 @@
+//! how long parsing takes, for the kinds of input that have been slow before, run with
+//! `cargo bench`, there's no benchmarking library, so each case is run for a fixed number of
+//! iterations and the mean time is printed, which is enough to spot something blowing up
+//!
+//! ```text
+//! cargo bench --bench parse [FILTER]
+//! ```
+
+use std::{hint::black_box, time::Instant};
+
+/// a command with a bit of everything in it
+const COMMAND: &str = "A=1 cmd -x 'a b' \"$HOME/x$(y $z)\" foo${bar} > out";
+
+fn bench(name: &str, iterations: u32, input: &str) {
+    let filter = std::env::args().skip(1).find(|x| !x.starts_with('-'));
+    if filter.is_some_and(|x| !name.contains(&x)) {
+        return;
+    }
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(cs128h_project::parse(black_box(input)).expect("benchmark input must parse"));
+    }
+    println!(
+        "{:<24} {:>12.3?}/iter ({} bytes)",
+        name,
+        start.elapsed() / iterations,
+        input.len()
+    );
+}
+
+fn main() {
+    bench("single command", 10_000, COMMAND);
+    for n in [100, 1_000, 10_000] {
+        let pipeline = vec![COMMAND; n].join(" | ");
+        bench(&format!("pipeline of {}", n), 10, &pipeline);
+        let list = vec![COMMAND; n].join("; ");
+        bench(&format!("list of {}", n), 10, &list);
+    }
+    let words = vec!["$a"; 10_000].join(" ");
+    bench("10000 variables", 10, &format!("echo {}", words));
+}
@@ -0,0 +1,80 @@
+//! aliases, which stand in for a command along with some arguments, e.g. `ll` for `ls -l`
+//!
+//! an alias is only expanded when it's the unquoted name of a command, and its value has to be a
+//! single command, which can include assignments and redirects, anything after the alias is
+//! appended to that command's arguments
+
+use std::{collections::BTreeMap, sync::OnceLock};
+
+use parking_lot::RwLock;
+
+use crate::ast::{self, CommandLine};
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum AliasError {
+    #[error("`{name}': invalid alias name")]
+    InvalidName { name: String },
+
+    #[error("{name}: alias must be a single command: {reason}")]
+    InvalidValue { name: String, reason: String },
+}
+
+fn aliases() -> &'static RwLock<BTreeMap<String, String>> {
+    static ALIASES: OnceLock<RwLock<BTreeMap<String, String>>> = OnceLock::new();
+    ALIASES.get_or_init(|| RwLock::new(BTreeMap::new()))
+}
+
+/// makes `name` an alias for `value`, replacing whatever it was before
+pub fn set(name: String, value: String) -> Result<(), AliasError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        return Err(AliasError::InvalidName { name });
+    }
+    if let Err(reason) = parse(&value) {
+        return Err(AliasError::InvalidValue { name, reason });
+    }
+    aliases().write().insert(name, value);
+    Ok(())
+}
+
+/// the command line `name` expands to, if it's an alias
+pub fn get(name: &str) -> Option<CommandLine<'static>> {
+    let value = aliases().read().get(name)?.clone();
+    // values are checked when they're set, so this only fails if something's very wrong
+    parse(&value).ok().map(CommandLine::into_owned)
+}
+
+/// the value of the alias `name`, as it was given
+pub fn value(name: &str) -> Option<String> {
+    aliases().read().get(name).cloned()
+}
+
+/// removes the alias `name`, returning its value if there was one
+pub fn remove(name: &str) -> Option<String> {
+    aliases().write().remove(name)
+}
+
+/// every alias and its value, sorted by name
+pub fn list() -> Vec<(String, String)> {
+    aliases()
+        .read()
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+fn parse(value: &str) -> Result<CommandLine<'_>, String> {
+    let cmdline = ast::generate_ast(value).map_err(|e| e.to_string())?.0;
+    match cmdline {
+        CommandLine { command: None, .. } => Err("no command".to_owned()),
+        CommandLine { next: Some(_), .. } => Err("more than one command".to_owned()),
+        CommandLine {
+            background: true, ..
+        } => Err("can't run in the background".to_owned()),
+        x => Ok(x),
+    }
+}
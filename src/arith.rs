@@ -0,0 +1,182 @@
+//! integer arithmetic, as used for assignments to variables declared with `declare -i`
+//!
+//! supports `+ - * / %`, unary `+`/`-`, parentheses, decimal literals, and variable names, a
+//! variable that's unset or empty is `0`, and one that holds an expression of its own is evaluated
+//! in turn, everything wraps around on overflow like it does in other shells
+
+/// how many variables deep an expression may refer to other expressions, so that something like
+/// `a=a` can't recurse forever
+const MAX_DEPTH: usize = 32;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ArithError {
+    #[error("syntax error in expression (error token is \"{token}\")")]
+    Syntax { token: String },
+
+    #[error("division by 0")]
+    DivideByZero,
+
+    #[error("expression recursion level exceeded")]
+    TooDeep,
+}
+
+/// evaluates `expr`, looking up the values of any variables it uses with `lookup`
+pub fn evaluate(expr: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<i64, ArithError> {
+    evaluate_nested(expr, lookup, 0)
+}
+
+fn evaluate_nested(
+    expr: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    depth: usize,
+) -> Result<i64, ArithError> {
+    if depth > MAX_DEPTH {
+        return Err(ArithError::TooDeep);
+    }
+    let mut parser = Parser {
+        rest: expr,
+        lookup,
+        depth,
+    };
+    if parser.skip_whitespace().is_empty() {
+        return Ok(0);
+    }
+    let value = parser.sum()?;
+    match parser.skip_whitespace() {
+        "" => Ok(value),
+        rest => Err(ArithError::Syntax {
+            token: rest.to_owned(),
+        }),
+    }
+}
+
+/// a recursive descent parser that evaluates as it goes
+struct Parser<'a> {
+    rest: &'a str,
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) -> &'a str {
+        self.rest = self.rest.trim_start();
+        self.rest
+    }
+
+    /// consumes `op` if it's next
+    fn eat(&mut self, op: char) -> bool {
+        match self.skip_whitespace().strip_prefix(op) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn sum(&mut self) -> Result<i64, ArithError> {
+        let mut value = self.product()?;
+        loop {
+            if self.eat('+') {
+                value = value.wrapping_add(self.product()?);
+            } else if self.eat('-') {
+                value = value.wrapping_sub(self.product()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<i64, ArithError> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value = value.wrapping_mul(self.unary()?);
+            } else if self.eat('/') {
+                let rhs = self.unary()?;
+                value = value.checked_div(rhs).ok_or(ArithError::DivideByZero)?;
+            } else if self.eat('%') {
+                let rhs = self.unary()?;
+                value = value.checked_rem(rhs).ok_or(ArithError::DivideByZero)?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, ArithError> {
+        if self.eat('-') {
+            return Ok(self.unary()?.wrapping_neg());
+        }
+        if self.eat('+') {
+            return self.unary();
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<i64, ArithError> {
+        if self.eat('(') {
+            let value = self.sum()?;
+            if !self.eat(')') {
+                return Err(self.syntax_error());
+            }
+            return Ok(value);
+        }
+
+        let rest = self.skip_whitespace();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let (token, rest) = rest.split_at(len);
+        if token.is_empty() {
+            return Err(self.syntax_error());
+        }
+        self.rest = rest;
+
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            // out of range literals wrap, the same as any other overflow
+            return token
+                .parse::<u64>()
+                .map(|x| x as i64)
+                .map_err(|_| ArithError::Syntax {
+                    token: token.to_owned(),
+                });
+        }
+        let value = (self.lookup)(token).unwrap_or_default();
+        evaluate_nested(&value, self.lookup, self.depth + 1)
+    }
+
+    fn syntax_error(&self) -> ArithError {
+        ArithError::Syntax {
+            token: self.rest.trim().to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        let lookup = |name: &str| match name {
+            "n" => Some("4".to_owned()),
+            "expr" => Some("n * 2".to_owned()),
+            "loop" => Some("loop".to_owned()),
+            _ => None,
+        };
+        let eval = |x| evaluate(x, &lookup);
+
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * -3"), Ok(-9));
+        assert_eq!(eval("n+1"), Ok(5));
+        assert_eq!(eval("expr % 5 - unset"), Ok(3));
+        assert_eq!(eval(""), Ok(0));
+        assert_eq!(eval("7 / 0"), Err(ArithError::DivideByZero));
+        assert_eq!(eval("loop"), Err(ArithError::TooDeep));
+        assert!(matches!(eval("1 +"), Err(ArithError::Syntax { .. })));
+        assert!(matches!(eval("(1"), Err(ArithError::Syntax { .. })));
+        assert!(matches!(eval("1 2"), Err(ArithError::Syntax { .. })));
+    }
+}
@@ -1,8 +1,15 @@
-use std::ffi::OsString;
+use std::{borrow::Cow, cell::Cell, ffi::OsStr, fmt};
 
-use pest::{iterators::Pair, Parser};
+use pest::{
+    iterators::{Pair, Pairs},
+    Parser,
+};
 
-use crate::parser::{Rule, ShellParser};
+use crate::{
+    metrics,
+    parser::{Rule, ShellParser},
+    profile::{self, Phase},
+};
 
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
@@ -17,6 +24,15 @@ pub enum AstError {
         parse_failure: Box<pest::error::Error<Rule>>,
     },
 
+    /// the line nests substitutions deeper than [`MAX_NESTING`], which is refused before parsing
+    /// since both parsing and AST generation recurse once per level and would overflow the stack
+    #[error("command nests substitutions more than {limit} levels deep")]
+    TooDeep { limit: usize },
+
+    /// a redirect names an fd too large to be one
+    #[error("invalid file descriptor in redirect: '{fd}'")]
+    InvalidFd { fd: String },
+
     /// this error should never be produced, if this error is produced then there is a logic error
     /// in the AST generation code that should be reported as a bug
     #[error("unable to generate AST node '{node_type}' from parser Rule '{pair_type:?}'")]
@@ -26,10 +42,50 @@ pub enum AstError {
         /// the parser pair it was actually passed
         pair_type: Rule,
     },
+
+    /// like [`AstError::RuleMismatch`] this should never be produced, it means a parser pair was
+    /// missing a child or had text that the grammar doesn't allow for the AST node it's for
+    #[error("unable to generate AST node '{node_type}' from malformed parser pair: '{text}'")]
+    MalformedPair {
+        node_type: &'static str,
+        /// the text of the pair
+        text: String,
+    },
+}
+
+/// the error for a pair with text `text` that can't be made into a `node_type`, see
+/// [`AstError::MalformedPair`]
+fn malformed(node_type: &'static str, text: &str) -> AstError {
+    AstError::MalformedPair {
+        node_type,
+        text: text.to_owned(),
+    }
+}
+
+/// the next child of a pair with text `text`, which is required to make a `node_type`
+fn next_pair<'i>(
+    inner: &mut Pairs<'i, Rule>,
+    node_type: &'static str,
+    text: &str,
+) -> Result<Pair<'i, Rule>, AstError> {
+    inner.next().ok_or_else(|| malformed(node_type, text))
+}
+
+/// the text of `pair`, borrowed from the input rather than copied
+fn text<'i>(pair: &Pair<'i, Rule>) -> Cow<'i, OsStr> {
+    Cow::Borrowed(OsStr::new(pair.as_str()))
+}
+
+/// the error for a child pair that `node_type` can't contain
+fn mismatch(node_type: &'static str, pair: &Pair<Rule>) -> AstError {
+    AstError::RuleMismatch {
+        node_type,
+        pair_type: pair.as_rule(),
+    }
 }
 
-trait FromPair {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError>
+trait FromPair<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError>
     where
         Self: Sized;
 }
@@ -37,59 +93,99 @@ trait FromPair {
 /// top-level component of an AST, it contains a single [`CommandLine`] and enforces the
 /// requirement that the parser evaluate the entire input string.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Main(pub CommandLine);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Main<'a>(pub CommandLine<'a>);
 
 /// high-level AST component that describes an entire command including its arguments, environment
 /// variables, etc.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct CommandLine {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CommandLine<'a> {
     /// one-shot environment variables to run the command with
-    pub envs: Vec<CommandEnv>,
-    /// the actual command itself
-    pub command: Command,
+    pub envs: Vec<CommandEnv<'a>>,
+    /// the actual command itself, which is absent for a line that only assigns variables, e.g.
+    /// `NAME=value`
+    pub command: Option<Command<'a>>,
     /// the arguments passed to the command
-    pub arguments: Vec<Argument>,
+    pub arguments: Vec<Argument<'a>>,
     /// stdio redirections
-    pub redirects: Vec<Redirection>,
+    pub redirects: Vec<Redirection<'a>>,
     /// a possible second command chained with this one using syntax like `;` or `|`
-    pub next: Option<(Separator, Box<CommandLine>)>,
+    pub next: Option<(Separator, Box<CommandLine<'a>>)>,
+    /// whether the command line ends in a trailing `&`, that is, whether the final command should
+    /// be run in the background
+    pub background: bool,
+    /// covers the whole command line, including any commands chained after it
+    pub span: Span,
 }
 
 /// mid-level AST component that describes an argument to a command
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Argument {
-    StringLiteral(StringLiteral),
-    SingleQuoteString(SingleQuoteString),
-    DoubleQuoteString(DoubleQuoteString),
-    ShellSubstitution(ShellSubstitution),
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Argument<'a> {
+    StringLiteral(StringLiteral<'a>),
+    SingleQuoteString(SingleQuoteString<'a>),
+    DoubleQuoteString(DoubleQuoteString<'a>),
+    ShellSubstitution(ShellSubstitution<'a>),
+    ProcessSubstitution(ProcessSubstitution<'a>),
 }
 
 /// mid-level AST component that describes a command, that is, the name or path of an executable or
 /// shell builtin
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Command {
-    StringLiteral(StringLiteral),
-    SingleQuoteString(SingleQuoteString),
-    DoubleQuoteString(DoubleQuoteString),
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Command<'a> {
+    StringLiteral(StringLiteral<'a>),
+    SingleQuoteString(SingleQuoteString<'a>),
+    DoubleQuoteString(DoubleQuoteString<'a>),
 }
 
 /// mid-level AST component that describes a redirection of a stdio fd to another file
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Redirection {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Redirection<'a> {
     pub op: RedirectOp,
-    pub arg: Argument,
+    /// the file being redirected to or from, this is only `None` for [`RedirectType::Close`] and
+    /// [`RedirectType::Duplicate`]
+    pub arg: Option<Argument<'a>>,
+    pub span: Span,
 }
 
 /// low-level AST component that defines a redirection operation, that is, the specific stdio fd
 /// that is being redirected and the type of redirection
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RedirectOp {
     pub fd: RedirectFd,
     pub r#type: RedirectType,
+    pub span: Span,
+}
+
+impl fmt::Display for RedirectOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fd = match self.fd {
+            RedirectFd::All => "&".to_owned(),
+            RedirectFd::Default => String::new(),
+            RedirectFd::Stdin => "0".to_owned(),
+            RedirectFd::Stdout => "1".to_owned(),
+            RedirectFd::Stderr => "2".to_owned(),
+            RedirectFd::Fd(x) => x.to_string(),
+        };
+        let r#type = match &self.r#type {
+            RedirectType::Out => ">".to_owned(),
+            RedirectType::OutAppend => ">>".to_owned(),
+            RedirectType::In => "<".to_owned(),
+            RedirectType::HereString => "<<<".to_owned(),
+            RedirectType::Close => ">&-".to_owned(),
+            RedirectType::Duplicate(x) => format!(">&{}", x.number().unwrap_or(1)),
+        };
+        write!(f, "{}{}", fd, r#type)
+    }
 }
 
 /// low-level AST component that defines the file descriptor to be redirected in a redirection.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RedirectFd {
     /// the All variant refers to either stdout and stderr if used with [`RedirectType::Out`] or
     /// [`RedirectType::OutAppend`] and to stdin, if used with [`RedirectType::In`]
@@ -99,18 +195,28 @@ pub enum RedirectFd {
     Stdin,
     Stdout,
     Stderr,
+    /// any other fd, which is parsed but (for now) refused when the command is run
+    Fd(u32),
 }
 
 /// low-level AST component that defines the type of redirection to be performed
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RedirectType {
     Out,
     OutAppend,
     In,
+    /// `<<<`, the argument is fed to stdin (followed by a newline) rather than naming a file
+    HereString,
+    /// `>&-`, closes the fd in the command rather than redirecting it, this has no argument
+    Close,
+    /// `>&N`, makes the fd a copy of fd `N` as it is at that point, this has no argument either
+    Duplicate(RedirectFd),
 }
 
 /// low-level AST component that defines how multiple [`CommandLine`]s should be chained together
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Separator {
     /// run the first command, wait for it to finish, then run the second
     Semicolon,
@@ -123,23 +229,38 @@ pub enum Separator {
 
 /// mid-level AST component that defines a one-shot environment variable to be set.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct CommandEnv {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CommandEnv<'a> {
     /// the name of the environment variable
-    pub name: EnvLiteral,
-    /// the value of the environment variable
-    pub value: Argument,
+    pub name: EnvLiteral<'a>,
+    /// the value of the environment variable, an empty [`StringLiteral`] for `NAME=` on its own
+    pub value: Argument<'a>,
+    pub span: Span,
 }
 
 /// low-level AST component that defines the name of an environment variable
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct EnvLiteral(pub OsString);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EnvLiteral<'a>(
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_os::os_cow"))] pub Cow<'a, OsStr>,
+    pub Span,
+);
 
 /// mid-level AST component that defines a shell substitution
 ///
 /// effectively an entire child AST the output of evaluating & executing this inner AST becomes the
 /// value of the [`ShellSubstitution`] during when evaluated
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ShellSubstitution(pub CommandLine);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ShellSubstitution<'a>(pub CommandLine<'a>, pub Span);
+
+/// mid-level AST component that defines a process substitution, `<(...)`
+///
+/// like [`ShellSubstitution`] this contains an entire child AST, but rather than its output, the
+/// path of a FIFO its output can be read from becomes the value when evaluated
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProcessSubstitution<'a>(pub CommandLine<'a>, pub Span);
 
 /// mid-level AST component that defines a string enclosed in double quotes
 ///
@@ -147,11 +268,16 @@ pub struct ShellSubstitution(pub CommandLine);
 /// they're represented as a vector of [`DoubleQuoteComponent`]s each of which is evaluated
 /// differently and then concatenated together during evaluation
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct DoubleQuoteString(pub Vec<DoubleQuoteComponent>);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DoubleQuoteString<'a>(pub Vec<DoubleQuoteComponent<'a>>, pub Span);
 
 /// low-level AST component that defines a string enclosed in single quotes
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct SingleQuoteString(pub OsString);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SingleQuoteString<'a>(
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_os::os_cow"))] pub Cow<'a, OsStr>,
+    pub Span,
+);
 
 /// mid-level AST component that defines a string not enclosed in quotes
 ///
@@ -159,31 +285,174 @@ pub struct SingleQuoteString(pub OsString);
 /// [`StringLiteralComponent`]s that are evaluated separately and then concatenated together during
 /// evaluation
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct StringLiteral(pub Vec<StringLiteralComponent>);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StringLiteral<'a>(pub Vec<StringLiteralComponent<'a>>, pub Span);
 
 /// low-level AST component that defines part of a [`DoubleQuoteString`]
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum DoubleQuoteComponent {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DoubleQuoteComponent<'a> {
     /// literal characters
-    Chars(Chars),
+    Chars(Chars<'a>),
+    /// a character escaped with a backslash
+    Escape(Escape),
     /// environment variable substitution
-    DollarEnv(DollarEnv),
+    DollarEnv(DollarEnv<'a>),
+    /// `$?`, the exit status of the last command
+    DollarStatus(Span),
     /// shell substitution
-    DollarShell(DollarShell),
+    DollarShell(DollarShell<'a>),
 }
 
 /// low-level AST component that defines part of a [`StringLiteral`]
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum StringLiteralComponent {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StringLiteralComponent<'a> {
     /// literal characters
-    RawChars(RawChars),
+    RawChars(RawChars<'a>),
     /// environment variable substitution
-    DollarEnv(DollarEnv),
+    DollarEnv(DollarEnv<'a>),
+    /// `$?`, the exit status of the last command
+    DollarStatus(Span),
 }
 
-/// low-level AST component that defines a environment variable substitution
+/// low-level AST component that defines a environment variable substitution, along with where it
+/// appears in the source for error reporting, and how to change the case of its value, for the
+/// `${NAME^^}` style of substitution
+///
+/// positional parameters (`$1`, `${10}`) are substituted the same way, with the number as the name
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct DollarEnv(pub EnvLiteral);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DollarEnv<'a>(
+    pub EnvLiteral<'a>,
+    pub Location,
+    pub Option<CaseModifier>,
+    pub Span,
+);
+
+/// changes the case of a substituted value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CaseModifier {
+    /// `^`, uppercases the first character
+    UpperFirst,
+    /// `^^`, uppercases every character
+    UpperAll,
+    /// `,`, lowercases the first character
+    LowerFirst,
+    /// `,,`, lowercases every character
+    LowerAll,
+}
+
+impl CaseModifier {
+    pub fn apply(self, value: &str) -> String {
+        let mut chars = value.chars();
+        match self {
+            CaseModifier::UpperAll => value.to_uppercase(),
+            CaseModifier::LowerAll => value.to_lowercase(),
+            CaseModifier::UpperFirst => match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            },
+            CaseModifier::LowerFirst => match chars.next() {
+                Some(first) => first.to_lowercase().chain(chars).collect(),
+                None => String::new(),
+            },
+        }
+    }
+}
+
+/// a position in the source a command line was parsed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Location {
+    /// the line, starting at 1
+    pub line: usize,
+    /// the column, starting at 1
+    pub column: usize,
+}
+
+impl Location {
+    /// the start of the input
+    pub const START: Self = Self { line: 1, column: 1 };
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// the characters an AST node was parsed from, as byte offsets into the input given to
+/// [`generate_ast`], so that errors (and anything else looking at the AST) can point back at them
+///
+/// the parts of a command line that came from an alias point into the alias's value instead
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// the part of `input` this covers, which has to be what the AST was parsed from
+    pub fn slice<'a>(&self, input: &'a str) -> &'a str {
+        &input[self.start..self.end]
+    }
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span<'_>) -> Self {
+        Self {
+            start: span.start(),
+            end: span.end(),
+        }
+    }
+}
+
+impl Argument<'_> {
+    pub fn span(&self) -> Span {
+        match self {
+            Argument::StringLiteral(x) => x.1,
+            Argument::SingleQuoteString(x) => x.1,
+            Argument::DoubleQuoteString(x) => x.1,
+            Argument::ShellSubstitution(x) => x.1,
+            Argument::ProcessSubstitution(x) => x.1,
+        }
+    }
+}
+
+impl Command<'_> {
+    pub fn span(&self) -> Span {
+        match self {
+            Command::StringLiteral(x) => x.1,
+            Command::SingleQuoteString(x) => x.1,
+            Command::DoubleQuoteString(x) => x.1,
+        }
+    }
+}
+
+impl DoubleQuoteComponent<'_> {
+    pub fn span(&self) -> Span {
+        match self {
+            DoubleQuoteComponent::Chars(x) => x.1,
+            DoubleQuoteComponent::Escape(x) => x.1,
+            DoubleQuoteComponent::DollarEnv(x) => x.3,
+            DoubleQuoteComponent::DollarStatus(x) => *x,
+            DoubleQuoteComponent::DollarShell(x) => x.1,
+        }
+    }
+}
+
+impl StringLiteralComponent<'_> {
+    pub fn span(&self) -> Span {
+        match self {
+            StringLiteralComponent::RawChars(x) => x.1,
+            StringLiteralComponent::DollarEnv(x) => x.3,
+            StringLiteralComponent::DollarStatus(x) => *x,
+        }
+    }
+}
 
 /// low-level AST component that defines a shell substitution inside a string using the `$()`
 /// syntax
@@ -191,25 +460,399 @@ pub struct DollarEnv(pub EnvLiteral);
 /// like [`ShellSubstitution`] this effectively contains an entire child AST that is evaluated and
 /// run to produce the final string value of this component
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct DollarShell(pub CommandLine);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DollarShell<'a>(pub CommandLine<'a>, pub Span);
 
 /// low-level AST component that defines literal characters that are inside a double quoted string
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Chars(pub OsString);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Chars<'a>(
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_os::os_cow"))] pub Cow<'a, OsStr>,
+    pub Span,
+);
+
+/// low-level AST component for `\"`, `\$` or `\\` inside a double quoted string, which stands for the
+/// character after the backslash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Escape(pub char, pub Span);
 
 /// low-level AST component that defines literal characters that aren't quoted
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct RawChars(pub OsString);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RawChars<'a>(
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_os::os_cow"))] pub Cow<'a, OsStr>,
+    pub Span,
+);
+
+// the `Display` impls below write an AST back out as shell syntax, in a canonical form: one space
+// between words, redirects after the arguments, and variables only braced when they have to be,
+// parsing the result gives back the same AST (apart from spans, unless it was already canonical)
+
+impl fmt::Display for Main<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for CommandLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut words = self
+            .envs
+            .iter()
+            .map(|x| x.to_string())
+            .chain(self.command.iter().map(|x| x.to_string()))
+            .chain(self.arguments.iter().map(|x| x.to_string()))
+            .chain(self.redirects.iter().map(|x| x.to_string()));
+        if let Some(first) = words.next() {
+            write!(f, "{}", first)?;
+        }
+        for word in words {
+            write!(f, " {}", word)?;
+        }
+        match &self.next {
+            Some((Separator::Semicolon, next)) => write!(f, "; {}", next),
+            Some((Separator::Pipe, next)) => write!(f, " | {}", next),
+            Some((Separator::Fork, next)) => write!(f, " & {}", next),
+            None if self.background => write!(f, " &"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Argument<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Argument::StringLiteral(x) => x.fmt(f),
+            Argument::SingleQuoteString(x) => x.fmt(f),
+            Argument::DoubleQuoteString(x) => x.fmt(f),
+            Argument::ShellSubstitution(x) => x.fmt(f),
+            Argument::ProcessSubstitution(x) => x.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for Command<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::StringLiteral(x) => x.fmt(f),
+            Command::SingleQuoteString(x) => x.fmt(f),
+            Command::DoubleQuoteString(x) => x.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for Redirection<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.arg {
+            Some(arg) => write!(f, "{} {}", self.op, arg),
+            None => self.op.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for CommandEnv<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+impl fmt::Display for EnvLiteral<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_string_lossy())
+    }
+}
+
+impl fmt::Display for ShellSubstitution<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({})", self.0)
+    }
+}
+
+impl fmt::Display for ProcessSubstitution<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<({})", self.0)
+    }
+}
+
+impl fmt::Display for DoubleQuoteString<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"")?;
+        for (i, component) in self.0.iter().enumerate() {
+            match component {
+                DoubleQuoteComponent::Chars(x) => x.fmt(f)?,
+                DoubleQuoteComponent::Escape(x) => x.fmt(f)?,
+                DoubleQuoteComponent::DollarEnv(x) => {
+                    let next = match self.0.get(i + 1) {
+                        Some(DoubleQuoteComponent::Chars(x)) => Some(&*x.0),
+                        _ => None,
+                    };
+                    x.write(f, next)?
+                }
+                DoubleQuoteComponent::DollarStatus(_) => write!(f, "$?")?,
+                DoubleQuoteComponent::DollarShell(x) => x.fmt(f)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+impl fmt::Display for SingleQuoteString<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}'", self.0.to_string_lossy())
+    }
+}
+
+impl fmt::Display for StringLiteral<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, component) in self.0.iter().enumerate() {
+            match component {
+                StringLiteralComponent::RawChars(x) => x.fmt(f)?,
+                StringLiteralComponent::DollarEnv(x) => {
+                    let next = match self.0.get(i + 1) {
+                        Some(StringLiteralComponent::RawChars(x)) => Some(&*x.0),
+                        _ => None,
+                    };
+                    x.write(f, next)?
+                }
+                StringLiteralComponent::DollarStatus(_) => write!(f, "$?")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DollarEnv<'_> {
+    /// writes the substitution, followed by the characters `next`, which would be taken as part of
+    /// the name if it started with them and wasn't braced
+    fn write(&self, f: &mut fmt::Formatter<'_>, next: Option<&OsStr>) -> fmt::Result {
+        let name = self.0 .0.to_string_lossy();
+        let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        let joins_next = next
+            .and_then(|x| x.to_string_lossy().chars().next())
+            .is_some_and(is_name_char);
+        // only a single digit is taken as a positional parameter without braces
+        let numeric = name.starts_with(|c: char| c.is_ascii_digit());
+        let modifier = match self.2 {
+            Some(CaseModifier::UpperFirst) => "^",
+            Some(CaseModifier::UpperAll) => "^^",
+            Some(CaseModifier::LowerFirst) => ",",
+            Some(CaseModifier::LowerAll) => ",,",
+            None => "",
+        };
+        match joins_next || (numeric && name.len() > 1) || !modifier.is_empty() {
+            true => write!(f, "${{{}{}}}", name, modifier),
+            false => write!(f, "${}", name),
+        }
+    }
+}
+
+impl fmt::Display for DollarEnv<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, None)
+    }
+}
+
+impl fmt::Display for DollarShell<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "$({})", self.0)
+    }
+}
+
+impl fmt::Display for Chars<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_string_lossy())
+    }
+}
+
+impl fmt::Display for Escape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\\{}", self.0)
+    }
+}
+
+impl fmt::Display for RawChars<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_string_lossy())
+    }
+}
+
+// an AST borrows its text from the input it was parsed from, `into_owned` copies it so that the
+// AST can outlive the input, e.g. to be stored somewhere
+
+impl Main<'_> {
+    pub fn into_owned(self) -> Main<'static> {
+        Main(self.0.into_owned())
+    }
+}
+
+impl CommandLine<'_> {
+    pub fn into_owned(self) -> CommandLine<'static> {
+        CommandLine {
+            envs: self.envs.into_iter().map(CommandEnv::into_owned).collect(),
+            command: self.command.map(Command::into_owned),
+            arguments: self
+                .arguments
+                .into_iter()
+                .map(Argument::into_owned)
+                .collect(),
+            redirects: self
+                .redirects
+                .into_iter()
+                .map(Redirection::into_owned)
+                .collect(),
+            next: self
+                .next
+                .map(|(sep, next)| (sep, Box::new(next.into_owned()))),
+            background: self.background,
+            span: self.span,
+        }
+    }
+}
+
+impl Argument<'_> {
+    pub fn into_owned(self) -> Argument<'static> {
+        match self {
+            Argument::StringLiteral(x) => Argument::StringLiteral(x.into_owned()),
+            Argument::SingleQuoteString(x) => Argument::SingleQuoteString(x.into_owned()),
+            Argument::DoubleQuoteString(x) => Argument::DoubleQuoteString(x.into_owned()),
+            Argument::ShellSubstitution(x) => Argument::ShellSubstitution(x.into_owned()),
+            Argument::ProcessSubstitution(x) => Argument::ProcessSubstitution(x.into_owned()),
+        }
+    }
+}
+
+impl Command<'_> {
+    pub fn into_owned(self) -> Command<'static> {
+        match self {
+            Command::StringLiteral(x) => Command::StringLiteral(x.into_owned()),
+            Command::SingleQuoteString(x) => Command::SingleQuoteString(x.into_owned()),
+            Command::DoubleQuoteString(x) => Command::DoubleQuoteString(x.into_owned()),
+        }
+    }
+}
+
+impl Redirection<'_> {
+    pub fn into_owned(self) -> Redirection<'static> {
+        Redirection {
+            op: self.op,
+            arg: self.arg.map(Argument::into_owned),
+            span: self.span,
+        }
+    }
+}
+
+impl CommandEnv<'_> {
+    pub fn into_owned(self) -> CommandEnv<'static> {
+        CommandEnv {
+            name: self.name.into_owned(),
+            value: self.value.into_owned(),
+            span: self.span,
+        }
+    }
+}
+
+impl EnvLiteral<'_> {
+    pub fn into_owned(self) -> EnvLiteral<'static> {
+        EnvLiteral(owned(self.0), self.1)
+    }
+}
+
+impl ShellSubstitution<'_> {
+    pub fn into_owned(self) -> ShellSubstitution<'static> {
+        ShellSubstitution(self.0.into_owned(), self.1)
+    }
+}
+
+impl ProcessSubstitution<'_> {
+    pub fn into_owned(self) -> ProcessSubstitution<'static> {
+        ProcessSubstitution(self.0.into_owned(), self.1)
+    }
+}
+
+impl DoubleQuoteString<'_> {
+    pub fn into_owned(self) -> DoubleQuoteString<'static> {
+        let components = self.0.into_iter().map(|x| match x {
+            DoubleQuoteComponent::Chars(x) => DoubleQuoteComponent::Chars(Chars(owned(x.0), x.1)),
+            DoubleQuoteComponent::Escape(x) => DoubleQuoteComponent::Escape(x),
+            DoubleQuoteComponent::DollarEnv(x) => DoubleQuoteComponent::DollarEnv(x.into_owned()),
+            DoubleQuoteComponent::DollarStatus(x) => DoubleQuoteComponent::DollarStatus(x),
+            DoubleQuoteComponent::DollarShell(x) => {
+                DoubleQuoteComponent::DollarShell(DollarShell(x.0.into_owned(), x.1))
+            }
+        });
+        DoubleQuoteString(components.collect(), self.1)
+    }
+}
+
+impl SingleQuoteString<'_> {
+    pub fn into_owned(self) -> SingleQuoteString<'static> {
+        SingleQuoteString(owned(self.0), self.1)
+    }
+}
+
+impl StringLiteral<'_> {
+    pub fn into_owned(self) -> StringLiteral<'static> {
+        let components = self.0.into_iter().map(|x| match x {
+            StringLiteralComponent::RawChars(x) => {
+                StringLiteralComponent::RawChars(RawChars(owned(x.0), x.1))
+            }
+            StringLiteralComponent::DollarEnv(x) => {
+                StringLiteralComponent::DollarEnv(x.into_owned())
+            }
+            StringLiteralComponent::DollarStatus(x) => StringLiteralComponent::DollarStatus(x),
+        });
+        StringLiteral(components.collect(), self.1)
+    }
+}
+
+impl DollarEnv<'_> {
+    pub fn into_owned(self) -> DollarEnv<'static> {
+        DollarEnv(self.0.into_owned(), self.1, self.2, self.3)
+    }
+}
+
+fn owned(text: Cow<'_, OsStr>) -> Cow<'static, OsStr> {
+    Cow::Owned(text.into_owned())
+}
 
 /// Parses a string into an AST acording to [`ShellParser`]
 ///
 /// returns a [`Main`] struct, the top-level struct of an AST.
 ///
-/// this function, and the implementations of [`FromPair`] that it relies on contain a large amount
-/// of [`unreachable!`] statements based on the parsing expression grammar defined in
-/// `src/grammar/shell.pest`, modify with caution
-pub fn generate_ast(expr: &str) -> Result<Main, AstError> {
-    let pairs = match ShellParser::parse(Rule::Main, expr) {
+/// the implementations of [`FromPair`] that this relies on expect the pairs they're given to have
+/// the shape the parsing expression grammar in `src/grammar/shell.pest` gives them, if the two
+/// drift apart the result is an [`AstError::RuleMismatch`] or [`AstError::MalformedPair`] rather
+/// than a panic
+///
+/// `\r\n` is read as `\n`, quotes included, so input written on windows runs the same, see
+/// [`normalize_line_endings`]
+pub fn generate_ast(expr: &str) -> Result<Main<'_>, AstError> {
+    let _timer = profile::start(Phase::Parse);
+    let ast = match normalize_line_endings(expr) {
+        Cow::Borrowed(x) => parse_main(x),
+        Cow::Owned(x) => parse_main(&x).map(Main::into_owned),
+    };
+    if ast.is_err() {
+        metrics::parse_failed();
+    }
+    ast
+}
+
+/// `input` with every `\r\n` turned into `\n`, the spans of an AST point into this rather than
+/// the input itself, so it's what errors about the AST are rendered against
+pub(crate) fn normalize_line_endings(input: &str) -> Cow<'_, str> {
+    match input.contains("\r\n") {
+        true => Cow::Owned(input.replace("\r\n", "\n")),
+        false => Cow::Borrowed(input),
+    }
+}
+
+fn parse_main(expr: &str) -> Result<Main<'_>, AstError> {
+    if nesting_depth(expr) > MAX_NESTING {
+        return Err(AstError::TooDeep { limit: MAX_NESTING });
+    }
+    let mut pairs = match ShellParser::parse(Rule::Main, expr) {
         Ok(x) => x,
         Err(e) => {
             return Err(AstError::ParseError {
@@ -219,144 +862,262 @@ pub fn generate_ast(expr: &str) -> Result<Main, AstError> {
         }
     };
 
-    let main = Main::from_pair(
-        pairs
-            .into_iter()
-            .next()
-            .expect("result of parsing Rule::Main must contain an inner pair"),
-    )?;
+    let source = Source::new(expr);
+    let main = Main::from_pair(next_pair(&mut pairs, "Main", expr)?, &source)?;
     Ok(main)
 }
 
-impl FromPair for Main {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+/// the input an AST is being built from, along with the byte offset at which each of its lines
+/// starts, so that the [`Location`] of any offset can be found without counting lines from the start
+struct Source<'i> {
+    input: &'i str,
+    line_starts: Vec<usize>,
+    /// the last location worked out along with its offset, the AST is built in the order it was
+    /// written, so a location later on the same line carries on from there rather than counting
+    /// columns from the start of the line, which would take time quadratic in its length
+    last: Cell<(usize, Location)>,
+}
+
+impl<'i> Source<'i> {
+    fn new(input: &'i str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(input.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            input,
+            line_starts,
+            last: Cell::new((0, Location::START)),
+        }
+    }
+
+    /// the location of the byte offset `pos`
+    fn location(&self, pos: usize) -> Location {
+        // there's always a line starting at 0, so this is at least 1
+        let line = self.line_starts.partition_point(|&x| x <= pos);
+        let start = self.line_starts[line - 1];
+        let (last, location) = self.last.get();
+        let (from, column) = match location.line == line && last <= pos {
+            true => (last, location.column),
+            false => (start, 1),
+        };
+        let location = Location {
+            line,
+            column: column + self.input[from..pos].chars().count(),
+        };
+        self.last.set((pos, location));
+        location
+    }
+}
+
+/// the deepest nesting of parentheses [`generate_ast`] accepts
+pub const MAX_NESTING: usize = 256;
+
+/// how deeply parentheses are nested in `expr`, ignoring any in single quoted strings or escaped
+/// with a backslash
+///
+/// parentheses in double quoted strings still count since substitutions nest inside them, this
+/// is only a rough guard against input that would blow the stack so it doesn't need to be exact
+fn nesting_depth(expr: &str) -> usize {
+    let mut depth = 0usize;
+    let mut max = 0;
+    let mut single = false;
+    let mut double = false;
+    let mut chars = expr.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if !double => single = !single,
+            '"' if !single => double = !double,
+            '\\' if !single => {
+                chars.next();
+            }
+            '(' if !single => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            ')' if !single => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+    max
+}
+
+impl<'i> FromPair<'i> for Main<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::Main {
             return Err(AstError::RuleMismatch {
                 node_type: "Main",
                 pair_type: pair.as_rule(),
             });
         }
+        let text = pair.as_str();
         Ok(Self(CommandLine::from_pair(
-            pair.into_inner()
-                .next()
-                .expect("Main Pair must contain CommandLine"),
+            next_pair(&mut pair.into_inner(), "Main", text)?,
+            source,
         )?))
     }
 }
 
-impl FromPair for CommandLine {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
-        let mut envs = Vec::new();
-        let mut command = None;
-        let mut arguments = Vec::new();
-        let mut redirects = Vec::new();
-        let mut next_sep = None;
-        let mut next_cmd = None;
+impl<'i> FromPair<'i> for CommandLine<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
+        let end = pair.as_span().end();
+        let text = pair.as_str();
 
+        // each command of the chain along with the separator before it, they're only linked
+        // together at the end, working backwards, since each one owns the rest of the chain
+        let mut chain: Vec<(Option<Separator>, Self)> = Vec::new();
+        let mut separator = None;
+        let mut current: Option<Self> = None;
         for inner in pair.into_inner() {
+            let start = inner.as_span().start();
+            let cmd = current.get_or_insert_with(|| Self {
+                envs: Vec::new(),
+                command: None,
+                arguments: Vec::new(),
+                redirects: Vec::new(),
+                next: None,
+                background: false,
+                span: Span { start, end },
+            });
             match inner.as_rule() {
-                Rule::CommandEnv => envs.push(CommandEnv::from_pair(inner)?),
-                Rule::Command => command = Some(Command::from_pair(inner)?),
-                Rule::Argument => arguments.push(Argument::from_pair(inner)?),
-                Rule::Redirection => redirects.push(Redirection::from_pair(inner)?),
-                Rule::Separator => next_sep = Some(Separator::from_pair(inner)?),
-                Rule::CommandLine => next_cmd = Some(CommandLine::from_pair(inner)?),
-                _ => unreachable!("CommandLine can only contain CommandEnv, Command, Argument, Redirection, Separator, or CommandLine"),
+                Rule::CommandEnv => cmd.envs.push(CommandEnv::from_pair(inner, source)?),
+                Rule::Command => cmd.command = Some(Command::from_pair(inner, source)?),
+                Rule::Argument => cmd.arguments.push(Argument::from_pair(inner, source)?),
+                Rule::Redirection => cmd.redirects.push(Redirection::from_pair(inner, source)?),
+                Rule::Background => cmd.background = true,
+                Rule::Separator => {
+                    let cmd = current
+                        .take()
+                        .ok_or_else(|| malformed("CommandLine", text))?;
+                    chain.push((separator.take(), cmd));
+                    separator = Some(Separator::from_pair(inner, source)?);
+                }
+                _ => return Err(mismatch("CommandLine", &inner)),
             }
         }
-
-        let next = if let Some(sep) = next_sep {
-            Some((
-                sep,
-                Box::new(
-                    next_cmd
-                        .expect("if CommandLine has Seperator it must also have child CommandLine"),
-                ),
-            ))
-        } else {
-            None
-        };
-
-        Ok(Self {
-            envs,
-            command: command.expect("Commandline must contain Command"),
-            arguments,
-            redirects,
-            next,
-        })
+        // a separator is always followed by another command
+        let mut cmd = current.ok_or_else(|| malformed("CommandLine", text))?;
+        while let Some((before, mut prev)) = chain.pop() {
+            let sep = separator.ok_or_else(|| malformed("CommandLine", text))?;
+            prev.next = Some((sep, Box::new(cmd)));
+            cmd = prev;
+            separator = before;
+        }
+        Ok(cmd)
     }
 }
 
-impl FromPair for Argument {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for Argument<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::Argument {
             return Err(AstError::RuleMismatch {
                 node_type: "Argument",
                 pair_type: pair.as_rule(),
             });
         }
-        let inner = pair
-            .into_inner()
-            .next()
-            .expect("Argument must contain inner pair");
+        let text = pair.as_str();
+        let inner = next_pair(&mut pair.into_inner(), "Argument", text)?;
         Ok(match inner.as_rule() {
-            Rule::ShellSubstitution => Self::ShellSubstitution(ShellSubstitution::from_pair(inner)?),
-            Rule::SingleQuoteString => Self::SingleQuoteString(SingleQuoteString::from_pair(inner)?),
-            Rule::DoubleQuoteString => Self::DoubleQuoteString(DoubleQuoteString::from_pair(inner)?),
-            Rule::StringLiteral => Self::StringLiteral(StringLiteral::from_pair(inner)?),
-            _ => unreachable!(
-                "Argument can only contain ShellSubstitution, SingleQuoteString, DoubleQuoteString, or StringLiteral"
-            ),
+            Rule::ShellSubstitution => {
+                Self::ShellSubstitution(ShellSubstitution::from_pair(inner, source)?)
+            }
+            Rule::ProcessSubstitution => {
+                Self::ProcessSubstitution(ProcessSubstitution::from_pair(inner, source)?)
+            }
+            Rule::SingleQuoteString => {
+                Self::SingleQuoteString(SingleQuoteString::from_pair(inner, source)?)
+            }
+            Rule::DoubleQuoteString => {
+                Self::DoubleQuoteString(DoubleQuoteString::from_pair(inner, source)?)
+            }
+            Rule::StringLiteral => Self::StringLiteral(StringLiteral::from_pair(inner, source)?),
+            _ => return Err(mismatch("Argument", &inner)),
         })
     }
 }
 
-impl FromPair for Command {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for Command<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::Command {
             return Err(AstError::RuleMismatch {
                 node_type: "Command",
                 pair_type: pair.as_rule(),
             });
         }
-        let inner = pair
-            .into_inner()
-            .next()
-            .expect("Command must contain inner pair");
+        let text = pair.as_str();
+        let inner = next_pair(&mut pair.into_inner(), "Command", text)?;
         Ok(match inner.as_rule() {
             Rule::SingleQuoteString => {
-                Self::SingleQuoteString(SingleQuoteString::from_pair(inner)?)
+                Self::SingleQuoteString(SingleQuoteString::from_pair(inner, source)?)
             }
             Rule::DoubleQuoteString => {
-                Self::DoubleQuoteString(DoubleQuoteString::from_pair(inner)?)
+                Self::DoubleQuoteString(DoubleQuoteString::from_pair(inner, source)?)
             }
-            Rule::StringLiteral => Self::StringLiteral(StringLiteral::from_pair(inner)?),
-            _ => unreachable!(
-                "Command can only contain SingleQuoteString, DoubleQuoteString, or StringLiteral"
-            ),
+            Rule::StringLiteral => Self::StringLiteral(StringLiteral::from_pair(inner, source)?),
+            _ => return Err(mismatch("Command", &inner)),
         })
     }
 }
 
-impl FromPair for Redirection {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for Redirection<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::Redirection {
             return Err(AstError::RuleMismatch {
                 node_type: "Redirection",
                 pair_type: pair.as_rule(),
             });
         }
-        const ERR_MSG: &str = "Redirection must contain at least two inner pairs";
+        let span = pair.as_span().into();
+        let text = pair.as_str();
         let mut inner = pair.into_inner();
+        let op = next_pair(&mut inner, "Redirection", text)?;
+        if op.as_rule() == Rule::DupOp {
+            let op_span = op.as_span().into();
+            let mut parts = op.into_inner();
+            let mut part = next_pair(&mut parts, "DupOp", text)?;
+            let mut fd = RedirectFd::Default;
+            if part.as_rule() == Rule::RedirectFd {
+                fd = RedirectFd::from_pair(part, source)?;
+                part = next_pair(&mut parts, "DupOp", text)?;
+            }
+            return Ok(Self {
+                op: RedirectOp {
+                    fd,
+                    r#type: RedirectType::Duplicate(RedirectFd::from_number(part.as_str())?),
+                    span: op_span,
+                },
+                arg: None,
+                span,
+            });
+        }
+        if op.as_rule() == Rule::CloseOp {
+            let op_span = op.as_span().into();
+            let fd = match op.into_inner().next() {
+                Some(x) => RedirectFd::from_pair(x, source)?,
+                None => RedirectFd::Default,
+            };
+            return Ok(Self {
+                op: RedirectOp {
+                    fd,
+                    r#type: RedirectType::Close,
+                    span: op_span,
+                },
+                arg: None,
+                span,
+            });
+        }
         Ok(Self {
-            op: RedirectOp::from_pair(inner.next().expect(ERR_MSG))?,
-            arg: Argument::from_pair(inner.next().expect(ERR_MSG))?,
+            op: RedirectOp::from_pair(op, source)?,
+            arg: Some(Argument::from_pair(
+                next_pair(&mut inner, "Redirection", text)?,
+                source,
+            )?),
+            span,
         })
     }
 }
 
-impl FromPair for RedirectOp {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for RedirectOp {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::RedirectOp {
             return Err(AstError::RuleMismatch {
                 node_type: "RedirectOp",
@@ -364,43 +1125,68 @@ impl FromPair for RedirectOp {
             });
         }
         let mut fd = RedirectFd::Default;
+        let span = pair.as_span().into();
 
+        let text = pair.as_str();
         let mut inner = pair.into_inner();
-        let mut next = inner.next().expect("RedirectOp must contain inner pair");
+        let mut next = next_pair(&mut inner, "RedirectOp", text)?;
         if let Rule::RedirectFd = next.as_rule() {
-            fd = RedirectFd::from_pair(next)?;
-            next = inner
-                .next()
-                .expect("RedirectOp must contain a RedirectType");
+            fd = RedirectFd::from_pair(next, source)?;
+            next = next_pair(&mut inner, "RedirectOp", text)?;
         }
 
         Ok(Self {
             fd,
-            r#type: RedirectType::from_pair(next)?,
+            r#type: RedirectType::from_pair(next, source)?,
+            span,
         })
     }
 }
 
-impl FromPair for RedirectFd {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for RedirectFd {
+    fn from_pair(pair: Pair<'i, Rule>, _source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::RedirectFd {
             return Err(AstError::RuleMismatch {
                 node_type: "RedirectFd",
                 pair_type: pair.as_rule(),
             });
         }
-        Ok(match pair.as_str() {
-            "&" => RedirectFd::All,
-            "0" => RedirectFd::Stdin,
-            "1" => RedirectFd::Stdout,
-            "2" => RedirectFd::Stderr,
-            _ => unreachable!("only '&', '0', '1', & '2' are valid redirect fds"),
+        if pair.as_str() == "&" {
+            return Ok(RedirectFd::All);
+        }
+        RedirectFd::from_number(pair.as_str())
+    }
+}
+
+impl RedirectFd {
+    fn from_number(text: &str) -> Result<Self, AstError> {
+        Ok(match text.parse::<u32>() {
+            Ok(0) => RedirectFd::Stdin,
+            Ok(1) => RedirectFd::Stdout,
+            Ok(2) => RedirectFd::Stderr,
+            Ok(x) => RedirectFd::Fd(x),
+            Err(_) => {
+                return Err(AstError::InvalidFd {
+                    fd: text.to_owned(),
+                })
+            }
         })
     }
+
+    /// the number of the fd, if it's a single one that was given (or implied) by number
+    pub fn number(&self) -> Option<u32> {
+        match self {
+            RedirectFd::Stdin => Some(0),
+            RedirectFd::Stdout => Some(1),
+            RedirectFd::Stderr => Some(2),
+            RedirectFd::Fd(x) => Some(*x),
+            RedirectFd::All | RedirectFd::Default => None,
+        }
+    }
 }
 
-impl FromPair for RedirectType {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for RedirectType {
+    fn from_pair(pair: Pair<'i, Rule>, _source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::RedirectType {
             return Err(AstError::RuleMismatch {
                 node_type: "RedirectType",
@@ -411,13 +1197,14 @@ impl FromPair for RedirectType {
             ">>" => RedirectType::OutAppend,
             ">" => RedirectType::Out,
             "<" => RedirectType::In,
-            _ => unreachable!("Separator can only be '>>', '>', or '<'"),
+            "<<<" => RedirectType::HereString,
+            x => return Err(malformed("RedirectType", x)),
         })
     }
 }
 
-impl FromPair for Separator {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for Separator {
+    fn from_pair(pair: Pair<'i, Rule>, _source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::Separator {
             return Err(AstError::RuleMismatch {
                 node_type: "Separator",
@@ -428,202 +1215,267 @@ impl FromPair for Separator {
             ";" => Separator::Semicolon,
             "|" => Separator::Pipe,
             "&" => Separator::Fork,
-            _ => unreachable!("Separator can only be ';', '|', or '&'"),
+            x => return Err(malformed("Separator", x)),
         })
     }
 }
 
-impl FromPair for CommandEnv {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for CommandEnv<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::CommandEnv {
             return Err(AstError::RuleMismatch {
                 node_type: "CommandEnv",
                 pair_type: pair.as_rule(),
             });
         }
-        const ERR_MSG: &str = "CommandEnv must contain at least two inner pairs";
+        let span: Span = pair.as_span().into();
+        let text = pair.as_str();
         let mut inner = pair.into_inner();
-        Ok(Self {
-            name: EnvLiteral::from_pair(inner.next().expect(ERR_MSG))?,
-            value: Argument::from_pair(inner.next().expect(ERR_MSG))?,
-        })
+        let name = EnvLiteral::from_pair(next_pair(&mut inner, "CommandEnv", text)?, source)?;
+        let value = match inner.next() {
+            Some(x) => Argument::from_pair(x, source)?,
+            None => {
+                let end = Span {
+                    start: span.end,
+                    end: span.end,
+                };
+                Argument::StringLiteral(StringLiteral(Vec::new(), end))
+            }
+        };
+        Ok(Self { name, value, span })
     }
 }
 
-impl FromPair for EnvLiteral {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for EnvLiteral<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, _source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::EnvLiteral {
             return Err(AstError::RuleMismatch {
                 node_type: "EnvLiteral",
                 pair_type: pair.as_rule(),
             });
         }
-        Ok(Self(pair.as_str().into()))
+        Ok(Self(text(&pair), pair.as_span().into()))
     }
 }
 
-impl FromPair for ShellSubstitution {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for ShellSubstitution<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::ShellSubstitution {
             return Err(AstError::RuleMismatch {
                 node_type: "ShellSubstitution",
                 pair_type: pair.as_rule(),
             });
         }
-        Ok(Self(CommandLine::from_pair(
-            pair.into_inner()
-                .next()
-                .expect("ShellSubstitution must contain inner pair"),
-        )?))
+        let span = pair.as_span().into();
+        let text = pair.as_str();
+        Ok(Self(
+            CommandLine::from_pair(
+                next_pair(&mut pair.into_inner(), "ShellSubstitution", text)?,
+                source,
+            )?,
+            span,
+        ))
+    }
+}
+
+impl<'i> FromPair<'i> for ProcessSubstitution<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
+        if pair.as_rule() != Rule::ProcessSubstitution {
+            return Err(AstError::RuleMismatch {
+                node_type: "ProcessSubstitution",
+                pair_type: pair.as_rule(),
+            });
+        }
+        let span = pair.as_span().into();
+        let text = pair.as_str();
+        Ok(Self(
+            CommandLine::from_pair(
+                next_pair(&mut pair.into_inner(), "ProcessSubstitution", text)?,
+                source,
+            )?,
+            span,
+        ))
     }
 }
 
-impl FromPair for DoubleQuoteString {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for DoubleQuoteString<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::DoubleQuoteString {
             return Err(AstError::RuleMismatch {
                 node_type: "DoubleQuoteString",
                 pair_type: pair.as_rule(),
             });
         }
+        let span = pair.as_span().into();
         Ok(Self(
             pair.into_inner()
-                .map(|inner| DoubleQuoteComponent::from_pair(inner))
+                .map(|x| DoubleQuoteComponent::from_pair(x, source))
                 .collect::<Result<Vec<_>, _>>()?,
+            span,
         ))
     }
 }
 
-impl FromPair for SingleQuoteString {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for SingleQuoteString<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, _source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::SingleQuoteString {
             return Err(AstError::RuleMismatch {
                 node_type: "SingleQuoteString",
                 pair_type: pair.as_rule(),
             });
         }
-        Ok(Self(
-            pair.into_inner()
-                .next()
-                .expect("SingleQuoteString must contain inner pair")
-                .as_str()
-                .into(),
-        ))
+        let span = pair.as_span().into();
+        let outer = pair.as_str();
+        let inner = next_pair(&mut pair.into_inner(), "SingleQuoteString", outer)?;
+        Ok(Self(text(&inner), span))
     }
 }
 
-impl FromPair for StringLiteral {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for StringLiteral<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::StringLiteral {
             return Err(AstError::RuleMismatch {
                 node_type: "StringLiteral",
                 pair_type: pair.as_rule(),
             });
         }
+        let span = pair.as_span().into();
         Ok(Self(
             pair.into_inner()
-                .map(|inner| StringLiteralComponent::from_pair(inner))
+                .map(|x| StringLiteralComponent::from_pair(x, source))
                 .collect::<Result<Vec<_>, _>>()?,
+            span,
         ))
     }
 }
 
-impl FromPair for DoubleQuoteComponent {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for DoubleQuoteComponent<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::DoubleQuoteComponent {
             return Err(AstError::RuleMismatch {
                 node_type: "DoubleQuoteComponent",
                 pair_type: pair.as_rule(),
             });
         }
-        let inner = pair
-            .into_inner()
-            .next()
-            .expect("DoubleQuoteComponent must contain inner pair");
+        let text = pair.as_str();
+        let inner = next_pair(&mut pair.into_inner(), "DoubleQuoteComponent", text)?;
         Ok(match inner.as_rule() {
-            Rule::Chars => Self::Chars(Chars::from_pair(inner)?),
-            Rule::DollarEnv => Self::DollarEnv(DollarEnv::from_pair(inner)?),
-            Rule::DollarShell => Self::DollarShell(DollarShell::from_pair(inner)?),
-            _ => unreachable!(
-                "DoubleQuoteComponent can only contain Chars, DollarEnv, or DollarShell"
-            ),
+            Rule::Chars => Self::Chars(Chars::from_pair(inner, source)?),
+            Rule::Escape => Self::Escape(Escape::from_pair(inner, source)?),
+            Rule::DollarEnv => Self::DollarEnv(DollarEnv::from_pair(inner, source)?),
+            Rule::DollarStatus => Self::DollarStatus(inner.as_span().into()),
+            Rule::DollarShell => Self::DollarShell(DollarShell::from_pair(inner, source)?),
+            _ => return Err(mismatch("DoubleQuoteComponent", &inner)),
         })
     }
 }
 
-impl FromPair for StringLiteralComponent {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for StringLiteralComponent<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::StringLiteralComponent {
             return Err(AstError::RuleMismatch {
                 node_type: "StringLiteralComponent",
                 pair_type: pair.as_rule(),
             });
         }
-        let inner = pair
-            .into_inner()
-            .next()
-            .expect("StringLiteralComponent must contain inner pair");
+        let text = pair.as_str();
+        let inner = next_pair(&mut pair.into_inner(), "StringLiteralComponent", text)?;
         Ok(match inner.as_rule() {
-            Rule::RawChars => Self::RawChars(RawChars::from_pair(inner)?),
-            Rule::DollarEnv => Self::DollarEnv(DollarEnv::from_pair(inner)?),
-            _ => unreachable!("StringLiteralComponent can only contain RawChars or DollarEnv"),
+            Rule::RawChars => Self::RawChars(RawChars::from_pair(inner, source)?),
+            Rule::DollarEnv => Self::DollarEnv(DollarEnv::from_pair(inner, source)?),
+            Rule::DollarStatus => Self::DollarStatus(inner.as_span().into()),
+            _ => return Err(mismatch("StringLiteralComponent", &inner)),
         })
     }
 }
 
-impl FromPair for DollarEnv {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for DollarEnv<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::DollarEnv {
             return Err(AstError::RuleMismatch {
                 node_type: "DollarEnv",
                 pair_type: pair.as_rule(),
             });
         }
-        Ok(Self(EnvLiteral::from_pair(
-            pair.into_inner()
-                .next()
-                .expect("DollarEnv Pair must contain inner pair"),
-        )?))
+        let span = pair.as_span();
+        let location = source.location(span.start());
+        let mut inner = pair.into_inner();
+        let name = next_pair(&mut inner, "DollarEnv", span.as_str())?;
+        let name = match name.as_rule() {
+            Rule::PositionalParam | Rule::PositionalDigit => {
+                EnvLiteral(text(&name), name.as_span().into())
+            }
+            _ => EnvLiteral::from_pair(name, source)?,
+        };
+        let modifier = match inner.next().as_ref().map(Pair::as_str) {
+            Some("^") => Some(CaseModifier::UpperFirst),
+            Some("^^") => Some(CaseModifier::UpperAll),
+            Some(",") => Some(CaseModifier::LowerFirst),
+            Some(",,") => Some(CaseModifier::LowerAll),
+            Some(x) => return Err(malformed("CaseModifier", x)),
+            None => None,
+        };
+        Ok(Self(name, location, modifier, span.into()))
     }
 }
 
-impl FromPair for DollarShell {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for DollarShell<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::DollarShell {
             return Err(AstError::RuleMismatch {
                 node_type: "DollarShell",
                 pair_type: pair.as_rule(),
             });
         }
-        Ok(Self(CommandLine::from_pair(
-            pair.into_inner()
-                .next()
-                .expect("DollarShell Pair must contain inner pair"),
-        )?))
+        let span = pair.as_span().into();
+        let text = pair.as_str();
+        Ok(Self(
+            CommandLine::from_pair(
+                next_pair(&mut pair.into_inner(), "DollarShell", text)?,
+                source,
+            )?,
+            span,
+        ))
     }
 }
 
-impl FromPair for Chars {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for Chars<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, _source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::Chars {
             return Err(AstError::RuleMismatch {
                 node_type: "Chars",
                 pair_type: pair.as_rule(),
             });
         }
-        Ok(Chars(pair.as_str().into()))
+        Ok(Chars(text(&pair), pair.as_span().into()))
+    }
+}
+
+impl<'i> FromPair<'i> for Escape {
+    fn from_pair(pair: Pair<'i, Rule>, _source: &Source) -> Result<Self, AstError> {
+        if pair.as_rule() != Rule::Escape {
+            return Err(AstError::RuleMismatch {
+                node_type: "Escape",
+                pair_type: pair.as_rule(),
+            });
+        }
+        let text = pair.as_str();
+        match text.chars().nth(1) {
+            Some(x) => Ok(Escape(x, pair.as_span().into())),
+            None => Err(mismatch("Escape", &pair)),
+        }
     }
 }
 
-impl FromPair for RawChars {
-    fn from_pair(pair: Pair<Rule>) -> Result<Self, AstError> {
+impl<'i> FromPair<'i> for RawChars<'i> {
+    fn from_pair(pair: Pair<'i, Rule>, _source: &Source) -> Result<Self, AstError> {
         if pair.as_rule() != Rule::RawChars {
             return Err(AstError::RuleMismatch {
                 node_type: "RawChars",
                 pair_type: pair.as_rule(),
             });
         }
-        Ok(RawChars(pair.as_str().into()))
+        Ok(RawChars(text(&pair), pair.as_span().into()))
     }
 }
 
@@ -631,28 +1483,430 @@ impl FromPair for RawChars {
 mod tests {
     use super::*;
 
+    fn span(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    #[test]
+    fn source_locations() {
+        let source = Source::new("ab\ncdé f\n\ng");
+        let at = |line, column| Location { line, column };
+        assert_eq!(source.location(0), at(1, 1));
+        assert_eq!(source.location(4), at(2, 2));
+        // `é` is two bytes but one column
+        assert_eq!(source.location(8), at(2, 5));
+        assert_eq!(source.location(11), at(4, 1));
+        // going backwards starts over from the line rather than carrying on
+        assert_eq!(source.location(1), at(1, 2));
+        assert_eq!(source.location(7), at(2, 4));
+    }
+
     #[test]
     fn basic_ast_gen() {
         let manual_ast = Main(CommandLine {
             envs: Vec::new(),
-            command: Command::StringLiteral(StringLiteral(vec![StringLiteralComponent::RawChars(
-                RawChars("test".into()),
-            )])),
+            command: Some(Command::StringLiteral(StringLiteral(
+                vec![StringLiteralComponent::RawChars(RawChars(
+                    OsStr::new("test").into(),
+                    span(0, 4),
+                ))],
+                span(0, 4),
+            ))),
             arguments: vec![
-                Argument::StringLiteral(StringLiteral(vec![StringLiteralComponent::RawChars(
-                    RawChars("0".into()),
-                )])),
-                Argument::SingleQuoteString(SingleQuoteString("1".into())),
-                Argument::DoubleQuoteString(DoubleQuoteString(vec![DoubleQuoteComponent::Chars(
-                    Chars("2".into()),
-                )])),
+                Argument::StringLiteral(StringLiteral(
+                    vec![StringLiteralComponent::RawChars(RawChars(
+                        OsStr::new("0").into(),
+                        span(5, 6),
+                    ))],
+                    span(5, 6),
+                )),
+                Argument::SingleQuoteString(SingleQuoteString(OsStr::new("1").into(), span(7, 10))),
+                Argument::DoubleQuoteString(DoubleQuoteString(
+                    vec![DoubleQuoteComponent::Chars(Chars(
+                        OsStr::new("2").into(),
+                        span(12, 13),
+                    ))],
+                    span(11, 14),
+                )),
             ],
             redirects: vec![],
             next: None,
+            background: false,
+            span: span(0, 14),
         });
 
         let gen_ast = generate_ast("test 0 '1' \"2\"").unwrap();
 
         assert_eq!(gen_ast, manual_ast);
     }
+
+    #[test]
+    fn here_string_redirect() {
+        let ast = generate_ast("cat <<< 'a b' < in").unwrap();
+        let types = ast
+            .0
+            .redirects
+            .iter()
+            .map(|x| x.op.r#type.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec![RedirectType::HereString, RedirectType::In]);
+    }
+
+    #[test]
+    fn close_redirect() {
+        let ast = generate_ast("cmd 2>&- >&- > out").unwrap();
+        let ops = ast
+            .0
+            .redirects
+            .iter()
+            .map(|x| (x.op.to_string(), x.arg.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            vec![
+                ("2>&-".to_owned(), false),
+                (">&-".to_owned(), false),
+                (">".to_owned(), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_redirect() {
+        let ast = generate_ast("cmd > out 2>&1 >&2 &>&0").unwrap();
+        let ops = ast
+            .0
+            .redirects
+            .iter()
+            .map(|x| (x.op.to_string(), x.arg.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            vec![
+                (">".to_owned(), true),
+                ("2>&1".to_owned(), false),
+                (">&2".to_owned(), false),
+                ("&>&0".to_owned(), false)
+            ]
+        );
+        assert_eq!(
+            ast.0.redirects[1].op.r#type,
+            RedirectType::Duplicate(RedirectFd::Stdout)
+        );
+    }
+
+    #[test]
+    fn braced_variables() {
+        let ast = generate_ast("echo ${a^^}${b} \"${c,}\"").unwrap();
+        let Argument::StringLiteral(StringLiteral(components, _)) = &ast.0.arguments[0] else {
+            panic!("expected a string literal");
+        };
+        let modifiers = components
+            .iter()
+            .map(|x| match x {
+                StringLiteralComponent::DollarEnv(x) => x.2,
+                _ => panic!("expected a variable"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(modifiers, [Some(CaseModifier::UpperAll), None]);
+        let Argument::DoubleQuoteString(DoubleQuoteString(components, _)) = &ast.0.arguments[1]
+        else {
+            panic!("expected a double quoted string");
+        };
+        assert!(matches!(
+            components[0],
+            DoubleQuoteComponent::DollarEnv(DollarEnv(_, _, Some(CaseModifier::LowerFirst), _))
+        ));
+        assert_eq!(CaseModifier::UpperFirst.apply("élan"), "Élan");
+    }
+
+    #[test]
+    fn positional_parameters() {
+        let ast = generate_ast("echo $12 ${12}").unwrap();
+        let names = ast
+            .0
+            .arguments
+            .iter()
+            .map(|x| match x {
+                Argument::StringLiteral(StringLiteral(x, _)) => x.clone(),
+                _ => panic!("expected a string literal"),
+            })
+            .collect::<Vec<_>>();
+        // only a single digit is taken without braces
+        assert!(matches!(
+            &names[0][..],
+            [
+                StringLiteralComponent::DollarEnv(DollarEnv(EnvLiteral(x, _), _, None, _)),
+                StringLiteralComponent::RawChars(_),
+            ] if &**x == "1"
+        ));
+        assert!(matches!(
+            &names[1][..],
+            [StringLiteralComponent::DollarEnv(DollarEnv(EnvLiteral(x, _), _, None, _))] if &**x == "12"
+        ));
+    }
+
+    #[test]
+    fn assignment_only() {
+        let ast = generate_ast("A=1 B=2; A=3 cmd").unwrap();
+        assert_eq!(ast.0.envs.len(), 2);
+        assert!(ast.0.command.is_none());
+        let (_, next) = ast.0.next.unwrap();
+        assert_eq!(next.envs.len(), 1);
+        assert!(next.command.is_some());
+    }
+
+    #[test]
+    fn empty_values() {
+        let line = "cmd \"\" ''; A= B=\"\" c";
+        let ast = generate_ast(line).unwrap().0;
+        assert!(matches!(
+            &ast.arguments[..],
+            [Argument::DoubleQuoteString(x), Argument::SingleQuoteString(y)]
+                if x.0.is_empty() && y.0.is_empty()
+        ));
+        let next = &ast.next.as_ref().unwrap().1;
+        let value = &next.envs[0].value;
+        assert!(matches!(value, Argument::StringLiteral(x) if x.0.is_empty()));
+        assert_eq!(value.span(), Span { start: 13, end: 13 });
+        assert_eq!(next.envs[0].span.slice(line), "A=");
+        assert_eq!(next.envs[1].value.span().slice(line), "\"\"");
+        assert_eq!(generate_ast(line).unwrap().to_string(), line);
+    }
+
+    #[test]
+    fn numeric_redirect_fds() {
+        let ast = generate_ast("cmd 02>a 10>b 3<c").unwrap();
+        let fds = ast
+            .0
+            .redirects
+            .iter()
+            .map(|x| x.op.fd.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fds,
+            vec![RedirectFd::Stderr, RedirectFd::Fd(10), RedirectFd::Fd(3)]
+        );
+        assert!(matches!(
+            generate_ast("cmd 99999999999>a"),
+            Err(AstError::InvalidFd { .. })
+        ));
+    }
+
+    #[test]
+    fn spans() {
+        let line = "A=\"$x\" cmd --flag 2>&- < (get path) | next";
+        let ast = generate_ast(line).unwrap().0;
+        assert_eq!(ast.span.slice(line), line);
+        assert_eq!(ast.envs[0].span.slice(line), "A=\"$x\"");
+        assert_eq!(ast.envs[0].value.span().slice(line), "\"$x\"");
+        assert_eq!(ast.command.unwrap().span().slice(line), "cmd");
+        assert_eq!(ast.arguments[0].span().slice(line), "--flag");
+        let redirects = ast
+            .redirects
+            .iter()
+            .map(|x| (x.span.slice(line), x.op.span.slice(line)))
+            .collect::<Vec<_>>();
+        assert_eq!(redirects, [("2>&-", "2>&-"), ("< (get path)", "<")]);
+        assert_eq!(
+            ast.redirects[1].arg.as_ref().unwrap().span().slice(line),
+            "(get path)"
+        );
+        assert_eq!(ast.next.unwrap().1.span.slice(line), "next");
+    }
+
+    #[test]
+    fn double_quote_escapes() {
+        let line = r#"echo "a\"b\$c\\$d\n""#;
+        let ast = generate_ast(line).unwrap();
+        let Argument::DoubleQuoteString(string) = &ast.0.arguments[0] else {
+            panic!("{:?}", ast.0.arguments);
+        };
+        let parts = string
+            .0
+            .iter()
+            .map(|x| match x {
+                DoubleQuoteComponent::Chars(x) => x.0.to_string_lossy().into_owned(),
+                DoubleQuoteComponent::Escape(x) => format!("escape {}", x.0),
+                DoubleQuoteComponent::DollarEnv(x) => format!("${}", x.0 .0.to_string_lossy()),
+                x => panic!("{:?}", x),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parts,
+            [
+                "a",
+                "escape \"",
+                "b",
+                "escape $",
+                "c",
+                "escape \\",
+                "$d",
+                "\\n"
+            ]
+        );
+        assert_eq!(string.0[1].span().slice(line), "\\\"");
+        assert_eq!(ast.to_string(), line);
+    }
+
+    #[test]
+    fn unicode_words() {
+        // anything that isn't whitespace or one of the shell's own characters is part of a word
+        let line = "NAME=ñandú café 🎉🦀 e\u{301}x \u{a0}nbsp $x😀 \"é $x\" < ünï";
+        let ast = generate_ast(line).unwrap().0;
+        assert_eq!(ast.envs[0].value.span().slice(line), "ñandú");
+        assert!(matches!(
+            &ast.command,
+            Some(Command::StringLiteral(StringLiteral(parts, _)))
+                if matches!(&parts[..], [StringLiteralComponent::RawChars(x)] if &*x.0 == "café")
+        ));
+        let words = ast
+            .arguments
+            .iter()
+            .map(|x| x.span().slice(line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            words,
+            ["🎉🦀", "e\u{301}x", "\u{a0}nbsp", "$x😀", "\"é $x\""]
+        );
+        assert!(matches!(
+            &ast.arguments[0],
+            Argument::StringLiteral(StringLiteral(parts, _))
+                if matches!(&parts[..], [StringLiteralComponent::RawChars(x)] if &*x.0 == "🎉🦀")
+        ));
+        assert_eq!(
+            ast.redirects[0].arg.as_ref().unwrap().span().slice(line),
+            "ünï"
+        );
+        assert_eq!(ast.to_string(), line);
+    }
+
+    #[test]
+    fn rendering() {
+        let canonical = [
+            "echo a 'b c' \"d $x ${y}z $(pwd)\" > out 2>&- | wc -l; A=1 B=$? cmd <(ls) &",
+            "echo ${1}2 ${12} ${a^^} $b_c (cat <<< $HOME) >> log & next",
+            "A=1",
+        ];
+        for line in canonical {
+            let ast = generate_ast(line).unwrap();
+            assert_eq!(ast.to_string(), line);
+            assert_eq!(generate_ast(&ast.to_string()).unwrap(), ast);
+        }
+
+        let messy = "  X=\"1\"   cmd >out  arg   2>>err;ls&";
+        let rendered = generate_ast(messy).unwrap().to_string();
+        assert_eq!(rendered, "X=\"1\" cmd arg > out 2>> err; ls &");
+        assert_eq!(generate_ast(&rendered).unwrap().to_string(), rendered);
+    }
+
+    #[test]
+    fn borrows_input() {
+        let line = "x=1 echo 'a b' c".to_owned();
+        let ast = generate_ast(&line).unwrap();
+        let range = line.as_bytes().as_ptr_range();
+        let borrowed = |text: &Cow<OsStr>| matches!(text, Cow::Borrowed(x) if range.contains(&x.as_encoded_bytes().as_ptr()));
+        assert!(borrowed(&ast.0.envs[0].name.0));
+        let [Argument::SingleQuoteString(quoted), Argument::StringLiteral(literal)] =
+            &ast.0.arguments[..]
+        else {
+            panic!("{:?}", ast.0.arguments);
+        };
+        assert!(borrowed(&quoted.0));
+        assert!(matches!(&literal.0[..], [StringLiteralComponent::RawChars(x)] if borrowed(&x.0)));
+
+        // and an owned copy outlives it
+        let owned = ast.into_owned();
+        drop(line);
+        assert_eq!(owned.to_string(), "x=1 echo 'a b' c");
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        // e.g. a `-c` string, and quotes spanning lines, parse the same as with `\n`, without a
+        // `\r` left in any word
+        let lines = ["A=1 echo a\r\n  b\r\n", "echo 'a\r\nb' \"c\r\n$d\""];
+        for line in lines {
+            let ast = generate_ast(line).unwrap();
+            assert_eq!(ast, generate_ast(&line.replace("\r\n", "\n")).unwrap());
+            assert!(!ast.to_string().contains('\r'), "{:?}", ast);
+        }
+    }
+
+    #[test]
+    fn long_chains() {
+        // built without recursing once per command, and still linked up in order
+        let line = vec!["a $x"; 2000].join(" | ") + "; b &";
+        let ast = generate_ast(&line).unwrap();
+        let mut cmd = &ast.0;
+        let mut count = 1;
+        while let Some((sep, next)) = &cmd.next {
+            assert_eq!(
+                *sep,
+                if count < 2000 {
+                    Separator::Pipe
+                } else {
+                    Separator::Semicolon
+                }
+            );
+            assert_eq!(next.span.end, line.len());
+            cmd = next;
+            count += 1;
+        }
+        assert_eq!(count, 2001);
+        assert!(cmd.background);
+
+        let locations = |line: &str| {
+            let mut locations = Vec::new();
+            let mut cmd = Some(&generate_ast(line).unwrap().0);
+            while let Some(x) = cmd {
+                for arg in &x.arguments {
+                    if let Argument::StringLiteral(x) = arg {
+                        for c in &x.0 {
+                            if let StringLiteralComponent::DollarEnv(x) = c {
+                                locations.push(x.1.to_string());
+                            }
+                        }
+                    }
+                }
+                cmd = x.next.as_ref().map(|x| &*x.1);
+            }
+            locations
+        };
+        assert_eq!(locations("é $a $b;\n  c ${d}"), ["1:3", "1:6", "2:5"]);
+        assert_eq!(locations("echo $a"), ["1:6"]);
+    }
+
+    #[test]
+    fn nesting_limit() {
+        assert_eq!(nesting_depth("a \"$(b (c))\" '((('"), 2);
+        // an apostrophe in double quotes doesn't start a single quoted string
+        assert_eq!(nesting_depth("a \"it's\" $(b (c))"), 2);
+        assert_eq!(nesting_depth("a \\( '\"' (b)"), 1);
+        let deep = format!("echo {}x{}", "(".repeat(300), ")".repeat(300));
+        assert!(matches!(
+            generate_ast(&deep),
+            Err(AstError::TooDeep { limit: MAX_NESTING })
+        ));
+    }
+
+    /// a cheap stand-in for fuzzing, every cut of some awkward lines has to either parse or give
+    /// an error, never panic
+    #[test]
+    fn no_panics() {
+        let lines = [
+            "A=1 B=\"$x${y^^}$(z $?)\" cmd 'a'b\"c\" 2>> e 3< f >&- &<<< w | (g <(h)) ; i &",
+            "${10,,} $1 \"$\" ''\"\"() <() > < | ; & é\u{0}",
+        ];
+        for line in lines {
+            for (i, _) in line.char_indices() {
+                let _ = generate_ast(&line[..i]);
+                let _ = generate_ast(&line[i..]);
+            }
+        }
+        assert!(matches!(
+            generate_ast("echo 99999999999>x"),
+            Err(AstError::InvalidFd { .. })
+        ));
+    }
 }
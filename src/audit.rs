@@ -0,0 +1,217 @@
+//! opt-in audit log of every command the shell runs
+//!
+//! when `RS_SHELL_AUDIT_LOG` is set to a path, a line of JSON is appended to that file for every
+//! stage of a command line that's run, i.e. everything up to a `;` or `&`, so a pipeline is a
+//! single entry, e.g.
+//!
+//! ```json
+//! {"timestamp":1718000000.123,"cwd":"/home/me","commands":[{"argv":["ls","-l"],"redirects":[]},{"argv":["wc","-l"],"redirects":[">count"]}],"background":false,"status":0,"duration":0.004}
+//! ```
+//!
+//! `timestamp` is in seconds since the unix epoch and `duration` is in seconds, `status` is `null`
+//! when the command couldn't be run at all, in which case `error` says why, and for background
+//! jobs it's only whether the job was started, if the shell panics while a stage is running, its
+//! entry is still written, with an `error` saying so
+
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use subprocess::ExitStatus;
+
+use crate::{ast::Separator, env, evaluator::FlattenedCmdline, proc_manager::ProcError};
+
+/// the variable naming the file the audit log is appended to, nothing is logged when it's unset
+const AUDIT_LOG_VAR: &str = "RS_SHELL_AUDIT_LOG";
+
+/// a stage of a command line that's about to be run, see [`Entry::start`]
+#[derive(Debug)]
+pub struct Entry {
+    log: PathBuf,
+    timestamp: SystemTime,
+    start: Instant,
+    cwd: PathBuf,
+    /// the argv and redirects of each command of the stage's pipeline
+    commands: Vec<(Vec<String>, Vec<String>)>,
+    background: bool,
+    /// whether the entry's been written, see [`Entry::finish`]
+    finished: bool,
+}
+
+impl Entry {
+    /// starts an entry for the first stage of `cmd`, if the audit log is enabled
+    pub fn start(cmd: &FlattenedCmdline) -> Option<Self> {
+        let log = env::get(AUDIT_LOG_VAR);
+        if log.is_empty() {
+            return None;
+        }
+
+        let mut commands = Vec::new();
+        let mut cmd = cmd;
+        let background = loop {
+            let argv = std::iter::once(&cmd.command)
+                .chain(&cmd.arguments)
+                .map(|x| x.to_string_lossy().into_owned())
+                .collect();
+            let redirects = cmd
+                .redirects
+                .iter()
+                .map(|(op, path, _)| format!("{}{}", op, path.to_string_lossy()))
+                .collect();
+            commands.push((argv, redirects));
+            match &cmd.next {
+                Some((Separator::Pipe, next)) => cmd = next,
+                Some((Separator::Fork, _)) => break true,
+                Some((Separator::Semicolon, _)) => break false,
+                None => break cmd.background,
+            }
+        };
+
+        Some(Self {
+            log: log.into(),
+            timestamp: SystemTime::now(),
+            start: Instant::now(),
+            cwd: std::env::current_dir().unwrap_or_default(),
+            commands,
+            background,
+            finished: false,
+        })
+    }
+
+    /// appends the entry to the log along with how the stage went, failing to write it is only
+    /// warned about, since it shouldn't get in the way of the command itself
+    pub fn finish(mut self, result: &Result<ExitStatus, ProcError>) {
+        self.write(&self.to_json(result, self.start.elapsed()));
+        self.finished = true;
+    }
+
+    fn write(&self, line: &str) {
+        let written = File::options()
+            .create(true)
+            .append(true)
+            .open(&self.log)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(e) = written {
+            warn!(
+                "unable to write to audit log '{}': {}",
+                self.log.display(),
+                e
+            );
+        }
+    }
+
+    fn to_json(&self, result: &Result<ExitStatus, ProcError>, duration: Duration) -> String {
+        let status = match result {
+            Ok(status) => status_code(*status).to_string(),
+            Err(e) => format!("null,\"error\":{}", string(&e.to_string())),
+        };
+        self.json_with_status(&status, duration)
+    }
+
+    /// the entry as a line of JSON, with `status` being the already formatted value of the status
+    /// field, along with any fields that go after it
+    fn json_with_status(&self, status: &str, duration: Duration) -> String {
+        let timestamp = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let list = |items: &[String]| {
+            let items = items.iter().map(|x| string(x)).collect::<Vec<_>>();
+            format!("[{}]", items.join(","))
+        };
+        let commands = self
+            .commands
+            .iter()
+            .map(|(argv, redirects)| {
+                format!(
+                    "{{\"argv\":{},\"redirects\":{}}}",
+                    list(argv),
+                    list(redirects)
+                )
+            })
+            .collect::<Vec<_>>();
+
+        format!(
+            "{{\"timestamp\":{:.3},\"cwd\":{},\"commands\":[{}],\"background\":{},\"status\":{},\"duration\":{:.3}}}\n",
+            timestamp,
+            string(&self.cwd.as_os_str().to_string_lossy()),
+            commands.join(","),
+            self.background,
+            status,
+            duration.as_secs_f64()
+        )
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        // the stage never finished because the shell panicked, so record that rather than lose it
+        if !self.finished && thread::panicking() {
+            let status = format!("null,\"error\":{}", string("the shell panicked"));
+            self.write(&self.json_with_status(&status, self.start.elapsed()));
+        }
+    }
+}
+
+/// the status as a number, the same as `$?` would be, signals are reported as 128 plus the signal
+fn status_code(status: ExitStatus) -> i64 {
+    match status {
+        ExitStatus::Exited(x) => x.into(),
+        ExitStatus::Signaled(x) => 128 + i64::from(x),
+        ExitStatus::Other(x) => x.into(),
+        ExitStatus::Undetermined => -1,
+    }
+}
+
+/// quotes and escapes `s` as a JSON string
+fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_line() {
+        let entry = Entry {
+            log: PathBuf::new(),
+            timestamp: UNIX_EPOCH + Duration::from_millis(1500),
+            start: Instant::now(),
+            cwd: PathBuf::from("/tmp"),
+            commands: vec![(
+                vec!["echo".to_owned(), "a \"b\"\n".to_owned()],
+                vec![">out".to_owned()],
+            )],
+            background: false,
+            finished: false,
+        };
+        assert_eq!(
+            entry.to_json(&Ok(ExitStatus::Exited(3)), Duration::from_millis(20)),
+            "{\"timestamp\":1.500,\"cwd\":\"/tmp\",\"commands\":[{\"argv\":[\"echo\",\"a \\\"b\\\"\\n\"],\"redirects\":[\">out\"]}],\"background\":false,\"status\":3,\"duration\":0.020}\n"
+        );
+    }
+}
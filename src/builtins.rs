@@ -1,6 +1,99 @@
-use subprocess::{CaptureData, Exec, ExitStatus};
+use std::{
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{self, IsTerminal, Read, Write},
+    num::NonZeroUsize,
+    os::fd::OwnedFd,
+    path::Path,
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::{env, evaluator::FlattenedCmdline};
+use log::warn;
+use nix::{errno::Errno, libc, sys::signal::Signal};
+use parking_lot::Mutex;
+use subprocess::{CaptureData, ExitStatus};
+
+use crate::{
+    ast::{self, Separator, Span},
+    direnv, dotenv,
+    env::{self, ScopeKind},
+    evaluator::{self, Evaluator, FlattenedCmdline},
+    frecency, i18n,
+    jobs::{self, JobState},
+    last_output, metrics,
+    options::{self, ShellOption},
+    path,
+    priority::{self, Priority},
+    proc_manager::{self, ExternalJob, JobMode, ProcError, ProcessBackend},
+    redirect::{self, Target},
+    sandbox, script, session,
+};
+
+type BuiltinFn = fn(FlattenedCmdline) -> CaptureData;
+
+/// a builtin that runs other commands, see [`Runner`]
+type RunnerFn = fn(FlattenedCmdline, &mut Runner) -> CaptureData;
+
+/// every builtin, keyed by the name it's invoked with
+const BUILTINS: &[(&str, BuiltinFn)] = &[
+    (".", builtin_source),
+    ("bg", builtin_bg),
+    ("cd", builtin_cd),
+    ("copyout", builtin_copyout),
+    ("declare", builtin_declare),
+    ("dotenv", builtin_dotenv),
+    ("envrc", builtin_envrc),
+    ("exit", builtin_exit),
+    ("export", builtin_export),
+    ("fg", builtin_fg),
+    ("hash", builtin_hash),
+    ("jobs", builtin_jobs),
+    ("local", builtin_local),
+    ("metrics", builtin_metrics),
+    ("readonly", builtin_readonly),
+    ("session", builtin_session),
+    ("set", builtin_set),
+    ("shopt", builtin_shopt),
+    ("source", builtin_source),
+    ("unset", builtin_unset),
+    ("z", builtin_z),
+];
+
+/// builtins that run other commands, keyed by the name they're invoked with
+const RUNNERS: &[(&str, RunnerFn)] = &[
+    ("nice", builtin_nice),
+    ("parallel", builtin_parallel),
+    ("time", builtin_time),
+    ("watch", builtin_watch),
+];
+
+/// set by `exit` to the status it was asked to exit with, or `None` for that of the last command,
+/// and taken by the evaluator running it
+fn exit_request() -> &'static Mutex<Option<Option<u8>>> {
+    static REQUEST: OnceLock<Mutex<Option<Option<u8>>>> = OnceLock::new();
+    REQUEST.get_or_init(|| Mutex::new(None))
+}
+
+/// asks whatever's running commands to stop and have the shell exit, with `code` or the status of
+/// the last command if that's `None`
+pub fn request_exit(code: Option<u8>) {
+    *exit_request().lock() = Some(code);
+}
+
+/// takes the request `exit` left behind, if it's been run since this was last called
+pub fn take_exit_request() -> Option<Option<u8>> {
+    exit_request().lock().take()
+}
+
+/// the names of every builtin command
+pub fn names() -> impl Iterator<Item = &'static str> {
+    BUILTINS
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(RUNNERS.iter().map(|(name, _)| *name))
+}
 
 pub enum BuiltinCheck {
     Yes(Builtin),
@@ -8,27 +101,380 @@ pub enum BuiltinCheck {
 }
 
 pub struct Builtin {
-
     cmd: FlattenedCmdline,
-    next: Vec<Exec>,
+    func: Func,
+    next: Vec<FlattenedCmdline>,
+}
 
+#[derive(Clone, Copy)]
+enum Func {
+    Plain(BuiltinFn),
+    Runner(RunnerFn),
 }
 
 impl Builtin {
     pub fn maybe_new(cmd: FlattenedCmdline) -> BuiltinCheck {
-        todo!()
+        let func = BUILTINS
+            .iter()
+            .find(|(name, _)| cmd.command == *name)
+            .map(|(_, func)| Func::Plain(*func))
+            .or_else(|| {
+                RUNNERS
+                    .iter()
+                    .find(|(name, _)| cmd.command == *name)
+                    .map(|(_, func)| Func::Runner(*func))
+            });
+        let Some(func) = func else {
+            if options::enabled(ShellOption::AutoCd) && Path::new(&cmd.command).is_dir() {
+                return BuiltinCheck::Yes(Self::auto_cd(cmd));
+            }
+            return BuiltinCheck::No(cmd);
+        };
+        BuiltinCheck::Yes(Self {
+            cmd,
+            func,
+            next: Vec::new(),
+        })
+    }
+
+    /// rewrites a bare directory used as a command into `cd <dir>`
+    fn auto_cd(mut cmd: FlattenedCmdline) -> Self {
+        let dir = std::mem::replace(&mut cmd.command, "cd".into());
+        cmd.arguments.insert(0, dir);
+        Self {
+            cmd,
+            func: Func::Plain(builtin_cd),
+            next: Vec::new(),
+        }
+    }
+
+    /// runs the builtin, writing its output to `stdout` and `stderr` (or the shell's own if not
+    /// given) unless they've been redirected
+    ///
+    /// like for an external command, any assignments before the builtin are exported for as long
+    /// as it runs, and then put back the way they were
+    ///
+    /// external commands it's been piped into are run with `backend`
+    pub fn execute(
+        mut self,
+        backend: &mut dyn ProcessBackend,
+        stdout: Option<File>,
+        stderr: Option<File>,
+    ) -> Result<ExitStatus, ProcError> {
+        if self.cmd.envs.is_empty() {
+            return self.execute_unscoped(backend, stdout, stderr);
+        }
+        let envs = std::mem::take(&mut self.cmd.envs);
+        env::scoped(ScopeKind::Function, || {
+            for (name, value) in envs {
+                env::local(name.clone(), None)
+                    .and_then(|_| env::export(name, Some(value)))
+                    .map_err(|e| ProcError::Env { internal: e })?;
+            }
+            self.execute_unscoped(backend, stdout, stderr)
+        })
+    }
+
+    fn execute_unscoped(
+        self,
+        backend: &mut dyn ProcessBackend,
+        stdout: Option<File>,
+        stderr: Option<File>,
+    ) -> Result<ExitStatus, ProcError> {
+        let func = match self.func {
+            Func::Plain(x) => x,
+            Func::Runner(x) => return self.execute_runner(x, backend, stdout, stderr),
+        };
+        if !self.next.is_empty() {
+            return self.execute_piped(func, backend, stdout, stderr);
+        }
+        let redirects = self.cmd.redirects.clone();
+        let data = func(self.cmd);
+        write_output(&data, &redirects, stdout, stderr, false)?;
+        Ok(data.exit_status)
+    }
+
+    /// the builtin's own command line followed by those of the commands it's piped into
+    pub fn commands(&self) -> impl Iterator<Item = &FlattenedCmdline> {
+        std::iter::once(&self.cmd).chain(&self.next)
+    }
+
+    pub fn pipe(mut self, into: FlattenedCmdline) -> Builtin {
+        self.next.push(into);
+        self
+    }
+
+    /// runs the builtin and feeds its stdout into the commands it's been piped into, unless it's
+    /// been redirected elsewhere, stderr of the builtin itself is always written out immediately
+    fn execute_piped(
+        mut self,
+        func: BuiltinFn,
+        backend: &mut dyn ProcessBackend,
+        stdout: Option<File>,
+        stderr: Option<File>,
+    ) -> Result<ExitStatus, ProcError> {
+        let redirects = self.cmd.redirects.clone();
+        let next = std::mem::take(&mut self.next);
+        let data = func(self.cmd);
+        let piped = write_output(
+            &data,
+            &redirects,
+            None,
+            stderr
+                .as_ref()
+                .map(File::try_clone)
+                .transpose()
+                .map_err(|e| redirect_error(OsStr::new("pipe"), e))?,
+            true,
+        )?;
+
+        let stdin = proc_manager::feed(piped).map_err(|e| redirect_error(OsStr::new("pipe"), e))?;
+        backend.run(ExternalJob {
+            commands: next,
+            stdin: Some(stdin),
+            stdout,
+            stderr,
+            mode: JobMode::Foreground,
+        })
+    }
+}
+
+impl Builtin {
+    /// runs a builtin that runs other commands, which write to wherever the builtin's own output
+    /// goes once its redirects have been applied
+    ///
+    /// if it's been piped into other commands that it doesn't make part of what it runs (see
+    /// [`Runner::pipeline`]), everything it writes is fed into them once it's finished, like the
+    /// output of any other builtin
+    fn execute_runner(
+        self,
+        func: RunnerFn,
+        backend: &mut dyn ProcessBackend,
+        stdout: Option<File>,
+        stderr: Option<File>,
+    ) -> Result<ExitStatus, ProcError> {
+        let Builtin { mut cmd, next, .. } = self;
+        let redirects = std::mem::take(&mut cmd.redirects);
+        let mut tees = Vec::new();
+        let [stdout, stderr] = redirected(&redirects, stdout, stderr, &mut tees)?;
+        let mut runner = Runner {
+            backend,
+            stdout,
+            stderr,
+            piped: None,
+        };
+        let mut collected = None;
+        if !next.is_empty() {
+            let (mut reader, writer) =
+                io::pipe().map_err(|e| redirect_error(OsStr::new("pipe"), e))?;
+            collected = Some(thread::spawn(move || {
+                let mut buf = Vec::new();
+                let _ = reader.read_to_end(&mut buf);
+                buf
+            }));
+            let writer = File::from(OwnedFd::from(writer));
+            let stdout = runner.stdout.replace(writer);
+            runner.piped = Some((next, stdout));
+        }
+
+        let data = func(cmd, &mut runner);
+        let Runner {
+            backend,
+            stdout,
+            stderr,
+            piped,
+        } = runner;
+        let stderr_copy = stderr
+            .as_ref()
+            .map(File::try_clone)
+            .transpose()
+            .map_err(|e| redirect_error(OsStr::new("pipe"), e))?;
+        // the builtin's stdout is dropped here, so whatever's collecting it sees the end of it
+        write_output(&data, &[], stdout, stderr_copy, false)?;
+        let status = match (piped, collected) {
+            (Some((commands, stdout)), Some(collected)) => {
+                let output = collected
+                    .join()
+                    .expect("builtin output collector must not panic");
+                let stdin = proc_manager::feed(output)
+                    .map_err(|e| redirect_error(OsStr::new("pipe"), e))?;
+                backend.run(ExternalJob {
+                    commands,
+                    stdin: Some(stdin),
+                    stdout,
+                    stderr,
+                    mode: JobMode::Foreground,
+                })?
+            }
+            _ => data.exit_status,
+        };
+        for tee in tees {
+            let _ = tee.join();
+        }
+        Ok(status)
+    }
+}
+
+/// what a builtin that runs other commands (like `time`) is given to run them with, so that they're
+/// run by the same backend as the builtin, and write to wherever it would
+pub struct Runner<'a> {
+    backend: &'a mut dyn ProcessBackend,
+    /// the builtin's stdout and stderr, the shell's own if not given
+    stdout: Option<File>,
+    stderr: Option<File>,
+    /// the commands the builtin's been piped into, along with where they write to, until then
+    /// `stdout` is a pipe whose contents are fed to them once the builtin's finished
+    piped: Option<(Vec<FlattenedCmdline>, Option<File>)>,
+}
+
+impl Runner<'_> {
+    /// runs `cmd` in the foreground, writing to the builtin's stdout and stderr
+    fn dispatch(&mut self, cmd: FlattenedCmdline) -> Result<ExitStatus, ProcError> {
+        proc_manager::run_chain(
+            self.backend,
+            cmd,
+            self.stdout.as_ref(),
+            self.stderr.as_ref(),
+            None,
+        )
+    }
+
+    /// pipes `cmd` into the commands the builtin's been piped into, if it's been piped into any,
+    /// for builtins like `time` that run a whole pipeline rather than a single command, so that
+    /// `time a | b` times both `a` and `b`
+    fn pipeline(&mut self, mut cmd: FlattenedCmdline) -> FlattenedCmdline {
+        let Some((next, stdout)) = self.piped.take() else {
+            return cmd;
+        };
+        self.stdout = stdout;
+        let mut rest = None;
+        for mut next_cmd in next.into_iter().rev() {
+            next_cmd.next = rest;
+            rest = Some((Separator::Pipe, Box::new(next_cmd)));
+        }
+        let mut last = &mut cmd;
+        while last.next.is_some() {
+            last = &mut last.next.as_mut().expect("checked above").1;
+        }
+        last.next = rest;
+        cmd
+    }
+
+    /// writes `bytes` to the builtin's stdout straight away, rather than once it's finished
+    fn print(&self, bytes: &[u8]) -> io::Result<()> {
+        write_stream(self.stdout.as_ref(), io::stdout(), bytes)
     }
 
-    pub fn execute(self) -> ExitStatus {
-        todo!()
+    /// like [`Runner::print`], for the builtin's stderr
+    fn eprint(&self, bytes: &[u8]) -> io::Result<()> {
+        write_stream(self.stderr.as_ref(), io::stderr(), bytes)
     }
 
-    pub fn capture(self) -> CaptureData {
-        todo!()
+    /// whether the builtin's stdout is a terminal
+    fn is_terminal(&self) -> bool {
+        match &self.stdout {
+            Some(file) => file.is_terminal(),
+            None => io::stdout().is_terminal(),
+        }
     }
+}
 
-    pub fn pipe(self, into: Exec) -> Builtin {
-        todo!()
+/// where a builtin's stdout and stderr go once `redirects` have been applied to `stdout` and
+/// `stderr` (the shell's own if not given), a stream redirected to more than one file is copied to
+/// each of them by a thread that's added to `tees`, and one that's been closed is discarded
+fn redirected(
+    redirects: &[(crate::ast::RedirectOp, OsString, Option<Span>)],
+    stdout: Option<File>,
+    stderr: Option<File>,
+    tees: &mut Vec<thread::JoinHandle<()>>,
+) -> Result<[Option<File>; 2], ProcError> {
+    let [_, out, err] = redirect::apply(redirects, false)?;
+    let defaults = [stdout, stderr];
+    let mut resolved = [None, None];
+    for (i, target) in [out, err].into_iter().enumerate() {
+        let fd = i + 1;
+        resolved[i] = match target {
+            Target::Inherited(x) if x == fd => defaults[i].as_ref().map(File::try_clone),
+            Target::Inherited(x @ (1 | 2)) => Some(match &defaults[x - 1] {
+                Some(file) => file.try_clone(),
+                None => proc_manager::shell_stream(x),
+            }),
+            Target::Inherited(x) => Some(proc_manager::shell_stream(x)),
+            Target::Files(files) => {
+                resolved[i] = proc_manager::fan_out(files, tees)?;
+                continue;
+            }
+            Target::Closed => {
+                resolved[i] = Some(proc_manager::file_write(OsStr::new("/dev/null"))?);
+                continue;
+            }
+        }
+        .transpose()
+        .map_err(|e| redirect_error(OsStr::new("pipe"), e))?;
+    }
+    Ok(resolved)
+}
+
+/// writes the output of a builtin to the terminal, or to wherever it's been redirected, applying
+/// the redirects in order the same as for an external command, see [`redirect::apply`]
+///
+/// a stream redirected more than once (e.g. `cmd > a > b`) is written to every target, and writing
+/// anything to one that's been closed with `>&-` fails, the same as it would for an external
+/// command
+///
+/// when `piped` whatever is left going to stdout is returned rather than written, so that it can be
+/// fed into the rest of the pipeline
+fn write_output(
+    data: &CaptureData,
+    redirects: &[(crate::ast::RedirectOp, OsString, Option<Span>)],
+    default_stdout: Option<File>,
+    default_stderr: Option<File>,
+    piped: bool,
+) -> Result<Vec<u8>, ProcError> {
+    let mut piped_stdout = Vec::new();
+    let [_, stdout, stderr] = redirect::apply(redirects, false)?;
+
+    for (target, bytes, path) in [
+        (stdout, &data.stdout, "stdout"),
+        (stderr, &data.stderr, "stderr"),
+    ] {
+        let result = match target {
+            Target::Inherited(1) if piped => {
+                piped_stdout.extend_from_slice(bytes);
+                Ok(())
+            }
+            Target::Inherited(1) => match default_stdout.as_ref() {
+                Some(mut file) => write_all(&mut file, bytes),
+                None => write_all(&mut io::stdout(), bytes),
+            },
+            Target::Inherited(2) => match default_stderr.as_ref() {
+                Some(mut file) => write_all(&mut file, bytes),
+                None => write_all(&mut io::stderr(), bytes),
+            },
+            Target::Inherited(x) => {
+                proc_manager::shell_stream(x).and_then(|mut file| write_all(&mut file, bytes))
+            }
+            Target::Files(files) => files
+                .into_iter()
+                .try_for_each(|mut file| write_all(&mut file, bytes)),
+            Target::Closed if bytes.is_empty() => Ok(()),
+            Target::Closed => Err(io::Error::from(Errno::EBADF)),
+        };
+        result.map_err(|e| redirect_error(OsStr::new(path), e))?;
+    }
+    Ok(piped_stdout)
+}
+
+fn write_all(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    out.write_all(bytes)?;
+    out.flush()
+}
+
+fn redirect_error(path: &OsStr, e: io::Error) -> ProcError {
+    ProcError::RedirectError {
+        path: path.to_string_lossy().to_string(),
+        internal: e,
+        span: None,
     }
 }
 
@@ -36,7 +482,7 @@ impl Builtin {
 fn exit_with_error(code: u32, msg: String) -> CaptureData {
     CaptureData {
         stdout: Vec::new(),
-        stderr: msg.into_bytes(),
+        stderr: format!("{}\n", msg).into_bytes(),
         exit_status: ExitStatus::Exited(code),
     }
 }
@@ -50,21 +496,1411 @@ fn exit_quiet_success() -> CaptureData {
     }
 }
 
-fn builtin_cd(cmd: FlattenedCmdline) -> CaptureData {
+#[inline]
+/// the error for a path the shell can't use from inside the sandbox
+fn outside_sandbox(path: &OsStr) -> ProcError {
+    ProcError::OutsideSandbox {
+        path: path.to_string_lossy().to_string(),
+        span: None,
+    }
+}
 
+fn exit_with_output(code: u32, output: String) -> CaptureData {
+    CaptureData {
+        stdout: output.into_bytes(),
+        stderr: Vec::new(),
+        exit_status: ExitStatus::Exited(code),
+    }
+}
+
+fn builtin_cd(cmd: FlattenedCmdline) -> CaptureData {
+    let old = std::env::current_dir().ok();
+    let target = cmd
+        .arguments
+        .first()
+        .cloned()
+        .unwrap_or_else(|| env::get("HOME"));
+    if !target.is_empty() && !sandbox::allows(Path::new(&target)) {
+        return exit_with_error(1, format!("cd: {}", outside_sandbox(&target)));
+    }
     match cmd.arguments.len() {
         0 => {
             let home = env::get("HOME");
-            if home.len() == 0 {
-                return exit_with_error(1, "$HOME variable not set".to_owned());
+            if home.is_empty() {
+                return exit_with_error(1, i18n::text("$HOME variable not set").to_owned());
             }
             if let Err(e) = std::env::set_current_dir(home) {
-                return exit_with_error(1, format!("unable to cd to $HOME: {}", e));
+                return exit_with_error(
+                    1,
+                    i18n::format("unable to cd to $HOME: {error}", &[("error", &e)]),
+                );
+            }
+        }
+        1 => {
+            let dir = &cmd.arguments[0];
+            if let Err(e) = std::env::set_current_dir(dir) {
+                let msg = i18n::format(
+                    "unable to cd to '{dir}': {error}",
+                    &[("dir", &dir.to_string_lossy()), ("error", &e)],
+                );
+                return exit_with_error(1, msg);
             }
+        }
+        _ => return exit_with_error(1, i18n::text("Too many arguments for cd").to_owned()),
+    };
+
+    if let Some(old) = old {
+        if let Err(e) = env::export("OLDPWD".into(), Some(old.into())) {
+            return exit_with_error(1, format!("cd: {}", e));
+        }
+    }
+    env::update_pwd();
+    if let Ok(dir) = std::env::current_dir() {
+        // not being able to record the visit shouldn't stop the `cd` from working
+        if let Err(e) = frecency::record(&dir) {
+            warn!("unable to record visit to '{}': {}", dir.display(), e);
+        }
+    }
+    if options::enabled(ShellOption::Direnv) {
+        let changes = direnv::update(false);
+        if !changes.is_empty() {
+            return CaptureData {
+                stdout: Vec::new(),
+                stderr: format!("{}\n", changes.join("\n")).into_bytes(),
+                exit_status: ExitStatus::Exited(0),
+            };
+        }
+    }
+    exit_quiet_success()
+}
+
+/// `copyout [-p]`, puts what the last foreground command wrote to stdout on the clipboard, or with
+/// `-p` prints it instead, only kept with the `keepoutput` option enabled, see [`last_output`]
+fn builtin_copyout(cmd: FlattenedCmdline) -> CaptureData {
+    let print = match cmd.arguments.as_slice() {
+        [] => false,
+        [flag] if flag == "-p" => true,
+        _ => return exit_with_error(2, "copyout: usage: copyout [-p]".to_owned()),
+    };
+    if !options::enabled(ShellOption::KeepOutput) {
+        let msg = i18n::text("output isn't being kept, enable it with `shopt -s keepoutput`");
+        return exit_with_error(1, format!("copyout: {}", msg));
+    }
+    let output = last_output::get();
+    if print {
+        return CaptureData {
+            stdout: output,
+            stderr: Vec::new(),
+            exit_status: ExitStatus::Exited(0),
+        };
+    }
+    if let Err(e) = last_output::copy_to_clipboard(&output) {
+        return exit_with_error(1, format!("copyout: /dev/tty: {}", e));
+    }
+    exit_quiet_success()
+}
+
+/// `z [-l] PATTERN...`, `cd`s to the most frecent directory that matches every pattern in order,
+/// or with `-l` lists the matches along with their scores, see [`frecency`]
+fn builtin_z(mut cmd: FlattenedCmdline) -> CaptureData {
+    let mut args = cmd
+        .arguments
+        .iter()
+        .map(|x| x.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let list = args.first().is_some_and(|x| x == "-l");
+    if list {
+        args.remove(0);
+    }
+    let found = match frecency::search(&args) {
+        Ok(x) => x,
+        Err(e) => return exit_with_error(1, format!("z: {}", e)),
+    };
+    if list {
+        let output = found
+            .iter()
+            .rev()
+            .map(|(score, path)| format!("{:<10.1} {}\n", score, path.display()))
+            .collect();
+        return exit_with_output(0, output);
+    }
+    if args.is_empty() {
+        return exit_with_error(2, "z: usage: z [-l] PATTERN...".to_owned());
+    }
+    let Some((_, best)) = found.into_iter().next() else {
+        return exit_with_error(1, format!("z: {}", i18n::text("no matching directory")));
+    };
+    cmd.arguments = vec![best.into()];
+    builtin_cd(cmd)
+}
+
+/// `export [-n] [NAME[=value]]...`, marks variables to be passed to the commands the shell runs,
+/// or with `-n` stops passing them, with no names every exported variable is listed
+fn builtin_export(cmd: FlattenedCmdline) -> CaptureData {
+    let mut args = cmd.arguments.as_slice();
+    let mut unexport = false;
+    while let Some(flag) = args
+        .first()
+        .filter(|x| x.to_string_lossy().starts_with('-'))
+    {
+        match flag.to_string_lossy().as_ref() {
+            "-n" => unexport = true,
+            "-p" => (),
+            "--" => {
+                args = &args[1..];
+                break;
+            }
+            x => {
+                return exit_with_error(
+                    1,
+                    format!("export: {}: {}", x, i18n::text("invalid option")),
+                )
+            }
+        }
+        args = &args[1..];
+    }
+
+    if args.is_empty() {
+        return exit_with_output(0, format_vars("export", env::exports()));
+    }
+    for arg in args {
+        let (name, value) = split_assignment(arg);
+        let result = match unexport {
+            true => env::unexport(name, value),
+            false => env::export(name, value),
+        };
+        if let Err(e) = result {
+            return exit_with_error(1, format!("export: {}", e));
+        }
+    }
+    exit_quiet_success()
+}
+
+/// `readonly [NAME[=value]]...`, keeps variables from being changed or unset for as long as the
+/// shell runs, with no names every readonly variable is listed
+fn builtin_readonly(cmd: FlattenedCmdline) -> CaptureData {
+    let args = match cmd.arguments.first().is_some_and(|x| x == "-p") {
+        true => &cmd.arguments[1..],
+        false => &cmd.arguments[..],
+    };
+    if args.is_empty() {
+        return exit_with_output(0, format_vars("readonly", env::readonly()));
+    }
+    for arg in args {
+        let (name, value) = split_assignment(arg);
+        if let Err(e) = env::make_readonly(name, value) {
+            return exit_with_error(1, format!("readonly: {}", e));
+        }
+    }
+    exit_quiet_success()
+}
+
+/// `local NAME[=value]...`, makes variables local to the current scope, so they're restored to
+/// what they were before once it ends
+fn builtin_local(cmd: FlattenedCmdline) -> CaptureData {
+    for arg in &cmd.arguments {
+        let (name, value) = split_assignment(arg);
+        if let Err(e) = env::local(name, value) {
+            return exit_with_error(1, format!("local: {}", e));
+        }
+    }
+    exit_quiet_success()
+}
+
+/// splits an argument like `NAME=value` into the name and value, or just the name if it has no `=`
+fn split_assignment(arg: &OsStr) -> (env::Name, Option<OsString>) {
+    let bytes = arg.as_encoded_bytes();
+    match bytes.iter().position(|x| *x == b'=') {
+        // SAFETY: both halves are split on an ascii character of a valid `OsStr`
+        Some(i) => unsafe {
+            (
+                env::Name::new(OsStr::from_encoded_bytes_unchecked(&bytes[..i])),
+                Some(OsStr::from_encoded_bytes_unchecked(&bytes[i + 1..]).to_owned()),
+            )
+        },
+        None => (env::Name::new(arg), None),
+    }
+}
+
+/// lists variables as the `keyword` commands (e.g. `export`) that would recreate them
+fn format_vars(keyword: &str, vars: Vec<(env::Name, Option<env::Value>)>) -> String {
+    vars.into_iter()
+        .map(|(name, value)| format_var(keyword, &name, value.as_ref()))
+        .collect()
+}
+
+/// formats a variable as the `keyword` command that would recreate it, arrays are formatted like
+/// `NAME=([0]=a [1]=b)`
+fn format_var(keyword: &str, name: &OsStr, value: Option<&env::Value>) -> String {
+    match value {
+        None => format!("{} {}\n", keyword, name.to_string_lossy()),
+        Some(value) => format!(
+            "{} {}={}\n",
+            keyword,
+            name.to_string_lossy(),
+            format_value(value)
+        ),
+    }
+}
+
+/// quotes a value so it reads back as the same value when assigned, arrays are formatted like
+/// `([0]=a [1]=b)`
+fn format_value(value: &env::Value) -> String {
+    let elements = |x: Vec<(String, &OsStr)>| {
+        let x = x
+            .into_iter()
+            .map(|(key, value)| format!("[{}]={}", key, proc_manager::quote(value)))
+            .collect::<Vec<_>>();
+        format!("({})", x.join(" "))
+    };
+    match value {
+        env::Value::Scalar(x) => proc_manager::quote(x),
+        env::Value::Indexed(x) => elements(
+            x.iter()
+                .map(|(k, v)| (k.to_string(), v.as_os_str()))
+                .collect(),
+        ),
+        env::Value::Associative(x) => elements(
+            x.iter()
+                .map(|(k, v)| (proc_manager::quote(k), v.as_os_str()))
+                .collect(),
+        ),
+    }
+}
+
+/// `declare [-p] [-ilnrux] [+ilnrux] [NAME[=value]]...`, sets the attributes of variables (see
+/// [`env::Attributes`]), `+` taking them away rather than giving them, with no names (or with
+/// `-p`) variables are listed along with their attributes instead
+///
+/// the attributes of the variable a nameref refers to are changed, rather than the nameref's own,
+/// unless `-n` or `+n` is given
+fn builtin_declare(cmd: FlattenedCmdline) -> CaptureData {
+    let mut args = cmd.arguments.as_slice();
+    let mut print = false;
+    let mut changes = Vec::new();
+    while let Some(arg) = args.first().map(|x| x.to_string_lossy()) {
+        let (value, flags) = match (arg.strip_prefix('-'), arg.strip_prefix('+')) {
+            (Some("-"), _) => {
+                args = &args[1..];
+                break;
+            }
+            (Some(flags), _) => (true, flags),
+            (_, Some(flags)) => (false, flags),
+            _ => break,
+        };
+        for flag in flags.chars() {
+            match flag {
+                'p' => print = true,
+                'i' | 'l' | 'n' | 'r' | 'u' | 'x' => changes.push((flag, value)),
+                _ => {
+                    return exit_with_error(
+                        1,
+                        format!("declare: -{}: {}", flag, i18n::text("invalid option")),
+                    )
+                }
+            }
+        }
+        args = &args[1..];
+    }
+
+    if print || args.is_empty() {
+        let names = args
+            .iter()
+            .map(|x| split_assignment(x).0)
+            .collect::<Vec<_>>();
+        let output = env::declared()
+            .into_iter()
+            .filter(|(name, _, _)| names.is_empty() || names.contains(name))
+            .map(|(name, attrs, value)| {
+                let flags = attrs.flags();
+                let keyword = match flags.is_empty() {
+                    true => "declare --".to_owned(),
+                    false => format!("declare -{}", flags),
+                };
+                format_var(&keyword, &name, value.as_ref())
+            })
+            .collect();
+        return exit_with_output(0, output);
+    }
+    let apply = |attrs: &mut env::Attributes| {
+        for (flag, value) in &changes {
+            match flag {
+                'i' => attrs.integer = *value,
+                // only one case can apply at a time, so the last one given wins
+                'l' => {
+                    attrs.lowercase = *value;
+                    attrs.uppercase &= !*value;
+                }
+                'u' => {
+                    attrs.uppercase = *value;
+                    attrs.lowercase &= !*value;
+                }
+                'n' => attrs.nameref = *value,
+                'r' => attrs.readonly = *value,
+                _ => attrs.exported = *value,
+            }
+        }
+    };
+    let by_ref = changes.iter().any(|(flag, _)| *flag == 'n');
+    for arg in args {
+        let (name, value) = split_assignment(arg);
+        let result = match by_ref {
+            true => env::declare_ref(name, value, apply),
+            false => env::declare(name, value, apply),
+        };
+        if let Err(e) = result {
+            return exit_with_error(1, format!("declare: {}", e));
+        }
+    }
+    exit_quiet_success()
+}
+
+/// `dotenv [--no-export] [FILE]`, loads the variables assigned in a dotenv file (`.env` by default,
+/// see [`dotenv`] for the format) as exported variables, or as shell variables with `--no-export`
+///
+/// the whole file is checked before any variable is set, so a file with a mistake in it doesn't
+/// get loaded halfway
+fn builtin_dotenv(cmd: FlattenedCmdline) -> CaptureData {
+    let mut args = cmd.arguments.as_slice();
+    let export = match args.first().is_some_and(|x| x == "--no-export") {
+        true => {
+            args = &args[1..];
+            false
+        }
+        false => true,
+    };
+    let path = match args {
+        [] => Path::new(".env"),
+        [path] => Path::new(path),
+        _ => return exit_with_error(2, "usage: dotenv [--no-export] [FILE]".to_owned()),
+    };
+
+    let input = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => return exit_with_error(1, format!("dotenv: {}: {}", path.display(), e)),
+    };
+    let vars = match dotenv::parse(&input) {
+        Ok(x) => x,
+        Err(e) => return exit_with_error(1, format!("dotenv: {}: {}", path.display(), e)),
+    };
+    for (name, value) in vars {
+        let result = match export {
+            true => env::export(name.into(), Some(value.into())),
+            false => env::set(name.into(), value.into()),
+        };
+        if let Err(e) = result {
+            return exit_with_error(1, format!("dotenv: {}", e));
+        }
+    }
+    exit_quiet_success()
+}
+
+/// `envrc allow|deny|reload`, approves the nearest `.rsshell-env` or `.envrc` and `.rsshellrc.local`
+/// as they are now so that they're loaded with the `direnv` option enabled, takes that back, or
+/// loads them again after they've been changed, see [`direnv`]
+fn builtin_envrc(cmd: FlattenedCmdline) -> CaptureData {
+    let action = match cmd.arguments.as_slice() {
+        [action] => action.to_string_lossy(),
+        _ => return exit_with_error(2, "envrc: usage: envrc allow|deny|reload".to_owned()),
+    };
+    let result = match action.as_ref() {
+        "allow" => direnv::allow(),
+        "deny" => direnv::deny(),
+        "reload" => Ok(()),
+        x => return exit_with_error(2, format!("envrc: {}: {}", x, i18n::text("invalid action"))),
+    };
+    if let Err(e) = result {
+        return exit_with_error(1, format!("envrc: {}", e));
+    }
+    if !options::enabled(ShellOption::Direnv) {
+        return exit_quiet_success();
+    }
+    let changes = direnv::update(true);
+    CaptureData {
+        stdout: Vec::new(),
+        stderr: changes
+            .iter()
+            .map(|x| format!("{}\n", x))
+            .collect::<String>()
+            .into_bytes(),
+        exit_status: ExitStatus::Exited(0),
+    }
+}
+
+/// `exit [N]`, exits the shell with status `N`, or that of the last command if not given
+fn builtin_exit(cmd: FlattenedCmdline) -> CaptureData {
+    let code = match cmd.arguments.as_slice() {
+        [] => None,
+        [code] => match code.to_string_lossy().parse::<i64>() {
+            // like other shells, only the low 8 bits are kept
+            Ok(x) => Some(x as u8),
+            Err(_) => {
+                request_exit(Some(2));
+                let msg = format!(
+                    "exit: {}: {}",
+                    code.to_string_lossy(),
+                    i18n::text("numeric argument required")
+                );
+                return exit_with_error(2, msg);
+            }
+        },
+        _ => return exit_with_error(1, format!("exit: {}", i18n::text("too many arguments"))),
+    };
+    request_exit(code);
+    CaptureData {
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        exit_status: ExitStatus::Exited(code.unwrap_or(0).into()),
+    }
+}
+
+/// `source FILE [ARG...]` (or `. FILE [ARG...]`), runs the commands in a file in the current shell,
+/// so any variables it sets stick around afterwards, with any arguments given as `$1`, `$2`, ...
+/// while it runs
+fn builtin_source(cmd: FlattenedCmdline) -> CaptureData {
+    let name = cmd.command.to_string_lossy();
+    let Some((path, args)) = cmd.arguments.split_first() else {
+        return exit_with_error(
+            2,
+            format!("{}: {}", name, i18n::text("filename argument required")),
+        );
+    };
+    if !sandbox::allows(Path::new(path)) {
+        return exit_with_error(1, format!("{}: {}", name, outside_sandbox(path)));
+    }
+    let mut evaluator = Evaluator::new();
+    let mut run = || script::run(&mut evaluator, Path::new(path));
+    let result = match args.is_empty() {
+        true => run(),
+        false => env::with_arguments(args.to_vec(), run),
+    };
+    // an `exit` in the file exits the shell sourcing it, not just the file
+    if evaluator.exit_requested() {
+        request_exit(result.as_ref().ok().map(|x| evaluator::exit_code(*x)));
+    }
+    match result {
+        Ok(status) => CaptureData {
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            exit_status: status,
         },
-        1 => {}
-        _ => return exit_with_error(1, "Too many arguments for cd".to_owned()),
+        Err(e) => exit_with_error(e.exit_code().into(), format!("{}: {}", name, e)),
+    }
+}
+
+/// `session save|restore FILE`, saves the shell's variables, aliases and working directory to a
+/// file, or restores them from one, see [`session`] for what exactly is saved
+fn builtin_session(cmd: FlattenedCmdline) -> CaptureData {
+    let (action, path) = match cmd.arguments.as_slice() {
+        [action, path] => (action.to_string_lossy(), Path::new(path)),
+        _ => return exit_with_error(2, "session: usage: session save|restore FILE".to_owned()),
+    };
+    match action.as_ref() {
+        "save" => match session::save(path) {
+            Ok(()) => exit_quiet_success(),
+            Err(e) => exit_with_error(1, format!("session: {}", e)),
+        },
+        "restore" => match session::restore(path) {
+            Ok(problems) if problems.is_empty() => exit_quiet_success(),
+            Ok(problems) => exit_with_error(
+                1,
+                problems
+                    .iter()
+                    .map(|x| format!("session: {}", x))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            Err(e) => exit_with_error(1, format!("session: {}", e)),
+        },
+        x => exit_with_error(
+            2,
+            format!("session: {}: {}", x, i18n::text("invalid action")),
+        ),
+    }
+}
+
+/// `metrics [-p|-r]`, shows what's been counted since the shell started (or was last reset), in
+/// the Prometheus text format with `-p`, `-r` starts counting from nothing again
+fn builtin_metrics(cmd: FlattenedCmdline) -> CaptureData {
+    let Some(metrics) = metrics::snapshot() else {
+        return exit_with_error(
+            1,
+            format!(
+                "metrics: {}",
+                i18n::text("metrics aren't being collected, start the shell with --metrics")
+            ),
+        );
+    };
+    match cmd.arguments.as_slice() {
+        [] => exit_with_output(0, metrics.summary()),
+        [x] if x == "-p" => exit_with_output(0, metrics.prometheus()),
+        [x] if x == "-r" => {
+            metrics::reset();
+            exit_quiet_success()
+        }
+        [x] => exit_with_error(
+            2,
+            format!(
+                "metrics: {}: {}",
+                x.to_string_lossy(),
+                i18n::text("invalid option")
+            ),
+        ),
+        _ => exit_with_error(2, format!("metrics: {}", i18n::text("too many arguments"))),
+    }
+}
+
+/// `unset [-n] NAME...`, removes shell variables, whether they're exported or not, a nameref is
+/// only removed itself with `-n`, otherwise the variable it refers to is
+fn builtin_unset(cmd: FlattenedCmdline) -> CaptureData {
+    let by_ref = cmd.arguments.first().is_some_and(|x| x == "-n");
+    for name in &cmd.arguments[by_ref as usize..] {
+        let result = match by_ref {
+            true => env::unset_ref(name),
+            false => env::unset(name),
+        };
+        if let Err(e) = result {
+            return exit_with_error(1, format!("unset: {}", e));
+        }
+    }
+    exit_quiet_success()
+}
+
+fn builtin_shopt(cmd: FlattenedCmdline) -> CaptureData {
+    let mut args = cmd.arguments.iter().map(|x| x.to_string_lossy());
+    let value = match args.next().as_deref() {
+        Some("-s") => Some(true),
+        Some("-u") => Some(false),
+        Some(name) => {
+            // `shopt name` queries a single option, exiting non-zero if it's unset
+            return match ShellOption::from_name(name) {
+                Some(opt) if opt.is_shopt() => {
+                    let enabled = options::enabled(opt);
+                    exit_with_output(if enabled { 0 } else { 1 }, format_shopt(opt, enabled))
+                }
+                _ => exit_with_error(
+                    1,
+                    format!(
+                        "shopt: {}: {}",
+                        name,
+                        i18n::text("invalid shell option name")
+                    ),
+                ),
+            };
+        }
+        None => None,
+    };
+
+    let names = args.collect::<Vec<_>>();
+    let Some(value) = value else {
+        let output = ShellOption::ALL
+            .iter()
+            .filter(|x| x.is_shopt())
+            .map(|x| format_shopt(*x, options::enabled(*x)))
+            .collect::<String>();
+        return exit_with_output(0, output);
+    };
+
+    for name in names {
+        match ShellOption::from_name(&name) {
+            Some(opt) if opt.is_shopt() => options::set(opt, value),
+            _ => {
+                return exit_with_error(
+                    1,
+                    format!(
+                        "shopt: {}: {}",
+                        name,
+                        i18n::text("invalid shell option name")
+                    ),
+                )
+            }
+        }
+    }
+    exit_quiet_success()
+}
+
+fn builtin_hash(cmd: FlattenedCmdline) -> CaptureData {
+    if cmd.arguments.is_empty() {
+        let hashed = path::hashed();
+        if hashed.is_empty() {
+            return exit_with_output(0, format!("hash: {}\n", i18n::text("hash table empty")));
+        }
+        let output = hashed
+            .iter()
+            .map(|(_, entry)| format!("{:>4}\t{}\n", entry.hits, entry.path.display()))
+            .collect::<String>();
+        return exit_with_output(0, format!("hits\tcommand\n{}", output));
+    }
+
+    let mut code = 0;
+    let mut errors = String::new();
+    for arg in &cmd.arguments {
+        if arg == "-r" {
+            path::clear_hashed();
+        } else if path::search(arg).is_none() {
+            code = 1;
+            errors.push_str(&format!(
+                "hash: {}: {}\n",
+                arg.to_string_lossy(),
+                i18n::text("not found")
+            ));
+        }
+    }
+    CaptureData {
+        stdout: Vec::new(),
+        stderr: errors.into_bytes(),
+        exit_status: ExitStatus::Exited(code),
+    }
+}
+
+fn builtin_time(mut cmd: FlattenedCmdline, runner: &mut Runner) -> CaptureData {
+    let verbose = cmd.arguments.first().is_some_and(|x| x == "-v");
+    if verbose {
+        cmd.arguments.remove(0);
+    }
+    if cmd.arguments.is_empty() {
+        return exit_with_error(1, format!("time: {}", i18n::text("no command given")));
+    }
+    // the whole pipeline is timed, not just its first command
+    let timed = runner.pipeline(FlattenedCmdline {
+        envs: cmd.envs,
+        command: cmd.arguments.remove(0),
+        command_span: None,
+        arguments: cmd.arguments,
+        redirects: Vec::new(),
+        next: None,
+        background: false,
+    });
+
+    jobs::take_last_rusage();
+    let start = Instant::now();
+    let exit_status = match runner.dispatch(timed) {
+        Ok(x) => x,
+        Err(e @ ProcError::CommandNotFound { .. }) => {
+            return exit_with_error(127, format!("rs-shell: {}", e))
+        }
+        Err(e) => return exit_with_error(1, format!("time: {}", e)),
+    };
+    let real = start.elapsed();
+    // builtins don't use any resources that can be measured separately from the shell's own
+    let rusage = jobs::last_rusage().unwrap_or_default();
+
+    let mut report = format!(
+        "\nreal\t{}\nuser\t{}\nsys\t{}\n",
+        format_time(real),
+        format_time(rusage.user),
+        format_time(rusage.system)
+    );
+    if verbose {
+        report.push_str(&format!(
+            "maxrss\t{} KB\nminflt\t{}\nmajflt\t{}\n",
+            rusage.max_rss, rusage.minor_faults, rusage.major_faults
+        ));
+    }
+    CaptureData {
+        stdout: Vec::new(),
+        stderr: report.into_bytes(),
+        exit_status,
+    }
+}
+
+/// the interval `watch` runs its command at if it isn't given one
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// `watch [-n SECS] COMMAND [ARG]...`, runs a command over and over, `SECS` (2 by default) after
+/// it last finished, clearing the screen before each run, and showing when it finished and what it
+/// exited with afterwards
+///
+/// it carries on until ^C, which interrupts the command if it's running, and otherwise the shell,
+/// which only stops the watching, or until the command's stopped with ^Z, the status is that of
+/// the last run
+fn builtin_watch(mut cmd: FlattenedCmdline, runner: &mut Runner) -> CaptureData {
+    let mut interval = WATCH_INTERVAL;
+    if cmd.arguments.first().is_some_and(|x| x == "-n") {
+        let secs = cmd.arguments.get(1).map(|x| x.to_string_lossy());
+        interval = match secs.as_deref().map(str::parse::<f64>) {
+            Some(Ok(x)) if x >= 0.1 && x.is_finite() => Duration::from_secs_f64(x),
+            Some(_) => {
+                let msg = format!(
+                    "{}: {}",
+                    secs.unwrap_or_default(),
+                    i18n::text("invalid interval")
+                );
+                return exit_with_error(1, format!("watch: {}", msg));
+            }
+            None => {
+                return exit_with_error(
+                    2,
+                    "watch: usage: watch [-n SECS] COMMAND [ARG]...".to_owned(),
+                )
+            }
+        };
+        cmd.arguments.drain(..2);
+    }
+    if cmd.arguments.is_empty() {
+        return exit_with_error(1, format!("watch: {}", i18n::text("no command given")));
+    }
+    let line = cmd
+        .arguments
+        .iter()
+        .map(|x| proc_manager::quote(x))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let watched = FlattenedCmdline {
+        envs: cmd.envs,
+        command: cmd.arguments.remove(0),
+        command_span: None,
+        arguments: cmd.arguments,
+        redirects: Vec::new(),
+        next: None,
+        background: false,
+    };
+    let header = i18n::format(
+        "Every {interval}s: {command}",
+        &[
+            ("interval", &format!("{:.1}", interval.as_secs_f64())),
+            ("command", &line),
+        ],
+    );
+
+    let clear = runner.is_terminal();
+    let interrupts = jobs::interrupts();
+    let exit_status = 'watching: loop {
+        // printed as it goes rather than returned, since it's never done
+        if clear {
+            let _ = runner.print(b"\x1b[H\x1b[2J");
+        }
+        let _ = runner.print(format!("{}\n\n", header).as_bytes());
+
+        let exit_status = match runner.dispatch(watched.clone()) {
+            Ok(x) => x,
+            Err(e @ ProcError::CommandNotFound { .. }) => {
+                return exit_with_error(127, format!("rs-shell: {}", e))
+            }
+            Err(e) => {
+                let _ = runner.eprint(format!("watch: {}\n", e).as_bytes());
+                e.exit_status()
+            }
+        };
+        let footer = format!(
+            "\n[{}] {}\n",
+            clock(),
+            i18n::format(
+                "exited with status {status}",
+                &[("status", &evaluator::exit_code(exit_status))]
+            )
+        );
+        let _ = runner.print(footer.as_bytes());
+        let stopped = [Signal::SIGINT, Signal::SIGTSTP].map(|x| ExitStatus::Signaled(x as u8));
+        if stopped.contains(&exit_status) {
+            break exit_status;
+        }
+
+        // slept in steps so that ^C doesn't have to wait for the whole interval to be noticed
+        let start = Instant::now();
+        while start.elapsed() < interval {
+            if jobs::interrupts() != interrupts {
+                break 'watching exit_status;
+            }
+            thread::sleep((interval - start.elapsed()).min(Duration::from_millis(50)));
+        }
+    };
+    CaptureData {
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        exit_status,
+    }
+}
+
+/// the local time of day, as `HH:MM:SS`
+fn clock() -> String {
+    // SAFETY: `localtime_r` only writes to `tm`, which is big enough for it
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        libc::localtime_r(&now, &mut tm);
+        tm
     };
+    format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
 
+/// formats a duration like `time` does in other shells, e.g. `0m1.250s`
+fn format_time(time: Duration) -> String {
+    let secs = time.as_secs_f64();
+    format!("{}m{:.3}s", (secs / 60.0).floor(), secs % 60.0)
+}
+
+/// `set [-+abc...] [-+o option]...`, changes shell options, with no arguments every variable that
+/// has a value is listed instead, as `NAME=value` sorted by name
+fn builtin_set(cmd: FlattenedCmdline) -> CaptureData {
+    if cmd.arguments.is_empty() {
+        let output = env::declared()
+            .into_iter()
+            .filter_map(|(name, _, value)| {
+                let value = format_value(&value?);
+                Some(format!("{}={}\n", name.to_string_lossy(), value))
+            })
+            .collect();
+        return exit_with_output(0, output);
+    }
+    let mut args = cmd.arguments.iter().map(|x| x.to_string_lossy());
+    while let Some(arg) = args.next() {
+        let (value, flags) = match (arg.strip_prefix('-'), arg.strip_prefix('+')) {
+            (Some(flags), _) => (true, flags),
+            (_, Some(flags)) => (false, flags),
+            _ => {
+                return exit_with_error(
+                    1,
+                    format!("set: {}: {}", arg, i18n::text("invalid option")),
+                )
+            }
+        };
+        if flags == "o" {
+            let Some(name) = args.next() else {
+                // `set -o` lists the options, `set +o` lists them as commands that recreate them
+                let output = ShellOption::ALL
+                    .iter()
+                    .filter(|x| !x.is_shopt())
+                    .map(|x| match value {
+                        true => format!("{:<15}\t{}\n", x.name(), on_off(options::enabled(*x))),
+                        false => format!(
+                            "set {}o {}\n",
+                            if options::enabled(*x) { '-' } else { '+' },
+                            x.name()
+                        ),
+                    })
+                    .collect::<String>();
+                return exit_with_output(0, output);
+            };
+            match ShellOption::from_name(&name) {
+                Some(opt) if !value && options::locked(opt) => {
+                    return exit_with_error(
+                        1,
+                        format!(
+                            "set: {}: {}",
+                            name,
+                            i18n::text("can't be turned off once it's on")
+                        ),
+                    )
+                }
+                Some(opt) if !opt.is_shopt() => options::set(opt, value),
+                _ => {
+                    return exit_with_error(
+                        1,
+                        format!("set: {}: {}", name, i18n::text("invalid option name")),
+                    )
+                }
+            }
+            continue;
+        }
+        for flag in flags.chars() {
+            match ShellOption::from_flag(flag) {
+                Some(opt) => options::set(opt, value),
+                None => {
+                    return exit_with_error(
+                        1,
+                        format!("set: -{}: {}", flag, i18n::text("invalid option")),
+                    )
+                }
+            }
+        }
+    }
     exit_quiet_success()
 }
+
+fn format_shopt(opt: ShellOption, enabled: bool) -> String {
+    format!("{}\t{}\n", opt.name(), on_off(enabled))
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn builtin_jobs(_: FlattenedCmdline) -> CaptureData {
+    jobs::poll();
+    let output = jobs::list()
+        .iter()
+        .map(|job| format!("{}\n", jobs::describe(job)))
+        .collect::<String>();
+    // like other shells, finished jobs are reported once and then forgotten
+    jobs::take_done();
+    exit_with_output(0, output)
+}
+
+/// resolves the job spec `fg` or `bg` was given, if any, to a job id
+fn job_arg(name: &str, cmd: &FlattenedCmdline) -> Result<usize, CaptureData> {
+    let spec = match cmd.arguments.as_slice() {
+        [] => None,
+        [spec] => Some(spec.to_string_lossy()),
+        _ => {
+            return Err(exit_with_error(
+                1,
+                format!("{}: {}", name, i18n::text("too many arguments")),
+            ))
+        }
+    };
+    jobs::poll();
+    jobs::find(spec.as_deref()).ok_or_else(|| match spec {
+        Some(spec) => exit_with_error(
+            1,
+            format!("{}: {}: {}", name, spec, i18n::text("no such job")),
+        ),
+        None => exit_with_error(1, format!("{}: {}", name, i18n::text("no current job"))),
+    })
+}
+
+fn builtin_fg(cmd: FlattenedCmdline) -> CaptureData {
+    let id = match job_arg("fg", &cmd) {
+        Ok(x) => x,
+        Err(e) => return e,
+    };
+    if let Some(job) = jobs::list().into_iter().find(|x| x.id == id) {
+        // printed immediately rather than returned since the job takes over the terminal first
+        println!("{}", job.command);
+    }
+    CaptureData {
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        exit_status: jobs::foreground(id, true),
+    }
+}
+
+fn builtin_bg(cmd: FlattenedCmdline) -> CaptureData {
+    let id = match job_arg("bg", &cmd) {
+        Ok(x) => x,
+        Err(e) => return e,
+    };
+    let Some(job) = jobs::list().into_iter().find(|x| x.id == id) else {
+        return exit_with_error(1, format!("bg: {}: {}", id, i18n::text("no such job")));
+    };
+    if job.state != JobState::Stopped {
+        return exit_with_error(
+            1,
+            format!(
+                "bg: {}",
+                i18n::format("job {id} already in background", &[("id", &id)])
+            ),
+        );
+    }
+    jobs::background(id);
+    exit_with_output(
+        0,
+        format!("[{}]{} {} &\n", id, jobs::marker(id), job.command),
+    )
+}
+
+/// `parallel [-j N] CMDLINE...`, runs each command line concurrently, at most `N` at a time
+/// (defaulting to the number of cpus), and fails with the status of the first one to fail in the
+/// order they were given, each failure is reported as it happens
+///
+/// the output of each command line is held back until it's finished and then written out all at
+/// once, so that the lines of ones running at the same time don't end up interleaved
+fn builtin_parallel(cmd: FlattenedCmdline, runner: &mut Runner) -> CaptureData {
+    let (limit, cmdlines) = match parallel_args(&cmd.arguments) {
+        Ok(x) => x,
+        Err(e) => return exit_with_error(1, format!("parallel: {}", e)),
+    };
+
+    // everything's flattened up front, so substitutions are run one at a time like usual, and the
+    // evaluator is kept around until the end so the FIFOs of process substitutions stick around
+    let mut evaluator = Evaluator::new();
+    let mut queue = Vec::with_capacity(cmdlines.len());
+    for (i, line) in cmdlines.iter().enumerate() {
+        let flat = ast::generate_ast(line)
+            .map_err(|e| e.to_string())
+            .and_then(|x| evaluator.flatten(x).map_err(|e| e.to_string()));
+        match flat {
+            Ok(x) => queue.push((i, x)),
+            Err(e) => return exit_with_error(1, format!("parallel: '{}': {}", line, e)),
+        }
+    }
+    // popped from the back, so reversed to start them in order
+    queue.reverse();
+
+    let queue = Mutex::new(queue);
+    let statuses = Mutex::new(vec![ExitStatus::Exited(0); cmdlines.len()]);
+    // held while a finished command line's output is written out
+    let output = Mutex::new([runner.stdout.as_ref(), runner.stderr.as_ref()]);
+    let workers = limit.get().min(cmdlines.len());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            // a `nice` that `parallel` is run under applies to every worker
+            let priority = priority::current();
+            let mut backend = runner.backend.fork();
+            let (queue, statuses, output, cmdlines) = (&queue, &statuses, &output, &cmdlines);
+            scope.spawn(move || {
+                priority::with(priority, || loop {
+                    let Some((i, cmd)) = queue.lock().pop() else {
+                        break;
+                    };
+                    let (result, [stdout, mut stderr]) = run_buffered(backend.as_mut(), cmd);
+                    let status = match result {
+                        Ok(x) => {
+                            // like a loop would be, the rest is abandoned when one is interrupted
+                            if x == ExitStatus::Signaled(Signal::SIGINT as u8) {
+                                queue.lock().clear();
+                            }
+                            if !x.success() {
+                                let msg = i18n::format(
+                                    "'{command}' exited with status {status}",
+                                    &[
+                                        ("command", &cmdlines[i]),
+                                        ("status", &evaluator::exit_code(x)),
+                                    ],
+                                );
+                                stderr.extend(format!("parallel: {}\n", msg).into_bytes());
+                            }
+                            x
+                        }
+                        Err(e) => {
+                            let msg = format!("parallel: '{}': {}\n", cmdlines[i], e);
+                            stderr.extend(msg.into_bytes());
+                            e.exit_status()
+                        }
+                    };
+                    let [out, err] = *output.lock();
+                    let _ = write_stream(out, io::stdout(), &stdout);
+                    let _ = write_stream(err, io::stderr(), &stderr);
+                    statuses.lock()[i] = status;
+                })
+            });
+        }
+    });
+
+    let exit_status = statuses
+        .into_inner()
+        .into_iter()
+        .find(|x| !x.success())
+        .unwrap_or(ExitStatus::Exited(0));
+    CaptureData {
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        exit_status,
+    }
+}
+
+/// runs `cmd` as a [`JobMode::Concurrent`] job with `backend`, returning its stdout and stderr
+/// once it's finished rather than letting them through as they come
+fn run_buffered(
+    backend: &mut dyn ProcessBackend,
+    cmd: FlattenedCmdline,
+) -> (Result<ExitStatus, ProcError>, [Vec<u8>; 2]) {
+    let pipes = match io::pipe().and_then(|x| Ok([x, io::pipe()?])) {
+        Ok(x) => x,
+        Err(e) => {
+            return (
+                Err(redirect_error(OsStr::new("pipe"), e)),
+                Default::default(),
+            )
+        }
+    };
+    thread::scope(|scope| {
+        let [(out_reader, out_writer), (err_reader, err_writer)] = pipes;
+        let [out, err] = [out_writer, err_writer].map(|x| File::from(OwnedFd::from(x)));
+        // read while it runs, so it doesn't block on a full pipe
+        let readers = [out_reader, err_reader].map(|mut reader| {
+            scope.spawn(move || {
+                let mut buf = Vec::new();
+                let _ = reader.read_to_end(&mut buf);
+                buf
+            })
+        });
+        let result = proc_manager::run_chain(
+            backend,
+            cmd,
+            Some(&out),
+            Some(&err),
+            Some(JobMode::Concurrent),
+        );
+        // the readers only see the end of the output once every copy of the pipes is closed
+        drop((out, err));
+        let output = readers.map(|x| x.join().expect("output reader must not panic"));
+        (result, output)
+    })
+}
+
+/// writes `bytes` to `file`, or to `default` if there isn't one
+fn write_stream(file: Option<&File>, mut default: impl Write, bytes: &[u8]) -> io::Result<()> {
+    match file {
+        Some(mut file) => write_all(&mut file, bytes),
+        None => write_all(&mut default, bytes),
+    }
+}
+
+/// `nice [-n N] [-i] COMMAND [ARG]...`, runs a command with its niceness raised by `N` (10 by
+/// default), and with `-i` in the idle I/O scheduling class, with no command it prints the shell's
+/// own niceness, anything it's piped into is run at the same niceness
+fn builtin_nice(mut cmd: FlattenedCmdline, runner: &mut Runner) -> CaptureData {
+    let (adjust, argc) = match nice_args(&cmd.arguments) {
+        Ok(x) => x,
+        Err(e) => return exit_with_error(1, format!("nice: {}", e)),
+    };
+    cmd.arguments.drain(..argc);
+    if cmd.arguments.is_empty() {
+        Errno::clear();
+        // SAFETY: only reads the shell's own priority
+        let niceness = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+        return exit_with_output(0, format!("{}\n", niceness));
+    }
+    let niced = runner.pipeline(FlattenedCmdline {
+        envs: cmd.envs,
+        command: cmd.arguments.remove(0),
+        command_span: None,
+        arguments: cmd.arguments,
+        redirects: Vec::new(),
+        next: None,
+        background: false,
+    });
+
+    // nested `nice`s add up, like they would running the external command
+    let base = priority::current().unwrap_or_default();
+    let priority = Priority {
+        niceness: base.niceness.saturating_add(adjust.niceness),
+        idle_io: base.idle_io || adjust.idle_io,
+    };
+    match priority::with(Some(priority), || runner.dispatch(niced)) {
+        Ok(exit_status) => CaptureData {
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            exit_status,
+        },
+        Err(e @ ProcError::CommandNotFound { .. }) => {
+            exit_with_error(127, format!("rs-shell: {}", e))
+        }
+        Err(e) => exit_with_error(1, format!("nice: {}", e)),
+    }
+}
+
+/// parses the options given to `nice`, returning the priority they ask for and how many arguments
+/// they took up
+fn nice_args(args: &[OsString]) -> Result<(Priority, usize), String> {
+    let mut priority = Priority {
+        niceness: 10,
+        idle_io: false,
+    };
+    let mut i = 0;
+    while let Some(arg) = args.get(i).map(|x| x.to_string_lossy()) {
+        let value = match arg.as_ref() {
+            "-i" => {
+                priority.idle_io = true;
+                i += 1;
+                continue;
+            }
+            "-n" => {
+                i += 1;
+                args.get(i)
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .ok_or_else(|| format!("-n: {}", i18n::text("option requires an argument")))?
+            }
+            x => match x.strip_prefix("-n") {
+                Some(value) => value.to_owned(),
+                None => break,
+            },
+        };
+        priority.niceness = value
+            .parse()
+            .map_err(|_| format!("-n: {}: {}", value, i18n::text("invalid adjustment")))?;
+        i += 1;
+    }
+    Ok((priority, i))
+}
+
+/// splits the arguments to `parallel` into how many commands may run at once and the command
+/// lines to run
+fn parallel_args(args: &[OsString]) -> Result<(NonZeroUsize, Vec<String>), String> {
+    let mut args = args.iter().map(|x| x.to_string_lossy().into_owned());
+    let mut limit = None;
+    let mut cmdlines = Vec::new();
+    while let Some(arg) = args.next() {
+        if !cmdlines.is_empty() || limit.is_some() || !arg.starts_with("-j") {
+            cmdlines.push(arg);
+            continue;
+        }
+        let value = match arg.strip_prefix("-j").filter(|x| !x.is_empty()) {
+            Some(x) => x.to_owned(),
+            None => args
+                .next()
+                .ok_or_else(|| format!("-j: {}", i18n::text("option requires an argument")))?,
+        };
+        limit = Some(
+            value
+                .parse::<NonZeroUsize>()
+                .map_err(|_| format!("-j: {}: {}", value, i18n::text("invalid job count")))?,
+        );
+    }
+    if cmdlines.is_empty() {
+        return Err(i18n::text("no commands given").to_owned());
+    }
+    let limit =
+        limit.unwrap_or_else(|| thread::available_parallelism().unwrap_or(NonZeroUsize::MIN));
+    Ok((limit, cmdlines))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proc_manager::recording::{assert_spawns, record, RecordingBackend},
+        sandbox::Sandbox,
+    };
+
+    #[test]
+    fn nice_options() {
+        let args = |x: &[&str]| x.iter().map(Into::into).collect::<Vec<OsString>>();
+
+        let (priority, argc) = nice_args(&args(&["make", "-n", "3"])).unwrap();
+        assert_eq!((priority.niceness, priority.idle_io, argc), (10, false, 0));
+        let (priority, argc) = nice_args(&args(&["-n", "-5", "-i", "make"])).unwrap();
+        assert_eq!((priority.niceness, priority.idle_io, argc), (-5, true, 3));
+        let (priority, argc) = nice_args(&args(&["-n19", "make"])).unwrap();
+        assert_eq!((priority.niceness, argc), (19, 1));
+        assert!(nice_args(&args(&["-n", "high", "make"])).is_err());
+        assert!(nice_args(&args(&["-n"])).is_err());
+    }
+
+    #[test]
+    fn nice_output() {
+        let (backend, status) =
+            record!("greet \"[$(nice echo hi)]\"", ExitStatus::Exited(0) => "hi");
+        assert!(status.unwrap().success());
+        assert_spawns!(backend, ["echo hi", "greet '[hi]'"]);
+
+        let (backend, _) = record!("nice -n 1 echo hi | tr a-z A-Z");
+        assert_spawns!(backend, ["echo hi | tr a-z A-Z"]);
+    }
+
+    #[test]
+    fn time_pipeline() {
+        let (backend, status) = record!("time a x | b | c", ExitStatus::Exited(4) => "");
+        assert_eq!(status.unwrap(), ExitStatus::Exited(4));
+        assert_spawns!(backend, ["a x | b | c"]);
+
+        let (backend, _) = record!("greet \"$(time -v echo hi)\"", ExitStatus::Exited(0) => "hi");
+        assert_spawns!(backend, ["echo hi", "greet hi"]);
+    }
+
+    #[test]
+    fn watch_output() {
+        let interrupted = ExitStatus::Signaled(Signal::SIGINT as u8);
+        let (backend, _) = record!("greet \"$(watch -n 1 a x)\"", interrupted => "out\n");
+        let jobs = backend.jobs.lock();
+        assert_eq!(jobs[0].commands[0].command, "a");
+        let output = jobs[1].commands[0].arguments[0].to_string_lossy();
+        assert!(
+            output.starts_with("Every 1.0s: a x\n\nout\n\n["),
+            "{}",
+            output
+        );
+        assert!(output.ends_with("] exited with status 130\n"), "{}", output);
+    }
+
+    #[test]
+    fn sandbox_confinement() {
+        let failed = |x: Result<ExitStatus, _>| !x.is_ok_and(|x| x.success());
+        options::with(ShellOption::Sandbox, true, || {
+            // `RS_SANDBOX` leaving out `fs` doesn't confine the shell at all
+            if !Sandbox::current().is_some_and(|x| x.read_only) {
+                return;
+            }
+            assert!(failed(record!("jobs > /tmp/rs-shell-sandbox-test").1));
+            assert!(failed(record!("jobs >> /etc/rs-shell-sandbox-test").1));
+            assert!(!Path::new("/tmp/rs-shell-sandbox-test").exists());
+            assert!(record!("jobs > /dev/null").1.unwrap().success());
+
+            let cwd = std::env::current_dir().unwrap();
+            assert!(failed(record!("cd /").1));
+            assert!(failed(record!("cd ..").1));
+            assert_eq!(std::env::current_dir().unwrap(), cwd);
+            assert!(failed(record!("source /etc/profile").1));
+
+            assert!(failed(record!("set +o sandbox").1));
+            assert!(options::enabled(ShellOption::Sandbox));
+        });
+    }
+
+    #[test]
+    fn shopt_options() {
+        use ExitStatus::Exited;
+
+        options::with(ShellOption::AutoCd, false, || {
+            let (backend, status) = record!("shopt -s autocd; greet \"$(shopt autocd)\"");
+            assert!(status.unwrap().success());
+            assert!(options::enabled(ShellOption::AutoCd));
+            assert_spawns!(backend, ["greet 'autocd\ton\n'"]);
+
+            assert!(record!("shopt -u autocd").1.unwrap().success());
+            assert!(!options::enabled(ShellOption::AutoCd));
+            assert_eq!(record!("shopt autocd").1.unwrap(), Exited(1));
+            // `set -o` options aren't `shopt` ones
+            assert_eq!(record!("shopt -s errexit").1.unwrap(), Exited(1));
+            assert_eq!(record!("shopt nonexistent").1.unwrap(), Exited(1));
+        });
+    }
+
+    #[test]
+    fn auto_cd() {
+        let builtin = |line| {
+            let ast = crate::ast::generate_ast(line).unwrap();
+            let cmd = crate::Evaluator::with_backend(RecordingBackend::default())
+                .flatten(ast)
+                .unwrap();
+            match Builtin::maybe_new(cmd) {
+                BuiltinCheck::Yes(x) => Some(x.cmd),
+                BuiltinCheck::No(_) => None,
+            }
+        };
+        assert!(builtin("/").is_none());
+        options::with(ShellOption::AutoCd, true, || {
+            // a directory used as a command is `cd`'d into
+            let cmd = builtin("/").unwrap();
+            assert_eq!(cmd.command, "cd");
+            assert_eq!(cmd.arguments, ["/"]);
+            // anything else is still run as a command
+            assert!(builtin("/etc/passwd").is_none());
+            assert!(builtin("rs-shell-no-such-dir").is_none());
+        });
+    }
+
+    #[test]
+    fn parallel_job_count() {
+        let args = |x: &[&str]| x.iter().map(Into::into).collect::<Vec<OsString>>();
+
+        let (limit, cmdlines) = parallel_args(&args(&["-j", "2", "a", "-j3"])).unwrap();
+        assert_eq!(limit.get(), 2);
+        assert_eq!(cmdlines, ["a", "-j3"]);
+        let (limit, _) = parallel_args(&args(&["-j4", "a"])).unwrap();
+        assert_eq!(limit.get(), 4);
+        assert!(parallel_args(&args(&["-j", "0", "a"])).is_err());
+        assert!(parallel_args(&args(&["-j", "2"])).is_err());
+    }
+
+    #[test]
+    fn parallel_output() {
+        use ExitStatus::Exited;
+
+        let (backend, _) = record!(
+            "greet \"$(parallel -j 1 'echo a' 'echo b')\"",
+            Exited(0) => "a\n",
+            Exited(0) => "b\n",
+        );
+        let jobs = backend.jobs.lock();
+        assert_eq!(jobs.len(), 3);
+        assert_eq!(jobs[2].commands[0].arguments, [OsString::from("a\nb\n")]);
+        drop(jobs);
+
+        let path = std::env::temp_dir().join(format!("rs-shell-par-{}", std::process::id()));
+        let input = format!("parallel 'echo a' 'echo b' > {}", path.display());
+        record!(&input, Exited(0) => "a\n", Exited(0) => "b\n")
+            .1
+            .unwrap();
+        let output = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mut lines = output.lines().collect::<Vec<_>>();
+        lines.sort();
+        assert_eq!(lines, ["a", "b"]);
+    }
+}
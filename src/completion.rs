@@ -0,0 +1,334 @@
+use std::{
+    collections::HashMap,
+    fs::{self, Metadata},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use cs128h_project::{alias, builtins, env, path};
+use log::warn;
+
+use crate::config;
+
+pub mod spec;
+
+use spec::{Arguments, Spec};
+
+/// a single completion candidate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    /// what's shown in the completion listing, the file name followed by an `ls -F` style type
+    /// indicator
+    pub display: String,
+    /// what's inserted into the line if this candidate is chosen
+    pub replacement: String,
+    /// the SGR parameters (e.g. `01;34`) to color the listing entry with, if any
+    pub style: Option<String>,
+}
+
+/// the kind of filesystem entry a candidate refers to, used to pick its color and indicator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    Executable,
+    File,
+}
+
+impl EntryKind {
+    fn of(symlink_meta: &Metadata, target_meta: Option<&Metadata>) -> Self {
+        let ty = symlink_meta.file_type();
+        if ty.is_symlink() {
+            EntryKind::Symlink
+        } else if ty.is_dir() {
+            EntryKind::Dir
+        } else if ty.is_fifo() {
+            EntryKind::Fifo
+        } else if ty.is_socket() {
+            EntryKind::Socket
+        } else if target_meta.unwrap_or(symlink_meta).permissions().mode() & 0o111 != 0 {
+            EntryKind::Executable
+        } else {
+            EntryKind::File
+        }
+    }
+
+    /// the `LS_COLORS` key for this kind of entry
+    fn key(self) -> &'static str {
+        match self {
+            EntryKind::Dir => "di",
+            EntryKind::Symlink => "ln",
+            EntryKind::Fifo => "pi",
+            EntryKind::Socket => "so",
+            EntryKind::Executable => "ex",
+            EntryKind::File => "fi",
+        }
+    }
+
+    /// the indicator `ls -F` appends to this kind of entry
+    fn indicator(self) -> &'static str {
+        match self {
+            EntryKind::Dir => "/",
+            EntryKind::Symlink => "@",
+            EntryKind::Fifo => "|",
+            EntryKind::Socket => "=",
+            EntryKind::Executable => "*",
+            EntryKind::File => "",
+        }
+    }
+}
+
+/// a parsed `LS_COLORS` value
+#[derive(Debug, Clone, Default)]
+pub struct LsColors {
+    /// styles keyed by two letter type codes like `di` or `ex`
+    types: HashMap<String, String>,
+    /// styles keyed by file extension (from `*.ext=` entries), without the leading dot
+    extensions: HashMap<String, String>,
+}
+
+impl LsColors {
+    pub fn from_env() -> Self {
+        let value = env::get("LS_COLORS");
+        if value.is_empty() {
+            Self::parse(&config::get().theme.ls)
+        } else {
+            Self::parse(&value.to_string_lossy())
+        }
+    }
+
+    pub fn parse(value: &str) -> Self {
+        let mut colors = Self::default();
+        for entry in value.split(':') {
+            let Some((key, style)) = entry.split_once('=') else {
+                continue;
+            };
+            match key.strip_prefix("*.") {
+                Some(ext) => colors.extensions.insert(ext.to_owned(), style.to_owned()),
+                None => colors.types.insert(key.to_owned(), style.to_owned()),
+            };
+        }
+        colors
+    }
+
+    fn style(&self, kind: EntryKind, name: &str) -> Option<String> {
+        // like ls, extension colors only apply to regular files
+        if matches!(kind, EntryKind::File | EntryKind::Executable) {
+            if let Some(style) = name
+                .rsplit_once('.')
+                .and_then(|(_, ext)| self.extensions.get(ext))
+            {
+                return Some(style.clone());
+            }
+        }
+        self.types.get(kind.key()).cloned()
+    }
+}
+
+/// completes the path being typed at `pos` in `line`
+///
+/// returns the byte offset the replacement starts at along with the candidates, each annotated
+/// with its `LS_COLORS` style and type indicator
+pub fn complete_path(line: &str, pos: usize, colors: &LsColors) -> (usize, Vec<Candidate>) {
+    let start = line[..pos]
+        .rfind(char::is_whitespace)
+        .map(|x| x + 1)
+        .unwrap_or(0);
+    let word = &line[start..pos];
+    let (dir, prefix) = match word.rfind('/') {
+        Some(x) => (&word[..=x], &word[x + 1..]),
+        None => ("", word),
+    };
+
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return (start, Vec::new());
+    };
+
+    let mut candidates = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            // hidden files are only offered once the user has started typing the dot
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let meta = entry.metadata().ok()?;
+            let target = fs::metadata(Path::new(dir).join(&name)).ok();
+            let kind = EntryKind::of(&meta, target.as_ref());
+            let followed_dir = target.as_ref().is_some_and(|x| x.is_dir());
+            Some(Candidate {
+                display: format!("{}{}", name, kind.indicator()),
+                replacement: format!("{}{}{}", dir, name, if followed_dir { "/" } else { "" }),
+                style: colors.style(kind, &name),
+            })
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+
+    (start, candidates)
+}
+
+/// completion specs, each loaded from the `completions` directory the first time it's needed, see
+/// [`spec`]
+#[derive(Debug, Default)]
+pub struct Specs {
+    dir: Option<PathBuf>,
+    /// every spec that's been looked for, keyed by command, `None` if there isn't one
+    loaded: HashMap<String, Option<Spec>>,
+}
+
+impl Specs {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            loaded: HashMap::new(),
+        }
+    }
+
+    /// the spec for `command`, if it has one, a spec that can't be loaded is reported the first
+    /// time, and treated as missing
+    fn get(&mut self, command: &str) -> Option<&Spec> {
+        let dir = self.dir.as_ref()?;
+        if command.is_empty() || command.starts_with('.') || command.contains('/') {
+            return None;
+        }
+        let spec = self.loaded.entry(command.to_owned()).or_insert_with(|| {
+            let path = ["toml", "json"]
+                .map(|x| dir.join(format!("{}.{}", command, x)))
+                .into_iter()
+                .find(|x| x.exists())?;
+            Spec::load(&path)
+                .inspect_err(|e| warn!("{}: {}", path.display(), e))
+                .ok()
+        });
+        spec.as_ref()
+    }
+}
+
+/// completes the word being typed at `pos` in `line`, with the commands it could name if it's the
+/// command itself, what the command's spec says it can be if it has one, or as a path otherwise
+pub fn complete(
+    line: &str,
+    pos: usize,
+    colors: &LsColors,
+    specs: &mut Specs,
+) -> (usize, Vec<Candidate>) {
+    let before = &line[..pos];
+    let start = before.rfind(char::is_whitespace).map_or(0, |x| x + 1);
+    let word = &before[start..];
+    // only the last command on the line matters, e.g. the one after a pipe
+    let command_start = before.rfind(['|', ';', '&', '(']).map_or(0, |x| x + 1);
+    let words: Vec<_> = before[command_start..start]
+        .split_whitespace()
+        .skip_while(|x| is_assignment(x))
+        .collect();
+    if words.is_empty() && !word.is_empty() && !word.contains('/') {
+        return (start, complete_command(word, colors));
+    }
+    let spec = words
+        .first()
+        .map(|x| x.rsplit('/').next().unwrap_or(x))
+        .and_then(|x| specs.get(x));
+    let Some(spec) = spec else {
+        return complete_path(line, pos, colors);
+    };
+
+    match spec.complete(&words[1..], word) {
+        Arguments::Files => complete_path(line, pos, colors),
+        Arguments::Directories => {
+            let (start, mut candidates) = complete_path(line, pos, colors);
+            candidates.retain(|x| x.replacement.ends_with('/'));
+            (start, candidates)
+        }
+        Arguments::None => (pos, Vec::new()),
+        Arguments::Words(mut words) => {
+            words.retain(|x| x.starts_with(word));
+            words.sort();
+            words.dedup();
+            let style = &config::get().theme.completion;
+            let candidates = words.into_iter().map(|x| Candidate {
+                display: x.clone(),
+                replacement: x,
+                style: Some(style.clone()).filter(|x| !x.is_empty()),
+            });
+            (start, candidates.collect())
+        }
+    }
+}
+
+/// every builtin, alias, and command in `$PATH` starting with `prefix`
+fn complete_command(prefix: &str, colors: &LsColors) -> Vec<Candidate> {
+    let mut names = (*path::commands()).clone();
+    names.extend(builtins::names().map(str::to_owned));
+    names.extend(alias::list().into_iter().map(|(name, _)| name));
+    names
+        .iter()
+        .filter(|x| x.starts_with(prefix))
+        .map(|name| Candidate {
+            display: name.clone(),
+            replacement: name.clone(),
+            style: colors.style(EntryKind::Executable, name),
+        })
+        .collect()
+}
+
+/// whether `word` is a `NAME=value` assignment in front of a command
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ls_colors_parsing() {
+        let colors = LsColors::parse("di=01;34:ex=01;32:*.tar=01;31:bogus");
+        assert_eq!(
+            colors.style(EntryKind::Dir, "src"),
+            Some("01;34".to_owned())
+        );
+        assert_eq!(
+            colors.style(EntryKind::File, "a.tar"),
+            Some("01;31".to_owned())
+        );
+        assert_eq!(colors.style(EntryKind::File, "a.txt"), None);
+    }
+
+    #[test]
+    fn spec_completion() {
+        let dir = std::env::temp_dir().join(format!("rs-shell-completions-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("tool.toml"),
+            "flags = [\"--all\", \"--any\", \"-v\"]\n[run]\n",
+        )
+        .unwrap();
+        let mut specs = Specs::new(Some(dir.clone()));
+        let colors = LsColors::default();
+
+        let complete = |line: &str, specs: &mut Specs| {
+            let (start, candidates) = complete(line, line.len(), &colors, specs);
+            let words: Vec<_> = candidates.into_iter().map(|x| x.replacement).collect();
+            (start, words)
+        };
+        assert_eq!(
+            complete("A=1 tool --a", &mut specs),
+            (9, vec!["--all".to_owned(), "--any".to_owned()])
+        );
+        assert_eq!(
+            complete("echo | /bin/tool r", &mut specs),
+            (17, vec!["run".to_owned()])
+        );
+        // the command itself is completed from builtins and `$PATH`, spec or no spec
+        let (start, words) = complete("ls; A=1 sho", &mut specs);
+        assert_eq!(start, 8);
+        assert!(words.contains(&"shopt".to_owned()), "{:?}", words);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+//! completion specs for external commands, which say what their flags, subcommands, and arguments
+//! are, so that commands without completion functions of their own still complete nicely, they're
+//! read from `completions/<command>.toml` (or `.json`) in the config directory, e.g. for cargo
+//!
+//! ```toml
+//! flags = ["--version", "--help"]
+//! "--color" = ["auto", "always", "never"]  # a flag that takes a value, and what it can be
+//!
+//! [build]
+//! flags = ["--release"]
+//! "--manifest-path" = "files"
+//!
+//! [new]
+//! arguments = "directories"
+//! ```
+//!
+//! where each table is a subcommand, with dotted ones for subcommands of those (e.g.
+//! `[remote.add]`), or the same as JSON, with subcommands nested in a `subcommands` object
+//!
+//! ```json
+//! {
+//!     "flags": ["--version", "--help"],
+//!     "--color": ["auto", "always", "never"],
+//!     "subcommands": {
+//!         "build": {"flags": ["--release"], "--manifest-path": "files"},
+//!         "new": {"arguments": "directories"}
+//!     }
+//! }
+//! ```
+//!
+//! `arguments`, and the value of a flag, is either a list of words, or one of `"files"` (the
+//! default), `"directories"`, or `"none"`, a command with subcommands completes those rather than
+//! its arguments, unless they're a list of words, in which case both are offered
+//!
+//! a spec that doesn't have that shape isn't used at all, only settings that are neither flags
+//! nor subcommands are warned about and skipped
+
+use std::{fs, path::Path};
+
+use log::warn;
+use serde::{
+    de::{MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+
+use crate::config::ConfigError;
+
+/// what can be completed for a command, or one of its subcommands, see the [module docs](self)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Spec {
+    /// flags that don't take a value
+    pub flags: Vec<String>,
+    /// flags that take a value, along with what the value can be
+    pub values: Vec<(String, Arguments)>,
+    pub arguments: Arguments,
+    pub subcommands: Vec<(String, Spec)>,
+}
+
+/// what an argument, or the value of a flag, can be
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "RawArguments")]
+pub enum Arguments {
+    #[default]
+    Files,
+    Directories,
+    /// nothing can be completed
+    None,
+    Words(Vec<String>),
+}
+
+/// [`Arguments`] as they're written
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawArguments {
+    Words(Vec<String>),
+    Named(Named),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Named {
+    Files,
+    Directories,
+    None,
+}
+
+impl From<RawArguments> for Arguments {
+    fn from(value: RawArguments) -> Self {
+        match value {
+            RawArguments::Words(x) => Arguments::Words(x),
+            RawArguments::Named(Named::Files) => Arguments::Files,
+            RawArguments::Named(Named::Directories) => Arguments::Directories,
+            RawArguments::Named(Named::None) => Arguments::None,
+        }
+    }
+}
+
+/// a [`Spec`] as it's written, in either format
+#[derive(Deserialize)]
+struct RawSpec {
+    #[serde(default)]
+    flags: Vec<String>,
+    #[serde(default)]
+    arguments: Arguments,
+    /// subcommands in JSON, TOML has them as tables among everything else
+    #[serde(default)]
+    subcommands: Ordered<RawSpec>,
+    /// flags that take a value, and subcommands in TOML
+    #[serde(flatten)]
+    rest: Ordered<Setting>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Setting {
+    Subcommand(RawSpec),
+    Value(Arguments),
+}
+
+/// the members of an object or table, in the order they were written
+struct Ordered<T>(Vec<(String, T)>);
+
+impl<T> Default for Ordered<T> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Ordered<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OrderedVisitor<T>(std::marker::PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for OrderedVisitor<T> {
+            type Value = Ordered<T>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a table")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut members = Vec::new();
+                while let Some(member) = map.next_entry()? {
+                    members.push(member);
+                }
+                Ok(Ordered(members))
+            }
+        }
+
+        deserializer.deserialize_map(OrderedVisitor(std::marker::PhantomData))
+    }
+}
+
+impl Spec {
+    /// reads the spec at `path`, which is JSON if it ends in `.json`, and TOML otherwise
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let input = fs::read_to_string(path).map_err(|e| ConfigError::Read { internal: e })?;
+        let raw = match path.extension().is_some_and(|x| x == "json") {
+            true => serde_json::from_str(&input).map_err(|e| ConfigError::Json { internal: e })?,
+            false => toml::from_str(&input).map_err(|e| ConfigError::Syntax { internal: e })?,
+        };
+        Ok(Self::from_raw(raw, path))
+    }
+
+    fn from_raw(raw: RawSpec, path: &Path) -> Self {
+        let mut spec = Spec {
+            flags: raw.flags,
+            arguments: raw.arguments,
+            ..Spec::default()
+        };
+        for (name, raw) in raw.subcommands.0 {
+            spec.subcommands.push((name, Self::from_raw(raw, path)));
+        }
+        for (key, setting) in raw.rest.0 {
+            match setting {
+                Setting::Subcommand(raw) => {
+                    spec.subcommands.push((key, Self::from_raw(raw, path)));
+                }
+                Setting::Value(value) if key.starts_with('-') => spec.values.push((key, value)),
+                Setting::Value(_) => warn!("{}: {}: unknown setting", path.display(), key),
+            }
+        }
+        spec
+    }
+
+    /// what the word being typed could be, given the `words` before it (not counting the command
+    /// itself), flags are only offered once the word starts with a `-`
+    pub fn complete(&self, words: &[&str], word: &str) -> Arguments {
+        let mut spec = self;
+        let mut words = words.iter();
+        while let Some(x) = words.next() {
+            if let Some((_, value)) = spec.values.iter().find(|(flag, _)| flag == x) {
+                match words.next() {
+                    Some(_) => continue,
+                    None => return value.clone(),
+                }
+            }
+            if let Some((_, subcommand)) = spec.subcommands.iter().find(|(name, _)| name == x) {
+                spec = subcommand;
+            }
+        }
+
+        if word.starts_with('-') {
+            let flags = spec.flags.iter().chain(spec.values.iter().map(|(x, _)| x));
+            return Arguments::Words(flags.cloned().collect());
+        }
+        if spec.subcommands.is_empty() {
+            return spec.arguments.clone();
+        }
+        let mut words: Vec<_> = spec.subcommands.iter().map(|(x, _)| x.clone()).collect();
+        if let Arguments::Words(x) = &spec.arguments {
+            words.extend(x.iter().cloned());
+        }
+        Arguments::Words(words)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specs() {
+        let toml = r#"
+flags = ["--version"]
+"--color" = ["auto", "never"]
+
+[build]
+flags = ["--release"]
+"--manifest-path" = "files"
+
+[remote.add]
+arguments = "none"
+"#;
+        let json = r#"{
+            "flags": ["--version"], "--color": ["auto", "never"],
+            "subcommands": {
+                "build": {"flags": ["--release"], "--manifest-path": "files"},
+                "remote": {"subcommands": {"add": {"arguments": "none"}}}
+            }
+        }"#;
+        let dir = std::env::temp_dir().join(format!("rs-shell-specs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("x.toml"), toml).unwrap();
+        fs::write(dir.join("x.json"), json).unwrap();
+        let spec = Spec::load(&dir.join("x.toml")).unwrap();
+        assert_eq!(Spec::load(&dir.join("x.json")).unwrap(), spec);
+
+        let words = |x: &[&str]| Arguments::Words(x.iter().map(|x| x.to_string()).collect());
+        assert_eq!(spec.complete(&[], ""), words(&["build", "remote"]));
+        assert_eq!(spec.complete(&[], "-"), words(&["--version", "--color"]));
+        assert_eq!(spec.complete(&["--color"], ""), words(&["auto", "never"]));
+        let args = ["--color", "auto", "build"];
+        assert_eq!(
+            spec.complete(&args, "-"),
+            words(&["--release", "--manifest-path"])
+        );
+        assert_eq!(spec.complete(&args, ""), Arguments::Files);
+        assert_eq!(spec.complete(&["remote", "add"], ""), Arguments::None);
+
+        fs::write(dir.join("x.json"), "{\"flags\": [\"a\"\n\"b\"]}").unwrap();
+        match Spec::load(&dir.join("x.json")) {
+            Err(ConfigError::Json { internal }) => assert_eq!(internal.line(), 2),
+            x => panic!("expected a syntax error, got {:?}", x),
+        }
+        fs::write(dir.join("x.toml"), "flags = \"a\"").unwrap();
+        assert!(matches!(
+            Spec::load(&dir.join("x.toml")),
+            Err(ConfigError::Syntax { .. })
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,378 @@
+//! the config file, `~/.config/rsshell/config.toml` (or whatever `$RS_SHELL_CONFIG` points at),
+//! which is read once at startup, e.g.
+//!
+//! ```toml
+//! [prompt]
+//! format = '\u:\w $ '
+//!
+//! [history]
+//! size = 5000
+//! file = "~/.local/share/rsshell/history"
+//!
+//! [colors]                      # see the theme module
+//! theme = "default"
+//! prompt_failure = "1;31"       # SGR parameters
+//! syntax_command = "1;32"       # and the rest of the syntax_* colors for the line being typed
+//! ls = "di=01;34:ex=01;32"      # used when LS_COLORS isn't set
+//!
+//! [keybindings]
+//! ctrl-f = "forward-word"
+//!
+//! [options]
+//! enable = ["autocd", "pipefail"]
+//!
+//! [aliases]
+//! ll = "ls -l"
+//!
+//! [accessibility]
+//! plain = true                  # like --plain
+//! ```
+//!
+//! variables like `PS1`, `HISTSIZE`, `HISTFILE` and `LS_COLORS` still take precedence over the
+//! file, a syntax error means none of the file is used, but a setting that's unknown or has the
+//! wrong kind of value is only warned about and skipped
+
+use std::{fs, io, path::PathBuf, sync::OnceLock};
+
+use log::warn;
+use rustyline::{Cmd, KeyCode, KeyEvent, Modifiers, Movement, Word};
+use toml::Value;
+
+use cs128h_project::{env, options::ShellOption};
+
+use crate::theme::{self, Theme};
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum ConfigError {
+    #[error("{internal}")]
+    Read { internal: io::Error },
+
+    #[error("{internal}")]
+    Syntax { internal: toml::de::Error },
+
+    #[error("{internal}")]
+    Json { internal: serde_json::Error },
+}
+
+/// everything the config file can set, see the [module docs](self)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    /// `prompt.format`, the prompt template used when `PS1` isn't set
+    pub prompt: Option<String>,
+    /// `history.size`, used when `HISTSIZE` isn't set
+    pub history_size: Option<usize>,
+    /// `history.file`, used when `HISTFILE` isn't set
+    pub history_file: Option<PathBuf>,
+    /// the `colors` table, the theme it names with whatever else it sets overriding that
+    pub theme: Theme,
+    /// the `keybindings` table
+    pub keybindings: Vec<(KeyEvent, Cmd)>,
+    /// `options.enable`, which are enabled before any command line flags are applied
+    pub options: Vec<ShellOption>,
+    /// the `aliases` table, in the order they appear
+    pub aliases: Vec<(String, String)>,
+    /// `accessibility.plain`, or `--plain`, for output that suits screen readers and dumb
+    /// terminals, without colors, escape sequences, or anything drawn over what's on the screen
+    pub plain: bool,
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// makes `config` the one returned by [`get`], this can only be done once
+pub fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        warn!("config was already initialized, ignoring new config");
+    }
+}
+
+/// the config loaded at startup, or the defaults if there wasn't one
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+impl Config {
+    /// reads and parses the config file at `path`
+    pub fn load(path: &std::path::Path) -> Result<Self, ConfigError> {
+        let input = fs::read_to_string(path).map_err(|e| ConfigError::Read { internal: e })?;
+        Self::parse(&input)
+    }
+
+    pub fn parse(input: &str) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        let mut entries = Vec::new();
+        for (table, value) in parse(input)? {
+            match value {
+                Value::Table(x) => {
+                    entries.extend(x.into_iter().map(|(k, v)| (table.clone(), k, v)))
+                }
+                _ => warn!("config: {}: unknown setting", table),
+            }
+        }
+        // the theme's overridden by the other colors, wherever they are
+        entries.sort_by_key(|(table, key, _)| !(table == "colors" && key == "theme"));
+        for (table, key, value) in entries {
+            if let Err(reason) = config.apply(&table, &key, value) {
+                warn!("config: {}.{}: {}", table, key, reason);
+            }
+        }
+        Ok(config)
+    }
+
+    fn apply(&mut self, table: &str, key: &str, value: Value) -> Result<(), String> {
+        match (table, key) {
+            ("prompt", "format") => self.prompt = Some(string(value)?),
+            ("history", "size") => {
+                let size = integer(value)?;
+                self.history_size = Some(usize::try_from(size).map_err(|_| "negative size")?);
+            }
+            ("history", "file") => self.history_file = Some(expand_home(string(value)?)),
+            ("colors", "theme") => {
+                let name = string(value)?;
+                self.theme = Theme::builtin(&name).ok_or_else(|| {
+                    format!(
+                        "unknown theme '{}', expected one of: {}",
+                        name,
+                        theme::NAMES.join(", ")
+                    )
+                })?;
+            }
+            ("colors", _) => self.theme.set(key, string(value)?)?,
+            ("keybindings", _) => {
+                let key_event = parse_key(key).ok_or("unknown key")?;
+                let cmd = parse_action(&string(value)?).ok_or("unknown action")?;
+                self.keybindings.push((key_event, cmd));
+            }
+            ("options", "enable") => {
+                for name in array(value)? {
+                    let name = string(name)?;
+                    let opt = ShellOption::from_name(&name)
+                        .ok_or_else(|| format!("unknown option '{}'", name))?;
+                    self.options.push(opt);
+                }
+            }
+            ("aliases", _) => self.aliases.push((key.to_owned(), string(value)?)),
+            ("accessibility", "plain") => self.plain = boolean(value)?,
+            _ => return Err("unknown setting".to_owned()),
+        }
+        Ok(())
+    }
+}
+
+/// a leading `~/` in `path` means the user's home directory
+fn expand_home(path: String) -> PathBuf {
+    match (path.strip_prefix("~/"), env::lookup("HOME")) {
+        (Some(rest), Some(home)) if !home.is_empty() => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// a key like `ctrl-r`, `alt-f`, `shift-tab`, `up`, `f5`, or a single character
+fn parse_key(key: &str) -> Option<KeyEvent> {
+    let mut mods = Modifiers::NONE;
+    let mut rest = key;
+    loop {
+        let lower = rest.to_ascii_lowercase();
+        let (modifier, len) = match () {
+            _ if lower.starts_with("ctrl-") => (Modifiers::CTRL, 5),
+            _ if lower.starts_with("alt-") => (Modifiers::ALT, 4),
+            _ if lower.starts_with("shift-") => (Modifiers::SHIFT, 6),
+            _ => break,
+        };
+        mods |= modifier;
+        rest = &rest[len..];
+    }
+    let code = match rest.to_ascii_lowercase().as_str() {
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "insert" => KeyCode::Insert,
+        "delete" => KeyCode::Delete,
+        "backspace" => KeyCode::Backspace,
+        "tab" => KeyCode::Tab,
+        "enter" => KeyCode::Enter,
+        "esc" => KeyCode::Esc,
+        "space" => KeyCode::Char(' '),
+        x => match x.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+            Some(n @ 1..=24) => KeyCode::F(n),
+            _ => {
+                let mut chars = rest.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => return None,
+                }
+            }
+        },
+    };
+    Some(KeyEvent::normalize(KeyEvent(code, mods)))
+}
+
+/// an editing action, named after its readline equivalent
+fn parse_action(action: &str) -> Option<Cmd> {
+    Some(match action {
+        "accept-line" => Cmd::AcceptLine,
+        "beginning-of-line" => Cmd::Move(Movement::BeginningOfLine),
+        "end-of-line" => Cmd::Move(Movement::EndOfLine),
+        "backward-char" => Cmd::Move(Movement::BackwardChar(1)),
+        "forward-char" => Cmd::Move(Movement::ForwardChar(1)),
+        "backward-word" => Cmd::Move(Movement::BackwardWord(1, Word::Emacs)),
+        "forward-word" => Cmd::Move(Movement::ForwardWord(
+            1,
+            rustyline::At::AfterEnd,
+            Word::Emacs,
+        )),
+        "kill-line" => Cmd::Kill(Movement::EndOfLine),
+        "backward-kill-line" => Cmd::Kill(Movement::BeginningOfLine),
+        "kill-whole-line" => Cmd::Kill(Movement::WholeLine),
+        "kill-word" => Cmd::Kill(Movement::ForwardWord(
+            1,
+            rustyline::At::AfterEnd,
+            Word::Emacs,
+        )),
+        "backward-kill-word" => Cmd::Kill(Movement::BackwardWord(1, Word::Big)),
+        "delete-char" => Cmd::Kill(Movement::ForwardChar(1)),
+        "backward-delete-char" => Cmd::Kill(Movement::BackwardChar(1)),
+        "yank" => Cmd::Yank(1, rustyline::Anchor::Before),
+        "yank-pop" => Cmd::YankPop,
+        "transpose-chars" => Cmd::TransposeChars,
+        "transpose-words" => Cmd::TransposeWords(1),
+        "upcase-word" => Cmd::UpcaseWord,
+        "downcase-word" => Cmd::DowncaseWord,
+        "capitalize-word" => Cmd::CapitalizeWord,
+        "undo" => Cmd::Undo(1),
+        "clear-screen" => Cmd::ClearScreen,
+        "complete" => Cmd::Complete,
+        "previous-history" => Cmd::PreviousHistory,
+        "next-history" => Cmd::NextHistory,
+        "beginning-of-history" => Cmd::BeginningOfHistory,
+        "end-of-history" => Cmd::EndOfHistory,
+        "history-search-backward" => Cmd::HistorySearchBackward,
+        "history-search-forward" => Cmd::HistorySearchForward,
+        "reverse-search-history" => Cmd::ReverseSearchHistory,
+        "forward-search-history" => Cmd::ForwardSearchHistory,
+        "quoted-insert" => Cmd::QuotedInsert,
+        "abort" => Cmd::Abort,
+        "noop" => Cmd::Noop,
+        _ => return None,
+    })
+}
+
+/// every table in `input`
+pub fn parse(input: &str) -> Result<toml::Table, ConfigError> {
+    input
+        .parse()
+        .map_err(|e| ConfigError::Syntax { internal: e })
+}
+
+fn expected(kind: &str, value: &Value) -> String {
+    format!("expected {}, found {}", kind, value.type_str())
+}
+
+fn string(value: Value) -> Result<String, String> {
+    match value {
+        Value::String(x) => Ok(x),
+        x => Err(expected("a string", &x)),
+    }
+}
+
+fn integer(value: Value) -> Result<i64, String> {
+    match value {
+        Value::Integer(x) => Ok(x),
+        x => Err(expected("an integer", &x)),
+    }
+}
+
+fn boolean(value: Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(x) => Ok(x),
+        x => Err(expected("a boolean", &x)),
+    }
+}
+
+fn array(value: Value) -> Result<Vec<Value>, String> {
+    match value {
+        Value::Array(x) => Ok(x),
+        x => Err(expected("an array", &x)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_files() {
+        let input = r#"
+# a comment
+[prompt]
+format = '\u:\w $ '  # literal, so no escapes
+
+[history]
+size = 5_000
+file = "/tmp/history"
+
+[colors]
+prompt_failure = "1;31"
+syntax_comment = ""
+theme = "bright"
+
+[keybindings]
+ctrl-f = "forward-word"
+alt-b = "backward-word"
+up = "history-search-backward"
+nowhere = "forward-word"
+
+[options]
+enable = ["autocd", "pipefail",]
+
+[aliases]
+ll = "ls -l"
+"la" = "ls -A"
+bad = 3
+
+[accessibility]
+plain = true
+"#;
+        let config = Config::parse(input).unwrap();
+        assert_eq!(config.prompt.as_deref(), Some("\\u:\\w $ "));
+        assert_eq!(config.history_size, Some(5000));
+        assert_eq!(config.history_file, Some(PathBuf::from("/tmp/history")));
+        assert_eq!(config.theme.prompt_failure, "1;31");
+        assert_eq!(config.theme.completion, "96");
+        assert_eq!(config.theme.syntax_comment, "");
+        assert_eq!(config.theme.syntax_string, "93");
+        assert_eq!(
+            config.keybindings.iter().map(|x| x.0).collect::<Vec<_>>(),
+            [
+                KeyEvent::ctrl('F'),
+                KeyEvent::alt('b'),
+                KeyEvent(KeyCode::Up, Modifiers::NONE)
+            ]
+        );
+        assert_eq!(config.options, [ShellOption::AutoCd, ShellOption::Pipefail]);
+        assert_eq!(
+            config.aliases,
+            [("ll", "ls -l"), ("la", "ls -A")].map(|(k, v)| (k.to_owned(), v.to_owned()))
+        );
+        assert!(config.plain);
+
+        let syntax_error = |input: &str| match Config::parse(input) {
+            Err(ConfigError::Syntax { internal }) => {
+                let start = internal.span().expect("parse errors have a span").start;
+                input[..start].matches('\n').count() + 1
+            }
+            x => panic!("expected a syntax error, got {:?}", x),
+        };
+        assert_eq!(syntax_error("[a]\nkey = \"open"), 2);
+        assert_eq!(syntax_error("[a"), 1);
+        assert_eq!(syntax_error("[a..b]"), 1);
+        assert_eq!(syntax_error("key value"), 1);
+        assert_eq!(syntax_error("key = 1 2"), 1);
+        assert_eq!(syntax_error("key = [1, 2"), 1);
+    }
+}
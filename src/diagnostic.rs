@@ -0,0 +1,205 @@
+//! errors that can point at the part of the command line they're about, which is underlined when
+//! they're reported, e.g.
+//!
+//! ```text
+//! rs-shell: unable to redirect 'missing/file': No such file or directory (os error 2)
+//!   |
+//! 1 | cat < missing/file > out
+//!   |       ^^^^^^^^^^^^ couldn't be opened
+//! ```
+
+use std::{
+    fmt::{self, Write as _},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+use parking_lot::RwLock;
+
+use crate::ast::{self, Span};
+
+/// a part of the command line an error points at, along with what's wrong with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub span: Span,
+    pub message: &'static str,
+}
+
+impl Label {
+    pub fn new(span: Span, message: &'static str) -> Self {
+        Self { span, message }
+    }
+}
+
+/// an error that can have [`Label`]s, only the ones whose span is known are given, so an error
+/// that didn't come from a command line the user typed has none
+pub trait Diagnostic: fmt::Display {
+    fn labels(&self) -> Vec<Label> {
+        Vec::new()
+    }
+}
+
+fn style() -> &'static RwLock<String> {
+    static STYLE: OnceLock<RwLock<String>> = OnceLock::new();
+    STYLE.get_or_init(|| RwLock::new(String::new()))
+}
+
+/// colors the underlines, and what they say, with `style`, SGR parameters like `1;31`, when
+/// diagnostics are rendered, they aren't colored if it's empty, which is the default, since what's
+/// rendered doesn't necessarily end up on a terminal
+pub fn set_style(value: impl Into<String>) {
+    *style().write() = value.into();
+}
+
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+/// with `value` set, labels are rendered as a line saying where they are rather than by underlining
+/// them, which reads better with a screen reader
+pub fn set_plain(value: bool) {
+    PLAIN.store(value, Ordering::Relaxed);
+}
+
+/// `diagnostic` followed by the lines of `source` its labels are on, with the labelled characters
+/// underlined, `first_line` being the line number `source` starts at (e.g. in a script)
+///
+/// labels that don't fit in `source` are left out, since the error didn't come from it
+pub fn render(diagnostic: &dyn Diagnostic, source: &str, first_line: usize) -> String {
+    let plain = PLAIN.load(Ordering::Relaxed);
+    render_as(diagnostic, source, first_line, plain)
+}
+
+fn render_as(diagnostic: &dyn Diagnostic, source: &str, first_line: usize, plain: bool) -> String {
+    // the spans of an AST are for its source as it was parsed
+    let source = &*ast::normalize_line_endings(source);
+    let mut out = format!("{}\n", diagnostic);
+    let mut labels = diagnostic
+        .labels()
+        .into_iter()
+        .filter(|x| {
+            x.span.start <= x.span.end
+                && source.is_char_boundary(x.span.start)
+                && source.is_char_boundary(x.span.end)
+        })
+        .map(|x| {
+            let line_start = source[..x.span.start].rfind('\n').map_or(0, |i| i + 1);
+            (line_start, x)
+        })
+        .collect::<Vec<_>>();
+    if labels.is_empty() {
+        return out;
+    }
+    labels.sort_by_key(|(_, x)| (x.span.start, x.span.end));
+
+    let line_number = |start: usize| first_line + source[..start].matches('\n').count();
+    if plain {
+        for (line_start, label) in labels {
+            let line = source[line_start..].lines().next().unwrap_or_default();
+            let end = label.span.end.min(line_start + line.len());
+            let _ = writeln!(
+                out,
+                "  line {}, column {}: {}: '{}'",
+                line_number(line_start),
+                source[line_start..label.span.start].chars().count() + 1,
+                label.message,
+                &source[label.span.start..end.max(label.span.start)]
+            );
+        }
+        return out;
+    }
+    let width = line_number(labels.last().expect("checked above").0)
+        .to_string()
+        .len();
+    let _ = writeln!(out, "{:width$} |", "");
+    let style = style().read();
+    let (start_style, end_style) = match style.is_empty() {
+        true => (String::new(), ""),
+        false => (format!("\x1b[{}m", style), "\x1b[0m"),
+    };
+    let mut current = None;
+    for (line_start, label) in labels {
+        let line = source[line_start..].lines().next().unwrap_or_default();
+        if current != Some(line_start) {
+            let _ = writeln!(out, "{:>width$} | {}", line_number(line_start), line);
+            current = Some(line_start);
+        }
+        let column = source[line_start..label.span.start].chars().count();
+        // a label that runs onto the next line is only underlined to the end of this one
+        let end = label
+            .span
+            .end
+            .min(line_start + line.len())
+            .max(label.span.start);
+        let length = source[label.span.start..end].chars().count().max(1);
+        let _ = writeln!(
+            out,
+            "{:width$} | {}{}{} {}{}",
+            "",
+            " ".repeat(column),
+            start_style,
+            "^".repeat(length),
+            label.message,
+            end_style
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Error(Vec<Label>);
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "something went wrong")
+        }
+    }
+
+    impl Diagnostic for Error {
+        fn labels(&self) -> Vec<Label> {
+            self.0.clone()
+        }
+    }
+
+    fn span(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    #[test]
+    fn rendering() {
+        let error = Error(vec![
+            Label::new(span(13, 16), "second"),
+            Label::new(span(7, 10), "first"),
+            Label::new(span(0, 3), "across lines"),
+            Label::new(span(20, 25), "from somewhere else"),
+        ]);
+        let expected = [
+            "something went wrong",
+            "   |",
+            " 9 | a",
+            "   | ^ across lines",
+            "10 | bc | éd > out",
+            "   |      ^^ first",
+            "   |           ^^^ second",
+        ];
+        assert_eq!(
+            render(&error, "a\nbc | éd > out", 9),
+            expected.map(|x| format!("{}\n", x)).concat()
+        );
+        assert_eq!(render(&Error(Vec::new()), "x", 1), "something went wrong\n");
+
+        let expected = [
+            "something went wrong",
+            "  line 9, column 1: across lines: 'a'",
+            "  line 10, column 6: first: 'éd'",
+            "  line 10, column 11: second: 'out'",
+        ];
+        assert_eq!(
+            render_as(&error, "a\nbc | éd > out", 9, true),
+            expected.map(|x| format!("{}\n", x)).concat()
+        );
+    }
+}
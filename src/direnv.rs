@@ -0,0 +1,474 @@
+//! per-directory environments, like direnv, with the `direnv` option enabled changing directory
+//! loads the variables assigned in the nearest `.rsshell-env` or `.envrc` in the new directory or
+//! any of its parents, and puts back whatever they replaced once it's left again
+//!
+//! the files are read as dotenv files (see [`crate::dotenv`]), so nothing in them is ever run, and
+//! a file is only loaded once it's been allowed with `envrc allow`, which approves its contents as
+//! they are right then, any change to it has to be allowed again, so a directory that's been
+//! cloned or unpacked can't set variables without the user knowing
+//!
+//! the nearest `.rsshellrc.local` is loaded the same way, allowed along with the environment file,
+//! but rather than variables it defines aliases for the directory, one per line like
+//!
+//! ```text
+//! # comments and blank lines are skipped
+//! alias build='cargo build --release'
+//! ```
+//!
+//! with values quoted like in a dotenv file, the shell doesn't have functions, so aliases are all
+//! it can define
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    alias::{self, AliasError},
+    dotenv::{self, DotenvError},
+    env::{self, EnvError},
+    i18n,
+};
+
+/// the names of the files that are loaded, the first one found in a directory is used
+pub const FILES: &[&str] = &[".rsshell-env", ".envrc"];
+
+/// the name of the file aliases are loaded from
+pub const RC_FILE: &str = ".rsshellrc.local";
+
+/// where allowed files are kept track of, rather than `rsshell/allow` in the user's data directory
+const ALLOW_DIR_VAR: &str = "RS_SHELL_ENVRC_DIR";
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum DirenvError {
+    #[error("{path}: {internal}")]
+    Io { path: String, internal: io::Error },
+
+    #[error("{path}: {internal}")]
+    Parse { path: String, internal: DotenvError },
+
+    #[error("{path}: line {line}: {}", i18n::text("expected alias NAME=VALUE"))]
+    NotAlias { path: String, line: usize },
+
+    #[error("{path}: {internal}")]
+    Alias { path: String, internal: AliasError },
+
+    #[error("{}", i18n::format(
+        "no {files} here or in any parent directory",
+        &[("files", &[FILES, &[RC_FILE]].concat().join(" or "))],
+    ))]
+    NotFound,
+
+    #[error("{}", i18n::format(
+        "nowhere to keep allowed files, set ${var}",
+        &[("var", &ALLOW_DIR_VAR)],
+    ))]
+    NoAllowDir,
+
+    #[error(transparent)]
+    Env(#[from] EnvError),
+}
+
+/// the file whose variables are loaded right now
+struct Loaded {
+    file: PathBuf,
+    /// the value each variable it set had before, and whether it was exported, so they can be put
+    /// back when it's unloaded
+    saved: Vec<(env::Name, Option<OsString>, bool)>,
+}
+
+fn loaded() -> &'static Mutex<Option<Loaded>> {
+    static LOADED: OnceLock<Mutex<Option<Loaded>>> = OnceLock::new();
+    LOADED.get_or_init(|| Mutex::new(None))
+}
+
+/// the rc file whose aliases are defined right now
+struct LoadedRc {
+    file: PathBuf,
+    /// the value each alias it defined had before, so they can be put back when it's unloaded
+    saved: Vec<(String, Option<String>)>,
+}
+
+fn loaded_rc() -> &'static Mutex<Option<LoadedRc>> {
+    static LOADED: OnceLock<Mutex<Option<LoadedRc>>> = OnceLock::new();
+    LOADED.get_or_init(|| Mutex::new(None))
+}
+
+/// the nearest file to load for `dir`, looking in it and then each of its parents
+pub fn find(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .flat_map(|x| FILES.iter().map(move |name| x.join(name)))
+        .find(|x| x.is_file())
+}
+
+/// the nearest rc file for `dir`, looking in it and then each of its parents
+pub fn find_rc(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|x| x.join(RC_FILE))
+        .find(|x| x.is_file())
+}
+
+/// brings the loaded variables and aliases in line with the current directory, unloading the files
+/// that were loaded if they no longer apply and loading the ones that do, or with `force` reloading
+/// them even if they're the same ones
+///
+/// returns what changed, as lines to be shown to the user
+pub fn update(force: bool) -> Vec<String> {
+    let dir = std::env::current_dir().ok();
+    let mut out = update_env(dir.as_deref().and_then(find), force);
+    out.extend(update_rc(dir.as_deref().and_then(find_rc), force));
+    out
+}
+
+/// reads `file` if it's been allowed as it is, otherwise says why not in `out`
+fn read_allowed(file: &Path, out: &mut Vec<String>) -> Option<Vec<u8>> {
+    let contents = match fs::read(file) {
+        Ok(x) => x,
+        Err(e) => {
+            out.push(format!("direnv: {}: {}", file.display(), e));
+            return None;
+        }
+    };
+    if !allow_dir().is_some_and(|dir| is_allowed(&dir, file, &contents)) {
+        out.push(format!(
+            "direnv: {}",
+            i18n::format(
+                "{file} is blocked, run `envrc allow` to approve its contents",
+                &[("file", &file.display())]
+            )
+        ));
+        return None;
+    }
+    out.push(format!(
+        "direnv: {}",
+        i18n::format("loading {file}", &[("file", &file.display())])
+    ));
+    Some(contents)
+}
+
+fn update_env(file: Option<PathBuf>, force: bool) -> Vec<String> {
+    let mut loaded = loaded().lock();
+    let mut out = Vec::new();
+    if !force && loaded.as_ref().map(|x| &x.file) == file.as_ref() {
+        return out;
+    }
+    if let Some(current) = loaded.take() {
+        unload(current);
+        out.push(format!("direnv: {}", i18n::text("unloading")));
+    }
+    let Some(file) = file else {
+        return out;
+    };
+    let Some(contents) = read_allowed(&file, &mut out) else {
+        return out;
+    };
+    match load(&file, &contents) {
+        Ok((state, changes)) => {
+            if !changes.is_empty() {
+                out.push(format!("direnv: export {}", changes.join(" ")));
+            }
+            *loaded = Some(state);
+        }
+        Err(e) => out.push(format!("direnv: {}", e)),
+    }
+    out
+}
+
+/// sets every variable assigned in `contents`, returning what's needed to unload them again along
+/// with each name prefixed by `+` if it's new or `~` if it replaced a value, sorted by name
+///
+/// the whole file is checked before anything is set, like the `dotenv` builtin
+fn load(file: &Path, contents: &[u8]) -> Result<(Loaded, Vec<String>), DirenvError> {
+    let vars =
+        dotenv::parse(&String::from_utf8_lossy(contents)).map_err(|e| DirenvError::Parse {
+            path: file.display().to_string(),
+            internal: e,
+        })?;
+    let mut state = Loaded {
+        file: file.to_owned(),
+        saved: Vec::new(),
+    };
+    let mut changes = Vec::new();
+    for (name, value) in vars {
+        let name = env::Name::from(name);
+        if !state.saved.iter().any(|(x, _, _)| *x == name) {
+            let old = env::lookup(&*name);
+            let exported = env::exports().iter().any(|(x, _)| *x == name);
+            changes.push(format!("{}{}", if old.is_some() { '~' } else { '+' }, name));
+            state.saved.push((name.clone(), old, exported));
+        }
+        if let Err(e) = env::export(name, Some(value.into())) {
+            // put back what was set before giving up on the rest
+            unload(state);
+            return Err(e.into());
+        }
+    }
+    changes.sort_by(|a, b| a[1..].cmp(&b[1..]));
+    Ok((state, changes))
+}
+
+/// puts back every variable a file set as it was before
+fn unload(loaded: Loaded) {
+    for (name, value, exported) in loaded.saved.into_iter().rev() {
+        // anything readonly by now is left alone
+        let _ = match (value, exported) {
+            (Some(value), true) => env::export(name, Some(value)),
+            (Some(value), false) => env::unexport(name, Some(value)),
+            (None, _) => env::unset(&*name),
+        };
+    }
+}
+
+fn update_rc(file: Option<PathBuf>, force: bool) -> Vec<String> {
+    let mut loaded = loaded_rc().lock();
+    let mut out = Vec::new();
+    if !force && loaded.as_ref().map(|x| &x.file) == file.as_ref() {
+        return out;
+    }
+    if let Some(current) = loaded.take() {
+        unload_rc(current);
+        out.push(format!("direnv: {}", i18n::text("unloading")));
+    }
+    let Some(file) = file else {
+        return out;
+    };
+    let Some(contents) = read_allowed(&file, &mut out) else {
+        return out;
+    };
+    match load_rc(&file, &contents) {
+        Ok((state, changes)) => {
+            if !changes.is_empty() {
+                out.push(format!("direnv: alias {}", changes.join(" ")));
+            }
+            *loaded = Some(state);
+        }
+        Err(e) => out.push(format!("direnv: {}", e)),
+    }
+    out
+}
+
+/// every alias defined in an rc file, in the order they appear
+fn parse_rc(file: &Path, input: &str) -> Result<Vec<(String, String)>, DirenvError> {
+    let mut aliases = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, value)) = line
+            .strip_prefix("alias")
+            .filter(|x| x.starts_with(char::is_whitespace))
+            .and_then(|x| x.trim_start().split_once('='))
+        else {
+            return Err(DirenvError::NotAlias {
+                path: file.display().to_string(),
+                line: line_no,
+            });
+        };
+        let value =
+            dotenv::parse_value(value.trim_start(), line_no).map_err(|e| DirenvError::Parse {
+                path: file.display().to_string(),
+                internal: e,
+            })?;
+        aliases.push((name.trim_end().to_owned(), value));
+    }
+    Ok(aliases)
+}
+
+/// defines every alias in `contents`, returning what's needed to unload them again along with
+/// each name prefixed by `+` if it's new or `~` if it replaced an alias, sorted by name
+///
+/// like [`load`], nothing is defined if any of them is invalid
+fn load_rc(file: &Path, contents: &[u8]) -> Result<(LoadedRc, Vec<String>), DirenvError> {
+    let aliases = parse_rc(file, &String::from_utf8_lossy(contents))?;
+    let mut state = LoadedRc {
+        file: file.to_owned(),
+        saved: Vec::new(),
+    };
+    let mut changes = Vec::new();
+    for (name, value) in aliases {
+        if !state.saved.iter().any(|(x, _)| *x == name) {
+            let old = alias::value(&name);
+            changes.push(format!("{}{}", if old.is_some() { '~' } else { '+' }, name));
+            state.saved.push((name.clone(), old));
+        }
+        if let Err(e) = alias::set(name, value) {
+            unload_rc(state);
+            return Err(DirenvError::Alias {
+                path: file.display().to_string(),
+                internal: e,
+            });
+        }
+    }
+    changes.sort_by(|a, b| a[1..].cmp(&b[1..]));
+    Ok((state, changes))
+}
+
+/// puts back every alias an rc file defined as it was before
+fn unload_rc(loaded: LoadedRc) {
+    for (name, value) in loaded.saved.into_iter().rev() {
+        match value {
+            // it was accepted before, so it still will be
+            Some(value) => {
+                let _ = alias::set(name, value);
+            }
+            None => {
+                alias::remove(&name);
+            }
+        }
+    }
+}
+
+/// where the contents of allowed files are kept, `$RS_SHELL_ENVRC_DIR`, or `rsshell/allow` in the
+/// user's data directory
+fn allow_dir() -> Option<PathBuf> {
+    let dir = env::get(ALLOW_DIR_VAR);
+    if !dir.is_empty() {
+        return Some(dir.into());
+    }
+    Some(dirs_next::data_dir()?.join("rsshell").join("allow"))
+}
+
+/// the copy of `file` that was approved, named after its absolute path with `%` and `/` escaped
+fn approval(allow_dir: &Path, file: &Path) -> PathBuf {
+    let mut name = Vec::new();
+    for &byte in file.as_os_str().as_bytes() {
+        match byte {
+            b'%' => name.extend_from_slice(b"%25"),
+            b'/' => name.extend_from_slice(b"%2F"),
+            x => name.push(x),
+        }
+    }
+    allow_dir.join(OsStr::from_bytes(&name))
+}
+
+fn is_allowed(allow_dir: &Path, file: &Path, contents: &[u8]) -> bool {
+    fs::read(approval(allow_dir, file)).is_ok_and(|x| x == contents)
+}
+
+/// the nearest environment file and rc file to the current directory, whichever of them exist, as
+/// absolute paths
+fn nearest() -> Result<Vec<PathBuf>, DirenvError> {
+    let dir = std::env::current_dir().map_err(|e| DirenvError::Io {
+        path: ".".to_owned(),
+        internal: e,
+    })?;
+    let files = [find(&dir), find_rc(&dir)]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    match files.is_empty() {
+        true => Err(DirenvError::NotFound),
+        false => Ok(files),
+    }
+}
+
+/// approves the nearest files as they are now
+pub fn allow() -> Result<(), DirenvError> {
+    let dir = allow_dir().ok_or(DirenvError::NoAllowDir)?;
+    let io_error = |path: &Path| {
+        let path = path.display().to_string();
+        move |internal| DirenvError::Io { path, internal }
+    };
+    for file in nearest()? {
+        let contents = fs::read(&file).map_err(io_error(&file))?;
+        fs::create_dir_all(&dir).map_err(io_error(&dir))?;
+        let approval = approval(&dir, &file);
+        fs::write(&approval, contents).map_err(io_error(&approval))?;
+    }
+    Ok(())
+}
+
+/// takes back the approval of the nearest files
+pub fn deny() -> Result<(), DirenvError> {
+    let dir = allow_dir().ok_or(DirenvError::NoAllowDir)?;
+    for file in nearest()? {
+        let approval = approval(&dir, &file);
+        match fs::remove_file(&approval) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(DirenvError::Io {
+                    path: approval.display().to_string(),
+                    internal: e,
+                })
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_and_unload() {
+        let dir = std::env::temp_dir().join(format!("rs-shell-direnv-{}", std::process::id()));
+        let project = dir.join("project");
+        fs::create_dir_all(project.join("src")).unwrap();
+        let file = project.join(".envrc");
+        fs::write(&file, "DIRENV_TEST_NEW=1\nexport DIRENV_TEST_OLD='x y'\n").unwrap();
+        assert_eq!(find(&project.join("src")), Some(file.clone()));
+
+        // only the exact contents that were approved are allowed
+        let allow = dir.join("allow");
+        assert!(!is_allowed(&allow, &file, b"DIRENV_TEST_NEW=1"));
+        fs::create_dir_all(&allow).unwrap();
+        fs::write(approval(&allow, &file), "DIRENV_TEST_NEW=1").unwrap();
+        assert!(is_allowed(&allow, &file, b"DIRENV_TEST_NEW=1"));
+        assert!(!is_allowed(&allow, &file, b"DIRENV_TEST_NEW=2"));
+
+        env::set("DIRENV_TEST_OLD".into(), "before".into()).unwrap();
+        let (state, changes) = load(&file, &fs::read(&file).unwrap()).unwrap();
+        assert_eq!(changes, ["+DIRENV_TEST_NEW", "~DIRENV_TEST_OLD"]);
+        assert_eq!(env::get("DIRENV_TEST_OLD"), "x y");
+        assert!(env::exports().iter().any(|(x, _)| *x == "DIRENV_TEST_NEW"));
+
+        unload(state);
+        assert_eq!(env::lookup("DIRENV_TEST_NEW"), None);
+        assert_eq!(env::get("DIRENV_TEST_OLD"), "before");
+        assert!(!env::exports().iter().any(|(x, _)| *x == "DIRENV_TEST_OLD"));
+
+        env::unset("DIRENV_TEST_OLD").unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rc_aliases() {
+        let file = Path::new(RC_FILE);
+        let input =
+            "# aliases\nalias direnvtestnew='echo new' # comment\n\nalias direnvtestold=ls\n";
+        assert_eq!(
+            parse_rc(file, input).unwrap(),
+            [
+                ("direnvtestnew".to_owned(), "echo new".to_owned()),
+                ("direnvtestold".to_owned(), "ls".to_owned()),
+            ]
+        );
+        assert!(matches!(
+            parse_rc(file, "alias ok=ls\nexport X=1"),
+            Err(DirenvError::NotAlias { line: 2, .. })
+        ));
+
+        alias::set("direnvtestold".to_owned(), "pwd".to_owned()).unwrap();
+        let (state, changes) = load_rc(file, input.as_bytes()).unwrap();
+        assert_eq!(changes, ["+direnvtestnew", "~direnvtestold"]);
+        assert_eq!(alias::value("direnvtestold").as_deref(), Some("ls"));
+        unload_rc(state);
+        assert_eq!(alias::value("direnvtestnew"), None);
+        assert_eq!(alias::value("direnvtestold").as_deref(), Some("pwd"));
+
+        // nothing's left defined if one of them is invalid
+        assert!(load_rc(file, b"alias direnvtestnew=ls\nalias direnvtestold='a | b'").is_err());
+        assert_eq!(alias::value("direnvtestnew"), None);
+        assert_eq!(alias::value("direnvtestold").as_deref(), Some("pwd"));
+        alias::remove("direnvtestold");
+    }
+}
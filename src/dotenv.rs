@@ -0,0 +1,136 @@
+//! parsing of dotenv (`.env`) files, as loaded by the `dotenv` builtin
+//!
+//! each line is either blank, a `#` comment, or a `KEY=VALUE` assignment, optionally preceded by
+//! `export`, values can be single quoted (taken literally), double quoted (where `\n`, `\t`, `\"`,
+//! `\\` and `\$` are escapes), or unquoted, in which case a ` #` starts a comment and surrounding
+//! whitespace is trimmed, nothing is ever expanded
+
+use crate::env;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DotenvError {
+    #[error("line {line}: expected KEY=VALUE")]
+    MissingEquals { line: usize },
+
+    #[error("line {line}: `{name}': not a valid identifier")]
+    InvalidName { line: usize, name: String },
+
+    #[error("line {line}: unterminated quote")]
+    UnterminatedQuote { line: usize },
+
+    #[error("line {line}: unexpected text after closing quote")]
+    TrailingText { line: usize },
+}
+
+/// every assignment in `input`, in the order they appear
+pub fn parse(input: &str) -> Result<Vec<(String, String)>, DotenvError> {
+    let mut vars = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line
+            .strip_prefix("export")
+            .filter(|x| x.starts_with(char::is_whitespace))
+            .map_or(line, str::trim_start);
+
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(DotenvError::MissingEquals { line: line_no });
+        };
+        let name = name.trim_end();
+        if !env::valid_name(name.as_ref()) {
+            return Err(DotenvError::InvalidName {
+                line: line_no,
+                name: name.to_owned(),
+            });
+        }
+        vars.push((name.to_owned(), parse_value(value.trim_start(), line_no)?));
+    }
+    Ok(vars)
+}
+
+/// a single value, quoted or not, with `line` being the line it's on for errors
+pub(crate) fn parse_value(value: &str, line: usize) -> Result<String, DotenvError> {
+    let (quote, rest) = match value.chars().next() {
+        Some(x @ ('\'' | '"')) => (x, &value[1..]),
+        _ => {
+            // a `#` only starts a comment after whitespace, so that it can appear in a value
+            let end = value
+                .char_indices()
+                .find(|&(i, c)| c == '#' && value[..i].ends_with(char::is_whitespace))
+                .map_or(value.len(), |(i, _)| i);
+            return Ok(value[..end].trim_end().to_owned());
+        }
+    };
+
+    let mut out = String::new();
+    let mut chars = rest.char_indices();
+    let end = loop {
+        match chars.next() {
+            None => return Err(DotenvError::UnterminatedQuote { line }),
+            Some((i, c)) if c == quote => break i + 1,
+            Some((_, '\\')) if quote == '"' => match chars.next() {
+                None => return Err(DotenvError::UnterminatedQuote { line }),
+                Some((_, 'n')) => out.push('\n'),
+                Some((_, 't')) => out.push('\t'),
+                Some((_, c @ ('"' | '\\' | '$'))) => out.push(c),
+                Some((_, c)) => {
+                    out.push('\\');
+                    out.push(c);
+                }
+            },
+            Some((_, c)) => out.push(c),
+        }
+    };
+
+    let rest = rest[end..].trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(DotenvError::TrailingText { line });
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dotenv_files() {
+        let input = r#"
+# a comment
+PLAIN=value # trailing comment
+export EXPORTED = spaced out
+HASH=a#b
+SINGLE='no $expansion \n here'
+DOUBLE="line\nbreak \"quoted\"" # comment
+EMPTY=
+"#;
+        let vars = parse(input).unwrap();
+        let expected = [
+            ("PLAIN", "value"),
+            ("EXPORTED", "spaced out"),
+            ("HASH", "a#b"),
+            ("SINGLE", "no $expansion \\n here"),
+            ("DOUBLE", "line\nbreak \"quoted\""),
+            ("EMPTY", ""),
+        ];
+        assert_eq!(vars, expected.map(|(k, v)| (k.to_owned(), v.to_owned())));
+
+        assert_eq!(
+            parse("A=1\nnope"),
+            Err(DotenvError::MissingEquals { line: 2 })
+        );
+        assert!(matches!(
+            parse("1A=x"),
+            Err(DotenvError::InvalidName { line: 1, .. })
+        ));
+        assert_eq!(
+            parse("A=\"open"),
+            Err(DotenvError::UnterminatedQuote { line: 1 })
+        );
+        assert_eq!(parse("A='x' y"), Err(DotenvError::TrailingText { line: 1 }));
+    }
+}
@@ -1,31 +1,1122 @@
-use std::{collections::HashMap, env, ffi::{OsStr, OsString}, sync::OnceLock};
+//! shell variables
+//!
+//! every variable is either local to the shell, which is what a plain `NAME=value` assignment
+//! creates, or exported, in which case it's also passed to every command the shell runs, the
+//! environment the shell itself was started with is imported as exported variables
+//!
+//! a variable's value is either a plain string or an array (see [`Value`]), only plain strings
+//! are ever exported, and an array expanded as if it were a string gives its first element
+//!
+//! changes to variables can be scoped (see [`push_scope`]), so that they're undone once whatever
+//! made them is done, e.g. assignments in a `$( ... )` substitution don't affect the rest of the
+//! command line, a subshell's scope rolls back the whole table, which is cheap since the table is
+//! copy-on-write
+//!
+//! a variable can also be a reference to another variable (see [`Attributes::nameref`]), in which
+//! case reading it, assigning to it, or unsetting it does so to the variable it refers to instead
+//!
+//! other parts of the shell can be told whenever a variable changes (see [`on_change`]), e.g. the
+//! command hash table is cleared whenever `PATH` changes
+//!
+//! the positional parameters (`$0`, `$1`, ...) are looked up like any other variable, but they're
+//! only ever set all at once, see [`set_positional`]
+//!
+//! there are also a few dynamic variables whose value is computed every time they're read (see
+//! [`DYNAMIC`]), these are never exported, and assigning to one replaces it with a plain variable
 
-use parking_lot::RwLock;
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    ffi::{CStr, CString, OsStr, OsString},
+    fmt,
+    ops::Deref,
+    os::unix::ffi::OsStrExt,
+    process,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 
-fn envs() -> &'static RwLock<HashMap<OsString, OsString>> {
-    static ENVS: OnceLock<RwLock<HashMap<OsString, OsString>>> = OnceLock::new();
-    ENVS.get_or_init(|| RwLock::new(HashMap::new()))
+use log::warn;
+use parking_lot::{Mutex, RwLock};
+
+use crate::arith::{self, ArithError};
+
+type DynamicFn = fn() -> OsString;
+
+/// called with the new value of a variable whenever it changes, `None` if it's been unset
+type Hook = Arc<dyn Fn(Option<&OsStr>) + Send + Sync>;
+
+/// every dynamic variable, keyed by name, along with what computes its value
+const DYNAMIC: &[(&str, DynamicFn)] = &[
+    ("EPOCHSECONDS", dynamic_epochseconds),
+    ("LINENO", dynamic_lineno),
+    ("RANDOM", dynamic_random),
+    ("SECONDS", dynamic_seconds),
+];
+
+/// the line of input that's being run, see [`set_line`]
+static LINENO: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum EnvError {
+    /// a readonly variable was assigned to or unset
+    #[error("{name}: readonly variable")]
+    Readonly { name: String },
+
+    /// the value assigned to an integer variable isn't a valid expression
+    #[error("{name}: {internal}")]
+    Arithmetic { name: String, internal: ArithError },
+
+    /// a variable name isn't an identifier, see [`valid_name`]
+    #[error("`{name}': not a valid identifier")]
+    InvalidName { name: String },
+
+    /// following a chain of namerefs led back to where it started
+    #[error("{name}: circular name reference")]
+    CircularRef { name: String },
+
+    /// a variable was made local outside of any scope
+    #[error("can only be used in a function")]
+    NoScope,
 }
 
-pub fn get<K: AsRef<OsStr>>(name: K) -> OsString {
-    match envs().read().get(name.as_ref()) {
-        Some(x) => x.clone(),
-        None => match env::var_os(name) {
-            Some(x) => x,
-            None => OsString::new(),
+/// what a scope undoes when it ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeKind {
+    /// only the variables made [`local`] to it, like a function call, or the assignments before a
+    /// builtin
+    Function,
+    /// every change made to any variable, like a subshell
+    Subshell,
+}
+
+/// the name of a variable, interned so that every copy of the same name shares one allocation,
+/// which makes copying the variable table (and the names in scopes and command lines) cheap
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Name(Arc<OsStr>);
+
+impl Name {
+    /// the shared copy of `name`, which is made the first time it's asked for
+    pub fn new(name: &OsStr) -> Self {
+        static NAMES: OnceLock<Mutex<HashSet<Arc<OsStr>>>> = OnceLock::new();
+        let mut names = NAMES.get_or_init(Default::default).lock();
+        if let Some(x) = names.get(name) {
+            return Self(x.clone());
+        }
+        // names nothing else holds on to any more are dropped before the set has to grow, so
+        // one that's made up on the fly (e.g. by `declare "x$i"`) doesn't stay around forever
+        if names.len() == names.capacity() {
+            names.retain(|x| Arc::strong_count(x) > 1);
+        }
+        let name = Arc::<OsStr>::from(name);
+        names.insert(name.clone());
+        Self(name)
+    }
+}
+
+impl Deref for Name {
+    type Target = OsStr;
+
+    fn deref(&self) -> &OsStr {
+        &self.0
+    }
+}
+
+impl AsRef<OsStr> for Name {
+    fn as_ref(&self) -> &OsStr {
+        &self.0
+    }
+}
+
+impl Borrow<OsStr> for Name {
+    fn borrow(&self) -> &OsStr {
+        &self.0
+    }
+}
+
+impl PartialEq<OsStr> for Name {
+    fn eq(&self, other: &OsStr) -> bool {
+        *self.0 == *other
+    }
+}
+
+impl PartialEq<str> for Name {
+    fn eq(&self, other: &str) -> bool {
+        *self.0 == *other
+    }
+}
+
+impl PartialEq<&str> for Name {
+    fn eq(&self, other: &&str) -> bool {
+        *self.0 == **other
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.to_string_lossy())
+    }
+}
+
+impl From<&OsStr> for Name {
+    fn from(name: &OsStr) -> Self {
+        Self::new(name)
+    }
+}
+
+impl From<OsString> for Name {
+    fn from(name: OsString) -> Self {
+        Self::new(&name)
+    }
+}
+
+impl From<&str> for Name {
+    fn from(name: &str) -> Self {
+        Self::new(name.as_ref())
+    }
+}
+
+impl From<String> for Name {
+    fn from(name: String) -> Self {
+        Self::new(name.as_ref())
+    }
+}
+
+/// the attributes a variable can be given with `declare`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attributes {
+    /// passed to the commands the shell runs
+    pub exported: bool,
+    /// can't be assigned to or unset, and its attributes can't be taken away
+    pub readonly: bool,
+    /// everything assigned to it is evaluated as an arithmetic expression, see [`crate::arith`]
+    pub integer: bool,
+    /// everything assigned to it is lowercased
+    pub lowercase: bool,
+    /// everything assigned to it is uppercased
+    pub uppercase: bool,
+    /// its value is the name of another variable, which is what's actually used whenever it's read,
+    /// assigned to, or has its other attributes changed
+    pub nameref: bool,
+}
+
+impl Attributes {
+    /// the flags `declare` gives these attributes with, e.g. `rx` for a readonly exported variable
+    pub fn flags(&self) -> String {
+        [
+            (self.integer, 'i'),
+            (self.lowercase, 'l'),
+            (self.nameref, 'n'),
+            (self.readonly, 'r'),
+            (self.uppercase, 'u'),
+            (self.exported, 'x'),
+        ]
+        .into_iter()
+        .filter_map(|(set, flag)| set.then_some(flag))
+        .collect()
+    }
+}
+
+/// the value of a variable
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Value {
+    Scalar(OsString),
+    /// an array indexed by number, which can have gaps in it
+    Indexed(BTreeMap<usize, OsString>),
+    /// an array indexed by string
+    Associative(BTreeMap<OsString, OsString>),
+}
+
+impl Value {
+    /// the value as a single string, which for an array is the element at index `0`, if any
+    pub fn scalar(&self) -> Option<OsString> {
+        match self {
+            Value::Scalar(x) => Some(x.clone()),
+            Value::Indexed(x) => x.get(&0).cloned(),
+            Value::Associative(x) => x.get(OsStr::new("0")).cloned(),
+        }
+    }
+
+    /// every element of the value in order, a string is a single element, this is what a
+    /// `"$@"`-style expansion expands to
+    pub fn elements(&self) -> Vec<OsString> {
+        match self {
+            Value::Scalar(x) => vec![x.clone()],
+            Value::Indexed(x) => x.values().cloned().collect(),
+            Value::Associative(x) => x.values().cloned().collect(),
+        }
+    }
+}
+
+impl From<OsString> for Value {
+    fn from(value: OsString) -> Self {
+        Self::Scalar(value)
+    }
+}
+
+impl From<Vec<OsString>> for Value {
+    fn from(value: Vec<OsString>) -> Self {
+        Self::Indexed(value.into_iter().enumerate().collect())
+    }
+}
+
+/// a snapshot of the environment commands are run with, see [`environ`]
+#[derive(Debug)]
+pub struct Environ {
+    /// each variable as `NAME=value`, which is the form `execve` wants them in
+    entries: Vec<CString>,
+}
+
+impl Environ {
+    fn new(vars: &HashMap<Name, Var>) -> Self {
+        let entries = vars
+            .iter()
+            .filter(|(_, x)| x.attrs.exported && !x.attrs.nameref)
+            .filter_map(|(name, x)| match &x.value {
+                // a variable with a nul byte in it can't be passed to a command at all
+                Some(Value::Scalar(value)) => {
+                    CString::new([name.as_bytes(), b"=", value.as_bytes()].concat()).ok()
+                }
+                _ => None,
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// every variable's name, along with the variable as `NAME=value`
+    pub fn iter(&self) -> impl Iterator<Item = (&OsStr, &CStr)> {
+        self.entries.iter().map(|x| {
+            let bytes = x.as_bytes();
+            let len = bytes.iter().position(|x| *x == b'=').unwrap_or(bytes.len());
+            (OsStr::from_bytes(&bytes[..len]), x.as_c_str())
+        })
+    }
+}
+
+/// a single shell variable
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Var {
+    /// `None` for a variable that's been exported without ever being given a value, which isn't
+    /// passed to commands until it is
+    value: Option<Value>,
+    attrs: Attributes,
+}
+
+/// the changes a scope will undo when it ends
+#[derive(Debug)]
+struct Scope {
+    /// the value each variable made [`local`] to the scope had before, `None` if it was unset
+    saved: HashMap<Name, Option<Var>>,
+    /// every variable as it was when a [`ScopeKind::Subshell`] scope started
+    snapshot: Option<Snapshot>,
+}
+
+/// every variable as it was when a [`ScopeKind::Subshell`] scope started
+#[derive(Debug, Clone)]
+struct Snapshot(Arc<HashMap<Name, Var>>);
+
+/// every variable, along with the scopes that are currently in effect, innermost last
+#[derive(Debug, Default)]
+struct Table {
+    /// shared with any snapshots of it, and only copied when it's changed while it is
+    vars: Arc<HashMap<Name, Var>>,
+    scopes: Vec<Scope>,
+    /// built from `vars` the first time it's needed after a change to an exported variable
+    environ: Option<Arc<Environ>>,
+}
+
+impl Table {
+    /// the variables, for changing them, this has to be called after [`Table::invalidate`] for
+    /// each variable that's changed
+    fn vars_mut(&mut self) -> &mut HashMap<Name, Var> {
+        Arc::make_mut(&mut self.vars)
+    }
+
+    /// follows `name` through any namerefs to the variable it ultimately refers to, as if it were
+    /// being referred to by `referrer`
+    fn resolve(&self, name: &OsStr, referrer: Option<&OsStr>) -> Result<Name, EnvError> {
+        let mut seen = referrer.into_iter().map(Name::new).collect::<Vec<_>>();
+        let mut name = Name::new(name);
+        loop {
+            if seen.contains(&name) {
+                return Err(EnvError::CircularRef {
+                    name: seen[0].to_string_lossy().into_owned(),
+                });
+            }
+            let next = match self.vars.get(&name) {
+                Some(Var {
+                    value: Some(Value::Scalar(x)),
+                    attrs,
+                }) if attrs.nameref && !x.is_empty() => Name::new(x),
+                _ => return Ok(name),
+            };
+            seen.push(name);
+            name = next;
+        }
+    }
+
+    /// drops the cached [`Environ`] if `name` is part of it
+    fn invalidate(&mut self, name: &OsStr) {
+        if self.vars.get(name).is_some_and(|x| x.attrs.exported) {
+            self.environ = None;
+        }
+    }
+
+    /// replaces `name` with a new unset variable that the innermost scope restores when it ends
+    fn local(&mut self, name: &Name) -> Result<(), EnvError> {
+        writable(&self.vars, name)?;
+        if self.scopes.is_empty() {
+            return Err(EnvError::NoScope);
+        }
+        self.invalidate(name);
+        let old = self.vars.get(name).cloned();
+        let scope = self.scopes.last_mut().expect("checked above");
+        scope.saved.entry(name.clone()).or_insert(old);
+        self.vars_mut().insert(
+            name.clone(),
+            Var {
+                value: None,
+                attrs: Attributes::default(),
+            },
+        );
+        Ok(())
+    }
+
+    fn push_scope(&mut self, kind: ScopeKind) {
+        let snapshot = (kind == ScopeKind::Subshell).then(|| Snapshot(self.vars.clone()));
+        self.scopes.push(Scope {
+            saved: HashMap::new(),
+            snapshot,
+        });
+    }
+
+    /// ends the innermost scope, returning the names of the variables that were restored
+    fn pop_scope(&mut self) -> Vec<Name> {
+        let Some(scope) = self.scopes.pop() else {
+            warn!("popped a variable scope that was never pushed");
+            return Vec::new();
+        };
+        if let Some(snapshot) = scope.snapshot {
+            // this covers the local variables too, since they were changed after it was taken
+            return self.restore(snapshot);
+        }
+        let mut restored = Vec::with_capacity(scope.saved.len());
+        for (name, var) in scope.saved {
+            self.invalidate(&name);
+            match var {
+                Some(var) => self.vars_mut().insert(name.clone(), var),
+                None => self.vars_mut().remove(&name),
+            };
+            self.invalidate(&name);
+            restored.push(name);
+        }
+        restored
+    }
+
+    /// puts every variable back the way it was in `snapshot`, returning the names of the ones that
+    /// were changed
+    fn restore(&mut self, snapshot: Snapshot) -> Vec<Name> {
+        if Arc::ptr_eq(&self.vars, &snapshot.0) {
+            return Vec::new();
+        }
+        let changed = self
+            .vars
+            .iter()
+            .filter(|(name, var)| snapshot.0.get(*name) != Some(*var))
+            .map(|(name, _)| name)
+            .chain(
+                snapshot
+                    .0
+                    .keys()
+                    .filter(|name| !self.vars.contains_key(*name)),
+            )
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in &changed {
+            self.invalidate(name);
+        }
+        self.vars = snapshot.0;
+        for name in &changed {
+            self.invalidate(name);
+        }
+        changed
+    }
+}
+
+fn vars() -> &'static RwLock<Table> {
+    static VARS: OnceLock<RwLock<Table>> = OnceLock::new();
+    VARS.get_or_init(|| {
+        let vars = env::vars_os()
+            .map(|(name, value)| {
+                let var = Var {
+                    value: Some(Value::Scalar(value)),
+                    attrs: Attributes {
+                        exported: true,
+                        ..Attributes::default()
+                    },
+                };
+                (Name::from(name), var)
+            })
+            .collect();
+        RwLock::new(Table {
+            vars: Arc::new(vars),
+            scopes: Vec::new(),
+            environ: None,
+        })
+    })
+}
+
+/// starts a new scope, changes made after this are undone by the matching [`pop_scope`], which
+/// ones depends on `kind`
+///
+/// prefer [`scoped`], which can't forget to end the scope
+pub fn push_scope(kind: ScopeKind) {
+    vars().write().push_scope(kind);
+}
+
+/// ends the innermost scope, restoring the variables it changed to what they were before
+pub fn pop_scope() {
+    let restored = vars().write().pop_scope();
+    for name in restored {
+        notify(&name);
+    }
+}
+
+/// runs `f` in a new scope, which is ended once it returns, even if it returns an error or panics
+pub fn scoped<T>(kind: ScopeKind, f: impl FnOnce() -> T) -> T {
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            pop_scope();
+        }
+    }
+
+    push_scope(kind);
+    let _guard = Guard;
+    f()
+}
+
+/// makes `name` local to the innermost scope, it starts out unset with no attributes, and is then
+/// set to `val` if given, fails outside of any scope
+pub fn local(name: Name, val: Option<OsString>) -> Result<(), EnvError> {
+    vars().write().local(&name)?;
+    match val {
+        Some(val) => set(name, val),
+        None => {
+            notify(&name);
+            Ok(())
+        }
+    }
+}
+
+fn hooks() -> &'static RwLock<HashMap<OsString, Vec<Hook>>> {
+    static HOOKS: OnceLock<RwLock<HashMap<OsString, Vec<Hook>>>> = OnceLock::new();
+    HOOKS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// calls `f` with the new value of `name` every time it's assigned to or unset, including when a
+/// scope ends and puts back what it was before, for as long as the shell runs
+///
+/// `f` is called after the change has been made, so it's free to look at (or change) other
+/// variables
+pub fn on_change(name: &str, f: impl Fn(Option<&OsStr>) + Send + Sync + 'static) {
+    hooks()
+        .write()
+        .entry(name.into())
+        .or_default()
+        .push(Arc::new(f));
+}
+
+/// runs the hooks registered for `name`, this must only be called once the variable table has been
+/// unlocked
+fn notify(name: &OsStr) {
+    // cloned so that a hook registering another one doesn't deadlock
+    let Some(hooks) = hooks().read().get(name).cloned() else {
+        return;
+    };
+    let value = lookup(name);
+    for hook in hooks {
+        hook(value.as_deref());
+    }
+}
+
+/// sets up the variables the shell maintains itself, `PWD` and `SHLVL`, this should be called
+/// once at startup
+pub fn init() {
+    start_time();
+    update_pwd();
+
+    let level = match lookup("SHLVL") {
+        Some(x) => match x.to_string_lossy().trim().parse::<u32>() {
+            Ok(x) => x.saturating_add(1),
+            Err(_) => {
+                warn!("resetting invalid SHLVL: '{}'", x.to_string_lossy());
+                1
+            }
         },
+        None => 1,
+    };
+    if let Err(e) = export("SHLVL".into(), Some(level.to_string().into())) {
+        warn!("unable to set SHLVL: {}", e);
+    }
+}
+
+/// sets `PWD` to the shell's working directory, which should be done every time it changes
+pub fn update_pwd() {
+    match env::current_dir() {
+        Ok(x) => {
+            if let Err(e) = export("PWD".into(), Some(x.into())) {
+                warn!("unable to set PWD: {}", e);
+            }
+        }
+        Err(e) => warn!("unable to set PWD: {}", e),
+    }
+}
+
+pub fn get<K: AsRef<OsStr>>(name: K) -> OsString {
+    lookup(name).unwrap_or_default()
+}
+
+/// like [`get`], but tells an unset variable apart from one that's set to an empty string
+pub fn lookup<K: AsRef<OsStr>>(name: K) -> Option<OsString> {
+    lookup_in(&vars().read(), name.as_ref())
+}
+
+/// like [`lookup`], for when the variable table is already locked
+fn lookup_in(table: &Table, name: &OsStr) -> Option<OsString> {
+    if let Some(index) = positional_index(name) {
+        return positional().read().get(index).cloned();
+    }
+    // most variables aren't namerefs, and don't need the allocations resolving one takes
+    if let Some(var) = table.vars.get(name).filter(|x| !x.attrs.nameref) {
+        return var.value.as_ref()?.scalar();
+    }
+    let name = match table.resolve(name, None) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("{}", e);
+            return None;
+        }
+    };
+    match table.vars.get(&name) {
+        Some(x) => x.value.as_ref()?.scalar(),
+        None => DYNAMIC
+            .iter()
+            .find(|(x, _)| name == *x)
+            .map(|(_, func)| func()),
+    }
+}
+
+fn positional() -> &'static RwLock<Vec<OsString>> {
+    static POSITIONAL: OnceLock<RwLock<Vec<OsString>>> = OnceLock::new();
+    POSITIONAL.get_or_init(|| RwLock::new(env::args_os().take(1).collect()))
+}
+
+/// the index of the positional parameter `name` refers to, if it's a number
+fn positional_index(name: &OsStr) -> Option<usize> {
+    let name = name.to_str()?;
+    match name.bytes().all(|x| x.is_ascii_digit()) {
+        true => name.parse().ok(),
+        false => None,
+    }
+}
+
+/// replaces the positional parameters, `params[0]` becomes `$0`, which is the name of the shell or
+/// the script it's running, and the rest are its arguments, by default only `$0` is set, to how
+/// the shell was invoked
+pub fn set_positional(params: Vec<OsString>) {
+    *positional().write() = params;
+}
+
+/// runs `f` with `$1`, `$2`, ... replaced by `args`, `$0` stays the same, like for a script that's
+/// been `source`d with arguments
+pub fn with_arguments<T>(args: Vec<OsString>, f: impl FnOnce() -> T) -> T {
+    let old = {
+        let mut params = positional().write();
+        let zero = params.first().cloned().unwrap_or_default();
+        std::mem::replace(&mut *params, std::iter::once(zero).chain(args).collect())
+    };
+    let result = f();
+    *positional().write() = old;
+    result
+}
+
+/// sets the line number `LINENO` expands to, which the frontend (or whatever's reading input)
+/// should do before running each line
+pub fn set_line(line: usize) {
+    LINENO.store(line, Ordering::Relaxed);
+}
+
+/// when the shell started, which `SECONDS` counts from
+fn start_time() -> Instant {
+    static START: OnceLock<Instant> = OnceLock::new();
+    *START.get_or_init(Instant::now)
+}
+
+fn dynamic_epochseconds() -> OsString {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs().to_string().into()
+}
+
+fn dynamic_lineno() -> OsString {
+    LINENO.load(Ordering::Relaxed).to_string().into()
+}
+
+/// a number from 0 to 32767, from a xorshift generator seeded with the time and pid, which is
+/// plenty for scripts but obviously not for anything that needs to be unpredictable
+fn dynamic_random() -> OsString {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    let mut x = STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        x = (nanos ^ (u64::from(process::id()) << 32)) | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    STATE.store(x, Ordering::Relaxed);
+    ((x >> 33) % 32768).to_string().into()
+}
+
+fn dynamic_seconds() -> OsString {
+    start_time().elapsed().as_secs().to_string().into()
+}
+
+/// sets a variable, a new variable is local to the shell, while one that's already been exported
+/// stays exported
+pub fn set(name: Name, val: OsString) -> Result<(), EnvError> {
+    declare(name, Some(val), |_| ())
+}
+
+/// like [`set`], but for any kind of value, an array is never exported, even if it's marked to be,
+/// and its elements aren't affected by the variable's attributes
+pub fn set_value(name: Name, val: Value) -> Result<(), EnvError> {
+    let mut table = vars().write();
+    let name = table.resolve(&name, None)?;
+    writable(&table.vars, &name)?;
+    table.invalidate(&name);
+    table
+        .vars_mut()
+        .entry(name.clone())
+        .or_insert(Var {
+            value: None,
+            attrs: Attributes::default(),
+        })
+        .value = Some(val);
+    drop(table);
+    notify(&name);
+    Ok(())
+}
+
+/// marks a variable to be passed to the commands the shell runs, setting it to `val` if given
+pub fn export(name: Name, val: Option<OsString>) -> Result<(), EnvError> {
+    declare(name, val, |x| x.exported = true)
+}
+
+/// makes an exported variable local to the shell again, keeping its value, which isn't allowed
+/// for readonly variables
+pub fn unexport(name: Name, val: Option<OsString>) -> Result<(), EnvError> {
+    declare(name, val, |x| x.exported = false)
+}
+
+/// marks a variable as readonly, setting it to `val` if given, after which it can't be assigned
+/// to or unset for as long as the shell runs
+pub fn make_readonly(name: Name, val: Option<OsString>) -> Result<(), EnvError> {
+    declare(name, val, |x| x.readonly = true)
+}
+
+/// changes the attributes of a variable with `f`, creating it without a value if it doesn't
+/// exist, and then assigns `val` to it if given, which is transformed according to its new
+/// attributes
+///
+/// the value of a readonly variable can't be changed, and neither can its attributes, other than
+/// adding to them
+///
+/// if `name` is a nameref, the variable it refers to is changed instead
+pub fn declare(
+    name: Name,
+    val: Option<OsString>,
+    f: impl FnOnce(&mut Attributes),
+) -> Result<(), EnvError> {
+    check_name(&name)?;
+    let name = vars().read().resolve(&name, None)?;
+    declare_ref(name, val, f)
+}
+
+/// like [`declare`], but always changes `name` itself, even if it's a nameref, this is how a
+/// nameref is created, or changed to refer to something else
+///
+/// the variable is checked and changed under one lock, which `f` is called with held, so it
+/// mustn't look at any variables itself
+pub fn declare_ref(
+    name: Name,
+    val: Option<OsString>,
+    f: impl FnOnce(&mut Attributes),
+) -> Result<(), EnvError> {
+    check_name(&name)?;
+    let mut table = vars().write();
+    let old = table.vars.get(&name).map(|x| x.attrs).unwrap_or_default();
+    let mut attrs = old;
+    f(&mut attrs);
+    let removed = Attributes {
+        exported: old.exported && !attrs.exported,
+        readonly: old.readonly && !attrs.readonly,
+        integer: old.integer && !attrs.integer,
+        lowercase: old.lowercase && !attrs.lowercase,
+        uppercase: old.uppercase && !attrs.uppercase,
+        nameref: old.nameref && !attrs.nameref,
+    };
+    if old.readonly && (val.is_some() || removed != Attributes::default()) {
+        return Err(EnvError::Readonly {
+            name: name.to_string_lossy().into_owned(),
+        });
+    }
+    let val = val
+        .map(|x| transform(&table, &name, x, attrs))
+        .transpose()?;
+    if let Some(val) = val.as_ref().filter(|_| attrs.nameref) {
+        check_name(val)?;
+        table.resolve(val, Some(&name))?;
+    }
+    let before = table.vars.get(&name).cloned();
+    let var = table.vars_mut().entry(name.clone()).or_insert(Var {
+        value: None,
+        attrs: Attributes::default(),
+    });
+    var.attrs = attrs;
+    let changed = val.is_some();
+    if let Some(val) = val {
+        var.value = Some(Value::Scalar(val));
+    }
+    // exporting a variable again with the value it already has, like `cd .` does to `PWD`, leaves
+    // the environment commands are run with as it was, so it's kept
+    let was_exported = before.as_ref().is_some_and(|x| x.attrs.exported);
+    if (attrs.exported || was_exported) && before.as_ref() != table.vars.get(&name) {
+        table.environ = None;
+    }
+    drop(table);
+    if changed {
+        notify(&name);
+    }
+    Ok(())
+}
+
+/// applies the attributes of a variable to a value that's being assigned to it, an integer's value
+/// can refer to other variables, which are looked up in `table`
+fn transform(
+    table: &Table,
+    name: &OsStr,
+    val: OsString,
+    attrs: Attributes,
+) -> Result<OsString, EnvError> {
+    if attrs.nameref {
+        return Ok(val);
+    }
+    if attrs.integer {
+        let lookup =
+            |x: &str| lookup_in(table, OsStr::new(x)).map(|x| x.to_string_lossy().into_owned());
+        return match arith::evaluate(&val.to_string_lossy(), &lookup) {
+            Ok(x) => Ok(x.to_string().into()),
+            Err(e) => Err(EnvError::Arithmetic {
+                name: name.to_string_lossy().into_owned(),
+                internal: e,
+            }),
+        };
+    }
+    Ok(match (attrs.lowercase, attrs.uppercase) {
+        (true, _) => val.to_string_lossy().to_lowercase().into(),
+        (_, true) => val.to_string_lossy().to_uppercase().into(),
+        _ => val,
+    })
+}
+
+/// unsets a variable, or the variable it refers to if it's a nameref
+pub fn unset<K: AsRef<OsStr>>(name: K) -> Result<(), EnvError> {
+    let name = vars().read().resolve(name.as_ref(), None)?;
+    unset_ref(name)
+}
+
+/// like [`unset`], but always unsets `name` itself, even if it's a nameref
+pub fn unset_ref<K: AsRef<OsStr>>(name: K) -> Result<(), EnvError> {
+    let mut table = vars().write();
+    writable(&table.vars, name.as_ref())?;
+    table.invalidate(name.as_ref());
+    table.vars_mut().remove(name.as_ref());
+    drop(table);
+    notify(name.as_ref());
+    Ok(())
+}
+
+/// whether `name` can be used as the name of a variable, which, like in other shells, means it's
+/// made up of ascii letters, digits and underscores, and doesn't start with a digit
+///
+/// variables imported from the environment the shell was started with don't have to follow this,
+/// but they can only be passed on to commands as they are
+pub fn valid_name(name: &OsStr) -> bool {
+    let bytes = name.as_bytes();
+    bytes
+        .first()
+        .is_some_and(|x| x.is_ascii_alphabetic() || *x == b'_')
+        && bytes
+            .iter()
+            .all(|x| x.is_ascii_alphanumeric() || *x == b'_')
+}
+
+fn check_name(name: &OsStr) -> Result<(), EnvError> {
+    match valid_name(name) {
+        true => Ok(()),
+        false => Err(EnvError::InvalidName {
+            name: name.to_string_lossy().into_owned(),
+        }),
+    }
+}
+
+/// fails if `name` isn't a valid name or is readonly
+fn writable(vars: &HashMap<Name, Var>, name: &OsStr) -> Result<(), EnvError> {
+    check_name(name)?;
+    match vars.get(name) {
+        Some(x) if x.attrs.readonly => Err(EnvError::Readonly {
+            name: name.to_string_lossy().into_owned(),
+        }),
+        _ => Ok(()),
     }
 }
 
-pub fn set(name: OsString, val: OsString) {
-    envs().write().insert(name, val);
+/// every readonly variable, along with its value if it has one, sorted by name
+pub fn readonly() -> Vec<(Name, Option<Value>)> {
+    list(|x| x.readonly)
 }
 
-// TODO: avoid deep copy of envs
-pub fn pairs() -> Vec<(OsString, OsString)> {
-    envs()
+/// every exported variable, along with its value if it has one, sorted by name
+pub fn exports() -> Vec<(Name, Option<Value>)> {
+    list(|x| x.exported)
+}
+
+fn list(filter: impl Fn(&Attributes) -> bool) -> Vec<(Name, Option<Value>)> {
+    declared()
+        .into_iter()
+        .filter(|(_, attrs, _)| filter(attrs))
+        .map(|(name, _, value)| (name, value))
+        .collect()
+}
+
+/// every variable along with its attributes and value, sorted by name
+pub fn declared() -> Vec<(Name, Attributes, Option<Value>)> {
+    let mut vars = vars()
         .read()
+        .vars
         .iter()
-        .map(|(x, y)| (x.clone(), y.clone()))
-        .collect::<Vec<_>>()
+        .map(|(name, x)| (name.clone(), x.attrs, x.value.clone()))
+        .collect::<Vec<_>>();
+    vars.sort_by(|a, b| a.0.cmp(&b.0));
+    vars
+}
+
+/// the environment commands are run with, i.e. every exported variable that has a value that isn't
+/// an array
+///
+/// this is a snapshot that's shared by everything that asks for it until one of the variables in
+/// it changes, so that spawning a command doesn't have to copy every variable
+pub fn environ() -> Arc<Environ> {
+    if let Some(environ) = &vars().read().environ {
+        return environ.clone();
+    }
+    let mut table = vars().write();
+    let table = &mut *table;
+    table
+        .environ
+        .get_or_insert_with(|| Arc::new(Environ::new(&table.vars)))
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dynamic_variables() {
+        for _ in 0..100 {
+            let random = get("RANDOM").to_string_lossy().parse::<u32>().unwrap();
+            assert!(random < 32768);
+        }
+        assert!(
+            get("EPOCHSECONDS")
+                .to_string_lossy()
+                .parse::<u64>()
+                .unwrap()
+                > 0
+        );
+        assert!(lookup("NOT_A_DYNAMIC_VARIABLE").is_none());
+    }
+
+    #[test]
+    fn array_values() {
+        let array = Value::from(vec![OsString::from("a"), OsString::from("b")]);
+        set_value("RS_TEST_ARRAY".into(), array.clone()).unwrap();
+        export("RS_TEST_ARRAY".into(), None).unwrap();
+        assert_eq!(get("RS_TEST_ARRAY"), "a");
+        assert_eq!(array.elements(), ["a", "b"]);
+        assert!(!environ().iter().any(|(x, _)| x == "RS_TEST_ARRAY"));
+
+        let sparse = Value::Indexed(BTreeMap::from([(3, OsString::from("c"))]));
+        set_value("RS_TEST_ARRAY".into(), sparse).unwrap();
+        assert_eq!(lookup("RS_TEST_ARRAY"), None);
+    }
+
+    #[test]
+    fn interned_names() {
+        let a = Name::from("RS_TEST_INTERNED");
+        let b = Name::from(OsString::from("RS_TEST_INTERNED"));
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+        assert_eq!(a, "RS_TEST_INTERNED");
+
+        set(a.clone(), "1".into()).unwrap();
+        let table = vars().read();
+        let (key, _) = table
+            .vars
+            .get_key_value(OsStr::new("RS_TEST_INTERNED"))
+            .unwrap();
+        assert!(Arc::ptr_eq(&key.0, &a.0));
+    }
+
+    #[test]
+    fn variable_names() {
+        for name in ["x", "_", "PATH", "a_1", "_9"] {
+            assert!(valid_name(OsStr::new(name)), "{name}");
+        }
+        for name in ["", "1a", "a=b", "a b", "a-b", "a\0b", "é"] {
+            assert!(!valid_name(OsStr::new(name)), "{name}");
+        }
+        assert!(matches!(
+            set("RS_TEST=NAME".into(), "1".into()),
+            Err(EnvError::InvalidName { .. })
+        ));
+        assert!(unset("RS TEST").is_err());
+    }
+
+    #[test]
+    fn integer_variables() {
+        set("RS_TEST_INT_BASE".into(), "4".into()).unwrap();
+        // the value's worked out from other variables while the table is locked for the change
+        declare(
+            "RS_TEST_INT".into(),
+            Some("RS_TEST_INT_BASE * 2 + 1".into()),
+            |x| x.integer = true,
+        )
+        .unwrap();
+        assert_eq!(get("RS_TEST_INT"), "9");
+        assert!(matches!(
+            set("RS_TEST_INT".into(), "1 +".into()),
+            Err(EnvError::Arithmetic { .. })
+        ));
+        assert_eq!(get("RS_TEST_INT"), "9");
+    }
+
+    #[test]
+    fn namerefs() {
+        set("RS_TEST_TARGET".into(), "1".into()).unwrap();
+        declare_ref("RS_TEST_REF".into(), Some("RS_TEST_TARGET".into()), |x| {
+            x.nameref = true
+        })
+        .unwrap();
+        assert_eq!(get("RS_TEST_REF"), "1");
+        set("RS_TEST_REF".into(), "2".into()).unwrap();
+        assert_eq!(get("RS_TEST_TARGET"), "2");
+        export("RS_TEST_REF".into(), None).unwrap();
+        assert!(environ().iter().any(|(x, _)| x == "RS_TEST_TARGET"));
+        assert!(!environ().iter().any(|(x, _)| x == "RS_TEST_REF"));
+
+        let cycle = declare_ref("RS_TEST_TARGET".into(), Some("RS_TEST_REF".into()), |x| {
+            x.nameref = true
+        });
+        assert!(matches!(cycle, Err(EnvError::CircularRef { .. })));
+        assert!(declare_ref("RS_TEST_REF".into(), Some("1x".into()), |_| ()).is_err());
+
+        unset("RS_TEST_REF").unwrap();
+        assert_eq!(lookup("RS_TEST_TARGET"), None);
+        unset_ref("RS_TEST_REF").unwrap();
+        assert_eq!(lookup("RS_TEST_REF"), None);
+    }
+
+    #[test]
+    fn change_hooks() {
+        let seen = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        on_change("RS_TEST_HOOK", move |x| {
+            hook_seen.lock().push(x.map(OsStr::to_owned))
+        });
+
+        set("RS_TEST_HOOK".into(), "1".into()).unwrap();
+        // only changing its attributes doesn't count
+        export("RS_TEST_HOOK".into(), None).unwrap();
+        unset("RS_TEST_HOOK").unwrap();
+        export("RS_TEST_HOOK".into(), Some("2".into())).unwrap();
+        assert_eq!(*seen.lock(), [Some("1".into()), None, Some("2".into())]);
+    }
+
+    #[test]
+    fn scopes() {
+        let var = |x: &str| Var {
+            value: Some(Value::Scalar(x.into())),
+            attrs: Attributes::default(),
+        };
+        let set = |table: &mut Table, name: &str, value| {
+            table.invalidate(OsStr::new(name));
+            table.vars_mut().insert(name.into(), var(value));
+        };
+        let get = |table: &Table, name: &str| table.vars.get(OsStr::new(name)).cloned();
+        let mut table = Table::default();
+        set(&mut table, "a", "outer");
+        assert!(matches!(table.local(&"a".into()), Err(EnvError::NoScope)));
+
+        table.push_scope(ScopeKind::Subshell);
+        set(&mut table, "b", "subshell");
+        table.push_scope(ScopeKind::Function);
+        table.local(&"a".into()).unwrap();
+        set(&mut table, "a", "local");
+        set(&mut table, "c", "global");
+
+        assert_eq!(table.pop_scope(), ["a"]);
+        assert_eq!(get(&table, "a"), Some(var("outer")));
+        assert_eq!(get(&table, "c"), Some(var("global")));
+        let mut restored = table.pop_scope();
+        restored.sort();
+        assert_eq!(restored, ["b", "c"]);
+        assert_eq!(get(&table, "b"), None);
+        assert_eq!(get(&table, "c"), None);
+
+        // a scope that doesn't change anything doesn't copy anything either
+        let vars = table.vars.clone();
+        table.push_scope(ScopeKind::Subshell);
+        assert!(table.pop_scope().is_empty());
+        assert!(Arc::ptr_eq(&vars, &table.vars));
+    }
+
+    #[test]
+    fn readonly_variables() {
+        set("RS_TEST_READONLY".into(), "1".into()).unwrap();
+        make_readonly("RS_TEST_READONLY".into(), Some("2".into())).unwrap();
+        assert!(set("RS_TEST_READONLY".into(), "3".into()).is_err());
+        assert!(unset("RS_TEST_READONLY").is_err());
+        assert!(make_readonly("RS_TEST_READONLY".into(), Some("4".into())).is_err());
+        assert_eq!(get("RS_TEST_READONLY"), "2");
+        // only changing its value is refused
+        export("RS_TEST_READONLY".into(), None).unwrap();
+        let environ = environ();
+        let entry = environ.iter().find(|(x, _)| *x == "RS_TEST_READONLY");
+        assert_eq!(
+            entry.map(|(_, x)| x.to_bytes()),
+            Some(&b"RS_TEST_READONLY=2"[..])
+        );
+    }
 }
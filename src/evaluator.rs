@@ -1,17 +1,54 @@
-use std::ffi::OsString;
+use std::{
+    ffi::OsString,
+    fmt, io,
+    time::{Duration, Instant},
+};
 
+use log::{error, warn};
 use subprocess::ExitStatus;
 
 use crate::{
+    alias,
     ast::*,
-    env,
-    proc_manager::{ProcError, ProcManager},
+    builtins,
+    diagnostic::{self, Diagnostic, Label},
+    env::{self, ScopeKind},
+    i18n, jobs,
+    options::{self, ShellOption},
+    proc_manager::{Fifo, NativeBackend, ProcError, ProcManager, ProcessBackend},
+    profile::{self, Phase},
 };
 
+/// the variable that sets how deeply substitutions may be nested, `0` lifts the limit (leaving
+/// only the parser's own, [`crate::ast::MAX_NESTING`])
+const SUBSTITUTION_DEPTH_VAR: &str = "RS_SHELL_SUBST_DEPTH";
+
+/// the substitution depth limit used when `RS_SHELL_SUBST_DEPTH` is unset
+const DEFAULT_SUBSTITUTION_DEPTH: usize = 64;
+
+/// the variable limiting how many aliases a command can go through before it's run, see
+/// [`expand_alias`]
+const ALIAS_DEPTH_VAR: &str = "RS_SHELL_ALIAS_DEPTH";
+
+/// the alias depth limit used when `RS_SHELL_ALIAS_DEPTH` is unset
+const DEFAULT_ALIAS_DEPTH: usize = 16;
+
+/// the variable the resource usage of the last foreground job is stored in, for display in the
+/// prompt
+const RUSAGE_VAR: &str = "RS_LAST_RUSAGE";
+
+/// the variable holding a command whose output is used as the prompt, e.g. `starship prompt`, see
+/// [`Evaluator::prompt_command`]
+const PROMPT_CMD_VAR: &str = "RS_SHELL_PROMPT_CMD";
+
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
 pub enum EvalError {
-    #[error("enviroment variable {name} is not valud utf-8: {value}")]
+    #[allow(dead_code)]
+    #[error("{}", i18n::format(
+        "enviroment variable {name} is not valud utf-8: {value}",
+        &[("name", .name), ("value", .value)],
+    ))]
     InvalidEnvValue {
         /// the name of the environment variable
         name: String,
@@ -21,47 +58,624 @@ pub enum EvalError {
         value: String,
     },
 
-    #[error("evaluator recived error attempting to dispath command: {internal}")]
+    #[error("{}", i18n::format(
+        "evaluator recived error attempting to dispath command: {error}",
+        &[("error", .internal)],
+    ))]
     DispatchError { internal: ProcError },
+
+    /// a variable that isn't set was expanded with `nounset` enabled
+    #[error("{location}: {name}: {}", i18n::text("unbound variable"))]
+    UnsetVariable {
+        name: String,
+        location: Location,
+        span: Span,
+    },
+
+    /// an assignment was made to a name that can't be a variable, see [`env::valid_name`]
+    #[error("`{name}': {}", i18n::text("not a valid identifier"))]
+    InvalidVarName { name: String, span: Span },
+
+    /// substitutions were nested deeper than `RS_SHELL_SUBST_DEPTH` allows
+    #[error("{}", i18n::format(
+        "substitutions nested more than {limit} levels deep (see $RS_SHELL_SUBST_DEPTH)",
+        &[("limit", .limit)],
+    ))]
+    RecursionLimit { limit: usize },
+
+    /// an alias expanded to another alias more times than `RS_SHELL_ALIAS_DEPTH` allows
+    #[error("{name}: {}", i18n::format(
+        "aliases nested more than {limit} levels deep (see $RS_SHELL_ALIAS_DEPTH)",
+        &[("limit", .limit)],
+    ))]
+    AliasLimit { name: String, limit: usize },
+}
+
+impl EvalError {
+    /// the status of a command line that failed with this error, see [`ProcError::exit_status`],
+    /// anything that stops it before it's dispatched counts as status 1
+    pub fn exit_status(&self) -> ExitStatus {
+        match self {
+            EvalError::DispatchError { internal } => internal.exit_status(),
+            _ => ExitStatus::Exited(1),
+        }
+    }
+}
+
+impl Diagnostic for EvalError {
+    fn labels(&self) -> Vec<Label> {
+        match self {
+            EvalError::DispatchError { internal } => internal.labels(),
+            EvalError::UnsetVariable { span, .. } => vec![Label::new(*span, i18n::text("not set"))],
+            EvalError::InvalidVarName { span, .. } => {
+                vec![Label::new(*span, i18n::text("not a valid name"))]
+            }
+            _ => Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug)]
 #[non_exhaustive]
 pub struct Evaluator {
     proc_manager: ProcManager,
+    /// exit status of the most recently evaluated command
+    last_status: ExitStatus,
+    /// wall-clock time the most recently evaluated command took
+    last_duration: Duration,
+    /// FIFOs of the process substitutions in the command being flattened, until they're handed to
+    /// the process manager along with it, see [`Evaluator::holding_fifos`]
+    fifos: Vec<Fifo>,
+    /// how many substitutions deep the evaluator currently is
+    depth: usize,
+    /// whether the last command line ran `exit`, so the shell should exit
+    exiting: bool,
+    hooks: Hooks,
+    /// where words made of several parts are put together, kept around so that its allocation can
+    /// be reused, see [`Evaluator::concat`]
+    scratch: OsString,
+}
+
+/// the result of dispatching a command line, as given to [`Evaluator::after_dispatch`] hooks
+pub type DispatchResult = Result<ExitStatus, ProcError>;
+
+/// hooks registered by whatever's embedding the shell, see [`Evaluator::on_parse`],
+/// [`Evaluator::before_dispatch`], and [`Evaluator::after_dispatch`]
+#[derive(Default)]
+struct Hooks {
+    parse: Vec<ParseHook>,
+    before_dispatch: Vec<BeforeDispatchHook>,
+    after_dispatch: Vec<AfterDispatchHook>,
+}
+
+type ParseHook = Box<dyn FnMut(&mut Main)>;
+type BeforeDispatchHook = Box<dyn FnMut(&mut FlattenedCmdline)>;
+type AfterDispatchHook = Box<dyn FnMut(&FlattenedCmdline, &DispatchResult)>;
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("parse", &self.parse.len())
+            .field("before_dispatch", &self.before_dispatch.len())
+            .field("after_dispatch", &self.after_dispatch.len())
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FlattenedCmdline {
-    pub envs: Vec<(OsString, OsString)>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_os::os_pairs"))]
+    pub envs: Vec<(env::Name, OsString)>,
+    /// empty when the command line only assigns variables, see [`FlattenedCmdline::is_assignment`]
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_os::os_string"))]
     pub command: OsString,
+    /// where the command was typed, if it came from the command line being run rather than e.g.
+    /// an alias, for pointing errors at it
+    pub command_span: Option<Span>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_os::os_strings"))]
     pub arguments: Vec<OsString>,
-    pub redirects: Vec<(RedirectOp, OsString)>,
+    /// each redirect along with the file it's to or from, which is empty for `>&-`, and where that
+    /// file was typed, like [`FlattenedCmdline::command_span`]
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_os::redirects"))]
+    pub redirects: Vec<(RedirectOp, OsString, Option<Span>)>,
     pub next: Option<(Separator, Box<FlattenedCmdline>)>,
+    pub background: bool,
+}
+
+impl FlattenedCmdline {
+    /// whether this only assigns variables (e.g. `NAME=value`) rather than running a command
+    pub fn is_assignment(&self) -> bool {
+        self.command.is_empty() && !self.envs.is_empty()
+    }
+}
+
+/// builds a [`FlattenedCmdline`] without going through the parser, e.g. to hand straight to a
+/// [`ProcManager`]
+///
+/// ```
+/// use cs128h_project::{ast::{RedirectFd, RedirectType}, CommandBuilder};
+///
+/// // LC_ALL=C sort -u names 2> errors | head -n 5
+/// let cmd = CommandBuilder::new("sort")
+///     .env("LC_ALL", "C")
+///     .args(["-u", "names"])
+///     .redirect(RedirectFd::Stderr, RedirectType::Out, "errors")
+///     .pipe(CommandBuilder::new("head").args(["-n", "5"]))
+///     .build();
+/// ```
+///
+/// arguments, variables and redirects go to the command the builder was created with, so every
+/// command in a chain is built separately before being chained on with [`CommandBuilder::pipe`],
+/// [`CommandBuilder::then`], or [`CommandBuilder::fork`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandBuilder {
+    cmd: FlattenedCmdline,
+}
+
+impl CommandBuilder {
+    pub fn new(command: impl Into<OsString>) -> Self {
+        Self {
+            cmd: FlattenedCmdline {
+                envs: Vec::new(),
+                command: command.into(),
+                command_span: None,
+                arguments: Vec::new(),
+                redirects: Vec::new(),
+                next: None,
+                background: false,
+            },
+        }
+    }
+
+    /// a command line that only assigns the variables given with [`CommandBuilder::env`], like
+    /// `NAME=value`
+    pub fn assignment() -> Self {
+        Self::new("")
+    }
+
+    pub fn arg(mut self, argument: impl Into<OsString>) -> Self {
+        self.cmd.arguments.push(argument.into());
+        self
+    }
+
+    pub fn args(mut self, arguments: impl IntoIterator<Item = impl Into<OsString>>) -> Self {
+        self.cmd
+            .arguments
+            .extend(arguments.into_iter().map(Into::into));
+        self
+    }
+
+    /// a one-shot variable, or one that's assigned for an [`CommandBuilder::assignment`]
+    pub fn env(mut self, name: impl Into<env::Name>, value: impl Into<OsString>) -> Self {
+        self.cmd.envs.push((name.into(), value.into()));
+        self
+    }
+
+    /// redirects `fd` to or from `path`, or for [`RedirectType::HereString`] feeds it `path`
+    /// itself, use [`CommandBuilder::close`] and [`CommandBuilder::duplicate`] rather than
+    /// [`RedirectType::Close`] and [`RedirectType::Duplicate`]
+    pub fn redirect(
+        mut self,
+        fd: RedirectFd,
+        r#type: RedirectType,
+        path: impl Into<OsString>,
+    ) -> Self {
+        let op = RedirectOp {
+            fd,
+            r#type,
+            span: Span::default(),
+        };
+        self.cmd.redirects.push((op, path.into(), None));
+        self
+    }
+
+    /// closes `fd` in the command, like `>&-`
+    pub fn close(self, fd: RedirectFd) -> Self {
+        self.redirect(fd, RedirectType::Close, "")
+    }
+
+    /// makes `fd` a copy of `target`, like `2>&1`, redirects apply in the order they're added
+    pub fn duplicate(self, fd: RedirectFd, target: RedirectFd) -> Self {
+        self.redirect(fd, RedirectType::Duplicate(target), "")
+    }
+
+    /// pipes the output of this into `next`, like `|`
+    pub fn pipe(self, next: CommandBuilder) -> Self {
+        self.chain(Separator::Pipe, next)
+    }
+
+    /// runs `next` once this has finished, like `;`
+    pub fn then(self, next: CommandBuilder) -> Self {
+        self.chain(Separator::Semicolon, next)
+    }
+
+    /// runs this in the background and `next` straight away, like `&` between two commands
+    pub fn fork(self, next: CommandBuilder) -> Self {
+        self.chain(Separator::Fork, next)
+    }
+
+    /// runs the end of the chain in the background, like a trailing `&`
+    pub fn background(mut self) -> Self {
+        self.last().background = true;
+        self
+    }
+
+    pub fn build(self) -> FlattenedCmdline {
+        self.cmd
+    }
+
+    fn chain(mut self, separator: Separator, next: CommandBuilder) -> Self {
+        self.last().next = Some((separator, Box::new(next.cmd)));
+        self
+    }
+
+    fn last(&mut self) -> &mut FlattenedCmdline {
+        let mut last = &mut self.cmd;
+        while last.next.is_some() {
+            last = &mut last.next.as_mut().expect("checked above").1;
+        }
+        last
+    }
+}
+
+impl From<CommandBuilder> for FlattenedCmdline {
+    fn from(builder: CommandBuilder) -> Self {
+        builder.build()
+    }
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Evaluator {
     pub fn new() -> Self {
+        Self::with_backend(NativeBackend::default())
+    }
+
+    /// creates an [`Evaluator`] that runs external commands with `backend`
+    pub fn with_backend(backend: impl ProcessBackend + 'static) -> Self {
         Self {
-            proc_manager: ProcManager::new(),
+            proc_manager: ProcManager::with_backend(backend),
+            last_status: ExitStatus::Exited(0),
+            last_duration: Duration::ZERO,
+            fifos: Vec::new(),
+            depth: 0,
+            scratch: OsString::new(),
+            exiting: false,
+            hooks: Hooks::default(),
         }
     }
 
+    /// registers a hook that's given every AST passed to [`Evaluator::eval`] before it's
+    /// evaluated, which it can change
+    pub fn on_parse(&mut self, hook: impl FnMut(&mut Main) + 'static) {
+        self.hooks.parse.push(Box::new(hook));
+    }
+
+    /// registers a hook that's given each stage of a command line (everything up to a `;` or `&`)
+    /// once it's been flattened, right before it's run, which it can change, e.g. to rewrite the
+    /// command
+    ///
+    /// only the stages of the command lines given to [`Evaluator::eval`] are passed to the
+    /// evaluator's hooks, not substitutions or `$precmd_functions` and `$preexec_functions`
+    pub fn before_dispatch(&mut self, hook: impl FnMut(&mut FlattenedCmdline) + 'static) {
+        self.hooks.before_dispatch.push(Box::new(hook));
+    }
+
+    /// registers a hook that's given each stage of a command line along with how running it went,
+    /// once it's finished (or been started, if it's run in the background), like
+    /// [`Evaluator::before_dispatch`] hooks
+    pub fn after_dispatch(
+        &mut self,
+        hook: impl FnMut(&FlattenedCmdline, &DispatchResult) + 'static,
+    ) {
+        self.hooks.after_dispatch.push(Box::new(hook));
+    }
+
+    /// runs a command line, returning the error that ended it if one did
+    ///
+    /// a stage that fails to dispatch, e.g. because its command can't be found, only ends the line
+    /// if it's the last one or errexit is set, the errors of the ones that don't are logged, see
+    /// [`Evaluator::eval_reporting`] to show them some other way
     pub fn eval(&mut self, ast: Main) -> Result<ExitStatus, EvalError> {
-        let flattened = self.flatten_commandline(ast.0)?;
-        match self.proc_manager.dispatch(flattened) {
-            Ok(x) => Ok(x),
-            Err(e) => Err(EvalError::DispatchError { internal: e }),
+        self.eval_reporting(ast, &mut |e| error!("{}", e))
+    }
+
+    /// like [`Evaluator::eval`], but the error of each stage that fails without ending the line is
+    /// given to `report` as soon as it happens, so it's shown before anything the rest of the line
+    /// prints
+    pub fn eval_reporting(
+        &mut self,
+        mut ast: Main,
+        report: &mut dyn FnMut(EvalError),
+    ) -> Result<ExitStatus, EvalError> {
+        for hook in &mut self.hooks.parse {
+            hook(&mut ast);
         }
+        let start = Instant::now();
+        // discard whatever a hook might have left behind
+        jobs::take_last_rusage();
+        self.depth = 0;
+        self.exiting = false;
+        let result = self.eval_internal(ast, report);
+        self.last_duration = start.elapsed();
+        if let Some(rusage) = jobs::take_last_rusage() {
+            if let Err(e) = env::set(RUSAGE_VAR.into(), rusage.to_string().into()) {
+                warn!("unable to set {}: {}", RUSAGE_VAR, e);
+            }
+        }
+        self.last_status = match &result {
+            Ok(x) => *x,
+            Err(e) => e.exit_status(),
+        };
+        result
     }
 
+    /// flattens a whole command line without running it, any substitutions in it are still run
+    /// though, and the FIFOs of process substitutions last as long as the evaluator does
+    pub fn flatten(&mut self, ast: Main) -> Result<FlattenedCmdline, EvalError> {
+        self.depth = 0;
+        self.flatten_commandline(ast.0)
+    }
+
+    /// the exit status of the most recently evaluated command, a command that failed to evaluate
+    /// or dispatch is treated as having exited with status 1, or 127 if it couldn't be found and 126
+    /// if it couldn't be executed
+    pub fn last_status(&self) -> ExitStatus {
+        self.last_status
+    }
+
+    /// sets the status `$?` and [`Evaluator::last_status`] give, for a line that failed before it
+    /// got to the evaluator, e.g. one that couldn't be parsed, so that everything that looks at the
+    /// status of the last command sees the same thing
+    pub fn set_last_status(&mut self, status: ExitStatus) {
+        self.last_status = status;
+    }
+
+    /// whether the most recently evaluated command line ran `exit`, in which case whatever's reading
+    /// commands should stop, [`Evaluator::last_status`] being the status to exit with
+    pub fn exit_requested(&self) -> bool {
+        self.exiting
+    }
+
+    /// how long the most recently evaluated command took to run
+    pub fn last_duration(&self) -> Duration {
+        self.last_duration
+    }
+
+    /// runs the command in `$RS_SHELL_PROMPT_CMD` and returns what it writes to stdout, to be shown
+    /// as the prompt instead of rendering `PS1`, `None` if it isn't set or the command fails
+    ///
+    /// it's run like a command substitution, with `RS_SHELL_STATUS`, `RS_SHELL_DURATION_MS` and
+    /// `RS_SHELL_JOBS` exported to it (and expanded in it) as the exit code and duration of the last
+    /// command and the number of jobs, e.g. for starship:
+    ///
+    /// ```text
+    /// starship prompt --status=$RS_SHELL_STATUS --cmd-duration=$RS_SHELL_DURATION_MS --jobs=$RS_SHELL_JOBS
+    /// ```
+    ///
+    /// like hooks it doesn't change [`Evaluator::last_status`]
+    pub fn prompt_command(&mut self) -> Option<String> {
+        let line = env::get(PROMPT_CMD_VAR);
+        // in dry-run mode it'd be printed before every prompt rather than run
+        if line.is_empty() || options::enabled(ShellOption::DryRun) {
+            return None;
+        }
+        self.run_prompt_command(&line.to_string_lossy())
+    }
+
+    fn run_prompt_command(&mut self, line: &str) -> Option<String> {
+        let vars = [
+            ("RS_SHELL_STATUS", exit_code(self.last_status).to_string()),
+            (
+                "RS_SHELL_DURATION_MS",
+                self.last_duration.as_millis().to_string(),
+            ),
+            ("RS_SHELL_JOBS", jobs::list().len().to_string()),
+        ];
+        let result: Result<_, EvalError> = env::scoped(ScopeKind::Subshell, || {
+            for (name, value) in vars {
+                env::export(name.into(), Some(value.into())).map_err(|e| {
+                    EvalError::DispatchError {
+                        internal: ProcError::Env { internal: e },
+                    }
+                })?;
+            }
+            let ast = match crate::ast::generate_ast(line) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("{} doesn't parse: {}", PROMPT_CMD_VAR, e);
+                    return Ok(None);
+                }
+            };
+            self.depth = 0;
+            let flat = self.holding_fifos(|x| x.flatten_commandline(ast.0))?;
+            let result = self.proc_manager.dispatch_capture(flat, false);
+            builtins::take_exit_request();
+            let (status, output) = result.map_err(|e| EvalError::DispatchError { internal: e })?;
+            if !status.success() {
+                warn!(
+                    "{} exited with status {}",
+                    PROMPT_CMD_VAR,
+                    exit_code(status)
+                );
+                return Ok(None);
+            }
+            Ok(Some(output))
+        });
+        match result {
+            Ok(x) => x.map(|x| x.to_string_lossy().trim_end_matches('\n').to_owned()),
+            Err(e) => {
+                warn!("{} failed: {}", PROMPT_CMD_VAR, e);
+                None
+            }
+        }
+    }
+
+    /// runs every command listed in `$precmd_functions`, this should be called right before each
+    /// prompt is displayed
+    pub fn precmd(&mut self) {
+        self.run_hook_functions("precmd_functions", Vec::new());
+    }
+
+    /// runs every command listed in `$preexec_functions` with the text of the command that's about
+    /// to be executed as its only argument
+    pub fn preexec(&mut self, line: &str) {
+        self.run_hook_functions("preexec_functions", vec![OsString::from(line)]);
+    }
+
+    /// runs each whitespace separated name in the variable `var` as a command in the shell, with
+    /// `arguments` after any the name stands for
+    ///
+    /// the shell doesn't have functions, so a hook that needs to change the shell's state, e.g. set
+    /// variables or `cd`, is an alias (or a builtin), since those are run by the shell itself, like
+    /// `alias load-env='dotenv .env'`
+    ///
+    /// hooks are dispatched directly rather than through [`Evaluator::eval`] so they don't clobber
+    /// the status of the last user command, failures are logged and otherwise ignored
+    fn run_hook_functions(&mut self, var: &str, arguments: Vec<OsString>) {
+        let hooks = env::get(var);
+        for hook in hooks.to_string_lossy().split_whitespace() {
+            let cmdline = match crate::ast::generate_ast(hook) {
+                Ok(Main(x)) if x.command.is_some() && x.next.is_none() && !x.background => x,
+                _ => {
+                    warn!("{} hook '{}' isn't the name of a command", var, hook);
+                    continue;
+                }
+            };
+            self.depth = 0;
+            let result = self
+                .holding_fifos(|x| x.flatten_single(cmdline))
+                .and_then(|mut flat| {
+                    flat.arguments.extend(arguments.iter().cloned());
+                    self.proc_manager
+                        .dispatch(flat)
+                        .map_err(|e| EvalError::DispatchError { internal: e })
+                });
+            // an `exit` in a hook doesn't make the shell exit
+            builtins::take_exit_request();
+            match result {
+                Ok(x) if !x.success() => {
+                    warn!(
+                        "{} hook '{}' exited with status {}",
+                        var,
+                        hook,
+                        exit_code(x)
+                    )
+                }
+                Ok(_) => {}
+                Err(e) => warn!("{} hook '{}' failed: {}", var, hook, e),
+            }
+        }
+    }
+
+    /// runs a command line one stage (everything up to a `;` or `&`) at a time, each stage is only
+    /// flattened right before it's run, so that `$?` and options set by earlier stages apply to it
+    fn eval_internal(
+        &mut self,
+        ast: Main,
+        report: &mut dyn FnMut(EvalError),
+    ) -> Result<ExitStatus, EvalError> {
+        let mut exit = ExitStatus::Exited(0);
+        let mut rest = Some(ast.0);
+        while let Some(cmdline) = rest {
+            let (stage, next) = split_stage(cmdline);
+            let mut flattened = {
+                let _timer = profile::start(Phase::Flatten);
+                self.holding_fifos(|x| x.flatten_commandline(stage))?
+            };
+            for hook in &mut self.hooks.before_dispatch {
+                hook(&mut flattened);
+            }
+            let background = last_in_pipeline(&flattened).background;
+            // only kept around if there's a hook to give it to afterwards
+            let ran = (!self.hooks.after_dispatch.is_empty()).then(|| flattened.clone());
+            let dispatched = {
+                let _timer = profile::start(Phase::Dispatch);
+                self.proc_manager.dispatch(flattened)
+            };
+            if let Some(ran) = ran {
+                for hook in &mut self.hooks.after_dispatch {
+                    hook(&ran, &dispatched);
+                }
+            }
+            // a stage that fails to dispatch has a status like any other, see
+            // [`EvalError::exit_status`]
+            let failed = match dispatched {
+                Ok(x) => {
+                    exit = x;
+                    None
+                }
+                Err(e) => {
+                    let e = EvalError::DispatchError { internal: e };
+                    exit = e.exit_status();
+                    Some(e)
+                }
+            };
+            if let Some(code) = builtins::take_exit_request() {
+                exit = code.map_or(self.last_status, |x| ExitStatus::Exited(x.into()));
+                self.last_status = exit;
+                self.exiting = true;
+                break;
+            }
+            self.last_status = exit;
+            // with errexit, a failing stage stops the rest of the line, its status becomes the
+            // status of the whole line (and so what a non-interactive shell exits with)
+            let stop = !background && !exit.success() && options::enabled(ShellOption::Errexit);
+            if let Some(e) = failed {
+                // like other shells, otherwise the rest of the line carries on with its status
+                if stop || next.is_none() {
+                    return Err(e);
+                }
+                report(e);
+            }
+            if stop {
+                break;
+            }
+            rest = next;
+        }
+        Ok(exit)
+    }
+
+    /// flattens every command of a chain, one after another rather than recursing once per `;`, `|`
+    /// or `&`, so chains of thousands of commands don't overflow the stack
     fn flatten_commandline(&mut self, cmdline: CommandLine) -> Result<FlattenedCmdline, EvalError> {
+        let mut flattened = Vec::new();
+        let mut rest = Some((None, cmdline));
+        while let Some((sep, mut cmdline)) = rest {
+            rest = cmdline.next.take().map(|(sep, next)| (Some(sep), *next));
+            flattened.push((sep, self.flatten_single(cmdline)?));
+        }
+        // linked back up from the end
+        let (mut sep, mut chain) = flattened.pop().expect("there's always a first command");
+        while let Some((prev_sep, mut prev)) = flattened.pop() {
+            prev.next = sep.map(|sep| (sep, Box::new(chain)));
+            (sep, chain) = (prev_sep, prev);
+        }
+        Ok(chain)
+    }
+
+    /// flattens a single command, leaving whatever it's chained to alone
+    fn flatten_single(&mut self, cmdline: CommandLine) -> Result<FlattenedCmdline, EvalError> {
+        let command_span = cmdline.command.as_ref().map(Command::span);
+        let typed_redirects = cmdline.redirects.len();
+        let cmdline = expand_alias(cmdline)?;
+        // an alias's redirects come first, and their spans point into its value rather than the
+        // command line
+        let alias_redirects = cmdline.redirects.len() - typed_redirects;
         let envs = cmdline
             .envs
             .into_iter()
             .map(|x| self.flatten_command_env(x))
             .collect::<Result<Vec<_>, EvalError>>()?;
-        let command = self.flatten_command(cmdline.command)?;
+        let command = match cmdline.command {
+            Some(x) => self.flatten_command(x)?,
+            None => OsString::new(),
+        };
         let arguments = cmdline
             .arguments
             .into_iter()
@@ -70,25 +684,25 @@ impl Evaluator {
         let redirects = cmdline
             .redirects
             .into_iter()
-            .map(|x| self.flatten_redirection(x))
+            .enumerate()
+            .map(|(i, x)| self.flatten_redirection(x, i >= alias_redirects))
             .collect::<Result<Vec<_>, EvalError>>()?;
-        let next = match cmdline.next {
-            Some((sep, next)) => Some((sep, Box::new(self.flatten_commandline(*next)?))),
-            None => None,
-        };
 
         Ok(FlattenedCmdline {
             envs,
             command,
+            command_span,
             arguments,
             redirects,
-            next,
+            next: None,
+            background: cmdline.background,
         })
     }
 
     fn flatten_argument(&mut self, arg: Argument) -> Result<OsString, EvalError> {
         match arg {
             Argument::ShellSubstitution(x) => self.flatten_shell_substitution(x),
+            Argument::ProcessSubstitution(x) => self.flatten_process_substitution(x),
             Argument::StringLiteral(x) => self.flatten_string_literal(x),
             Argument::SingleQuoteString(x) => self.flatten_single_string(x),
             Argument::DoubleQuoteString(x) => self.flatten_double_string(x),
@@ -104,12 +718,28 @@ impl Evaluator {
     }
 
     #[inline]
-    fn flatten_redirection(&mut self, red: Redirection) -> Result<(RedirectOp, OsString), EvalError> {
-        Ok((red.op, self.flatten_argument(red.arg)?))
+    fn flatten_redirection(
+        &mut self,
+        red: Redirection,
+        typed: bool,
+    ) -> Result<(RedirectOp, OsString, Option<Span>), EvalError> {
+        let span = typed.then(|| red.arg.as_ref().map_or(red.op.span, Argument::span));
+        match red.arg {
+            Some(arg) => Ok((red.op, self.flatten_argument(arg)?, span)),
+            None => Ok((red.op, OsString::new(), span)),
+        }
     }
 
     #[inline]
-    fn flatten_command_env(&mut self, env: CommandEnv) -> Result<(OsString, OsString), EvalError> {
+    fn flatten_command_env(&mut self, env: CommandEnv) -> Result<(env::Name, OsString), EvalError> {
+        // the grammar only allows identifiers, but these are handed straight to the OS, so an AST
+        // built some other way is checked too
+        if !env::valid_name(&env.name.0) {
+            return Err(EvalError::InvalidVarName {
+                name: env.name.0.to_string_lossy().into_owned(),
+                span: env.name.1,
+            });
+        }
         Ok((
             self.flatten_env_litteral(env.name)?,
             self.flatten_argument(env.value)?,
@@ -117,83 +747,327 @@ impl Evaluator {
     }
 
     #[inline]
-    fn flatten_env_litteral(&self, env: EnvLiteral) -> Result<OsString, EvalError> {
-        Ok(env.0)
+    fn flatten_env_litteral(&self, env: EnvLiteral) -> Result<env::Name, EvalError> {
+        Ok(env::Name::new(&env.0))
     }
 
     fn flatten_double_string(&mut self, string: DoubleQuoteString) -> Result<OsString, EvalError> {
-        Ok(string
-            .0
-            .into_iter()
-            .map(|x| self.flatten_double_string_component(x))
-            .collect::<Result<Vec<_>, EvalError>>()?
-            .into_iter()
-            .fold(OsString::new(), |mut acc, x| {
-                acc.push(&x);
-                acc
-            }))
+        self.concat(string.0, |evaluator, component, out| {
+            match component {
+                DoubleQuoteComponent::Chars(x) => out.push(&*x.0),
+                DoubleQuoteComponent::Escape(x) => out.push(x.0.encode_utf8(&mut [0; 4])),
+                DoubleQuoteComponent::DollarEnv(x) => out.push(evaluator.flatten_dollar_env(x)?),
+                DoubleQuoteComponent::DollarStatus(_) => {
+                    out.push(evaluator.flatten_dollar_status())
+                }
+                DoubleQuoteComponent::DollarShell(x) => {
+                    out.push(evaluator.flatten_dollar_shell(x)?)
+                }
+            }
+            Ok(())
+        })
     }
 
     #[inline]
     fn flatten_single_string(&self, string: SingleQuoteString) -> Result<OsString, EvalError> {
-        Ok(string.0)
+        Ok(string.0.into_owned())
     }
 
     fn flatten_string_literal(&mut self, string: StringLiteral) -> Result<OsString, EvalError> {
-        Ok(string
-            .0
-            .into_iter()
-            .map(|x| self.flatten_string_linteral_component(x))
-            .fold(OsString::new(), |mut acc, x| {
-                acc.push(&x);
-                acc
-            }))
+        // the most common word of all, a plain one, is only copied out of the input
+        if let [StringLiteralComponent::RawChars(_)] = &string.0[..] {
+            let Some(StringLiteralComponent::RawChars(x)) = string.0.into_iter().next() else {
+                unreachable!("checked above");
+            };
+            return Ok(x.0.into_owned());
+        }
+        self.concat(string.0, |evaluator, component, out| {
+            match component {
+                StringLiteralComponent::RawChars(x) => out.push(&*x.0),
+                StringLiteralComponent::DollarEnv(x) => out.push(evaluator.flatten_dollar_env(x)?),
+                StringLiteralComponent::DollarStatus(_) => {
+                    out.push(evaluator.flatten_dollar_status())
+                }
+            }
+            Ok(())
+        })
     }
 
-    fn flatten_double_string_component(
+    /// puts together a word from its parts, each of which `push` appends to the word so far
+    ///
+    /// this is done in [`Evaluator::scratch`], so the word only needs allocating once it's
+    /// finished, at exactly the size it ends up being, rather than every part being allocated
+    /// separately and the word growing as they're added
+    fn concat<T>(
         &mut self,
-        component: DoubleQuoteComponent,
+        parts: Vec<T>,
+        mut push: impl FnMut(&mut Self, T, &mut OsString) -> Result<(), EvalError>,
     ) -> Result<OsString, EvalError> {
-        match component {
-            DoubleQuoteComponent::Chars(x) => Ok(x.0),
-            DoubleQuoteComponent::DollarEnv(x) => Ok(self.flatten_dollar_env(x)),
-            DoubleQuoteComponent::DollarShell(x) => self.flatten_dollar_shell(x),
-        }
+        // taken rather than borrowed, since a part can be a substitution that flattens a whole
+        // other command line
+        let mut word = std::mem::take(&mut self.scratch);
+        word.clear();
+        let result = parts.into_iter().try_for_each(|x| push(self, x, &mut word));
+        let finished = word.as_os_str().to_owned();
+        self.scratch = word;
+        result.map(|()| finished)
     }
 
-    fn flatten_string_linteral_component(
-        &self,
-        component: StringLiteralComponent,
-    ) -> OsString {
-        match component {
-            StringLiteralComponent::RawChars(x) => x.0,
-            StringLiteralComponent::DollarEnv(x) => self.flatten_dollar_env(x),
-        }
+    /// the exit status of the last command, inside a substitution this is the status from before
+    /// it ran, since a substitution's stages are all flattened before any of them run
+    fn flatten_dollar_status(&self) -> OsString {
+        exit_code(self.last_status).to_string().into()
     }
 
     #[inline]
     fn flatten_dollar_shell(&mut self, shell: DollarShell) -> Result<OsString, EvalError> {
-        self.flatten_shell_substitution(ShellSubstitution(shell.0))
+        self.flatten_shell_substitution(ShellSubstitution(shell.0, shell.1))
     }
 
-    fn flatten_dollar_env(&self, env: DollarEnv) -> OsString {
-        env::get(&env.0.0)
+    fn flatten_dollar_env(&self, env: DollarEnv) -> Result<OsString, EvalError> {
+        match env::lookup(&env.0 .0) {
+            Some(x) => Ok(match env.2 {
+                Some(modifier) => modifier.apply(&x.to_string_lossy()).into(),
+                None => x,
+            }),
+            None if options::enabled(ShellOption::Nounset) => Err(EvalError::UnsetVariable {
+                name: env.0 .0.to_string_lossy().into_owned(),
+                location: env.1,
+                span: env.3,
+            }),
+            None => Ok(OsString::new()),
+        }
     }
 
     // TODO: implement this command
-    fn flatten_shell_substitution(&mut self, sub: ShellSubstitution) -> Result<OsString, EvalError> {
-        let flat = self.flatten_commandline(sub.0)?;
-        Ok(self
-            .proc_manager
-            .dispatch_capture(flat)
-            .map_err(|e| EvalError::DispatchError { internal: e })?
-            .1)
+    fn flatten_shell_substitution(
+        &mut self,
+        sub: ShellSubstitution,
+    ) -> Result<OsString, EvalError> {
+        // like a subshell, nothing the command does to variables outlives it
+        env::scoped(ScopeKind::Subshell, || {
+            let flat = self.holding_fifos(|x| x.nested(|x| x.flatten_commandline(sub.0)))?;
+            let result = self
+                .proc_manager
+                .dispatch_capture(flat, options::enabled(ShellOption::CaptureStderr));
+            // like in a subshell, `exit` only ends the substitution
+            builtins::take_exit_request();
+            Ok(result
+                .map_err(|e| EvalError::DispatchError { internal: e })?
+                .1)
+        })
+    }
+
+    /// runs `flatten`, then hands the FIFOs of the process substitutions it started to the process
+    /// manager to be held by the command it flattened, see [`ProcManager::hold`], any made before
+    /// belong to an enclosing command so they're left alone
+    fn holding_fifos<T>(
+        &mut self,
+        flatten: impl FnOnce(&mut Self) -> Result<T, EvalError>,
+    ) -> Result<T, EvalError> {
+        let outer = std::mem::take(&mut self.fifos);
+        let result = flatten(self);
+        let fifos = std::mem::replace(&mut self.fifos, outer);
+        if result.is_ok() {
+            self.proc_manager.hold(fifos);
+        }
+        result
+    }
+
+    /// runs `f` one substitution deeper, failing instead if that's deeper than the limit set by
+    /// `RS_SHELL_SUBST_DEPTH`
+    fn nested<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, EvalError>,
+    ) -> Result<T, EvalError> {
+        let limit = substitution_depth();
+        if self.depth >= limit {
+            return Err(EvalError::RecursionLimit { limit });
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// starts the command of a process substitution, the path of the FIFO its output can be read
+    /// from is substituted in its place
+    fn flatten_process_substitution(
+        &mut self,
+        sub: ProcessSubstitution,
+    ) -> Result<OsString, EvalError> {
+        let fifo = env::scoped(ScopeKind::Subshell, || {
+            let flat = self.holding_fifos(|x| x.nested(|x| x.flatten_commandline(sub.0)))?;
+            self.proc_manager
+                .dispatch_fifo(flat)
+                .map_err(|e| EvalError::DispatchError { internal: e })
+        })?;
+        let path = fifo.path().as_os_str().to_owned();
+        self.fifos.push(fifo);
+        Ok(path)
+    }
+}
+
+/// replaces the command of `cmdline` with what it stands for if it's an alias, see [`alias`]
+///
+/// only the first command is expanded, the rest are expanded as they're flattened, if an alias
+/// stands for another alias that's expanded too, but an alias is never expanded inside itself, so
+/// it can refer to a command of the same name, and the chain can't be longer than the limit set
+/// by `RS_SHELL_ALIAS_DEPTH`
+fn expand_alias(mut cmdline: CommandLine) -> Result<CommandLine, EvalError> {
+    let mut expanded = Vec::new();
+    while let Some(name) = alias_name(&cmdline) {
+        if expanded.contains(&name) {
+            break;
+        }
+        let Some(alias) = alias::get(&name) else {
+            break;
+        };
+        let limit = depth_limit(ALIAS_DEPTH_VAR, DEFAULT_ALIAS_DEPTH);
+        if expanded.len() >= limit {
+            return Err(EvalError::AliasLimit { name, limit });
+        }
+        expanded.push(name);
+        cmdline = CommandLine {
+            envs: [cmdline.envs, alias.envs].concat(),
+            command: alias.command,
+            arguments: [alias.arguments, cmdline.arguments].concat(),
+            redirects: [alias.redirects, cmdline.redirects].concat(),
+            next: cmdline.next,
+            background: cmdline.background,
+            span: cmdline.span,
+        };
+    }
+    Ok(cmdline)
+}
+
+/// the command of `cmdline` if it's a plain word that could be an alias
+fn alias_name(cmdline: &CommandLine) -> Option<String> {
+    let Some(Command::StringLiteral(StringLiteral(parts, _))) = &cmdline.command else {
+        return None;
+    };
+    let [StringLiteralComponent::RawChars(RawChars(name, _))] = parts.as_slice() else {
+        return None;
+    };
+    name.to_str().map(str::to_owned)
+}
+
+/// prints an error that stopped a command line from running, in whatever way suits it, `source`
+/// being the command line, and `line` the line it starts on, so the error can point into it
+pub fn report_error(e: EvalError, source: &str, line: usize) {
+    let _ = write_error(&mut io::stderr(), e, source, line);
+}
+
+/// like [`report_error`], but errors that would be printed are written to `out` instead, the rest
+/// are still logged
+pub fn write_error(
+    out: &mut dyn io::Write,
+    e: EvalError,
+    source: &str,
+    line: usize,
+) -> io::Result<()> {
+    match e {
+        EvalError::InvalidEnvValue { name, value } => {
+            error!(
+                "environment variable '{}' is not valid UTF-8: {}",
+                name, value
+            );
+        }
+        EvalError::DispatchError {
+            internal:
+                e @ (ProcError::CommandNotFound { .. }
+                | ProcError::SubprocessError { .. }
+                | ProcError::FileExists { .. }
+                | ProcError::OutsideSandbox { .. }
+                | ProcError::UnsupportedFd { .. }
+                | ProcError::InvalidRedirect { .. }
+                | ProcError::RedirectError { span: Some(_), .. }
+                | ProcError::Env { .. }),
+        } => {
+            write!(out, "rs-shell: {}", diagnostic::render(&e, source, line))?;
+        }
+        e @ (EvalError::UnsetVariable { .. }
+        | EvalError::RecursionLimit { .. }
+        | EvalError::AliasLimit { .. }
+        | EvalError::InvalidVarName { .. }) => {
+            write!(out, "rs-shell: {}", diagnostic::render(&e, source, line))?;
+        }
+        EvalError::DispatchError { internal } => {
+            error!("error dispatching command:\n{}", internal);
+        }
+    }
+    Ok(())
+}
+
+/// how deeply substitutions may be nested, from `RS_SHELL_SUBST_DEPTH`
+fn substitution_depth() -> usize {
+    depth_limit(SUBSTITUTION_DEPTH_VAR, DEFAULT_SUBSTITUTION_DEPTH)
+}
+
+/// the limit set by the variable `var`, or `default` if it's unset or invalid, 0 meaning there's
+/// no limit
+fn depth_limit(var: &str, default: usize) -> usize {
+    let value = env::get(var);
+    if value.is_empty() {
+        return default;
+    }
+    match value.to_string_lossy().parse() {
+        Ok(0) => usize::MAX,
+        Ok(x) => x,
+        Err(_) => {
+            warn!("ignoring invalid {}: '{}'", var, value.to_string_lossy());
+            default
+        }
+    }
+}
+
+/// splits the first stage, everything up to the first `;` or `&`, off of a command line, a stage
+/// ending in `&` is marked as running in the background
+fn split_stage(mut cmdline: CommandLine) -> (CommandLine, Option<CommandLine>) {
+    let mut last = &mut cmdline;
+    while let Some((Separator::Pipe, _)) = last.next {
+        last = &mut last.next.as_mut().expect("checked above").1;
+    }
+    let rest = match last.next.take() {
+        Some((Separator::Fork, rest)) => {
+            last.background = true;
+            Some(*rest)
+        }
+        Some((_, rest)) => Some(*rest),
+        None => None,
+    };
+    (cmdline, rest)
+}
+
+/// the last command of a pipeline
+fn last_in_pipeline(mut cmd: &FlattenedCmdline) -> &FlattenedCmdline {
+    while let Some((Separator::Pipe, next)) = &cmd.next {
+        cmd = next;
+    }
+    cmd
+}
+
+/// converts an [`ExitStatus`] into the 8-bit code a shell reports for it
+///
+/// this is the number `$?` is set to for `status`, like other shells a process killed by a signal gives
+/// 128 plus the signal's number
+pub fn exit_code(status: ExitStatus) -> u8 {
+    match status {
+        ExitStatus::Exited(x) => x as u8,
+        ExitStatus::Signaled(x) => 128u8.wrapping_add(x),
+        ExitStatus::Other(x) => x as u8,
+        ExitStatus::Undetermined => u8::MAX,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::proc_manager::{
+        recording::{assert_spawns, record, RecordingBackend},
+        JobMode,
+    };
+    use ExitStatus::Exited;
 
     #[test]
     fn basic_ast_flatten() {
@@ -204,14 +1078,448 @@ mod tests {
         let manual_flatten = FlattenedCmdline {
             envs: Vec::new(),
             command: OsString::from("test"),
+            command_span: Some(Span { start: 0, end: 4 }),
             arguments: vec!["0", "1", "2"]
                 .into_iter()
-                .map(|x| OsString::from(x))
+                .map(OsString::from)
                 .collect(),
             redirects: Vec::new(),
             next: None,
+            background: false,
         };
 
         assert_eq!(gen_flatten, manual_flatten);
     }
+
+    #[test]
+    fn empty_words() {
+        let mut evaluator = Evaluator::new();
+        let ast = crate::ast::generate_ast("A= B=\"\" cmd \"\" '' \"$RS_TEST_UNSET\"").unwrap();
+        let flat = evaluator.flatten(ast).unwrap();
+        assert_eq!(
+            flat.envs,
+            [("A".into(), "".into()), ("B".into(), "".into())]
+        );
+        assert_eq!(flat.arguments, ["", "", ""]);
+    }
+
+    #[test]
+    fn double_quote_escapes() {
+        let mut evaluator = Evaluator::new();
+        let ast = crate::ast::generate_ast(r#"echo "\"\$RS_TEST_UNSET\\\t""#).unwrap();
+        let flat = evaluator.flatten(ast).unwrap();
+        assert_eq!(flat.arguments, [r#""$RS_TEST_UNSET\\t"#]);
+    }
+
+    #[test]
+    fn unicode_words() {
+        let mut evaluator = Evaluator::new();
+        let ast = crate::ast::generate_ast("A=ñandú café \"🎉 ${RS_TEST_UNSET}é\" x$?ü").unwrap();
+        let flat = evaluator.flatten(ast).unwrap();
+        assert_eq!(flat.envs, [("A".into(), "ñandú".into())]);
+        assert_eq!(flat.command, "café");
+        assert_eq!(flat.arguments, ["🎉 é", "x0ü"]);
+    }
+
+    #[test]
+    fn aliases() {
+        alias::set("rs_test_ll".to_owned(), "B=2 ls -l".to_owned()).unwrap();
+        let mut evaluator = Evaluator::new();
+        let ast = crate::ast::generate_ast("A=1 rs_test_ll x | 'rs_test_ll' y").unwrap();
+        let flat = evaluator.flatten_commandline(ast.0).unwrap();
+
+        let envs = flat.envs.iter().map(|(k, v)| (k.to_str(), v.to_str()));
+        assert_eq!(
+            envs.collect::<Vec<_>>(),
+            [(Some("A"), Some("1")), (Some("B"), Some("2"))]
+        );
+        assert_eq!(flat.command, "ls");
+        assert_eq!(flat.arguments, ["-l", "x"]);
+        // quoting the name stops it being expanded
+        assert_eq!(flat.next.unwrap().1.command, "rs_test_ll");
+    }
+
+    #[test]
+    fn nested_aliases() {
+        let flatten = |line| {
+            let ast = crate::ast::generate_ast(line).unwrap();
+            Evaluator::new().flatten_commandline(ast.0)
+        };
+        alias::set("rs_test_la".to_owned(), "rs_test_ls -a".to_owned()).unwrap();
+        alias::set("rs_test_ls".to_owned(), "rs_test_ls --color".to_owned()).unwrap();
+        let flat = flatten("rs_test_la x").unwrap();
+        // an alias isn't expanded inside itself, so one can refer to a command of the same name
+        assert_eq!(flat.command, "rs_test_ls");
+        assert_eq!(flat.arguments, ["--color", "-a", "x"]);
+
+        for i in 0..=DEFAULT_ALIAS_DEPTH {
+            let next = format!("rs_test_chain{}", i + 1);
+            alias::set(format!("rs_test_chain{}", i), next).unwrap();
+        }
+        assert!(matches!(
+            flatten("rs_test_chain1"),
+            Ok(x) if x.command == *format!("rs_test_chain{}", DEFAULT_ALIAS_DEPTH + 1)
+        ));
+        assert!(matches!(
+            flatten("rs_test_chain0"),
+            Err(EvalError::AliasLimit {
+                limit: DEFAULT_ALIAS_DEPTH,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn substitution_limit() {
+        let nested = |depth| {
+            let line = format!("{}b{}", "a (".repeat(depth), ")".repeat(depth));
+            record!(&line).1
+        };
+        assert!(nested(DEFAULT_SUBSTITUTION_DEPTH).is_ok());
+        assert!(matches!(
+            nested(DEFAULT_SUBSTITUTION_DEPTH + 1),
+            Err(EvalError::RecursionLimit {
+                limit: DEFAULT_SUBSTITUTION_DEPTH
+            })
+        ));
+    }
+
+    #[test]
+    fn pipelines_are_one_job() {
+        let (backend, status) = record!("a | b x; c", Exited(3) => "", Exited(4) => "");
+        assert_eq!(status.unwrap(), ExitStatus::Exited(4));
+        assert_spawns!(backend, ["a | b x", "c"]);
+    }
+
+    #[test]
+    fn status_between_stages() {
+        let (backend, status) = record!("a; b $? \"x$?\"", Exited(5) => "");
+        assert_eq!(status.unwrap(), ExitStatus::Exited(0));
+        assert_spawns!(backend, ["a", "b 5 x5"]);
+    }
+
+    fn not_found(name: &str) -> ProcError {
+        ProcError::CommandNotFound {
+            name: name.to_owned(),
+            suggestion: None,
+            span: None,
+        }
+    }
+
+    #[test]
+    fn dispatch_error_status() {
+        let backend = RecordingBackend::default()
+            .then(Exited(0), "")
+            .fail(not_found("nosuchcmd"));
+        let mut evaluator = Evaluator::with_backend(backend);
+        let ast = crate::ast::generate_ast("a; nosuchcmd").unwrap();
+        assert!(matches!(
+            evaluator.eval(ast),
+            Err(EvalError::DispatchError {
+                internal: ProcError::CommandNotFound { .. }
+            })
+        ));
+        assert_eq!(evaluator.last_status(), Exited(127));
+    }
+
+    #[test]
+    fn dispatch_errors_carry_on() {
+        let backend = RecordingBackend::default().fail(not_found("nosuchcmd"));
+        let mut evaluator = Evaluator::with_backend(backend.clone());
+        let line = "nosuchcmd; echo $?; readonly RS_TEST_CARRY_ON=1; RS_TEST_CARRY_ON=2; echo $?";
+        let ast = crate::ast::generate_ast(line).unwrap();
+        let mut reported = Vec::new();
+        let status = evaluator.eval_reporting(ast, &mut |e| reported.push(e));
+        assert_eq!(status.unwrap(), Exited(0));
+        assert_spawns!(backend, ["nosuchcmd", "echo 127", "echo 1"]);
+        assert!(matches!(
+            reported.as_slice(),
+            [
+                EvalError::DispatchError {
+                    internal: ProcError::CommandNotFound { .. }
+                },
+                EvalError::DispatchError {
+                    internal: ProcError::Env { .. }
+                },
+            ]
+        ));
+
+        // unless errexit is set
+        options::with(ShellOption::Errexit, true, || {
+            let backend = RecordingBackend::default().fail(not_found("nosuchcmd"));
+            let mut evaluator = Evaluator::with_backend(backend.clone());
+            let ast = crate::ast::generate_ast("nosuchcmd; echo $?").unwrap();
+            assert!(evaluator.eval_reporting(ast, &mut |_| panic!()).is_err());
+            assert_eq!(evaluator.last_status(), Exited(127));
+            assert_spawns!(backend, ["nosuchcmd"]);
+        });
+    }
+
+    #[test]
+    fn errexit() {
+        options::with(ShellOption::Errexit, true, || {
+            // the first failure stops the line, a job in the background doesn't count, and the
+            // failure's status is the line's
+            let (backend, status) = record!(
+                "a; b & c; d; e",
+                Exited(0) => "",
+                Exited(1) => "",
+                Exited(0) => "",
+                Exited(3) => "",
+            );
+            assert_eq!(status.unwrap(), Exited(3));
+            assert_spawns!(backend, ["a", "b &", "c", "d"]);
+
+            // like other shells without `inherit_errexit`, a substitution runs to the end
+            let (backend, status) = record!("greet \"$(a; b)\"", Exited(1) => "", Exited(0) => "x");
+            assert!(status.unwrap().success());
+            assert_spawns!(backend, ["a", "b", "greet x"]);
+        });
+    }
+
+    #[test]
+    fn prompt_commands() {
+        let backend = RecordingBackend::default()
+            .then(Exited(0), "user@host ❯ \n")
+            .then(Exited(1), "");
+        let mut evaluator = Evaluator::with_backend(backend.clone());
+        evaluator.set_last_status(Exited(3));
+        let line = "starship prompt --status=$RS_SHELL_STATUS --cmd-duration=$RS_SHELL_DURATION_MS";
+        assert_eq!(
+            evaluator.run_prompt_command(line).as_deref(),
+            Some("user@host ❯ ")
+        );
+        // a prompt command that fails falls back to the usual prompt
+        assert_eq!(evaluator.run_prompt_command(line), None);
+        assert_spawns!(
+            backend,
+            [
+                "starship prompt --status=3 --cmd-duration=0",
+                "starship prompt --status=3 --cmd-duration=0"
+            ]
+        );
+        assert_eq!(evaluator.last_status(), Exited(3));
+        assert_eq!(env::lookup("RS_SHELL_STATUS"), None);
+    }
+
+    #[test]
+    fn hook_functions() {
+        let backend = RecordingBackend::default().then(Exited(1), "");
+        let mut evaluator = Evaluator::with_backend(backend.clone());
+        evaluator.set_last_status(Exited(3));
+        alias::set(
+            "rs-test-hook".to_owned(),
+            "export RS_TEST_HOOK=ran".to_owned(),
+        )
+        .unwrap();
+        // not `precmd_functions` and `preexec_functions`, which other tests' hooks would run
+        env::set("rs_test_precmd".into(), " rs-test-hook  'a;b' ".into()).unwrap();
+        env::set("rs_test_preexec".into(), "log-cmd".into()).unwrap();
+
+        // an alias is run by the shell itself, so it can change its state
+        evaluator.run_hook_functions("rs_test_precmd", Vec::new());
+        assert_eq!(env::get("RS_TEST_HOOK"), "ran");
+        evaluator.run_hook_functions("rs_test_preexec", vec!["ls -l".into()]);
+        assert_spawns!(backend, ["'a;b'", "log-cmd 'ls -l'"]);
+        assert_eq!(evaluator.last_status(), Exited(3));
+    }
+
+    #[test]
+    fn status_of_failures() {
+        let mut evaluator = Evaluator::with_backend(RecordingBackend::default());
+        let not_found = EvalError::DispatchError {
+            internal: ProcError::CommandNotFound {
+                name: "a".to_owned(),
+                suggestion: None,
+                span: None,
+            },
+        };
+        assert_eq!(not_found.exit_status(), Exited(127));
+        assert_eq!(exit_code(ExitStatus::Signaled(15)), 143);
+
+        // a failure that never reaches the evaluator is still what `$?` gives
+        evaluator.set_last_status(Exited(2));
+        assert_eq!(evaluator.flatten_dollar_status(), "2");
+    }
+
+    #[test]
+    fn recorded_jobs() {
+        let (backend, _) = record!("A='x y' a 2> f < g | b >&- & c -n >> 'h i'; d");
+        assert_spawns!(backend, ["A='x y' a 2>f <g | b >&- &", "c -n >>'h i'", "d"]);
+        let jobs = backend.jobs.lock();
+        assert_eq!(jobs[0].mode, JobMode::Background);
+        assert_eq!(jobs[0].commands[0].arguments, Vec::<OsString>::new());
+    }
+
+    #[test]
+    fn long_chains() {
+        let line = vec!["a $x"; 10000].join(" | ") + "; b";
+        let ast = || crate::ast::generate_ast(&line).unwrap();
+        let mut flattened = Evaluator::new().flatten(ast()).unwrap();
+        let mut count = 1;
+        while let Some((_, next)) = flattened.next {
+            flattened = *next;
+            count += 1;
+        }
+        assert_eq!((count, flattened.command.to_str()), (10001, Some("b")));
+
+        let backend = RecordingBackend::default();
+        Evaluator::with_backend(backend.clone())
+            .eval(ast())
+            .unwrap();
+        let jobs = backend.jobs.lock();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].commands.len(), 10000);
+    }
+
+    #[test]
+    fn substitution_output() {
+        let backend = RecordingBackend::default().then(ExitStatus::Exited(0), "world");
+        let mut evaluator = Evaluator::with_backend(backend.clone());
+        let ast = crate::ast::generate_ast("greet \"hello $(name)\"").unwrap();
+        evaluator.eval(ast).unwrap();
+
+        let jobs = backend.jobs.lock();
+        assert_eq!(jobs[0].commands[0].command, "name");
+        assert_eq!(
+            jobs[1].commands[0].arguments,
+            vec![OsString::from("hello world")]
+        );
+    }
+
+    #[test]
+    fn process_substitution_fifos() {
+        let backend = RecordingBackend::default();
+        let mut evaluator = Evaluator::with_backend(backend.clone());
+        let ast = crate::ast::generate_ast("diff <(a) <(b x)").unwrap();
+        evaluator.eval(ast).unwrap();
+
+        let jobs = backend.jobs.lock();
+        assert_eq!(jobs.len(), 3);
+        assert_eq!(jobs[1].commands[0].arguments, vec![OsString::from("x")]);
+        // the FIFOs only exist for as long as the command using them, along with the directories
+        // they're made in
+        for path in &jobs[2].commands[0].arguments {
+            let path = std::path::Path::new(path);
+            assert!(!path.exists());
+            assert!(!path.parent().unwrap().exists());
+        }
+    }
+
+    #[test]
+    fn background_process_substitution_fifos() {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let backend = RecordingBackend::default();
+        let mut evaluator = Evaluator::with_backend(backend.clone());
+        let ast = crate::ast::generate_ast("cat <(a) & b <(c)").unwrap();
+        evaluator.eval(ast).unwrap();
+
+        let mut jobs = backend.jobs.lock();
+        assert_eq!(jobs.len(), 4);
+        // a job left running in the background still has its FIFO until it's been reaped, which
+        // is in a directory only the user can get into
+        let background = std::path::PathBuf::from(&jobs[1].commands[0].arguments[0]);
+        assert_eq!(jobs[1].fifos[0].path(), background);
+        assert!(std::fs::metadata(&background)
+            .unwrap()
+            .file_type()
+            .is_fifo());
+        let dir = std::fs::metadata(background.parent().unwrap()).unwrap();
+        assert_eq!(dir.permissions().mode() & 0o777, 0o700);
+        assert!(!std::path::Path::new(&jobs[3].commands[0].arguments[0]).exists());
+
+        jobs.remove(1);
+        assert!(!background.exists());
+    }
+
+    #[test]
+    fn hooks() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let backend = RecordingBackend::default()
+            .then(ExitStatus::Exited(0), "")
+            .then(ExitStatus::Exited(0), "")
+            .then(ExitStatus::Exited(2), "");
+        let mut evaluator = Evaluator::with_backend(backend.clone());
+        let parsed = Rc::new(RefCell::new(Vec::new()));
+        let ran = Rc::new(RefCell::new(Vec::new()));
+        evaluator.on_parse({
+            let parsed = parsed.clone();
+            move |ast| parsed.borrow_mut().push(ast.to_string())
+        });
+        evaluator.before_dispatch(|cmd| {
+            if cmd.command == "rm" {
+                cmd.command = "trash".into();
+            }
+        });
+        evaluator.after_dispatch({
+            let ran = ran.clone();
+            move |cmd, result| {
+                let status = *result.as_ref().unwrap();
+                ran.borrow_mut().push((cmd.command.clone(), status));
+            }
+        });
+        let ast = crate::ast::generate_ast("rm -rf x; b (c)").unwrap();
+        assert_eq!(evaluator.eval(ast).unwrap(), ExitStatus::Exited(2));
+
+        assert_eq!(*parsed.borrow(), ["rm -rf x; b (c)"]);
+        // the substitution isn't passed to the hooks, but still runs
+        assert_eq!(
+            *ran.borrow(),
+            [
+                ("trash".into(), ExitStatus::Exited(0)),
+                ("b".into(), ExitStatus::Exited(2))
+            ]
+        );
+        let jobs = backend.jobs.lock();
+        assert_eq!(jobs[0].commands[0].command, "trash");
+        assert_eq!(jobs.len(), 3);
+    }
+
+    #[test]
+    fn built_commands() {
+        let cmd = CommandBuilder::new("a")
+            .env("A", "1")
+            .arg("x")
+            .redirect(RedirectFd::Stderr, RedirectType::Out, "f")
+            .pipe(CommandBuilder::new("b").args(["y", "z"]))
+            .then(CommandBuilder::new("c"))
+            .background()
+            .build();
+        let expected = crate::ast::generate_ast("A=1 a x 2> f | b y z; c &").unwrap();
+        let mut flat = Evaluator::new().flatten_commandline(expected.0).unwrap();
+        // the parsed one knows where everything was typed
+        fn forget_spans(cmd: &mut FlattenedCmdline) {
+            cmd.command_span = None;
+            for (op, _, span) in &mut cmd.redirects {
+                op.span = Span::default();
+                *span = None;
+            }
+            if let Some((_, next)) = &mut cmd.next {
+                forget_spans(next);
+            }
+        }
+        forget_spans(&mut flat);
+        assert_eq!(cmd, flat);
+
+        let backend = RecordingBackend::default();
+        let mut proc_manager = ProcManager::with_backend(backend.clone());
+        proc_manager
+            .dispatch(
+                CommandBuilder::new("d")
+                    .pipe(CommandBuilder::new("e"))
+                    .into(),
+            )
+            .unwrap();
+        let jobs = backend.jobs.lock();
+        assert_eq!(
+            jobs[0]
+                .commands
+                .iter()
+                .map(|x| &x.command)
+                .collect::<Vec<_>>(),
+            ["d", "e"]
+        );
+    }
 }
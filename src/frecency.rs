@@ -0,0 +1,220 @@
+//! directories ranked by frecency, for the `z` builtin, every directory `cd` changes to is recorded
+//! along with how often and how recently it's been visited
+//!
+//! they're kept in the same `path|rank|time` format z uses, one directory per line, so an existing
+//! `~/.z` can be carried on with by pointing `$RS_SHELL_Z_DATA` at it
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::env;
+
+/// where visits are recorded, rather than `rsshell/z` in the user's data directory
+const DATA_VAR: &str = "RS_SHELL_Z_DATA";
+
+/// once the ranks add up to more than this they're all aged, so that directories that haven't been
+/// visited in a while eventually drop out, like z does
+const MAX_TOTAL_RANK: f64 = 9000.0;
+
+/// a directory that's been visited
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// roughly how many times it's been visited
+    pub rank: f64,
+    /// when it was last visited, in seconds since the unix epoch
+    pub time: u64,
+}
+
+impl Entry {
+    /// how good a match this is at `now`, the rank weighted by how recently it was visited
+    pub fn frecency(&self, now: u64) -> f64 {
+        match now.saturating_sub(self.time) {
+            0..=3599 => self.rank * 4.0,
+            3600..=86_399 => self.rank * 2.0,
+            86_400..=604_799 => self.rank / 2.0,
+            _ => self.rank / 4.0,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn data_path() -> Option<PathBuf> {
+    let path = env::get(DATA_VAR);
+    if !path.is_empty() {
+        return Some(path.into());
+    }
+    Some(dirs_next::data_dir()?.join("rsshell").join("z"))
+}
+
+/// every entry in `input`, lines that aren't `path|rank|time` are skipped
+fn parse(input: &str) -> Vec<Entry> {
+    input
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.rsplitn(3, '|');
+            let time = fields.next()?.parse().ok()?;
+            let rank = fields.next()?.parse().ok()?;
+            let path = fields.next().filter(|x| !x.is_empty())?;
+            Some(Entry {
+                path: path.into(),
+                rank,
+                time,
+            })
+        })
+        .collect()
+}
+
+fn render(entries: &[Entry]) -> String {
+    entries
+        .iter()
+        .map(|x| format!("{}|{}|{}\n", x.path.display(), x.rank, x.time))
+        .collect()
+}
+
+/// adds a visit to `dir` at `now`, ageing every entry if the ranks have grown too large
+fn visit(entries: &mut Vec<Entry>, dir: &Path, now: u64) {
+    match entries.iter_mut().find(|x| x.path == dir) {
+        Some(entry) => {
+            entry.rank += 1.0;
+            entry.time = now;
+        }
+        None => entries.push(Entry {
+            path: dir.to_owned(),
+            rank: 1.0,
+            time: now,
+        }),
+    }
+    if entries.iter().map(|x| x.rank).sum::<f64>() > MAX_TOTAL_RANK {
+        for entry in entries.iter_mut() {
+            entry.rank *= 0.99;
+        }
+        entries.retain(|x| x.rank >= 1.0);
+    }
+}
+
+/// every recorded directory, an empty list if nothing's been recorded yet
+pub fn entries() -> io::Result<Vec<Entry>> {
+    let Some(path) = data_path() else {
+        return Ok(Vec::new());
+    };
+    match fs::read_to_string(path) {
+        Ok(x) => Ok(parse(&x)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// records a visit to `dir`, which should be absolute, the home directory isn't recorded since
+/// it's only ever a `cd` away anyway
+///
+/// the file is replaced rather than written in place, so another shell reading it at the same time
+/// never sees it half written
+pub fn record(dir: &Path) -> io::Result<()> {
+    let home = env::get("HOME");
+    let Some(path) = data_path().filter(|_| home.is_empty() || dir != Path::new(&home)) else {
+        return Ok(());
+    };
+    let mut entries = entries()?;
+    visit(&mut entries, dir, now());
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temp = path.clone().into_os_string();
+    temp.push(format!(".{}", process::id()));
+    fs::write(&temp, render(&entries))?;
+    fs::rename(&temp, &path)
+}
+
+/// the directories that match every one of `patterns` in order, best first, along with their
+/// frecency, directories that no longer exist are left out
+///
+/// like z, matching is case sensitive unless that finds nothing
+pub fn matches(entries: &[Entry], patterns: &[String], now: u64) -> Vec<(f64, PathBuf)> {
+    let find = |fold: bool| {
+        let patterns = patterns
+            .iter()
+            .map(|x| if fold { x.to_lowercase() } else { x.clone() })
+            .collect::<Vec<_>>();
+        let mut found = entries
+            .iter()
+            .filter(|entry| {
+                let path = entry.path.to_string_lossy();
+                let path = if fold {
+                    path.to_lowercase()
+                } else {
+                    path.into_owned()
+                };
+                in_order(&path, &patterns)
+            })
+            .filter(|x| x.path.is_dir())
+            .map(|x| (x.frecency(now), x.path.clone()))
+            .collect::<Vec<_>>();
+        found.sort_by(|a, b| b.0.total_cmp(&a.0));
+        found
+    };
+    match find(false) {
+        x if x.is_empty() => find(true),
+        x => x,
+    }
+}
+
+/// [`matches`] for what's been recorded, as of now
+pub fn search(patterns: &[String]) -> io::Result<Vec<(f64, PathBuf)>> {
+    Ok(matches(&entries()?, patterns, now()))
+}
+
+/// whether every pattern appears in `path`, each after the one before it
+fn in_order(path: &str, patterns: &[String]) -> bool {
+    let mut rest = path;
+    for pattern in patterns {
+        match rest.find(pattern.as_str()) {
+            Some(i) => rest = &rest[i + pattern.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranking() {
+        let dir = std::env::temp_dir().join(format!("rs-frecency-{}", process::id()));
+        let (old, new) = (dir.join("Projects/shell"), dir.join("src/shell"));
+        fs::create_dir_all(&old).unwrap();
+        fs::create_dir_all(&new).unwrap();
+
+        let now = 1_000_000;
+        let mut entries = parse("/no/such/dir/shell|50|999999\nnot an entry\n");
+        for _ in 0..4 {
+            visit(&mut entries, &old, now - 700_000);
+        }
+        visit(&mut entries, &new, now);
+        assert_eq!(parse(&render(&entries)), entries);
+
+        let patterns = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        // visited less often but more recently, and the directory that's gone is left out
+        let found = matches(&entries, &patterns(&["shell"]), now);
+        assert_eq!(found, [(4.0, new.clone()), (1.0, old.clone())]);
+        // each pattern comes after the last, and case only matters if it can
+        let found = matches(&entries, &patterns(&["projects", "sh"]), now);
+        assert_eq!(found, [(1.0, old)]);
+        assert!(matches(&entries, &patterns(&["shell", "src"]), now).is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,31 +1,522 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
 use color_eyre::Result;
+use log::warn;
+use nix::sys::termios::{self, SetArg};
+use parking_lot::Mutex;
 pub use rustyline::error::ReadlineError;
-use rustyline::{history::DefaultHistory, Editor};
+use rustyline::{
+    completion::{Completer, Pair},
+    highlight::Highlighter,
+    hint::Hinter,
+    history::{DefaultHistory, History},
+    validate::Validator,
+    ColorMode, CompletionType, Config, Context, Editor, EventHandler, ExternalPrinter, Helper,
+    KeyEvent,
+};
+
+use cs128h_project::{
+    env,
+    lexer::Incremental,
+    repl::{Input, LineSource},
+    shutdown,
+};
+
+use crate::{
+    completion::{self, LsColors, Specs},
+    config, osc,
+    picker::{Picker, Source},
+    prompt::{self, Prompt},
+    theme,
+};
 
 #[non_exhaustive]
 #[derive(Debug)]
 pub struct Frontend {
-    editor: Editor<(), DefaultHistory>,
+    editor: Editor<ShellHelper, DefaultHistory>,
+    /// whether rustyline is reading a line, and so whether the prompt can be repainted
+    reading: Arc<AtomicBool>,
+    /// the size `HISTSIZE` was changed to, which is applied before the next line is read
+    history_size: Arc<Mutex<Option<usize>>>,
+    /// where the history is saved, see [`history_file`]
+    history_file: Option<PathBuf>,
+    /// lines read since the history was last saved, which are appended to the history file if the
+    /// shell panics
+    unsaved: Arc<Mutex<Vec<String>>>,
+    /// how long to wait for a line before exiting, from `TMOUT`
+    timeout: Arc<Mutex<Option<Duration>>>,
+    /// every line in the history, for the history picker, see [`crate::picker`]
+    history_lines: Arc<Mutex<Vec<String>>>,
 }
 
 impl Frontend {
     pub fn new() -> Result<Self> {
-        let editor = Editor::new()?;
-        Ok(Self { editor })
+        let plain = config::get().plain;
+        let mut config = Config::builder().completion_type(CompletionType::List);
+        // nothing's highlighted, so the prompt's never repainted and completions aren't colored
+        if plain {
+            config = config
+                .color_mode(ColorMode::Disabled)
+                .bracketed_paste(false);
+        }
+        if let Some(size) = history_size_or_default(env::lookup("HISTSIZE").as_deref()) {
+            config = config.max_history_size(size)?;
+        }
+        let mut editor = Editor::with_config(config.build())?;
+        // bound first so that the config file can bind these keys to something else
+        let history_lines = Arc::new(Mutex::new(Vec::new()));
+        let pickers = [
+            (KeyEvent::ctrl('T'), Source::Files),
+            (KeyEvent::alt('r'), Source::History),
+        ];
+        // they take over the whole screen, which a screen reader can't make much sense of
+        for (key, source) in pickers.into_iter().filter(|_| !plain) {
+            let picker = Picker::new(source, history_lines.clone());
+            editor.bind_sequence(key, EventHandler::Conditional(Box::new(picker)));
+        }
+        for (key, cmd) in &config::get().keybindings {
+            editor.bind_sequence(*key, cmd.clone());
+        }
+        let helper = ShellHelper::new();
+
+        let history_size = Arc::new(Mutex::new(None));
+        let pending = history_size.clone();
+        env::on_change("HISTSIZE", move |x| {
+            if let Some(size) = history_size_or_default(x) {
+                *pending.lock() = Some(size);
+            }
+        });
+        let timeout = Arc::new(Mutex::new(idle_timeout(env::lookup("TMOUT").as_deref())));
+        let pending = timeout.clone();
+        env::on_change("TMOUT", move |x| *pending.lock() = idle_timeout(x));
+        // repaints the prompt if it's showing, otherwise it's picked up by the next one anyway
+        let prompt = helper.prompt.clone();
+        env::on_change("PS1", move |_| prompt.invalidate());
+        let reading = Arc::new(AtomicBool::new(false));
+        // there's nothing to repaint without a terminal, or in plain mode
+        if let Some(mut printer) = (!plain)
+            .then(|| editor.create_external_printer().ok())
+            .flatten()
+        {
+            let reading = reading.clone();
+            helper.prompt.on_update(move || {
+                // outside of `readline` it'd be written straight to the terminal
+                if reading.load(Ordering::Acquire) {
+                    let _ = printer.print(REPAINT.to_owned());
+                }
+            });
+        }
+
+        editor.set_helper(Some(helper));
+
+        let history_file = history_file();
+        if let Some(path) = &history_file {
+            match fs::read_to_string(path) {
+                Ok(history) => {
+                    for line in history.lines() {
+                        editor.add_history_entry(line)?;
+                    }
+                    history_lines
+                        .lock()
+                        .extend(history.lines().map(str::to_owned));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => warn!("unable to read history from '{}': {}", path.display(), e),
+            }
+        }
+        let unsaved = Arc::new(Mutex::new(Vec::new()));
+        if let Some(path) = history_file.clone() {
+            let unsaved = unsaved.clone();
+            shutdown::on_shutdown(move || {
+                let lines = std::mem::take(&mut *unsaved.lock());
+                if !lines.is_empty() {
+                    if let Err(e) = append_history(&path, &lines) {
+                        eprintln!("rs-shell: unable to save history: {}", e);
+                    }
+                }
+            });
+        }
+
+        Ok(Self {
+            editor,
+            reading,
+            history_size,
+            history_file,
+            unsaved,
+            timeout,
+            history_lines,
+        })
+    }
+
+    /// writes the whole history out to the history file, if there is one
+    pub fn save_history(&mut self) {
+        let Some(path) = &self.history_file else {
+            return;
+        };
+        let lines = self.editor.history().iter().map(String::as_str);
+        match write_history(path, lines) {
+            Ok(()) => self.unsaved.lock().clear(),
+            Err(e) => warn!("unable to save history to '{}': {}", path.display(), e),
+        }
+    }
+
+    /// reads a line with rustyline, unless `TMOUT` runs out first, in which case the shell exits
+    /// with `status`
+    fn read_line(&mut self, prompt: &str, status: u8) -> Result<String, ReadlineError> {
+        let timeout = *self.timeout.lock();
+        let _watchdog = timeout.map(|x| Watchdog::start(x, status));
+        self.reading.store(true, Ordering::Release);
+        let line = self.editor.readline(prompt);
+        self.reading.store(false, Ordering::Release);
+        line
     }
 
-    pub fn readline(&mut self) -> Result<String, ReadlineError> {
-        let mut value = self.editor.readline("rs-shell $ ")?;
+    /// renders the prompt and reads a single line after it
+    fn read_command_line(&mut self, ctx: &prompt::Context) -> Result<String, ReadlineError> {
+        if let Some(size) = self.history_size.lock().take() {
+            self.editor.history_mut().set_max_len(size)?;
+        }
+        let template = template();
+        let helper = self
+            .editor
+            .helper_mut()
+            .expect("Frontend editor must always have a helper");
+        // a prompt from `$RS_SHELL_PROMPT_CMD` is shown as it is, it does its own colors
+        let rendered = match &ctx.prompt {
+            Some(x) => x.clone(),
+            None => helper.prompt.render(&template, ctx),
+        };
+        helper.generation = helper.prompt.generation();
+        helper.ctx.clone_from(ctx);
+        helper.rendered.clone_from(&rendered);
+
+        if let Ok(cwd) = std::env::current_dir() {
+            osc::emit(&osc::cwd(&cwd));
+        }
+        self.read_line(&rendered, ctx.status)
+    }
 
-        if value.is_empty() {
-            return self.readline();
+    /// reads a command, skipping over blank lines and joining lines that end in `\` with the next
+    pub fn readline(&mut self, ctx: &prompt::Context) -> Result<String, ReadlineError> {
+        let mut value = String::new();
+        while value.is_empty() {
+            value = self.read_command_line(ctx)?;
         }
 
-        while value.chars().last().unwrap() == '\\' {
+        while value.ends_with('\\') {
             value.pop();
-            value.push_str(&self.editor.readline(">> ")?);
+            value.push_str(&self.read_line(">> ", ctx.status)?);
         }
 
+        if self.editor.add_history_entry(value.as_str())? {
+            self.unsaved.lock().push(value.clone());
+            self.history_lines.lock().push(value.clone());
+        }
         Ok(value)
     }
 }
+
+impl LineSource for Frontend {
+    fn read_line(&mut self, ctx: &prompt::Context) -> io::Result<Input> {
+        match self.readline(ctx) {
+            Ok(x) => Ok(Input::Line(x)),
+            Err(ReadlineError::Eof) => Ok(Input::Eof),
+            Err(ReadlineError::Interrupted) => Ok(Input::Interrupted),
+            Err(ReadlineError::Io(e)) => Err(e),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    fn command_started(&mut self) {
+        osc::emit(osc::OUTPUT_START);
+    }
+
+    fn command_finished(&mut self, status: u8) {
+        osc::emit(&osc::command_finished(status));
+    }
+
+    fn long_command_finished(&mut self, line: &str, status: u8, duration: Duration) {
+        let body = format!(
+            "{} (status {}, took {})",
+            line,
+            status,
+            prompt::format_duration(duration)
+        );
+        osc::emit(&osc::notify("rs-shell: command finished", &body));
+    }
+
+    fn finish(&mut self) {
+        self.save_history();
+    }
+}
+
+/// printed through rustyline's external printer to have it repaint the prompt, which it does after
+/// clearing the prompt and line and printing this, this moves up a row and back down so that
+/// nothing's left behind
+const REPAINT: &str = "\x1b[A\n";
+
+/// exits the shell unless it's dropped before its timeout runs out, there's no way to interrupt
+/// rustyline while it's waiting for input, so the exit has to happen from another thread
+struct Watchdog {
+    /// dropped along with the watchdog, which is what tells the thread to stop waiting
+    _cancel: mpsc::Sender<()>,
+}
+
+impl Watchdog {
+    fn start(timeout: Duration, status: u8) -> Self {
+        let (cancel, cancelled) = mpsc::channel();
+        // the terminal's modes from before rustyline switches it to raw mode, to put back on exit
+        let modes = termios::tcgetattr(io::stdin()).ok();
+        thread::spawn(move || {
+            if cancelled.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
+            if let Some(modes) = modes {
+                let _ = termios::tcsetattr(io::stdin(), SetArg::TCSANOW, &modes);
+            }
+            eprintln!("\ntimed out waiting for input: auto-logout");
+            shutdown::run();
+            std::process::exit(status.into());
+        });
+        Self { _cancel: cancel }
+    }
+}
+
+/// how long `TMOUT` says to wait for input, `None` if it's unset, zero, or invalid, in which case
+/// there's no limit
+fn idle_timeout(value: Option<&OsStr>) -> Option<Duration> {
+    let value = value?.to_string_lossy();
+    match value.trim().parse::<u64>() {
+        Ok(0) => None,
+        Ok(x) => Some(Duration::from_secs(x)),
+        Err(_) => {
+            warn!("ignoring invalid TMOUT: '{}'", value);
+            None
+        }
+    }
+}
+
+/// the file history is kept in between sessions, `$HISTFILE`, or the one from the config file, or
+/// `~/.rs_shell_history` if neither is set, setting `HISTFILE` to nothing turns off saving the
+/// history
+fn history_file() -> Option<PathBuf> {
+    match env::lookup("HISTFILE") {
+        Some(x) if x.is_empty() => None,
+        Some(x) => Some(x.into()),
+        None if config::get().history_file.is_some() => config::get().history_file.clone(),
+        None => {
+            let home = env::lookup("HOME").filter(|x| !x.is_empty())?;
+            Some(PathBuf::from(home).join(".rs_shell_history"))
+        }
+    }
+}
+
+/// replaces the contents of the history file with `lines`, one per line
+fn write_history<'a>(path: &Path, lines: impl Iterator<Item = &'a str>) -> io::Result<()> {
+    let mut history = String::new();
+    for line in lines {
+        history.push_str(line);
+        history.push('\n');
+    }
+    fs::write(path, history)
+}
+
+/// adds `lines` to the end of the history file
+fn append_history(path: &Path, lines: &[String]) -> io::Result<()> {
+    let mut file = File::options().create(true).append(true).open(path)?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// the prompt template from `PS1`, or the config file if that isn't set
+fn template() -> String {
+    match env::get("PS1").to_string_lossy() {
+        x if x.is_empty() => config::get()
+            .prompt
+            .clone()
+            .unwrap_or_else(|| prompt::DEFAULT_TEMPLATE.to_owned()),
+        x => x.into_owned(),
+    }
+}
+
+/// how many lines of history to keep according to `HISTSIZE`, `None` if it's unset or invalid, in
+/// which case rustyline's default is used
+fn history_size(value: Option<&OsStr>) -> Option<usize> {
+    let value = value?.to_string_lossy();
+    match value.trim().parse::<usize>() {
+        Ok(x) => Some(x),
+        Err(_) => {
+            warn!("ignoring invalid HISTSIZE: '{}'", value);
+            None
+        }
+    }
+}
+
+/// like [`history_size`], but an unset `HISTSIZE` goes back to the size from the config file, or
+/// rustyline's default
+fn history_size_or_default(value: Option<&OsStr>) -> Option<usize> {
+    match value {
+        Some(_) => history_size(value),
+        None => Some(
+            config::get()
+                .history_size
+                .unwrap_or_else(|| Config::default().max_history_size()),
+        ),
+    }
+}
+
+/// rustyline helper that repaints the prompt when async prompt segments finish computing
+#[derive(Debug)]
+struct ShellHelper {
+    prompt: Prompt,
+    /// the context the current prompt was rendered with
+    ctx: prompt::Context,
+    /// the prompt string that was handed to rustyline
+    rendered: String,
+    /// [`Prompt::generation`] at the time the current prompt was rendered
+    generation: u64,
+    /// `LS_COLORS` styles of the candidates from the most recent completion, keyed by their
+    /// display string since that's all rustyline hands back when highlighting them
+    candidate_styles: RefCell<HashMap<String, String>>,
+    /// completion specs for external commands, loaded as they're needed
+    specs: RefCell<Specs>,
+    /// the tokens of the line being edited, for highlighting it
+    lexer: RefCell<Incremental>,
+}
+
+impl ShellHelper {
+    fn new() -> Self {
+        Self {
+            prompt: Prompt::new(),
+            ctx: prompt::Context::default(),
+            rendered: String::new(),
+            generation: 0,
+            candidate_styles: RefCell::new(HashMap::new()),
+            specs: RefCell::new(Specs::new(
+                crate::config_dir().map(|x| x.join("completions")),
+            )),
+            lexer: RefCell::new(Incremental::default()),
+        }
+    }
+}
+
+impl Highlighter for ShellHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        let theme = &config::get().theme;
+        if !theme.highlights_syntax() {
+            return Cow::Borrowed(line);
+        }
+        let mut lexer = self.lexer.borrow_mut();
+        let mut highlighted = String::with_capacity(line.len());
+        let mut end = 0;
+        for token in lexer.update(line) {
+            let style = theme.syntax(token.kind);
+            if style.is_empty() {
+                continue;
+            }
+            highlighted.push_str(&line[end..token.span.start]);
+            highlighted.push_str(&theme::paint(style, token.span.slice(line)));
+            end = token.span.end;
+        }
+        highlighted.push_str(&line[end..]);
+        Cow::Owned(highlighted)
+    }
+
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
+        &'s self,
+        prompt: &'p str,
+        _default: bool,
+    ) -> Cow<'b, str> {
+        if prompt != self.rendered {
+            return Cow::Borrowed(prompt);
+        }
+
+        let mut shown = Cow::Borrowed(prompt);
+        if self.ctx.prompt.is_none() && self.prompt.generation() != self.generation {
+            // rustyline positions the cursor based on the width of the prompt it was originally
+            // given, so the repainted prompt is fitted to that, the next prompt shows it in full
+            let fresh = self.prompt.rerender(&template(), &self.ctx);
+            shown = Cow::Owned(prompt::fit(&fresh, prompt));
+        }
+
+        // the semantic prompt marks are added here rather than in the prompt itself because
+        // rustyline would otherwise count the body of the OSC sequences towards the prompt's width
+        Cow::Owned(format!("{}{}{}", osc::PROMPT_START, shown, osc::PROMPT_END))
+    }
+
+    fn highlight_candidate<'c>(
+        &self,
+        candidate: &'c str,
+        _completion: CompletionType,
+    ) -> Cow<'c, str> {
+        match self.candidate_styles.borrow().get(candidate) {
+            Some(style) => Cow::Owned(theme::paint(style, candidate)),
+            None => Cow::Borrowed(candidate),
+        }
+    }
+
+    // rustyline only redraws the line through `highlight` when this says to
+    fn highlight_char(&self, _line: &str, _pos: usize, _forced: bool) -> bool {
+        self.prompt.generation() != self.generation || config::get().theme.highlights_syntax()
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, candidates) = completion::complete(
+            line,
+            pos,
+            &LsColors::from_env(),
+            &mut self.specs.borrow_mut(),
+        );
+
+        let mut styles = self.candidate_styles.borrow_mut();
+        styles.clear();
+        let pairs = candidates
+            .into_iter()
+            .map(|x| {
+                if let Some(style) = x.style {
+                    styles.insert(x.display.clone(), style);
+                }
+                Pair {
+                    display: x.display,
+                    replacement: x.replacement,
+                }
+            })
+            .collect();
+
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
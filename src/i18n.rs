@@ -0,0 +1,197 @@
+//! translations of the shell's own messages (errors, what builtins complain about, the REPL's
+//! warnings), into the language named by `LC_ALL`, `LC_MESSAGES`, or `LANG`, whichever is set
+//! first, like other programs do
+//!
+//! messages are looked up by their English text, gettext style, in a catalog for the language,
+//! either one built into the shell (see `src/i18n/`), or a `<language>.po` file in
+//! `$RS_SHELL_LOCALEDIR`, which is used instead so that translations can be fixed or added without
+//! rebuilding, a catalog is the simplest kind of PO file
+//!
+//! ```text
+//! # a comment
+//! msgid "command not found: {name}"
+//! msgstr "Befehl nicht gefunden: {name}"
+//! ```
+//!
+//! where `{name}` and the like are filled in with the same things as in the English, a language
+//! is looked for by its full name first (e.g. `pt_BR`), then without the country (`pt`), and
+//! anything that isn't translated stays in English
+
+use std::{collections::HashMap, fmt, fs, path::PathBuf, sync::OnceLock};
+
+use log::warn;
+use parking_lot::RwLock;
+
+use crate::env;
+
+/// the variable naming a directory of catalogs that take precedence over the built-in ones
+const LOCALEDIR_VAR: &str = "RS_SHELL_LOCALEDIR";
+
+/// the catalogs compiled into the shell, by language
+const BUILTIN: &[(&str, &str)] = &[("de", include_str!("i18n/de.po"))];
+
+/// translations, keyed by their English text
+type Catalog = HashMap<String, String>;
+
+/// the catalog for each locale that's been used, `None` if there isn't one, they're kept for as
+/// long as the shell runs, so translations can be handed out as `&'static str`
+fn catalogs() -> &'static RwLock<HashMap<String, Option<&'static Catalog>>> {
+    static CATALOGS: OnceLock<RwLock<HashMap<String, Option<&'static Catalog>>>> = OnceLock::new();
+    CATALOGS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// the catalog for the current locale, if there is one
+fn catalog() -> Option<&'static Catalog> {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .into_iter()
+        .filter_map(env::lookup)
+        .find(|x| !x.is_empty())?
+        .to_string_lossy()
+        .into_owned();
+    if let Some(catalog) = catalogs().read().get(&locale) {
+        return *catalog;
+    }
+    let catalog = load(&locale).map(|x| &*Box::leak(Box::new(x)));
+    catalogs().write().insert(locale, catalog);
+    catalog
+}
+
+/// finds and parses the catalog for `locale`, e.g. `de_DE.UTF-8`
+fn load(locale: &str) -> Option<Catalog> {
+    // the encoding and modifier don't matter, since messages are always UTF-8
+    let full = locale.split(['.', '@']).next().unwrap_or_default();
+    let language = full.split('_').next().unwrap_or_default();
+    let dir = env::lookup(LOCALEDIR_VAR).filter(|x| !x.is_empty());
+    for name in [full, language] {
+        if name.is_empty() {
+            continue;
+        }
+        if let Some(dir) = &dir {
+            let path = PathBuf::from(dir).join(format!("{}.po", name));
+            if let Ok(input) = fs::read_to_string(&path) {
+                return Some(parse(&input, &path.display().to_string()));
+            }
+        }
+        if let Some((_, input)) = BUILTIN.iter().find(|(x, _)| *x == name) {
+            return Some(parse(input, name));
+        }
+    }
+    None
+}
+
+/// the `msgid`/`msgstr` pairs of a PO file, `name` is only used for warnings about lines that
+/// don't make sense, which are skipped
+fn parse(input: &str, name: &str) -> Catalog {
+    let mut catalog = Catalog::new();
+    // the id being translated and its translation so far, strings can be continued on the lines
+    // after the keyword
+    let mut id: Option<String> = None;
+    let mut translation: Option<String> = None;
+    let mut finish = |id: &mut Option<String>, translation: &mut Option<String>| {
+        if let (Some(id), Some(translation)) = (id.take(), translation.take()) {
+            if !translation.is_empty() {
+                catalog.insert(id, translation);
+            }
+        }
+    };
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (target, rest) = if let Some(rest) = line.strip_prefix("msgid ") {
+            finish(&mut id, &mut translation);
+            (&mut id, rest)
+        } else if let Some(rest) = line.strip_prefix("msgstr ") {
+            (&mut translation, rest)
+        } else if translation.is_some() {
+            (&mut translation, line)
+        } else {
+            (&mut id, line)
+        };
+        match unquote(rest.trim()) {
+            Some(x) => target.get_or_insert_with(String::new).push_str(&x),
+            None => warn!("{}: line {}: expected a quoted string", name, i + 1),
+        }
+    }
+    finish(&mut id, &mut translation);
+    catalog
+}
+
+/// the contents of a `"` string with C-style escapes
+fn unquote(input: &str) -> Option<String> {
+    let input = input.strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::new();
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => out.push('\n'),
+                't' => out.push('\t'),
+                x => out.push(x),
+            },
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+/// `message` in the current language, or as it is if there's no translation for it
+pub fn text(message: &'static str) -> &'static str {
+    catalog()
+        .and_then(|x| x.get(message))
+        .map_or(message, String::as_str)
+}
+
+/// like [`text`], with each `{name}` in the message replaced by the argument with that name
+pub fn format(message: &'static str, args: &[(&str, &dyn fmt::Display)]) -> String {
+    let mut out = text(message).to_owned();
+    for (name, value) in args {
+        out = out.replace(&format!("{{{}}}", name), &value.to_string());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalogs() {
+        let input = r#"
+# a comment
+msgid "unable to cd to '{dir}': {error}"
+msgstr "kann nicht nach '{dir}' wechseln: "
+"{error}"
+
+msgid "untranslated"
+msgstr ""
+msgid "with \"quotes\""
+msgstr "mit \"Anführungszeichen\""
+nonsense
+"#;
+        let catalog = parse(input, "test");
+        assert_eq!(catalog.len(), 2);
+        assert_eq!(
+            catalog["unable to cd to '{dir}': {error}"],
+            "kann nicht nach '{dir}' wechseln: {error}"
+        );
+        assert_eq!(catalog["with \"quotes\""], "mit \"Anführungszeichen\"");
+
+        // every translation that's built in has to have the same placeholders as the English
+        for (language, input) in BUILTIN {
+            for (id, translation) in parse(input, language) {
+                let placeholders = |x: &str| {
+                    let mut names: Vec<_> = x
+                        .split('{')
+                        .skip(1)
+                        .filter_map(|x| Some(x.split_once('}')?.0.to_owned()))
+                        .collect();
+                    names.sort();
+                    names
+                };
+                assert_eq!(placeholders(&id), placeholders(&translation), "{}", id);
+            }
+        }
+    }
+}
@@ -0,0 +1,888 @@
+//! job control
+//!
+//! every command the shell spawns is tracked as a job in a global job table, when the shell is
+//! interactive each job is placed in its own process group(s) and the foreground job is handed the
+//! controlling terminal while it runs, so that terminal generated signals like `^C` and `^Z` are
+//! delivered to it rather than to the shell.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, IsTerminal, Read},
+    os::fd::{AsRawFd, IntoRawFd, OwnedFd},
+    sync::{
+        atomic::{AtomicI32, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, OFlag},
+    libc,
+    sys::{
+        signal::{self, killpg, SaFlags, SigAction, SigHandler, SigSet, SigmaskHow, Signal},
+        termios::{self, SetArg, Termios},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{self, Pid},
+};
+use parking_lot::Mutex;
+use subprocess::ExitStatus;
+
+use crate::{
+    options::{self, ShellOption},
+    proc_manager::SharedFifos,
+};
+
+/// the state a job is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    Done(ExitStatus),
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobState::Running => write!(f, "Running"),
+            JobState::Stopped => write!(f, "Stopped"),
+            JobState::Done(ExitStatus::Exited(0)) => write!(f, "Done"),
+            JobState::Done(ExitStatus::Exited(x)) => write!(f, "Exit {}", x),
+            JobState::Done(ExitStatus::Signaled(x)) => match signal_description(*x) {
+                Some(description) => write!(f, "{}", description),
+                None => write!(f, "Killed by signal {}", x),
+            },
+            JobState::Done(_) => write!(f, "Done"),
+        }
+    }
+}
+
+/// resources used by the processes of a job, as reported by `wait4`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rusage {
+    /// time spent running in user mode
+    pub user: Duration,
+    /// time spent running in kernel mode
+    pub system: Duration,
+    /// the largest resident set size of any single process, in kilobytes
+    pub max_rss: u64,
+    /// page faults serviced without any I/O
+    pub minor_faults: u64,
+    /// page faults that required I/O
+    pub major_faults: u64,
+}
+
+impl Rusage {
+    fn from_raw(usage: &libc::rusage) -> Self {
+        let duration = |x: libc::timeval| Duration::new(x.tv_sec as u64, x.tv_usec as u32 * 1000);
+        Self {
+            user: duration(usage.ru_utime),
+            system: duration(usage.ru_stime),
+            max_rss: usage.ru_maxrss as u64,
+            minor_faults: usage.ru_minflt as u64,
+            major_faults: usage.ru_majflt as u64,
+        }
+    }
+
+    /// adds in the usage of another process of the same job
+    fn add(&mut self, other: Rusage) {
+        self.user += other.user;
+        self.system += other.system;
+        self.max_rss = self.max_rss.max(other.max_rss);
+        self.minor_faults += other.minor_faults;
+        self.major_faults += other.major_faults;
+    }
+}
+
+impl fmt::Display for Rusage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "utime={:.3} stime={:.3} maxrss={} minflt={} majflt={}",
+            self.user.as_secs_f64(),
+            self.system.as_secs_f64(),
+            self.max_rss,
+            self.minor_faults,
+            self.major_faults
+        )
+    }
+}
+
+/// a single command or pipeline started by the shell
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: usize,
+    /// the command the job was started from, used when reporting on the job
+    pub command: String,
+    /// the process group every process of the job belongs to, which is given the terminal when
+    /// the job is in the foreground
+    ///
+    /// this is `None` when job control is disabled and the job shares the shell's process group
+    pub pgid: Option<Pid>,
+    /// every process in the job, along with its exit status once it's been reaped
+    pub procs: Vec<(Pid, Option<ExitStatus>)>,
+    pub state: JobState,
+    /// resources used by the processes of the job that have been waited on in the foreground
+    pub rusage: Rusage,
+    /// whether the shell is (or is about to start) waiting on the job itself, the background reaper
+    /// leaves these alone so it doesn't steal their exit statuses
+    foreground: bool,
+    /// whether the job has finished or stopped since the user was last told about it
+    changed: bool,
+    /// the terminal modes the job had when it was stopped, restored when it's resumed
+    modes: Option<Termios>,
+    /// whether the job was started by the shell as part of running another command (e.g. a
+    /// process substitution), these aren't listed or reported, and are dropped once they finish
+    hidden: bool,
+    /// the FIFOs of process substitutions the job might still read from, let go of once it's
+    /// finished
+    fifos: SharedFifos,
+}
+
+impl Job {
+    /// the exit status of the job as a whole, see [`pipeline_status`]
+    fn status(&self) -> ExitStatus {
+        let statuses = self
+            .procs
+            .iter()
+            .map(|(_, x)| x.unwrap_or(ExitStatus::Undetermined))
+            .collect::<Vec<_>>();
+        pipeline_status(&statuses, options::enabled(ShellOption::Pipefail))
+    }
+
+    fn signal(&self, sig: Signal) {
+        match self.pgid {
+            Some(pgid) => {
+                let _ = killpg(pgid, sig);
+            }
+            None => {
+                for (pid, _) in self.procs.iter().filter(|(_, x)| x.is_none()) {
+                    let _ = signal::kill(*pid, sig);
+                }
+            }
+        }
+    }
+}
+
+/// state saved when the shell takes control of its terminal
+#[derive(Debug)]
+struct ShellTerminal {
+    pgid: Pid,
+    modes: Termios,
+}
+
+#[derive(Debug, Default)]
+struct JobTable {
+    jobs: BTreeMap<usize, Job>,
+    /// the job `fg` and `bg` act on when not given a job (`%+`)
+    current: Option<usize>,
+    /// the job that was current before `current` (`%-`)
+    previous: Option<usize>,
+    /// present once the shell has taken control of the terminal, i.e. job control is enabled
+    shell: Option<ShellTerminal>,
+    /// resource usage of the last job to finish in the foreground, until it's taken
+    last_rusage: Option<Rusage>,
+}
+
+impl JobTable {
+    fn make_current(&mut self, id: usize) {
+        if self.current != Some(id) {
+            self.previous = self.current;
+            self.current = Some(id);
+        }
+    }
+
+    fn remove(&mut self, id: usize) -> Option<Job> {
+        let job = self.jobs.remove(&id);
+        if self.current == Some(id) {
+            self.current = self.previous.take();
+        }
+        if self.previous == Some(id) {
+            self.previous = None;
+        }
+        if self.current.is_none() {
+            self.current = self.jobs.values().rev().find(|x| !x.hidden).map(|x| x.id);
+        }
+        job
+    }
+}
+
+fn table() -> &'static Mutex<JobTable> {
+    static JOBS: OnceLock<Mutex<JobTable>> = OnceLock::new();
+    JOBS.get_or_init(|| Mutex::new(JobTable::default()))
+}
+
+extern "C" fn ignore_signal(_: libc::c_int) {}
+
+/// how many times the shell itself has been sent SIGINT, see [`interrupts`]
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn count_interrupt(_: libc::c_int) {
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// how many times the shell itself has been sent SIGINT since job control was enabled, which it
+/// otherwise survives, so that a builtin that runs for a while (e.g. `watch`) can tell it's been
+/// interrupted by ^C while no job has the terminal
+pub fn interrupts() -> usize {
+    INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// write end of the pipe the SIGCHLD handler uses to wake the reaper thread, -1 until it's started
+static REAPER_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn wake_reaper(_: libc::c_int) {
+    let errno = Errno::last_raw();
+    let fd = REAPER_PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // SAFETY: write is async-signal-safe, and if the pipe is full the reaper already has a
+        // wakeup pending so the nonblocking write failing doesn't matter
+        unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+    }
+    Errno::set_raw(errno);
+}
+
+/// starts a thread that reaps background jobs as soon as they exit or stop, keeping the job table
+/// up to date and making sure they don't linger as zombies
+///
+/// the thread is woken by SIGCHLD through a pipe, since the job table can't be touched from a
+/// signal handler
+pub fn start_reaper() -> io::Result<()> {
+    let (mut reader, writer) = io::pipe()?;
+    let writer = OwnedFd::from(writer);
+    fcntl(writer.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+    REAPER_PIPE.store(writer.into_raw_fd(), Ordering::Relaxed);
+
+    thread::Builder::new()
+        .name("reaper".to_owned())
+        .spawn(move || {
+            let mut buf = [0u8; 64];
+            while matches!(reader.read(&mut buf), Ok(1..)) {
+                poll();
+                if options::enabled(ShellOption::Notify) {
+                    notify_now();
+                }
+            }
+        })?;
+
+    let action = SigAction::new(
+        SigHandler::Handler(wake_reaper),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only calls async-signal-safe functions
+    unsafe { signal::sigaction(Signal::SIGCHLD, &action)? };
+    Ok(())
+}
+
+/// enables job control by putting the shell in its own process group and making that the
+/// foreground process group of the terminal on stdin
+///
+/// does nothing if stdin isn't a terminal
+pub fn init() -> nix::Result<()> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Ok(());
+    }
+
+    // if the shell was started in the background, wait until it's brought to the foreground
+    // before taking over the terminal
+    while unistd::tcgetpgrp(&stdin)? != unistd::getpgrp() {
+        killpg(unistd::getpgrp(), Signal::SIGTTIN)?;
+    }
+
+    // these are handled rather than ignored, ignored signals stay ignored across exec, which would
+    // make every child immune to ^C and ^Z, whereas handlers are reset to the default on exec
+    //
+    // SIGINT and SIGQUIT are included so the shell survives them even when sent from elsewhere,
+    // those generated by the terminal go to the foreground job rather than the shell anyway, unless
+    // the shell is the foreground job, which SIGINT is counted for
+    let count = SigAction::new(
+        SigHandler::Handler(count_interrupt),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only touches an atomic, which is async-signal-safe
+    unsafe { signal::sigaction(Signal::SIGINT, &count)? };
+    let action = SigAction::new(
+        SigHandler::Handler(ignore_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for sig in [
+        Signal::SIGQUIT,
+        Signal::SIGTSTP,
+        Signal::SIGTTIN,
+        Signal::SIGTTOU,
+    ] {
+        // SAFETY: the handler doesn't do anything, so it's trivially async-signal-safe
+        unsafe { signal::sigaction(sig, &action)? };
+    }
+
+    let pid = unistd::getpid();
+    if unistd::getpgrp() != pid {
+        // fails for session leaders, which are already the leader of their own process group
+        let _ = unistd::setpgid(pid, pid);
+    }
+    let pgid = unistd::getpgrp();
+    give_terminal(pgid)?;
+
+    table().lock().shell = Some(ShellTerminal {
+        pgid,
+        modes: termios::tcgetattr(&stdin)?,
+    });
+    Ok(())
+}
+
+/// whether job control is enabled, that is, whether spawned jobs should get their own process
+/// groups
+pub fn enabled() -> bool {
+    table().lock().shell.is_some()
+}
+
+/// makes `pgid` the foreground process group of the shell's terminal
+fn give_terminal(pgid: Pid) -> nix::Result<()> {
+    // SIGTTOU is blocked because tcsetpgrp from a background process group (which the shell is
+    // when taking the terminal back from a job) would otherwise signal the shell instead
+    let mut block = SigSet::empty();
+    block.add(Signal::SIGTTOU);
+    let mut old = SigSet::empty();
+    signal::pthread_sigmask(SigmaskHow::SIG_BLOCK, Some(&block), Some(&mut old))?;
+    let result = unistd::tcsetpgrp(io::stdin(), pgid);
+    signal::pthread_sigmask(SigmaskHow::SIG_SETMASK, Some(&old), None)?;
+    result
+}
+
+/// adds a newly spawned job to the job table and returns its id, the job is considered to be in
+/// the foreground until it's passed to [`background`]
+pub fn add(command: String, pids: Vec<Pid>, pgid: Option<Pid>, fifos: SharedFifos) -> usize {
+    let mut table = table().lock();
+    let id = table.jobs.keys().next_back().map(|x| x + 1).unwrap_or(1);
+    table.jobs.insert(
+        id,
+        Job {
+            id,
+            command,
+            pgid,
+            procs: pids.into_iter().map(|x| (x, None)).collect(),
+            state: JobState::Running,
+            rusage: Rusage::default(),
+            foreground: true,
+            changed: false,
+            modes: None,
+            hidden: false,
+            fifos,
+        },
+    );
+    id
+}
+
+/// finds the job referred to by a job spec like `%1`, `%+`, `%-`, or `1`, `None` refers to the
+/// current job
+pub fn find(spec: Option<&str>) -> Option<usize> {
+    let table = table().lock();
+    let id = match spec {
+        None | Some("%") | Some("%%") | Some("%+") => table.current,
+        Some("%-") => table.previous,
+        Some(x) => x.strip_prefix('%').unwrap_or(x).parse().ok(),
+    };
+    id.filter(|x| table.jobs.get(x).is_some_and(|x| !x.hidden))
+}
+
+/// a snapshot of every job in the table
+pub fn list() -> Vec<Job> {
+    table()
+        .lock()
+        .jobs
+        .values()
+        .filter(|x| !x.hidden)
+        .cloned()
+        .collect()
+}
+
+/// the marker shown next to a job in listings, `+` for the current job and `-` for the previous
+pub fn marker(id: usize) -> char {
+    let table = table().lock();
+    if table.current == Some(id) {
+        '+'
+    } else if table.previous == Some(id) {
+        '-'
+    } else {
+        ' '
+    }
+}
+
+/// removes every job that has finished from the table, returning them
+pub fn take_done() -> Vec<Job> {
+    let mut table = table().lock();
+    let done = table
+        .jobs
+        .values()
+        .filter(|x| matches!(x.state, JobState::Done(_)))
+        .map(|x| x.id)
+        .collect::<Vec<_>>();
+    done.into_iter().filter_map(|id| table.remove(id)).collect()
+}
+
+/// checks on every running job without blocking, updating the table with any processes that have
+/// exited or stopped
+pub fn poll() {
+    let mut table = table().lock();
+    for job in table.jobs.values_mut().filter(|x| !x.foreground) {
+        let mut stopped = false;
+        for (pid, status) in job.procs.iter_mut().filter(|(_, x)| x.is_none()) {
+            match waitpid(*pid, Some(WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED)) {
+                Ok(WaitStatus::Stopped(..)) => stopped = true,
+                Ok(x) => *status = convert_status(x).or(*status),
+                Err(Errno::ECHILD) => *status = Some(ExitStatus::Undetermined),
+                Err(_) => (),
+            }
+        }
+        let state = if job.procs.iter().all(|(_, x)| x.is_some()) {
+            JobState::Done(job.status())
+        } else if stopped {
+            JobState::Stopped
+        } else {
+            continue;
+        };
+        if job.state != state {
+            if matches!(state, JobState::Done(_)) {
+                job.fifos = SharedFifos::default();
+            }
+            job.state = state;
+            job.changed = true;
+        }
+    }
+    table
+        .jobs
+        .retain(|_, x| !(x.hidden && matches!(x.state, JobState::Done(_))));
+}
+
+/// describes a job the way it's shown by `jobs` and in notifications, e.g.
+/// `[1]+  Done                    sleep 10`
+pub fn describe(job: &Job) -> String {
+    format!(
+        "[{}]{}  {:<22}  {}",
+        job.id,
+        marker(job.id),
+        job.state.to_string(),
+        job.command
+    )
+}
+
+/// describes every job that finished or stopped since the last call, finished jobs are removed
+/// from the table once they've been reported
+pub fn take_notifications() -> Vec<String> {
+    let changed = list().into_iter().filter(|x| x.changed).collect::<Vec<_>>();
+    let lines = changed.iter().map(describe).collect();
+
+    let mut table = table().lock();
+    for job in changed {
+        if matches!(job.state, JobState::Done(_)) {
+            table.remove(job.id);
+        } else if let Some(job) = table.jobs.get_mut(&job.id) {
+            job.changed = false;
+        }
+    }
+    lines
+}
+
+/// reports job state changes right away (with `set -b`), rather than waiting for the next prompt
+///
+/// these can show up while the user is in the middle of typing a line, so each one is put on a
+/// line of its own, the raw-mode terminal needs the explicit carriage returns
+fn notify_now() {
+    for line in take_notifications() {
+        eprint!("\r{}\r\n", line);
+    }
+}
+
+/// runs a job in the foreground, handing it the terminal and waiting until it either finishes or
+/// is stopped, if `resume` is set the job is sent `SIGCONT` first
+///
+/// finished jobs are removed from the job table, stopped ones are kept and reported
+pub fn foreground(id: usize, resume: bool) -> ExitStatus {
+    let (job, shell_pgid) = {
+        let mut table = table().lock();
+        let Some(job) = table.jobs.get_mut(&id) else {
+            return ExitStatus::Undetermined;
+        };
+        job.state = JobState::Running;
+        job.foreground = true;
+        let job = job.clone();
+        (job, table.shell.as_ref().map(|x| x.pgid))
+    };
+
+    if shell_pgid.is_some() {
+        if let Some(pgid) = job.pgid {
+            let _ = give_terminal(pgid);
+        }
+        if let Some(modes) = &job.modes {
+            let _ = termios::tcsetattr(io::stdin(), SetArg::TCSADRAIN, modes);
+        }
+    }
+    if resume {
+        job.signal(Signal::SIGCONT);
+    }
+
+    // ^C and ^Z don't need passing along to the rest of a pipeline, every process of the job is
+    // in the one group that owns the terminal (the shell's own with job control disabled), so they
+    // reach all of them
+    let mut stopped = false;
+    let mut procs = job.procs.clone();
+    let mut rusage = Rusage::default();
+    // the processes that dumped core, so that can be mentioned when reporting the signal
+    let mut dumped = Vec::new();
+    'procs: for (pid, status) in procs.iter_mut().filter(|(_, x)| x.is_none()) {
+        loop {
+            match wait4(*pid, &mut rusage) {
+                Ok(WaitStatus::Stopped(..)) => {
+                    stopped = true;
+                    break 'procs;
+                }
+                Ok(x) => {
+                    if let WaitStatus::Signaled(_, _, true) = x {
+                        dumped.push(*pid);
+                    }
+                    if let Some(x) = convert_status(x) {
+                        *status = Some(x);
+                        break;
+                    }
+                }
+                Err(Errno::EINTR) => continue,
+                Err(_) => {
+                    *status = Some(ExitStatus::Undetermined);
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut table = table().lock();
+    if let Some(shell_pgid) = shell_pgid {
+        let _ = give_terminal(shell_pgid);
+        let stdin = io::stdin();
+        let job_modes = termios::tcgetattr(&stdin).ok();
+        if let Some(shell) = &table.shell {
+            let _ = termios::tcsetattr(&stdin, SetArg::TCSADRAIN, &shell.modes);
+        }
+        if let Some(job) = table.jobs.get_mut(&id) {
+            job.modes = job_modes;
+        }
+    }
+
+    let Some(entry) = table.jobs.get_mut(&id) else {
+        return ExitStatus::Undetermined;
+    };
+    entry.procs = procs;
+    entry.rusage.add(rusage);
+
+    if stopped {
+        entry.state = JobState::Stopped;
+        entry.foreground = false;
+        let command = entry.command.clone();
+        table.make_current(id);
+        eprintln!("\n[{}]+  Stopped                 {}", id, command);
+        ExitStatus::Signaled(Signal::SIGTSTP as u8)
+    } else {
+        let status = entry.status();
+        let core_dumped = entry
+            .procs
+            .iter()
+            .any(|(pid, x)| *x == Some(status) && dumped.contains(pid));
+        table.last_rusage = Some(entry.rusage);
+        table.remove(id);
+        drop(table);
+        if let ExitStatus::Signaled(sig) = status {
+            report_signal(sig, core_dumped);
+        }
+        status
+    }
+}
+
+/// what a process being killed by `sig` is reported as, using the same wording as other shells
+pub fn signal_description(sig: u8) -> Option<&'static str> {
+    Some(match Signal::try_from(i32::from(sig)).ok()? {
+        Signal::SIGHUP => "Hangup",
+        Signal::SIGINT => "Interrupt",
+        Signal::SIGQUIT => "Quit",
+        Signal::SIGILL => "Illegal instruction",
+        Signal::SIGTRAP => "Trace/breakpoint trap",
+        Signal::SIGABRT => "Aborted",
+        Signal::SIGBUS => "Bus error",
+        Signal::SIGFPE => "Floating point exception",
+        Signal::SIGKILL => "Killed",
+        Signal::SIGUSR1 => "User defined signal 1",
+        Signal::SIGSEGV => "Segmentation fault",
+        Signal::SIGUSR2 => "User defined signal 2",
+        Signal::SIGPIPE => "Broken pipe",
+        Signal::SIGALRM => "Alarm clock",
+        Signal::SIGTERM => "Terminated",
+        Signal::SIGXCPU => "CPU time limit exceeded",
+        Signal::SIGXFSZ => "File size limit exceeded",
+        Signal::SIGSYS => "Bad system call",
+        _ => return None,
+    })
+}
+
+/// tells the user a foreground job was killed by `sig`, apart from `SIGINT` and `SIGPIPE`, which
+/// the user either caused themselves or are just how a pipeline reading less than it's given ends
+fn report_signal(sig: u8, core_dumped: bool) {
+    if sig == Signal::SIGINT as u8 || sig == Signal::SIGPIPE as u8 {
+        return;
+    }
+    let description = match signal_description(sig) {
+        Some(x) => x.to_owned(),
+        None => format!("Killed by signal {}", sig),
+    };
+    match core_dumped {
+        true => eprintln!("{} (core dumped)", description),
+        false => eprintln!("{}", description),
+    }
+}
+
+/// waits for every process of a job to finish without handing it the terminal, then removes it
+/// from the table and returns its status, the job is hidden while it's waited for
+///
+/// this is how jobs run alongside each other are waited for, by a thread each, the job stays
+/// marked as being in the foreground so the reaper leaves it alone
+pub fn wait(id: usize) -> ExitStatus {
+    let mut procs = {
+        let mut table = table().lock();
+        let Some(job) = table.jobs.get_mut(&id) else {
+            return ExitStatus::Undetermined;
+        };
+        job.hidden = true;
+        job.procs.clone()
+    };
+
+    for (pid, status) in procs.iter_mut().filter(|(_, x)| x.is_none()) {
+        loop {
+            match waitpid(*pid, None) {
+                Ok(x) => {
+                    if let Some(x) = convert_status(x) {
+                        *status = Some(x);
+                        break;
+                    }
+                }
+                Err(Errno::EINTR) => continue,
+                Err(_) => {
+                    *status = Some(ExitStatus::Undetermined);
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut table = table().lock();
+    let Some(entry) = table.jobs.get_mut(&id) else {
+        return ExitStatus::Undetermined;
+    };
+    entry.procs = procs;
+    let status = entry.status();
+    table.remove(id);
+    status
+}
+
+/// waits for `pid` to terminate or stop, adding the resources it used to `rusage` if it terminated
+fn wait4(pid: Pid, rusage: &mut Rusage) -> nix::Result<WaitStatus> {
+    let mut status = 0;
+    // SAFETY: an all zero rusage is valid, and it's only read if wait4 succeeds, in which case
+    // it's been filled in
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    // SAFETY: both pointers are valid for the duration of the call
+    let res = unsafe { libc::wait4(pid.as_raw(), &mut status, libc::WUNTRACED, &mut usage) };
+    let pid = Pid::from_raw(Errno::result(res)?);
+    let status = WaitStatus::from_raw(pid, status)?;
+    if !matches!(status, WaitStatus::Stopped(..)) {
+        rusage.add(Rusage::from_raw(&usage));
+    }
+    Ok(status)
+}
+
+/// the resources used by the last job to finish in the foreground, if one has finished since
+/// this was last called
+pub fn take_last_rusage() -> Option<Rusage> {
+    table().lock().last_rusage.take()
+}
+
+/// like [`take_last_rusage`], but leaves it in place for the next caller
+pub fn last_rusage() -> Option<Rusage> {
+    table().lock().last_rusage
+}
+
+/// resumes a stopped job in the background, making it the current job
+pub fn background(id: usize) {
+    let mut table = table().lock();
+    if let Some(job) = table.jobs.get_mut(&id) {
+        job.signal(Signal::SIGCONT);
+        job.state = JobState::Running;
+        job.foreground = false;
+    }
+    table.make_current(id);
+}
+
+/// leaves a job running in the background without making it current, it's never listed or
+/// reported and is dropped from the table as soon as it finishes
+pub fn detach(id: usize) {
+    if let Some(job) = table().lock().jobs.get_mut(&id) {
+        job.foreground = false;
+        job.hidden = true;
+    }
+}
+
+/// whether any jobs are still stopped or running (in that order of precedence), which the user is
+/// warned about before exiting
+pub fn unfinished() -> Option<JobState> {
+    poll();
+    let states = list().into_iter().map(|x| x.state).collect::<Vec<_>>();
+    [JobState::Stopped, JobState::Running]
+        .into_iter()
+        .find(|x| states.contains(x))
+}
+
+/// deals with the jobs left over when the shell exits, with `huponexit` every job is sent `SIGHUP`,
+/// otherwise running jobs are left running, stopped jobs can never be resumed once the shell is
+/// gone though, so they're always sent `SIGHUP` (and `SIGCONT` so they actually receive it)
+///
+/// if the shell's exiting because it panicked, each job that's still around is listed too, since
+/// the user never got warned about them
+pub fn hangup_on_exit() {
+    let hup = options::enabled(ShellOption::HupOnExit);
+    // the panic could've happened with the table locked
+    let Some(table) = table().try_lock_for(Duration::from_secs(1)) else {
+        eprintln!("rs-shell: job table is locked, leaving jobs as they are");
+        return;
+    };
+    for job in table.jobs.values().filter(|x| !x.hidden) {
+        if thread::panicking() && !matches!(job.state, JobState::Done(_)) {
+            eprintln!("rs-shell: [{}] {}\t{}", job.id, job.state, job.command);
+        }
+        match job.state {
+            JobState::Stopped => {
+                job.signal(Signal::SIGHUP);
+                job.signal(Signal::SIGCONT);
+            }
+            JobState::Running if hup => {
+                job.signal(Signal::SIGHUP);
+            }
+            JobState::Running | JobState::Done(_) => (),
+        }
+    }
+}
+
+/// the exit status of a pipeline given the statuses of its members from left to right, which is
+/// the status of the last member, or with `pipefail` the status of the rightmost member that
+/// failed
+pub fn pipeline_status(statuses: &[ExitStatus], pipefail: bool) -> ExitStatus {
+    let last = statuses.last().copied().unwrap_or(ExitStatus::Undetermined);
+    if !pipefail {
+        return last;
+    }
+    statuses
+        .iter()
+        .rev()
+        .find(|x| !x.success())
+        .copied()
+        .unwrap_or(last)
+}
+
+/// converts the result of waiting on a process to an exit status, `None` means the process hasn't
+/// terminated
+fn convert_status(status: WaitStatus) -> Option<ExitStatus> {
+    match status {
+        WaitStatus::Exited(_, x) => Some(ExitStatus::Exited(x as u32)),
+        WaitStatus::Signaled(_, sig, _) => Some(ExitStatus::Signaled(sig as u8)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipeline_exit_status() {
+        use ExitStatus::*;
+        let statuses = [Exited(1), Signaled(13), Exited(0)];
+        assert_eq!(pipeline_status(&statuses, false), Exited(0));
+        assert_eq!(pipeline_status(&statuses, true), Signaled(13));
+        assert_eq!(pipeline_status(&[Exited(0), Exited(2)], true), Exited(2));
+        assert_eq!(pipeline_status(&[Exited(0), Exited(0)], true), Exited(0));
+    }
+
+    #[test]
+    fn hangup_on_exit_signals() {
+        let spawn = || {
+            let child = std::process::Command::new("sleep")
+                .arg("30")
+                .spawn()
+                .unwrap()
+                .id();
+            Pid::from_raw(child as i32)
+        };
+        let (running, stopped) = (spawn(), spawn());
+        let running_id = add(
+            "sleep 30".to_owned(),
+            vec![running],
+            None,
+            Default::default(),
+        );
+        let stopped_id = add(
+            "sleep 30".to_owned(),
+            vec![stopped],
+            None,
+            Default::default(),
+        );
+        signal::kill(stopped, Signal::SIGSTOP).unwrap();
+        table().lock().jobs.get_mut(&stopped_id).unwrap().state = JobState::Stopped;
+        // stopped jobs are the ones worth warning about first
+        assert_eq!(unfinished(), Some(JobState::Stopped));
+
+        // a stopped job could never be resumed, so it's always hung up on, unlike a running one
+        hangup_on_exit();
+        let hup = |pid| waitpid(pid, None) == Ok(WaitStatus::Signaled(pid, Signal::SIGHUP, false));
+        assert!(hup(stopped));
+        table().lock().remove(stopped_id);
+        let alive = waitpid(running, Some(WaitPidFlag::WNOHANG));
+        assert_eq!(alive, Ok(WaitStatus::StillAlive));
+
+        options::with(ShellOption::HupOnExit, true, hangup_on_exit);
+        assert!(hup(running));
+        table().lock().remove(running_id);
+    }
+
+    #[test]
+    fn wait4_rusage() {
+        // reaped by wait4 rather than the child's own wait
+        let child = std::process::Command::new("sh")
+            .args(["-c", "exit 3"])
+            .spawn()
+            .unwrap()
+            .id();
+        let pid = Pid::from_raw(child as i32);
+        let mut rusage = Rusage::default();
+        assert_eq!(wait4(pid, &mut rusage), Ok(WaitStatus::Exited(pid, 3)));
+        assert!(rusage.max_rss > 0);
+    }
+
+    #[test]
+    fn signal_descriptions() {
+        assert_eq!(signal_description(15), Some("Terminated"));
+        assert_eq!(signal_description(9), Some("Killed"));
+        assert_eq!(signal_description(11), Some("Segmentation fault"));
+        assert_eq!(signal_description(0), None);
+        assert_eq!(
+            JobState::Done(ExitStatus::Signaled(9)).to_string(),
+            "Killed"
+        );
+        assert_eq!(
+            JobState::Done(ExitStatus::Signaled(Signal::SIGWINCH as u8)).to_string(),
+            "Killed by signal 28"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! what the last foreground command wrote to stdout, kept with the `keepoutput` option enabled so
+//! that `copyout` can put it on the clipboard, e.g. to paste an error message into a bug report
+//!
+//! to keep a copy, the command's stdout is a pipe rather than the terminal itself, and a thread
+//! copies everything written to it on to the shell's stdout as well as keeping the end of it, so
+//! programs that behave differently when not writing to a terminal (e.g. `ls` or editors) will,
+//! which is why it's off by default
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::OwnedFd,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+
+/// how much of a command's output is kept, anything before the last this many bytes is dropped
+pub const LIMIT: usize = 64 * 1024;
+
+/// the builtin that copies the output, whose own (empty) output doesn't replace what it copies
+pub const COPY_BUILTIN: &str = "copyout";
+
+/// how long to wait for the rest of a command's output once it's finished, anything it's left
+/// running in the background (or a job that's been stopped) may still have the pipe open, in which
+/// case its output carries on being passed along and kept, it just isn't waited for
+const FINISH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// the output that's been kept, along with which recording it's from, so that one that finishes
+/// late doesn't replace the output of a command started after it
+fn kept() -> &'static Mutex<(u64, Vec<u8>)> {
+    static KEPT: OnceLock<Mutex<(u64, Vec<u8>)>> = OnceLock::new();
+    KEPT.get_or_init(|| Mutex::new((0, Vec::new())))
+}
+
+/// the end of what the last foreground command wrote to stdout, at most [`LIMIT`] bytes of it
+pub fn get() -> Vec<u8> {
+    kept().lock().1.clone()
+}
+
+/// keeps the end of what's written to a pipe while passing all of it on
+#[derive(Debug)]
+pub struct Recorder {
+    done: mpsc::Receiver<()>,
+}
+
+impl Recorder {
+    /// starts recording, returning the file the command should write its stdout to, what's written
+    /// to it is passed on to `out`, or the shell's stdout if not given
+    pub fn start(out: Option<File>) -> io::Result<(Self, File)> {
+        static RECORDINGS: AtomicU64 = AtomicU64::new(0);
+        let recording = RECORDINGS.fetch_add(1, Ordering::Relaxed) + 1;
+        let (mut reader, writer) = io::pipe()?;
+        let (finished, done) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let mut buf = [0u8; 8192];
+            let mut out: Box<dyn Write + Send> = match out {
+                Some(x) => Box::new(x),
+                None => Box::new(io::stdout()),
+            };
+            loop {
+                let len = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(x) => x,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                };
+                // the command keeps running even if the terminal's gone away
+                let _ = out.write_all(&buf[..len]).and_then(|_| out.flush());
+                keep(&mut output, &buf[..len]);
+            }
+            // so that whatever `out` is, it sees the end of the output straight away
+            drop(out);
+            let mut kept = kept().lock();
+            if kept.0 < recording {
+                *kept = (recording, output);
+            }
+            let _ = finished.send(());
+        });
+        Ok((Self { done }, File::from(OwnedFd::from(writer))))
+    }
+
+    /// waits (for a while, see [`FINISH_TIMEOUT`]) for every copy of the pipe's write end to be
+    /// closed, so that the output's all been passed along and kept
+    pub fn finish(self) {
+        let _ = self.done.recv_timeout(FINISH_TIMEOUT);
+    }
+}
+
+/// adds `data` to the end of `kept`, dropping from the start whatever takes it past [`LIMIT`]
+fn keep(kept: &mut Vec<u8>, data: &[u8]) {
+    let data = &data[data.len().saturating_sub(LIMIT)..];
+    let excess = (kept.len() + data.len()).saturating_sub(LIMIT);
+    kept.drain(..excess);
+    kept.extend_from_slice(data);
+}
+
+/// puts `data` on the system clipboard with OSC 52, which the terminal the shell is running in
+/// handles (if it supports it), so it works over ssh too, it's written to the controlling terminal
+/// rather than stdout so it doesn't matter where that's been redirected
+pub fn copy_to_clipboard(data: &[u8]) -> io::Result<()> {
+    let mut tty = File::options().write(true).open("/dev/tty")?;
+    tty.write_all(format!("\x1b]52;c;{}\x07", base64(data)).as_bytes())?;
+    tty.flush()
+}
+
+/// `data` in standard base64, with padding
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeping_output() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"error: oops\n"), "ZXJyb3I6IG9vcHMK");
+
+        let mut kept = Vec::new();
+        keep(&mut kept, b"start");
+        keep(&mut kept, &vec![b'x'; LIMIT - 5]);
+        keep(&mut kept, b"end");
+        assert_eq!(kept.len(), LIMIT);
+        assert!(kept.starts_with(b"rtx"));
+        assert!(kept.ends_with(b"xend"));
+        keep(&mut kept, &vec![b'y'; LIMIT + 1]);
+        assert_eq!(kept, vec![b'y'; LIMIT]);
+    }
+}
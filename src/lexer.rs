@@ -0,0 +1,413 @@
+//! splitting a command line into tokens, for highlighting it as it's typed
+//!
+//! the tokens follow the grammar in `grammar/shell.pest`, but lexing never fails, a line that's
+//! still being typed is usually incomplete (e.g. a quote that hasn't been closed yet), and whatever
+//! the parser would reject is still split up as best it can be
+//!
+//! [`Incremental`] keeps the tokens of the previous version of a line, and only lexes a new one
+//! from the start of the word the first change is in, so highlighting a long line doesn't get
+//! slower the longer it is
+
+use crate::ast::Span;
+
+/// what a token is, which decides how it's colored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// (part of) the word naming the command to run
+    Command,
+    /// (part of) any other unquoted word
+    Word,
+    /// the name and `=` of an assignment, e.g. `NAME=` in `NAME=value cmd`
+    Assignment,
+    /// `$NAME`, `${NAME}`, `$1` or `$?`
+    Variable,
+    /// a quoted string, quotes included, apart from any variables and substitutions in it
+    String,
+    /// `;`, `|`, `&`, a redirect, or a parenthesis around a substitution
+    Operator,
+    /// from a `#` at the start of a word to the end of the line
+    Comment,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// a substitution that hasn't been closed yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Frame {
+    /// whether it's inside a double quoted string, which carries on once it's closed
+    quoted: bool,
+    /// whether the word after it names a command, e.g. it's the value of an assignment
+    command: bool,
+}
+
+/// everything about what's been lexed so far that affects what comes next
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State {
+    /// innermost last
+    nesting: Vec<Frame>,
+    /// whether the next word names a command
+    command: bool,
+}
+
+impl State {
+    const START: State = State {
+        nesting: Vec::new(),
+        command: true,
+    };
+}
+
+/// every token in `line`, in order
+pub fn tokens(line: &str) -> Vec<Token> {
+    Incremental::default().update(line).to_vec()
+}
+
+/// lexes a line as it's edited, see the [module docs](self)
+#[derive(Debug, Default)]
+pub struct Incremental {
+    line: String,
+    tokens: Vec<Token>,
+    /// the start of every word outside of a string, which lexing can be picked back up from,
+    /// along with the state it was in there, in order
+    checkpoints: Vec<(usize, State)>,
+}
+
+impl Incremental {
+    /// the tokens in `line`, which is usually the line from the last call with an edit made to it
+    pub fn update(&mut self, line: &str) -> &[Token] {
+        let changed = self
+            .line
+            .bytes()
+            .zip(line.bytes())
+            .position(|(a, b)| a != b)
+            .unwrap_or(self.line.len().min(line.len()));
+        if changed == line.len() && changed == self.line.len() && !self.checkpoints.is_empty() {
+            return &self.tokens;
+        }
+
+        // everything before a word only depends on what's before it, so that much is kept
+        let keep = self.checkpoints.partition_point(|(x, _)| *x <= changed);
+        let (from, state) = match keep {
+            0 => (0, State::START),
+            _ => self.checkpoints[keep - 1].clone(),
+        };
+        self.checkpoints.truncate(keep.saturating_sub(1));
+        let kept = self.tokens.partition_point(|x| x.span.end <= from);
+        self.tokens.truncate(kept);
+
+        Lexer {
+            line: line.as_bytes(),
+            pos: from,
+            state,
+            tokens: &mut self.tokens,
+            checkpoints: &mut self.checkpoints,
+        }
+        .run();
+        self.line.clear();
+        self.line.push_str(line);
+        &self.tokens
+    }
+}
+
+struct Lexer<'a> {
+    line: &'a [u8],
+    pos: usize,
+    state: State,
+    tokens: &'a mut Vec<Token>,
+    checkpoints: &'a mut Vec<(usize, State)>,
+}
+
+impl Lexer<'_> {
+    fn run(mut self) {
+        self.checkpoints.push((self.pos, self.state.clone()));
+        while self.pos < self.line.len() {
+            if is_whitespace(self.line[self.pos]) {
+                while self.pos < self.line.len() && is_whitespace(self.line[self.pos]) {
+                    self.pos += 1;
+                }
+                self.checkpoints.push((self.pos, self.state.clone()));
+            } else if self.line[self.pos] == b'#' {
+                let end = self.line[self.pos..]
+                    .iter()
+                    .position(|x| *x == b'\n')
+                    .map_or(self.line.len(), |x| self.pos + x);
+                self.push(TokenKind::Comment, self.pos, end);
+                self.pos = end;
+            } else if !self.operator() {
+                self.word();
+            }
+        }
+    }
+
+    fn push(&mut self, kind: TokenKind, start: usize, end: usize) {
+        if start < end {
+            self.tokens.push(Token {
+                kind,
+                span: Span { start, end },
+            });
+        }
+    }
+
+    /// lexes the operator at the current position, if there is one
+    fn operator(&mut self) -> bool {
+        let start = self.pos;
+        let rest = &self.line[start..];
+        let len = match (redirect_len(rest), rest) {
+            (Some(len), _) => len,
+            (None, [b'<', b'(', ..] | [b'(', ..]) => {
+                self.state.nesting.push(Frame {
+                    quoted: false,
+                    command: self.state.command,
+                });
+                self.state.command = true;
+                rest.iter().position(|x| *x == b'(').unwrap_or_default() + 1
+            }
+            (None, [b')', ..]) => {
+                self.push(TokenKind::Operator, start, start + 1);
+                self.pos += 1;
+                let Some(frame) = self.state.nesting.pop() else {
+                    return true;
+                };
+                self.state.command = frame.command;
+                // the rest of the string, and the word it's in
+                if frame.quoted && !self.double_quoted(false) {
+                    self.parts(TokenKind::Word);
+                }
+                return true;
+            }
+            (None, [b';' | b'|' | b'&', ..]) => {
+                self.state.command = true;
+                1
+            }
+            (None, _) => return false,
+        };
+        self.push(TokenKind::Operator, start, start + len);
+        self.pos += len;
+        true
+    }
+
+    fn word(&mut self) {
+        if !self.state.command {
+            return self.parts(TokenKind::Word);
+        }
+        match assignment_len(&self.line[self.pos..]) {
+            // the value's just a word, and the next word is still a command
+            Some(len) => {
+                self.push(TokenKind::Assignment, self.pos, self.pos + len);
+                self.pos += len;
+                self.parts(TokenKind::Word);
+            }
+            None => {
+                self.state.command = false;
+                self.parts(TokenKind::Command);
+            }
+        }
+    }
+
+    /// lexes the rest of a word, each unquoted part of which is a `kind` token
+    fn parts(&mut self, kind: TokenKind) {
+        let mut plain = self.pos;
+        while self.pos < self.line.len() {
+            match self.line[self.pos] {
+                x if is_whitespace(x) => break,
+                b';' | b'|' | b'&' | b'(' | b')' | b'<' | b'>' => break,
+                b'\\' => self.pos = (self.pos + 2).min(self.line.len()),
+                b'\'' => {
+                    self.push(kind, plain, self.pos);
+                    let end = self.line[self.pos + 1..]
+                        .iter()
+                        .position(|x| *x == b'\'')
+                        .map_or(self.line.len(), |x| self.pos + x + 2);
+                    self.push(TokenKind::String, self.pos, end);
+                    self.pos = end;
+                    plain = end;
+                }
+                b'"' => {
+                    self.push(kind, plain, self.pos);
+                    if self.double_quoted(true) {
+                        return;
+                    }
+                    plain = self.pos;
+                }
+                b'$' => match variable_len(&self.line[self.pos..]) {
+                    Some(len) => {
+                        self.push(kind, plain, self.pos);
+                        self.push(TokenKind::Variable, self.pos, self.pos + len);
+                        self.pos += len;
+                        plain = self.pos;
+                    }
+                    None => self.pos += 1,
+                },
+                _ => self.pos += 1,
+            }
+        }
+        self.push(kind, plain, self.pos);
+    }
+
+    /// lexes a double quoted string, from its opening quote if `opening`, otherwise from the end of
+    /// a substitution in it, returning whether it stopped at the start of another substitution
+    fn double_quoted(&mut self, opening: bool) -> bool {
+        let mut start = self.pos;
+        if opening {
+            self.pos += 1;
+        }
+        while self.pos < self.line.len() {
+            let rest = &self.line[self.pos..];
+            match rest {
+                [b'"', ..] => {
+                    self.pos += 1;
+                    self.push(TokenKind::String, start, self.pos);
+                    return false;
+                }
+                [b'\\', ..] => self.pos = (self.pos + 2).min(self.line.len()),
+                [b'$', b'(', ..] => {
+                    self.push(TokenKind::String, start, self.pos);
+                    self.push(TokenKind::Operator, self.pos, self.pos + 2);
+                    self.pos += 2;
+                    self.state.nesting.push(Frame {
+                        quoted: true,
+                        command: self.state.command,
+                    });
+                    self.state.command = true;
+                    return true;
+                }
+                _ => match variable_len(rest) {
+                    Some(len) => {
+                        self.push(TokenKind::String, start, self.pos);
+                        self.push(TokenKind::Variable, self.pos, self.pos + len);
+                        self.pos += len;
+                        start = self.pos;
+                    }
+                    None => self.pos += 1,
+                },
+            }
+        }
+        // it was never closed
+        self.push(TokenKind::String, start, self.pos);
+        false
+    }
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+/// the length of the redirect operator `rest` starts with, if it does, e.g. `2>>`, `&>` or `>&-`
+fn redirect_len(rest: &[u8]) -> Option<usize> {
+    let fd = match rest.first() {
+        Some(b'&') => 1,
+        _ => rest.iter().take_while(|x| x.is_ascii_digit()).count(),
+    };
+    let op = &rest[fd..];
+    let len = match op {
+        [b'>', b'&', b'-', ..] | [b'<', b'<', b'<', ..] => 3,
+        [b'>', b'&', x, ..] if x.is_ascii_digit() => {
+            2 + op[2..].iter().take_while(|x| x.is_ascii_digit()).count()
+        }
+        [b'>', b'>', ..] => 2,
+        [b'<', b'(', ..] => return None,
+        [b'>' | b'<', ..] => 1,
+        _ => return None,
+    };
+    Some(fd + len)
+}
+
+/// the length of the variable `rest` starts with, if it does
+fn variable_len(rest: &[u8]) -> Option<usize> {
+    match rest {
+        [b'$', b'?', ..] => Some(2),
+        [b'$', b'{', ..] => Some(
+            rest.iter()
+                .position(|x| *x == b'}')
+                .map_or(rest.len(), |x| x + 1),
+        ),
+        [b'$', x, ..] if x.is_ascii_digit() => Some(2),
+        [b'$', x, ..] if x.is_ascii_alphabetic() || *x == b'_' => {
+            let name = rest[1..]
+                .iter()
+                .take_while(|x| x.is_ascii_alphanumeric() || **x == b'_');
+            Some(1 + name.count())
+        }
+        _ => None,
+    }
+}
+
+/// the length of the `NAME=` that `rest` starts with, if it does
+fn assignment_len(rest: &[u8]) -> Option<usize> {
+    if !rest
+        .first()
+        .is_some_and(|x| x.is_ascii_alphabetic() || *x == b'_')
+    {
+        return None;
+    }
+    let name = rest
+        .iter()
+        .take_while(|x| x.is_ascii_alphanumeric() || **x == b'_');
+    let len = name.count();
+    (rest.get(len) == Some(&b'=')).then_some(len + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds() {
+        use TokenKind::*;
+        let line = "A=1 cmd x$y 2>f \"a $b $(c 'd') e\" | g <(f) a#b #comment";
+        let tokens = tokens(line)
+            .into_iter()
+            .map(|x| (x.kind, x.span.slice(line)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                (Assignment, "A="),
+                (Word, "1"),
+                (Command, "cmd"),
+                (Word, "x"),
+                (Variable, "$y"),
+                (Operator, "2>"),
+                (Word, "f"),
+                (String, "\"a "),
+                (Variable, "$b"),
+                (String, " "),
+                (Operator, "$("),
+                (Command, "c"),
+                (String, "'d'"),
+                (Operator, ")"),
+                (String, " e\""),
+                (Operator, "|"),
+                (Command, "g"),
+                (Operator, "<("),
+                (Command, "f"),
+                (Operator, ")"),
+                (Word, "a#b"),
+                (Comment, "#comment"),
+            ]
+        );
+    }
+
+    #[test]
+    fn incremental_matches_full() {
+        let line = "X=\"$(a | b)\" cmd 'q w' ${v} \"unclosed $(x; y\" z & w >&- 1>>o #c";
+        let mut lexer = Incremental::default();
+        // typed out one character at a time, then deleted from the middle one at a time
+        for end in (0..=line.len()).filter(|x| line.is_char_boundary(*x)) {
+            assert_eq!(
+                lexer.update(&line[..end]),
+                tokens(&line[..end]),
+                "{}",
+                &line[..end]
+            );
+        }
+        let mut edited = line.to_owned();
+        while edited.len() > 1 {
+            edited.remove(edited.len() / 2);
+            assert_eq!(lexer.update(&edited), tokens(&edited), "{}", edited);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! the rs-shell engine, everything needed to parse and run shell commands without the interactive
+//! frontend, so that it can be embedded in other programs, e.g. a TUI or a test harness
+//!
+//! [`parse`], [`evaluate`], and [`run`] cover the common cases, the modules they're built on are
+//! public for anything more involved, e.g. [`evaluator::Evaluator::with_backend`] to run commands
+//! somewhere other than this machine
+//!
+//! the shell's state (variables, aliases, jobs, options, ...) is global to the process, so every
+//! [`Evaluator`] shares it, just like the commands run by a single shell do
+
+pub mod alias;
+mod arith;
+pub mod ast;
+mod audit;
+pub mod builtins;
+pub mod diagnostic;
+mod direnv;
+mod dotenv;
+pub mod env;
+pub mod evaluator;
+mod frecency;
+pub mod i18n;
+pub mod jobs;
+mod last_output;
+pub mod lexer;
+pub mod lint;
+pub mod metrics;
+pub mod options;
+mod pager;
+mod parse_cache;
+pub mod parser;
+pub mod path;
+mod priority;
+pub mod proc_manager;
+pub mod profile;
+mod redirect;
+pub mod repl;
+#[cfg(test)]
+mod roundtrip;
+mod sandbox;
+pub mod script;
+#[cfg(feature = "serde")]
+mod serde_os;
+pub mod session;
+pub mod shutdown;
+mod spawn;
+mod suggest;
+pub mod terminal;
+pub mod visit;
+
+pub use ast::{AstError, Main};
+pub use evaluator::{CommandBuilder, EvalError, Evaluator};
+pub use subprocess::ExitStatus;
+
+/// anything that can go wrong in [`run`]
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] AstError),
+
+    #[error(transparent)]
+    Eval(#[from] EvalError),
+}
+
+/// parses a command line into an AST, which can then be run with [`evaluate`]
+pub fn parse(input: &str) -> Result<Main<'_>, AstError> {
+    ast::generate_ast(input)
+}
+
+/// runs a parsed command line with `evaluator`, returning the status of the last command in it
+pub fn evaluate(evaluator: &mut Evaluator, ast: Main) -> Result<ExitStatus, EvalError> {
+    evaluator.eval(ast)
+}
+
+/// parses and runs a command line with a fresh [`Evaluator`], the shell's state is global, so
+/// anything it changes (e.g. variables, or the working directory) stays changed afterwards
+pub fn run(input: &str) -> Result<ExitStatus, Error> {
+    let ast = parse(input)?;
+    Ok(evaluate(&mut Evaluator::new(), ast)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedding() {
+        assert!(matches!(run("RS_TEST_EMBED=1"), Ok(ExitStatus::Exited(0))));
+        assert!(matches!(run("echo 'unterminated"), Err(Error::Parse(_))));
+    }
+}
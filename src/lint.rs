@@ -0,0 +1,351 @@
+//! static checks for scripts, a shellcheck-lite run by `rs-shell lint`, for mistakes that can be
+//! spotted without running anything
+//!
+//! rs-shell never splits words or expands globs, so unlike in other shells an unquoted `$x` is
+//! always passed as a single argument, what does catch people out is the opposite, a glob that's
+//! passed as is rather than being expanded, so that's what's warned about instead
+
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fmt,
+};
+
+use crate::{
+    ast::{
+        self, Argument, Chars, Command, CommandLine, DollarEnv, RawChars, RedirectType, Separator,
+        SingleQuoteString, Span, StringLiteral, StringLiteralComponent,
+    },
+    diagnostic::{Diagnostic, Label},
+    script::{self, ScriptError},
+    visit::{self, Visitor},
+};
+
+/// the kinds of problem that are looked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Check {
+    /// an unquoted `*` or `?`, which is passed to the command as is
+    Glob,
+    /// `cat file | cmd`, where `cmd < file` would do
+    UselessCat,
+    /// a variable that's assigned but never used afterwards, nor exported, since a plain
+    /// assignment isn't seen by the commands that are run
+    UnusedAssignment,
+    /// a file that's written to by the same pipeline that reads it, so that it's emptied (or grows
+    /// forever) before it's read
+    SameFile,
+}
+
+impl Check {
+    /// what the check's called in warnings
+    pub fn name(self) -> &'static str {
+        match self {
+            Check::Glob => "glob",
+            Check::UselessCat => "useless-cat",
+            Check::UnusedAssignment => "unused-assignment",
+            Check::SameFile => "same-file",
+        }
+    }
+}
+
+/// a problem found in a script, it's a [`Diagnostic`], so it can be rendered pointing at the
+/// statement it's in with [`diagnostic::render`](crate::diagnostic::render)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub check: Check,
+    /// the line the statement starts on
+    pub line: usize,
+    /// the statement the problem's in, which the labels' spans are into
+    pub source: String,
+    message: String,
+    labels: Vec<Label>,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}]", self.message, self.check.name())
+    }
+}
+
+impl Diagnostic for Warning {
+    fn labels(&self) -> Vec<Label> {
+        self.labels.clone()
+    }
+}
+
+/// checks every statement of `script`, returning what's wrong in the order it comes up in
+///
+/// `name` is only used for errors, which are for the first statement that can't be parsed,
+/// nothing's checked then, since the script wouldn't run anyway
+pub fn check(name: &str, script: &str) -> Result<Vec<Warning>, ScriptError> {
+    let mut linter = Linter::default();
+    for (line, statement) in script::statements(script) {
+        let ast = ast::generate_ast(&statement).map_err(|e| ScriptError::Parse {
+            path: name.to_owned(),
+            line,
+            internal: e,
+        })?;
+        linter.line = line;
+        linter.source.clone_from(&statement);
+        linter.piped = false;
+        linter.visit_main(&ast);
+    }
+
+    let Linter {
+        mut warnings,
+        assignments,
+        used,
+        ..
+    } = linter;
+    for (name, warning) in assignments {
+        if !used.contains(&name) {
+            warnings.push(warning);
+        }
+    }
+    warnings.sort_by_key(|x| (x.line, x.labels.first().map(|x| x.span.start)));
+    Ok(warnings)
+}
+
+#[derive(Default)]
+struct Linter {
+    warnings: Vec<Warning>,
+    /// the first assignment to each variable, as the warning to give if it isn't used
+    assignments: Vec<(OsString, Warning)>,
+    /// every name that's substituted, or appears as a word anywhere (e.g. after `export`, or in an
+    /// arithmetic expression), so that it counts as used
+    used: HashSet<OsString>,
+    /// the current statement and where it starts
+    line: usize,
+    source: String,
+    /// whether the command line about to be visited is piped into from the one before it
+    piped: bool,
+}
+
+impl Linter {
+    fn warn(&mut self, check: Check, message: String, labels: Vec<Label>) {
+        self.warnings.push(Warning {
+            check,
+            line: self.line,
+            source: self.source.clone(),
+            message,
+            labels,
+        });
+    }
+
+    /// `cat file | cmd`, where `cat` has nothing else to do
+    fn useless_cat(&mut self, node: &CommandLine) {
+        let Some(command) = &node.command else {
+            return;
+        };
+        let [file] = &node.arguments[..] else {
+            return;
+        };
+        let is_cat = literal_command(command).is_some_and(|x| x == "cat");
+        let piped = matches!(node.next, Some((Separator::Pipe, _)));
+        let is_file = literal(file).is_some_and(|x| !x.to_string_lossy().starts_with('-'));
+        if !is_cat || !piped || !is_file || !node.envs.is_empty() || !node.redirects.is_empty() {
+            return;
+        }
+        let span = Span {
+            start: command.span().start,
+            end: file.span().end,
+        };
+        self.warn(
+            Check::UselessCat,
+            "useless cat, the file can be redirected into the next command with '<'".to_owned(),
+            vec![Label::new(span, "could be a redirect")],
+        );
+    }
+
+    /// a file that's written to by one command of the pipeline starting at `node` and read by
+    /// another (or the same one), either as an argument or by redirecting it to stdin
+    fn same_file(&mut self, node: &CommandLine) {
+        let mut read = Vec::new();
+        let mut written = Vec::new();
+        let mut stage = Some(node);
+        while let Some(x) = stage {
+            for arg in &x.arguments {
+                read.extend(literal(arg).map(|name| (name, arg.span())));
+            }
+            for redirect in &x.redirects {
+                let Some(arg) = &redirect.arg else {
+                    continue;
+                };
+                let Some(name) = literal(arg) else {
+                    continue;
+                };
+                match redirect.op.r#type {
+                    RedirectType::In => read.push((name, arg.span())),
+                    RedirectType::Out | RedirectType::OutAppend => {
+                        written.push((name, redirect.op.r#type.clone(), arg.span()))
+                    }
+                    RedirectType::HereString | RedirectType::Close | RedirectType::Duplicate(_) => {
+                    }
+                }
+            }
+            stage = match &x.next {
+                Some((Separator::Pipe, next)) => Some(next),
+                _ => None,
+            };
+        }
+
+        for (name, r#type, span) in written {
+            let Some((_, read_at)) = read.iter().find(|(x, _)| *x == name) else {
+                continue;
+            };
+            let what = match r#type {
+                RedirectType::OutAppend => "appended to while it's read, which may never finish",
+                _ => "emptied before it's read",
+            };
+            let message = format!(
+                "'{}' is read and written by the same pipeline, so it's {}",
+                name.to_string_lossy(),
+                what
+            );
+            let labels = vec![
+                Label::new(*read_at, "read here"),
+                Label::new(span, "written here"),
+            ];
+            self.warn(Check::SameFile, message, labels);
+        }
+    }
+
+    /// marks every word of `text` that could be a variable name as used
+    fn words(&mut self, text: &OsStr) {
+        let text = text.to_string_lossy();
+        let words = text.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'));
+        self.used
+            .extend(words.filter(|x| !x.is_empty()).map(OsString::from));
+    }
+}
+
+impl<'ast> Visitor<'ast> for Linter {
+    fn visit_command_line(&mut self, node: &'ast CommandLine) {
+        // only look at a pipeline from its first command, the rest are part of it
+        if !std::mem::take(&mut self.piped) {
+            self.same_file(node);
+        }
+        self.useless_cat(node);
+        if node.command.is_none() {
+            for env in &node.envs {
+                let name = &env.name.0;
+                if self.assignments.iter().any(|(x, _)| x == name)
+                    || is_environment_variable(&name.to_string_lossy())
+                {
+                    continue;
+                }
+                let warning = Warning {
+                    check: Check::UnusedAssignment,
+                    line: self.line,
+                    source: self.source.clone(),
+                    message: format!("'{}' is assigned but never used", name.to_string_lossy()),
+                    labels: vec![Label::new(env.name.1, "never used")],
+                };
+                self.assignments.push((name.to_os_string(), warning));
+            }
+        }
+        visit::walk_command_line(self, node);
+    }
+
+    fn visit_separator(&mut self, node: &'ast Separator) {
+        self.piped = *node == Separator::Pipe;
+    }
+
+    fn visit_dollar_env(&mut self, node: &'ast DollarEnv) {
+        self.used.insert(node.0 .0.to_os_string());
+    }
+
+    fn visit_single_quote_string(&mut self, node: &'ast SingleQuoteString) {
+        self.words(&node.0);
+    }
+
+    fn visit_chars(&mut self, node: &'ast Chars) {
+        self.words(&node.0);
+    }
+
+    fn visit_raw_chars(&mut self, node: &'ast RawChars) {
+        self.words(&node.0);
+        let text = node.0.to_string_lossy();
+        if text.contains(['*', '?']) {
+            let message = format!("'{}' is passed as is, rs-shell doesn't expand globs", text);
+            self.warn(
+                Check::Glob,
+                message,
+                vec![Label::new(node.1, "quote this if that's intended")],
+            );
+        }
+    }
+}
+
+/// variables written in capitals are left alone, since they're usually for the shell itself (e.g.
+/// `PS1`) or already in the environment, so assigning them changes what commands see
+fn is_environment_variable(name: &str) -> bool {
+    !name.chars().any(|c| c.is_ascii_lowercase())
+}
+
+/// the text of `arg` if it's the same whatever the shell's state, i.e. it has nothing in it that's
+/// substituted
+fn literal(arg: &Argument) -> Option<OsString> {
+    match arg {
+        Argument::StringLiteral(x) => unquoted(x),
+        Argument::SingleQuoteString(x) => Some(x.0.to_os_string()),
+        _ => None,
+    }
+}
+
+fn literal_command(command: &Command) -> Option<OsString> {
+    match command {
+        Command::StringLiteral(x) => unquoted(x),
+        Command::SingleQuoteString(x) => Some(x.0.to_os_string()),
+        Command::DoubleQuoteString(_) => None,
+    }
+}
+
+fn unquoted(string: &StringLiteral) -> Option<OsString> {
+    let mut out = OsString::new();
+    for component in &string.0 {
+        match component {
+            StringLiteralComponent::RawChars(x) => out.push(&x.0),
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checks(script: &str) -> Vec<(usize, Check)> {
+        let warnings = check("test", script).unwrap();
+        warnings.iter().map(|x| (x.line, x.check)).collect()
+    }
+
+    #[test]
+    fn linting() {
+        assert_eq!(checks("echo *.rs '*.rs' \"a?\"\n"), [(1, Check::Glob)]);
+        assert_eq!(
+            checks("cat f | wc -l\ncat -n f | wc\necho (cat f | wc)\n"),
+            [(1, Check::UselessCat), (3, Check::UselessCat)]
+        );
+        let script = "a=1\nb=2\nc=3\nPATH=x\necho $b\nexport c\nd=4 cmd\n";
+        assert_eq!(checks(script), [(1, Check::UnusedAssignment)]);
+        assert_eq!(
+            checks("sort f > f\nsort < f | uniq >> f\nsort f > g\nsort f; cat > f\n"),
+            [(1, Check::SameFile), (2, Check::SameFile)]
+        );
+
+        let warning = &check("test", "cat x | sort > x\n").unwrap()[1];
+        let labels: Vec<_> = warning
+            .labels()
+            .iter()
+            .map(|x| x.span.slice(&warning.source))
+            .collect();
+        assert_eq!(labels, ["x", "x"]);
+        assert!(matches!(
+            check("test", "a\nb (\n"),
+            Err(ScriptError::Parse { line: 2, .. })
+        ));
+    }
+}
@@ -1,33 +1,347 @@
-use crate::{
-    evaluator::{EvalError, Evaluator},
-    frontend::{Frontend, ReadlineError},
-};
-use clap::Parser;
+use crate::{config::Config, frontend::Frontend, theme::Theme};
+use clap::{Parser, Subcommand};
 use color_eyre::Result;
-use log::{debug, error, info};
-use std::process::ExitCode;
+use cs128h_project::{
+    alias, ast, diagnostic, env,
+    evaluator::{self, Evaluator},
+    jobs, lint, metrics,
+    options::{self, ShellOption},
+    profile,
+    repl::Repl,
+    script, session, shutdown,
+};
+use log::{debug, info, warn};
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
-mod ast;
-mod builtins;
-mod env;
-mod evaluator;
+mod completion;
+mod config;
 mod frontend;
-mod parser;
-mod proc_manager;
+mod osc;
+mod picker;
+mod prompt;
+mod theme;
 
-static LOG_LEVEL_ENV: &'static str = "RS_SHELL_LOG";
-static LOG_STYLE_ENV: &'static str = "RS_SHELL_LOG_STYLE";
+static LOG_LEVEL_ENV: &str = "RS_SHELL_LOG";
+static LOG_STYLE_ENV: &str = "RS_SHELL_LOG_STYLE";
+static RC_ENV: &str = "RS_SHELL_RC";
+static CONFIG_ENV: &str = "RS_SHELL_CONFIG";
+
+/// run by login shells before anything else, for settings shared by every user
+static SYSTEM_PROFILE: &str = "/etc/rsshell/profile";
+/// run by login shells after the system profile, relative to `$HOME`
+static USER_PROFILE: &str = ".rsshell_profile";
+/// run when a login shell exits, relative to `$HOME`
+static USER_LOGOUT: &str = ".rsshell_logout";
 
 #[derive(Debug, Clone, Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    action: Option<Action>,
+
     /// evaluate given expression and then exit
     #[arg(short, long)]
     command: Option<String>,
+
+    /// print commands instead of running them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// only check the script or command for syntax errors, without running any of it
+    #[arg(short = 'n')]
+    no_exec: bool,
+
+    /// report how long each command spends being parsed, expanded, and run
+    #[arg(long)]
+    profile: bool,
+
+    /// count the commands run and how long they take, see the `metrics` builtin
+    #[arg(long)]
+    metrics: bool,
+
+    /// write the metrics to FILE in the Prometheus text format when the shell exits, this implies
+    /// --metrics
+    #[arg(long, value_name = "FILE")]
+    metrics_file: Option<PathBuf>,
+
+    /// run commands without network access and with a read-only filesystem (see $RS_SANDBOX)
+    #[arg(long)]
+    sandbox: bool,
+
+    /// exit as soon as a command fails, like `set -e`
+    #[arg(short = 'e')]
+    errexit: bool,
+
+    /// print commands before running them, like `set -x`
+    #[arg(short = 'x')]
+    xtrace: bool,
+
+    /// treat expanding an unset variable as an error, like `set -u`
+    #[arg(short = 'u')]
+    nounset: bool,
+
+    /// enable the named shell option, like `set -o` or `shopt -s`, can be given more than once
+    #[arg(short = 'o', value_name = "OPTION", value_parser = parse_option)]
+    options: Vec<ShellOption>,
+
+    /// act as a login shell, running the profile files on startup and the logout file on exit,
+    /// this is also the case when invoked with a name starting with '-'
+    #[arg(short, long)]
+    login: bool,
+
+    /// output that suits screen readers and dumb terminals, without colors, escape sequences, the
+    /// prompt being redrawn, or full-screen pickers, this is also the case when $TERM is 'dumb'
+    #[arg(long)]
+    plain: bool,
+
+    /// don't run the rc file before starting an interactive shell (see $RS_SHELL_RC)
+    #[arg(long)]
+    norc: bool,
+
+    /// save the shell's variables, aliases and working directory to FILE when it exits
+    #[arg(long, value_name = "FILE")]
+    save_session: Option<PathBuf>,
+
+    /// restore the variables, aliases and working directory saved to FILE with --save-session
+    /// before running anything, but after the rc file
+    #[arg(long, value_name = "FILE")]
+    restore_session: Option<PathBuf>,
+
+    /// script to run instead of starting an interactive shell, or with --command, the name to give
+    /// it as $0
+    script: Option<PathBuf>,
+
+    /// arguments to pass to the script or command, as $1, $2, ...
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<OsString>,
+}
+
+// things to do with scripts other than running them, as subcommands
+#[derive(Debug, Clone, Subcommand)]
+enum Action {
+    /// reformat scripts in place, or stdin to stdout if none are given, so that quoting, spacing
+    /// and blank lines are consistent
+    Fmt {
+        /// don't change anything, just list the scripts that would be, and fail if there are any
+        #[arg(long)]
+        check: bool,
+
+        scripts: Vec<PathBuf>,
+    },
+
+    /// check scripts, or stdin if none are given, for common mistakes, without running them
+    Lint { scripts: Vec<PathBuf> },
+}
+
+fn parse_option(name: &str) -> Result<ShellOption, String> {
+    ShellOption::from_name(name).ok_or_else(|| {
+        let names = ShellOption::ALL.iter().map(|x| x.name());
+        format!("expected one of: {}", names.collect::<Vec<_>>().join(", "))
+    })
+}
+
+/// parses the command or script `args` asked for, reporting every syntax error in it, or `None`
+/// if it asked for neither
+fn syntax_check(args: &Args) -> Option<ExitCode> {
+    // each error along with the status to exit with because of it
+    let errors: Vec<_> = match (&args.command, &args.script) {
+        (Some(cmd), _) => ast::generate_ast(cmd)
+            .err()
+            .map(|e| (e.to_string(), 2))
+            .into_iter()
+            .collect(),
+        (None, Some(script)) => script::check(script)
+            .into_iter()
+            .map(|e| (e.to_string(), e.exit_code()))
+            .collect(),
+        (None, None) => return None,
+    };
+    for (e, _) in &errors {
+        eprintln!("rs-shell: {}", e);
+    }
+    Some(
+        errors
+            .first()
+            .map_or(ExitCode::SUCCESS, |(_, x)| ExitCode::from(*x)),
+    )
+}
+
+/// formats each of `scripts`, or stdin if there aren't any, see [`script::format`]
+///
+/// with `check`, nothing's written, and the status is 1 if any of them would've been changed
+fn fmt(check: bool, scripts: &[PathBuf]) -> Result<ExitCode> {
+    if scripts.is_empty() {
+        let input = io::read_to_string(io::stdin())?;
+        return Ok(match script::format("<stdin>", &input) {
+            Ok(x) if check => ExitCode::from(u8::from(x != input)),
+            Ok(x) => {
+                print!("{}", x);
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("rs-shell: {}", e);
+                ExitCode::from(e.exit_code())
+            }
+        });
+    }
+
+    let mut code = ExitCode::SUCCESS;
+    for path in scripts {
+        let formatted = script::read(path)
+            .and_then(|x| Ok((script::format(&path.display().to_string(), &x)?, x)));
+        let (formatted, original) = match formatted {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("rs-shell: {}", e);
+                code = ExitCode::from(e.exit_code());
+                continue;
+            }
+        };
+        if formatted == original {
+            continue;
+        }
+        if check {
+            println!("{}", path.display());
+            code = ExitCode::FAILURE;
+        } else if let Err(e) = fs::write(path, formatted) {
+            eprintln!("rs-shell: {}: {}", path.display(), e);
+            code = ExitCode::FAILURE;
+        }
+    }
+    Ok(code)
+}
+
+/// lints each of `scripts`, or stdin if there aren't any, printing what's wrong with them, the
+/// status is 1 if anything is, see [`lint`]
+fn lint(scripts: &[PathBuf]) -> Result<ExitCode> {
+    let inputs = match scripts.is_empty() {
+        true => vec![("<stdin>".to_owned(), Ok(io::read_to_string(io::stdin())?))],
+        false => scripts
+            .iter()
+            .map(|x| (x.display().to_string(), script::read(x)))
+            .collect(),
+    };
+
+    let mut code = ExitCode::SUCCESS;
+    for (name, script) in inputs {
+        let warnings = match script.and_then(|x| lint::check(&name, &x)) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("rs-shell: {}", e);
+                code = ExitCode::from(e.exit_code());
+                continue;
+            }
+        };
+        for warning in &warnings {
+            let rendered = diagnostic::render(warning, &warning.source, warning.line);
+            print!("{}: line {}: {}", name, warning.line, rendered);
+        }
+        if !warnings.is_empty() && code == ExitCode::SUCCESS {
+            code = ExitCode::FAILURE;
+        }
+    }
+    Ok(code)
+}
+
+/// `rsshell` in `$XDG_CONFIG_HOME`, or `~/.config` if that isn't set
+fn config_dir() -> Option<PathBuf> {
+    let config = match env::lookup("XDG_CONFIG_HOME").filter(|x| !x.is_empty()) {
+        Some(x) => PathBuf::from(x),
+        None => PathBuf::from(env::lookup("HOME").filter(|x| !x.is_empty())?).join(".config"),
+    };
+    Some(config.join("rsshell"))
+}
+
+/// the rc file run before the first prompt, `$RS_SHELL_RC` if it's set, otherwise `rsshellrc` in
+/// the [`config_dir`]
+fn rc_path() -> Option<PathBuf> {
+    let rc = env::get(RC_ENV);
+    if !rc.is_empty() {
+        return Some(rc.into());
+    }
+    Some(config_dir()?.join("rsshellrc"))
+}
+
+/// loads the config file, `$RS_SHELL_CONFIG` if it's set, otherwise `config.toml` in the
+/// [`config_dir`], like the rc file, it not existing is only an error if it was asked for
+///
+/// a config file that can't be used is reported and the defaults are used instead
+fn load_config() -> Config {
+    let explicit = env::get(CONFIG_ENV);
+    let path = match explicit.is_empty() {
+        true => match config_dir() {
+            Some(x) => x.join("config.toml"),
+            None => return Config::default(),
+        },
+        false => PathBuf::from(explicit),
+    };
+    if env::get(CONFIG_ENV).is_empty() && !path.exists() {
+        return Config::default();
+    }
+    debug!("loading config file '{}'", path.display());
+    Config::load(&path).unwrap_or_else(|e| {
+        eprintln!("rs-shell: {}: {}", path.display(), e);
+        Config::default()
+    })
+}
+
+/// runs the rc file, it not existing is only an error if it was asked for with `$RS_SHELL_RC`
+fn run_rc(evaluator: &mut Evaluator) {
+    if let Some(path) = rc_path() {
+        run_startup_file(evaluator, &path, !env::get(RC_ENV).is_empty());
+    }
+}
+
+/// `name` in the user's home directory, if they have one
+fn home_file(name: &str) -> Option<PathBuf> {
+    let home = env::lookup("HOME").filter(|x| !x.is_empty())?;
+    Some(PathBuf::from(home).join(name))
+}
+
+/// runs the file at `path` in the shell itself, like `source` would, it not existing is only
+/// reported if it's `required`
+fn run_startup_file(evaluator: &mut Evaluator, path: &Path, required: bool) {
+    if !required && !path.exists() {
+        return;
+    }
+    debug!("running startup file '{}'", path.display());
+    if let Err(e) = script::run(evaluator, path) {
+        eprintln!("rs-shell: {}", e);
+    }
+}
+
+/// restores the session saved to `path`, reporting anything that couldn't be restored
+fn restore_session(path: &Path) {
+    debug!("restoring session from '{}'", path.display());
+    match session::restore(path) {
+        Ok(problems) => {
+            for problem in problems {
+                eprintln!("rs-shell: {}: {}", path.display(), problem);
+            }
+        }
+        Err(e) => eprintln!("rs-shell: {}", e),
+    }
+}
+
+/// whether the shell was started as a login shell, either with `--login` or by `login(1)` and
+/// friends, which put a '-' in front of its name
+fn is_login(args: &Args) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    args.login
+        || std::env::args_os()
+            .next()
+            .is_some_and(|x| x.as_bytes().starts_with(b"-"))
 }
 
 fn main() -> Result<ExitCode> {
     color_eyre::install()?;
+    shutdown::install_panic_hook();
     env_logger::init_from_env(
         env_logger::Env::new()
             .filter_or(LOG_LEVEL_ENV, "warn")
@@ -36,54 +350,172 @@ fn main() -> Result<ExitCode> {
     info!("global logger initalized");
 
     let args = Args::parse();
+    match &args.action {
+        Some(Action::Fmt { check, scripts }) => return fmt(*check, scripts),
+        Some(Action::Lint { scripts }) => return lint(scripts),
+        None => (),
+    }
+    env::init();
+
+    let mut config = load_config();
+    config.plain |= args.plain || env::get("TERM") == "dumb";
+    if config.plain {
+        config.theme = Theme::builtin("plain").expect("the plain theme is built in");
+        diagnostic::set_plain(true);
+    }
+    for opt in &config.options {
+        options::set(*opt, true);
+    }
+    for (name, value) in &config.aliases {
+        if let Err(e) = alias::set(name.clone(), value.clone()) {
+            eprintln!("rs-shell: config: {}", e);
+        }
+    }
+    // errors are only colored when they're going straight to a terminal
+    if io::stderr().is_terminal() {
+        diagnostic::set_style(config.theme.diagnostic.clone());
+    }
+    config::init(config);
+    let flags = [
+        (args.dry_run, ShellOption::DryRun),
+        (args.sandbox, ShellOption::Sandbox),
+        (args.errexit, ShellOption::Errexit),
+        (args.xtrace, ShellOption::Xtrace),
+        (args.nounset, ShellOption::Nounset),
+    ];
+    for (_, opt) in flags.into_iter().filter(|(x, _)| *x) {
+        options::set(opt, true);
+    }
+    for opt in &args.options {
+        options::set(*opt, true);
+    }
+    if args.profile {
+        profile::enable();
+    }
+    if args.metrics || args.metrics_file.is_some() {
+        metrics::enable();
+    }
+
+    if args.no_exec {
+        match syntax_check(&args) {
+            Some(code) => return Ok(code),
+            None => warn!("-n only applies to scripts and commands, ignoring it"),
+        }
+    }
 
     let mut evaluator = Evaluator::new();
     debug!("constructed evaluator singleton");
 
-    if let Some(cmd) = args.command {
-        let ast = ast::generate_ast(&cmd)?;
-        return Ok(ExitCode::from(match evaluator.eval(ast)? {
-            subprocess::ExitStatus::Exited(x) => x as u8,
-            subprocess::ExitStatus::Signaled(x) => x,
-            subprocess::ExitStatus::Other(x) => x as u8,
-            subprocess::ExitStatus::Undetermined => u8::MAX,
-        }));
+    if let Err(e) = jobs::start_reaper() {
+        warn!("unable to start background job reaper: {}", e);
     }
 
-    let mut frontend = Frontend::new()?;
-    debug!("constructed frontend singleton");
+    let login = is_login(&args);
+    if login {
+        run_startup_file(&mut evaluator, Path::new(SYSTEM_PROFILE), false);
+        if let Some(path) = home_file(USER_PROFILE) {
+            run_startup_file(&mut evaluator, &path, false);
+        }
+    }
 
-    loop {
-        let input = match frontend.readline() {
-            Ok(x) => x,
-            Err(e) => match e {
-                ReadlineError::Eof => break,
-                _ => return Err(e.into()),
-            },
-        };
-        debug!("read line from user: '{}'", input);
-        let ast = match ast::generate_ast(&input) {
+    if let Some(path) = args.save_session.clone() {
+        shutdown::on_shutdown(move || {
+            if let Err(e) = session::save(&path) {
+                eprintln!("rs-shell: unable to save session: {}", e);
+            }
+        });
+    }
+
+    if let Some(path) = args.metrics_file.clone() {
+        shutdown::on_shutdown(move || {
+            let Some(metrics) = metrics::snapshot() else {
+                return;
+            };
+            if let Err(e) = fs::write(&path, metrics.prometheus()) {
+                eprintln!("rs-shell: unable to write metrics: {}", e);
+            }
+        });
+    }
+
+    let code = run(args, &mut evaluator);
+
+    if login {
+        if let Some(path) = home_file(USER_LOGOUT) {
+            run_startup_file(&mut evaluator, &path, false);
+        }
+    }
+    shutdown::run();
+    code
+}
+
+/// does whatever `args` asked for, be that running a command, a script, or the REPL, returning the
+/// status to exit with
+fn run(args: Args, evaluator: &mut Evaluator) -> Result<ExitCode> {
+    let interactive = args.command.is_none() && args.script.is_none();
+    if let Some(path) = args.restore_session.as_deref().filter(|_| !interactive) {
+        restore_session(path);
+    }
+
+    if let Some(cmd) = args.command {
+        // like `sh -c`, the first argument after the command is its $0, the rest are $1, $2, ...
+        if let Some(name) = args.script {
+            let mut params = vec![name.into_os_string()];
+            params.extend(args.args);
+            env::set_positional(params);
+        }
+        env::set_line(1);
+        // like a line of a script that doesn't parse, this counts as a command exiting with 2
+        let ast = match ast::generate_ast(&cmd) {
             Ok(x) => x,
             Err(e) => {
-                error!("{}", e);
-                continue;
+                eprintln!("rs-shell: {}", e);
+                return Ok(ExitCode::from(2));
             }
         };
-        debug!("successful AST generation");
-        if let Err(e) = evaluator.eval(ast) {
-            match e {
-                EvalError::InvalidEnvValue { name, value } => {
-                    error!(
-                        "environment variable '{}' is not valid UTF-8: {}",
-                        name, value
-                    );
-                }
-                EvalError::DispatchError { internal } => {
-                    error!("error dispatching command:\n{}", internal);
-                }
+        let report = &mut |e| evaluator::report_error(e, &cmd, 1);
+        if let Err(e) = evaluator.eval_reporting(ast, report) {
+            report(e);
+        }
+        profile::report("-c");
+        return Ok(ExitCode::from(evaluator::exit_code(
+            evaluator.last_status(),
+        )));
+    }
+
+    if let Some(script) = args.script {
+        let mut params = vec![script.clone().into_os_string()];
+        params.extend(args.args);
+        env::set_positional(params);
+        return Ok(match script::run(evaluator, &script) {
+            Ok(status) => ExitCode::from(evaluator::exit_code(status)),
+            Err(e) => {
+                eprintln!("rs-shell: {}", e);
+                ExitCode::from(e.exit_code())
             }
+        });
+    }
+
+    let frontend = Frontend::new()?;
+    debug!("constructed frontend singleton");
+
+    if let Err(e) = jobs::init() {
+        warn!("unable to enable job control: {}", e);
+    }
+    shutdown::on_shutdown(jobs::hangup_on_exit);
+
+    if !args.norc {
+        run_rc(evaluator);
+        if evaluator.exit_requested() {
+            return Ok(ExitCode::from(evaluator::exit_code(
+                evaluator.last_status(),
+            )));
         }
     }
+    if let Some(path) = &args.restore_session {
+        restore_session(path);
+    }
+
+    let status = Repl::new(Box::new(frontend), Box::new(io::stderr())).run(evaluator)?;
     info!("REPL loop exited without error, exiting");
-    Ok(ExitCode::SUCCESS)
+    Ok(ExitCode::from(evaluator::exit_code(status)))
 }
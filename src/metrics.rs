@@ -0,0 +1,282 @@
+//! counts of what the shell's been doing, for when it's used to drive automation, collected once
+//! it's started with `--metrics` (or [`enable`] is called), and shown by the `metrics` builtin, or
+//! in the Prometheus text format with `metrics -p` or `--metrics-file`
+//!
+//! what's counted is every command that's run, builtin or external, every command line that
+//! fails to parse, and how long each command took, as a histogram per command name, a pipeline
+//! takes as long as its slowest command, so each of its commands is counted as taking that long,
+//! and commands left running in the background aren't timed at all
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// the upper bounds of the histogram buckets, in seconds, the same as Prometheus client libraries
+/// default to
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// whether a command was run by the shell itself or as a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Builtin,
+    External,
+}
+
+/// how long every run of a command took
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// how many runs took at most each of [`BUCKETS`], not counting the runs in smaller buckets
+    buckets: [u64; BUCKETS.len()],
+    pub count: u64,
+    pub sum: Duration,
+    pub max: Duration,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|x| seconds <= *x) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += duration;
+        self.max = self.max.max(duration);
+    }
+}
+
+/// everything that's been counted so far
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    pub builtins: u64,
+    pub externals: u64,
+    pub parse_failures: u64,
+    /// command lines whose AST was found in the parse cache, see `parse_cache`
+    pub ast_cache_hits: u64,
+    pub ast_cache_misses: u64,
+    /// keyed by the name the command was run as
+    pub durations: BTreeMap<String, Histogram>,
+}
+
+/// the metrics collected so far, `None` unless they've been enabled
+fn current() -> &'static Mutex<Option<Metrics>> {
+    static CURRENT: OnceLock<Mutex<Option<Metrics>>> = OnceLock::new();
+    CURRENT.get_or_init(|| Mutex::new(None))
+}
+
+/// starts collecting metrics, this can't be undone
+pub fn enable() {
+    current().lock().get_or_insert_with(Metrics::default);
+}
+
+pub fn enabled() -> bool {
+    current().lock().is_some()
+}
+
+/// a copy of the metrics collected so far, if they're enabled
+pub fn snapshot() -> Option<Metrics> {
+    current().lock().clone()
+}
+
+/// starts over from nothing, if metrics are enabled
+pub fn reset() {
+    if let Some(metrics) = current().lock().as_mut() {
+        *metrics = Metrics::default();
+    }
+}
+
+/// counts a command line that couldn't be parsed
+pub fn parse_failed() {
+    if let Some(metrics) = current().lock().as_mut() {
+        metrics.parse_failures += 1;
+    }
+}
+
+/// counts a lookup in the parse cache, and whether the command line was found there
+pub fn ast_cache(hit: bool) {
+    if let Some(metrics) = current().lock().as_mut() {
+        match hit {
+            true => metrics.ast_cache_hits += 1,
+            false => metrics.ast_cache_misses += 1,
+        }
+    }
+}
+
+/// counts the commands of a stage that's been run, along with how long it took if it was waited
+/// for
+pub fn record(commands: Vec<(String, Kind)>, took: Option<Duration>) {
+    let mut guard = current().lock();
+    let Some(metrics) = guard.as_mut() else {
+        return;
+    };
+    for (name, kind) in commands {
+        match kind {
+            Kind::Builtin => metrics.builtins += 1,
+            Kind::External => metrics.externals += 1,
+        }
+        if let Some(took) = took {
+            metrics.durations.entry(name).or_default().observe(took);
+        }
+    }
+}
+
+/// when the stage that's about to run started, if metrics are enabled, see [`record`]
+pub fn start() -> Option<Instant> {
+    enabled().then(Instant::now)
+}
+
+impl Metrics {
+    /// a summary for people to read, as shown by the `metrics` builtin
+    pub fn summary(&self) -> String {
+        let total = self.builtins + self.externals;
+        let ratio = match total {
+            0 => 0.0,
+            _ => self.builtins as f64 / total as f64 * 100.0,
+        };
+        let mut out = format!(
+            "commands: {} ({} builtin, {} external, {:.0}% builtin)\nparse failures: {}\nast cache: {} hits, {} misses\n",
+            total,
+            self.builtins,
+            self.externals,
+            ratio,
+            self.parse_failures,
+            self.ast_cache_hits,
+            self.ast_cache_misses
+        );
+        if self.durations.is_empty() {
+            return out;
+        }
+        out.push_str("\nruns\t    mean\t     max\tcommand\n");
+        for (name, histogram) in &self.durations {
+            let mean = histogram.sum / histogram.count.max(1) as u32;
+            let _ = writeln!(
+                out,
+                "{:>4}\t{:>8.3}\t{:>8.3}\t{}",
+                histogram.count,
+                mean.as_secs_f64(),
+                histogram.max.as_secs_f64(),
+                name
+            );
+        }
+        out
+    }
+
+    /// the metrics in the Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP rs_shell_commands_total Commands run, by whether they're builtins.\n");
+        out.push_str("# TYPE rs_shell_commands_total counter\n");
+        let _ = writeln!(
+            out,
+            "rs_shell_commands_total{{kind=\"builtin\"}} {}",
+            self.builtins
+        );
+        let _ = writeln!(
+            out,
+            "rs_shell_commands_total{{kind=\"external\"}} {}",
+            self.externals
+        );
+        out.push_str("# HELP rs_shell_parse_failures_total Command lines that failed to parse.\n");
+        out.push_str("# TYPE rs_shell_parse_failures_total counter\n");
+        let _ = writeln!(out, "rs_shell_parse_failures_total {}", self.parse_failures);
+        out.push_str("# HELP rs_shell_ast_cache_total Parse cache lookups, by whether they hit.\n");
+        out.push_str("# TYPE rs_shell_ast_cache_total counter\n");
+        let _ = writeln!(
+            out,
+            "rs_shell_ast_cache_total{{result=\"hit\"}} {}",
+            self.ast_cache_hits
+        );
+        let _ = writeln!(
+            out,
+            "rs_shell_ast_cache_total{{result=\"miss\"}} {}",
+            self.ast_cache_misses
+        );
+        out.push_str(
+            "# HELP rs_shell_command_duration_seconds How long commands took, by command name.\n",
+        );
+        out.push_str("# TYPE rs_shell_command_duration_seconds histogram\n");
+        for (name, histogram) in &self.durations {
+            let name = label(name);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "rs_shell_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    name, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "rs_shell_command_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                name, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "rs_shell_command_duration_seconds_sum{{command=\"{}\"}} {}",
+                name,
+                histogram.sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "rs_shell_command_duration_seconds_count{{command=\"{}\"}} {}",
+                name, histogram.count
+            );
+        }
+        out
+    }
+}
+
+/// escapes `value` for use as a label value
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition() {
+        let mut metrics = Metrics {
+            builtins: 1,
+            externals: 2,
+            parse_failures: 3,
+            ast_cache_hits: 4,
+            ..Default::default()
+        };
+        let histogram = metrics.durations.entry("a\"b".to_owned()).or_default();
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(60));
+
+        let out = metrics.prometheus();
+        assert!(out.contains("rs_shell_commands_total{kind=\"external\"} 2\n"));
+        assert!(out.contains("rs_shell_parse_failures_total 3\n"));
+        assert!(out.contains("rs_shell_ast_cache_total{result=\"hit\"} 4\n"));
+        assert!(out.contains("rs_shell_ast_cache_total{result=\"miss\"} 0\n"));
+        let bucket = |le: &str, n: u64| {
+            format!(
+                "rs_shell_command_duration_seconds_bucket{{command=\"a\\\"b\",le=\"{}\"}} {}\n",
+                le, n
+            )
+        };
+        assert!(out.contains(&bucket("0.01", 0)), "{}", out);
+        assert!(out.contains(&bucket("0.025", 1)), "{}", out);
+        assert!(out.contains(&bucket("10", 1)), "{}", out);
+        assert!(out.contains(&bucket("+Inf", 2)), "{}", out);
+        assert!(out.contains("rs_shell_command_duration_seconds_sum{command=\"a\\\"b\"} 60.02\n"));
+
+        assert!(metrics
+            .summary()
+            .starts_with("commands: 3 (1 builtin, 2 external, 33% builtin)\nparse failures: 3\n"));
+    }
+}
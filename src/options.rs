@@ -0,0 +1,188 @@
+use std::{collections::HashSet, sync::OnceLock};
+
+use parking_lot::RwLock;
+
+/// a shell option that can be toggled at runtime with `set -o` or `shopt`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ShellOption {
+    /// typing the path of a directory as a command `cd`s into it
+    AutoCd,
+    /// offer to run the closest match when a command isn't found
+    Correct,
+    /// report background jobs finishing as soon as they do rather than at the next prompt
+    Notify,
+    /// a pipeline fails if any of its commands do, rather than only if the last one does
+    Pipefail,
+    /// print commands, fully expanded, instead of running them
+    DryRun,
+    /// stop running a command line as soon as one of its commands fails
+    Errexit,
+    /// print commands, fully expanded, to stderr before running them
+    Xtrace,
+    /// treat expanding a variable that isn't set as an error
+    Nounset,
+    /// run commands with restricted access to the system, see [`crate::sandbox`]
+    Sandbox,
+    /// refuse to overwrite existing files with `>` redirects
+    Noclobber,
+    /// include what commands write to stderr in the output of command substitutions
+    CaptureStderr,
+    /// send `SIGHUP` to every running job when the shell exits, rather than leaving them running
+    HupOnExit,
+    /// load the variables in the nearest allowed `.rsshell-env` or `.envrc`, and the aliases in the
+    /// nearest `.rsshellrc.local`, on `cd`, see [`crate::direnv`]
+    Direnv,
+    /// keep a copy of what the last foreground command wrote to stdout, for `copyout`, see
+    /// [`crate::last_output`]
+    KeepOutput,
+    /// show the output of foreground commands in a pager when it doesn't fit on the screen, see
+    /// [`crate::pager`]
+    AutoPage,
+}
+
+impl ShellOption {
+    pub const ALL: &'static [ShellOption] = &[
+        ShellOption::AutoCd,
+        ShellOption::Correct,
+        ShellOption::Notify,
+        ShellOption::Pipefail,
+        ShellOption::DryRun,
+        ShellOption::Errexit,
+        ShellOption::Xtrace,
+        ShellOption::Nounset,
+        ShellOption::Sandbox,
+        ShellOption::Noclobber,
+        ShellOption::CaptureStderr,
+        ShellOption::HupOnExit,
+        ShellOption::Direnv,
+        ShellOption::KeepOutput,
+        ShellOption::AutoPage,
+    ];
+
+    /// the name used to refer to this option in `set -o` and `shopt`
+    pub fn name(self) -> &'static str {
+        match self {
+            ShellOption::AutoCd => "autocd",
+            ShellOption::Correct => "correct",
+            ShellOption::Notify => "notify",
+            ShellOption::Pipefail => "pipefail",
+            ShellOption::DryRun => "dryrun",
+            ShellOption::Errexit => "errexit",
+            ShellOption::Xtrace => "xtrace",
+            ShellOption::Nounset => "nounset",
+            ShellOption::Sandbox => "sandbox",
+            ShellOption::Noclobber => "noclobber",
+            ShellOption::CaptureStderr => "capturestderr",
+            ShellOption::HupOnExit => "huponexit",
+            ShellOption::Direnv => "direnv",
+            ShellOption::KeepOutput => "keepoutput",
+            ShellOption::AutoPage => "autopage",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|x| x.name() == name)
+    }
+
+    /// whether this option is managed by `shopt` (as opposed to `set -o`)
+    pub fn is_shopt(self) -> bool {
+        match self {
+            ShellOption::AutoCd
+            | ShellOption::Correct
+            | ShellOption::CaptureStderr
+            | ShellOption::HupOnExit
+            | ShellOption::Direnv
+            | ShellOption::KeepOutput
+            | ShellOption::AutoPage => true,
+            ShellOption::Notify
+            | ShellOption::Pipefail
+            | ShellOption::DryRun
+            | ShellOption::Errexit
+            | ShellOption::Xtrace
+            | ShellOption::Nounset
+            | ShellOption::Sandbox
+            | ShellOption::Noclobber => false,
+        }
+    }
+
+    /// the single letter flag that toggles this option with `set -x`/`set +x`, if any
+    pub fn flag(self) -> Option<char> {
+        match self {
+            ShellOption::Notify => Some('b'),
+            ShellOption::Errexit => Some('e'),
+            ShellOption::Xtrace => Some('x'),
+            ShellOption::Nounset => Some('u'),
+            ShellOption::Noclobber => Some('C'),
+            ShellOption::AutoCd
+            | ShellOption::Correct
+            | ShellOption::Pipefail
+            | ShellOption::DryRun
+            | ShellOption::Sandbox
+            | ShellOption::CaptureStderr
+            | ShellOption::HupOnExit
+            | ShellOption::Direnv
+            | ShellOption::KeepOutput
+            | ShellOption::AutoPage => None,
+        }
+    }
+
+    pub fn from_flag(flag: char) -> Option<Self> {
+        Self::ALL.iter().copied().find(|x| x.flag() == Some(flag))
+    }
+}
+
+fn options() -> &'static RwLock<HashSet<ShellOption>> {
+    static OPTIONS: OnceLock<RwLock<HashSet<ShellOption>>> = OnceLock::new();
+    OPTIONS.get_or_init(|| RwLock::new(HashSet::new()))
+}
+
+pub fn enabled(opt: ShellOption) -> bool {
+    #[cfg(test)]
+    if let Some(value) = OVERRIDES.with(|x| x.borrow().get(&opt).copied()) {
+        return value;
+    }
+    options().read().contains(&opt)
+}
+
+/// turning off a [`locked`] option does nothing
+pub fn set(opt: ShellOption, value: bool) {
+    #[cfg(test)]
+    if OVERRIDES.with(|x| x.borrow().contains_key(&opt)) && !locked(opt) {
+        OVERRIDES.with(|x| x.borrow_mut().insert(opt, value));
+        return;
+    }
+    if value {
+        if opt == ShellOption::Sandbox {
+            crate::sandbox::lock();
+        }
+        options().write().insert(opt);
+    } else if !locked(opt) {
+        options().write().remove(&opt);
+    }
+}
+
+/// whether `opt` is on and can't be turned off again, which is only ever the case for `sandbox`,
+/// so nothing run in the sandbox can get out of it
+pub fn locked(opt: ShellOption) -> bool {
+    opt == ShellOption::Sandbox && enabled(opt)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// options overridden for the test running on this thread, see [`with`]
+    static OVERRIDES: std::cell::RefCell<std::collections::HashMap<ShellOption, bool>> =
+        Default::default();
+}
+
+/// runs `f` with `opt` set to `value` on this thread only, so a test can enable an option without
+/// affecting the tests running alongside it, [`set`] only changes the override while `f` runs
+#[cfg(test)]
+pub(crate) fn with<T>(opt: ShellOption, value: bool, f: impl FnOnce() -> T) -> T {
+    let old = OVERRIDES.with(|x| x.borrow_mut().insert(opt, value));
+    let result = f();
+    OVERRIDES.with(|x| match old {
+        Some(old) => x.borrow_mut().insert(opt, old),
+        None => x.borrow_mut().remove(&opt),
+    });
+    result
+}
@@ -0,0 +1,97 @@
+//! terminal integration escape sequences
+//!
+//! these are operating system commands (OSCs) understood by modern terminal emulators, terminals
+//! that don't support a given sequence are expected to silently ignore it
+
+use std::{
+    io::{self, IsTerminal, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use crate::config;
+
+/// string terminator that ends an OSC sequence
+const ST: &str = "\x1b\\";
+
+/// OSC 133 A, marks the start of the prompt
+pub const PROMPT_START: &str = "\x1b]133;A\x1b\\";
+
+/// OSC 133 B, marks the end of the prompt and the start of user input
+pub const PROMPT_END: &str = "\x1b]133;B\x1b\\";
+
+/// OSC 133 C, marks the end of user input and the start of the command's output
+pub const OUTPUT_START: &str = "\x1b]133;C\x1b\\";
+
+/// OSC 133 D, marks the end of a command's output along with its exit status
+pub fn command_finished(status: u8) -> String {
+    format!("\x1b]133;D;{}{}", status, ST)
+}
+
+/// OSC 7, reports the current working directory to the terminal so that new tabs and splits can
+/// open in the same directory
+pub fn cwd(path: &Path) -> String {
+    let host = nix::unistd::gethostname()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!(
+        "\x1b]7;file://{}{}{}",
+        host,
+        percent_encode(path.as_os_str().as_bytes()),
+        ST
+    )
+}
+
+/// OSC 777, a desktop notification, which the terminal decides whether to show (e.g. some only
+/// show them when they aren't focused), control characters would end it early so they're taken
+/// out, as are `;`s in the title, which would be taken as the start of the body
+pub fn notify(title: &str, body: &str) -> String {
+    let title = title.replace(|c: char| c.is_control() || c == ';', "");
+    let body = body.replace(char::is_control, " ");
+    format!("\x1b]777;notify;{};{}{}", title, body, ST)
+}
+
+/// writes `seq` to stdout if, and only if, stdout is a terminal, and the shell isn't in plain mode
+/// (see [`config::Config::plain`]), where a screen reader or dumb terminal could end up
+/// reading it out
+pub fn emit(seq: &str) {
+    let mut stdout = io::stdout();
+    if stdout.is_terminal() && !config::get().plain {
+        // failing to report to the terminal is never worth interrupting the user over
+        let _ = stdout
+            .write_all(seq.as_bytes())
+            .and_then(|_| stdout.flush());
+    }
+}
+
+/// percent-encodes everything except unreserved URI characters and `/`
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_encoding() {
+        assert_eq!(percent_encode(b"/home/me/my dir"), "/home/me/my%20dir");
+        assert_eq!(percent_encode("/tmp/café".as_bytes()), "/tmp/caf%C3%A9");
+    }
+
+    #[test]
+    fn notifications() {
+        assert_eq!(
+            notify("a;b", "make;\x1b\necho done"),
+            "\x1b]777;notify;ab;make;  echo done\x1b\\"
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! paging of long output, with the `autopage` option enabled, what a foreground command run at a
+//! terminal writes to stdout is held back until there's more of it than fits on the screen, and if
+//! there is, once the command's finished it's all shown in `$PAGER` (`less -R` if that isn't set),
+//! so that tools without a pager of their own stay readable, output that fits is written out as
+//! soon as the command's done
+//!
+//! like with `keepoutput`, the command writes to a pipe rather than the terminal, which programs
+//! that take over the screen don't cope with, so the ones named in `$RS_SHELL_NOPAGE` (a `:`
+//! separated list, with common editors, pagers, and the like by default) are left alone
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::OwnedFd,
+    path::Path,
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+
+use crate::{env, evaluator::FlattenedCmdline, terminal};
+
+/// the commands that are never paged, rather than [`DEFAULT_NOPAGE`]
+const NOPAGE_VAR: &str = "RS_SHELL_NOPAGE";
+
+/// commands that take over the terminal, or page their own output
+const DEFAULT_NOPAGE: &str =
+    "vi:vim:nvim:nano:emacs:less:more:most:man:top:htop:btop:ssh:tmux:screen:fzf:watch";
+
+/// the pager used if `$PAGER` isn't set
+const DEFAULT_PAGER: &[&str] = &["less", "-R"];
+
+/// once this much output's been held back it's written out instead, so a command that carries on
+/// writing for a long time doesn't use up all the memory
+const MAX_HELD: usize = 16 * 1024 * 1024;
+
+/// how long to wait for the rest of a command's output once it's finished, like for
+/// [`crate::last_output`], anything still writing after that is passed straight through
+const FINISH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// whether a command called `name` (or a path to one) should be paged
+pub fn pages(name: &str) -> bool {
+    let name = Path::new(name)
+        .file_name()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let nopage = env::lookup(NOPAGE_VAR)
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_else(|| DEFAULT_NOPAGE.to_owned());
+    !nopage.split(':').any(|x| x == name)
+}
+
+/// the output that's been held back so far
+#[derive(Debug, Default)]
+struct Held {
+    output: Vec<u8>,
+    /// how many rows of the screen the output would take up
+    rows: usize,
+    /// how far along the last of those rows it's got
+    column: usize,
+    /// whether there's too much for one screen, so it's going to be paged
+    overflowed: bool,
+    /// whether output's being written out as it comes rather than held, once there's been too much
+    /// of it or the command's been given up on waiting for
+    passthrough: bool,
+}
+
+impl Held {
+    /// adds `data`, keeping track of how much of a `rows` by `cols` screen it'd fill
+    fn add(&mut self, data: &[u8], (rows, cols): (usize, usize)) {
+        self.output.extend_from_slice(data);
+        for &byte in data {
+            match byte {
+                b'\n' => {
+                    self.rows += 1;
+                    self.column = 0;
+                }
+                // only the first byte of each character takes up a column
+                x if x & 0xc0 != 0x80 => {
+                    self.column += 1;
+                    if self.column > cols {
+                        self.rows += 1;
+                        self.column = 1;
+                    }
+                }
+                _ => (),
+            }
+        }
+        // a row is left for the prompt
+        self.overflowed |= self.rows >= rows.saturating_sub(1).max(1);
+    }
+
+    /// writes out everything that's been held back
+    fn flush(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&self.output).and_then(|_| stdout.flush());
+        self.output = Vec::new();
+    }
+}
+
+/// holds back what's written to a pipe until the command writing to it finishes, see the
+/// [module docs](self)
+#[derive(Debug)]
+pub struct Pager {
+    held: Arc<Mutex<Held>>,
+    done: mpsc::Receiver<()>,
+}
+
+impl Pager {
+    /// starts holding back output, returning the file the command should write its stdout to
+    pub fn start() -> io::Result<(Self, File)> {
+        let (mut reader, writer) = io::pipe()?;
+        let held = Arc::new(Mutex::new(Held::default()));
+        let (finished, done) = mpsc::channel();
+        let size = terminal::size();
+        let shared = held.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            loop {
+                let len = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(x) => x,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                };
+                let mut held = shared.lock();
+                held.add(&buf[..len], size);
+                if held.passthrough || held.output.len() > MAX_HELD {
+                    held.passthrough = true;
+                    held.flush();
+                }
+            }
+            let mut held = shared.lock();
+            if !held.overflowed {
+                held.flush();
+            }
+            let _ = finished.send(());
+        });
+        Ok((Self { held, done }, File::from(OwnedFd::from(writer))))
+    }
+
+    /// waits (for a while, see [`FINISH_TIMEOUT`]) for the command to close its stdout, and returns
+    /// its output if it needs paging, otherwise it's been written out already
+    pub fn finish(self) -> Option<Vec<u8>> {
+        let finished = self.done.recv_timeout(FINISH_TIMEOUT).is_ok();
+        let mut held = self.held.lock();
+        if !finished {
+            held.passthrough = true;
+            held.flush();
+            return None;
+        }
+        match held.overflowed && !held.passthrough {
+            true => Some(std::mem::take(&mut held.output)),
+            false => None,
+        }
+    }
+}
+
+/// the command to page output with, from `$PAGER`, which is split on whitespace, or
+/// [`DEFAULT_PAGER`]
+pub fn command() -> FlattenedCmdline {
+    let value = env::get("PAGER").to_string_lossy().into_owned();
+    let mut words = match value.split_whitespace().collect::<Vec<_>>() {
+        x if x.is_empty() => DEFAULT_PAGER.to_vec(),
+        x => x,
+    }
+    .into_iter()
+    .map(Into::into);
+    FlattenedCmdline {
+        envs: Vec::new(),
+        command: words.next().unwrap_or_default(),
+        command_span: None,
+        arguments: words.collect(),
+        redirects: Vec::new(),
+        next: None,
+        background: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn screenfuls() {
+        let mut held = Held::default();
+        held.add(b"one\ntwo\n", (4, 10));
+        assert!(!held.overflowed);
+        // wrapped lines take up more than one row, characters only take up one column each
+        held.add("ééééé12345678".as_bytes(), (4, 10));
+        assert_eq!((held.rows, held.column), (3, 3));
+        assert!(held.overflowed);
+
+        assert!(pages("git"));
+        assert!(!pages("/usr/bin/vim"));
+    }
+}
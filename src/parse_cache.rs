@@ -0,0 +1,104 @@
+//! the ASTs of recently read command lines, so that running one again (say, from the history)
+//! doesn't parse it all over again, only lines read by the REPL go through here, and only short
+//! ones, copying a cached AST out takes time (and stack) in proportion to its size too
+
+use std::{collections::HashMap, sync::OnceLock};
+
+use parking_lot::Mutex;
+
+use crate::{
+    ast::{self, AstError, Main},
+    metrics,
+};
+
+/// how many command lines are kept, the least recently used one makes room for another
+const CAPACITY: usize = 128;
+/// the longest command line that's kept, in bytes
+const MAX_LEN: usize = 4096;
+
+#[derive(Debug)]
+struct Cache {
+    capacity: usize,
+    entries: HashMap<String, Entry>,
+    /// ticks on every lookup, so entries can be ordered by when they were last used
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    ast: Main<'static>,
+    used: u64,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, input: &str) -> Option<Main<'static>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(input)?;
+        entry.used = self.clock;
+        Some(entry.ast.clone())
+    }
+
+    fn insert(&mut self, input: &str, ast: Main<'static>) {
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(input) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, x)| x.used)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        let used = self.clock;
+        self.entries.insert(input.to_owned(), Entry { ast, used });
+    }
+}
+
+fn cache() -> &'static Mutex<Cache> {
+    static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(Cache::new(CAPACITY)))
+}
+
+/// parses `input` like [`ast::generate_ast`], unless it was parsed recently, lines that fail to
+/// parse aren't kept, so they're reported (and counted as failures) every time
+pub fn parse(input: &str) -> Result<Main<'_>, AstError> {
+    if input.len() > MAX_LEN {
+        return ast::generate_ast(input);
+    }
+    let cached = cache().lock().get(input);
+    metrics::ast_cache(cached.is_some());
+    if let Some(ast) = cached {
+        return Ok(ast);
+    }
+    let ast = ast::generate_ast(input)?;
+    cache().lock().insert(input, ast.clone().into_owned());
+    Ok(ast)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used() {
+        let mut cache = Cache::new(2);
+        let ast = |x: &str| ast::generate_ast(x).unwrap().into_owned();
+        cache.insert("a", ast("a"));
+        cache.insert("b", ast("b"));
+        assert_eq!(cache.get("a"), Some(ast("a")));
+        cache.insert("c", ast("c"));
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("a"), Some(ast("a")));
+        assert_eq!(cache.get("c"), Some(ast("c")));
+        assert_eq!(parse("echo hi").unwrap(), ast("echo hi"));
+        assert_eq!(parse("echo hi").unwrap(), ast("echo hi"));
+    }
+}
@@ -0,0 +1,318 @@
+//! finding commands in `$PATH`, each directory's executables are indexed the first time they're
+//! needed and shared by command lookup, completion and typo correction, so none of them have to
+//! read every directory again each time, a directory is only read again once its modification time
+//! changes, and the whole index is rebuilt when `$PATH` changes
+//!
+//! the names of every command, which typo correction and completion go through, are only collected
+//! again once `$PATH` changes, `hash -r` is run, or a lookup finds a directory's changed, so a
+//! mistyped command doesn't read every directory of `$PATH` each time
+//!
+//! on top of that, where each command was found is remembered in a hash table, as shown by the
+//! `hash` builtin
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::{OsStr, OsString},
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::SystemTime,
+};
+
+use log::debug;
+use parking_lot::Mutex;
+
+use crate::env;
+
+/// a command remembered by the hash table
+#[derive(Debug, Clone)]
+pub struct HashEntry {
+    pub path: PathBuf,
+    /// how many times the command has been looked up since it was remembered
+    pub hits: usize,
+}
+
+/// remembers where commands were found in `$PATH` so repeated lookups don't have to walk it
+/// again, every entry is forgotten when `$PATH` changes
+#[derive(Debug, Default)]
+struct HashTable {
+    entries: HashMap<OsString, HashEntry>,
+}
+
+fn hash_table() -> &'static Mutex<HashTable> {
+    static HASH: OnceLock<Mutex<HashTable>> = OnceLock::new();
+    HASH.get_or_init(|| {
+        env::on_change("PATH", |_| clear_hashed());
+        Mutex::new(HashTable::default())
+    })
+}
+
+/// resolves a command name to the executable that would be run for it
+///
+/// names containing a `/` are treated as paths and are returned as-is if they point at an
+/// executable file, otherwise each directory of `$PATH` is searched in order, with the result
+/// remembered in the hash table
+pub fn search(name: &OsStr) -> Option<PathBuf> {
+    let path = Path::new(name);
+    if name.as_encoded_bytes().contains(&b'/') {
+        return is_executable(path).then(|| path.to_path_buf());
+    }
+
+    hash_table().lock().lookup(name, locate)
+}
+
+impl HashTable {
+    /// where `name` was found before, or otherwise where `locate` finds it, which is remembered
+    fn lookup(
+        &mut self,
+        name: &OsStr,
+        locate: impl FnOnce(&OsStr) -> Option<PathBuf>,
+    ) -> Option<PathBuf> {
+        if let Some(entry) = self.entries.get_mut(name) {
+            // the executable may have been removed since it was hashed
+            if is_executable(&entry.path) {
+                entry.hits += 1;
+                debug!(
+                    "hash hit for '{}' ({} hits)",
+                    name.to_string_lossy(),
+                    entry.hits
+                );
+                return Some(entry.path.clone());
+            }
+            self.entries.remove(name);
+        }
+
+        debug!(
+            "hash miss for '{}', searching $PATH",
+            name.to_string_lossy()
+        );
+        let found = locate(name)?;
+        self.entries.insert(
+            name.to_owned(),
+            HashEntry {
+                path: found.clone(),
+                hits: 1,
+            },
+        );
+        Some(found)
+    }
+}
+
+/// the first executable called `name` in a directory of `$PATH`
+fn locate(name: &OsStr) -> Option<PathBuf> {
+    let mut index = index().lock();
+    index.refresh(env::get("PATH"));
+    let found = index
+        .dirs
+        .iter()
+        .find(|x| x.commands.contains(name))
+        .map(|x| x.dir.join(name))
+        .filter(|x| is_executable(x));
+    // making a file executable doesn't change its directory's modification time, so anything the
+    // index doesn't know about is still looked for directly
+    found.or_else(|| {
+        std::env::split_paths(&index.path_var)
+            .map(|dir| dir.join(name))
+            .find(|x| is_executable(x))
+    })
+}
+
+/// every command in the hash table, sorted by name
+pub fn hashed() -> Vec<(OsString, HashEntry)> {
+    let table = hash_table().lock();
+    let mut entries = table
+        .entries
+        .iter()
+        .map(|(name, entry)| (name.clone(), entry.clone()))
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+}
+
+/// forgets every remembered command location, and what's in every directory of `$PATH`
+pub fn clear_hashed() {
+    hash_table().lock().entries.clear();
+    *index().lock() = PathIndex::default();
+}
+
+/// the names of every executable reachable through `$PATH`, sorted and deduplicated, see the
+/// [module docs](self) for when they're collected again
+pub fn commands() -> Arc<BTreeSet<String>> {
+    index().lock().names(env::get("PATH"))
+}
+
+/// the executables in one directory of `$PATH`
+#[derive(Debug)]
+struct IndexedDir {
+    dir: PathBuf,
+    /// the directory's modification time when it was read, `None` if it couldn't be, in which
+    /// case it's tried again every time
+    modified: Option<SystemTime>,
+    commands: BTreeSet<OsString>,
+}
+
+impl IndexedDir {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            modified: None,
+            commands: BTreeSet::new(),
+        }
+    }
+
+    /// reads the directory again if it's changed since it was last read, returning whether it
+    /// was
+    fn refresh(&mut self) -> bool {
+        let modified = fs::metadata(&self.dir).and_then(|x| x.modified()).ok();
+        if modified.is_some() && modified == self.modified {
+            return false;
+        }
+        debug!("indexing '{}'", self.dir.display());
+        self.commands = fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| is_executable(&entry.path()))
+            .map(|entry| entry.file_name())
+            .collect();
+        self.modified = modified;
+        true
+    }
+}
+
+/// what's in each directory of `$PATH`, see the [module docs](self)
+#[derive(Debug, Default)]
+struct PathIndex {
+    /// the `$PATH` the index was built from
+    path_var: OsString,
+    dirs: Vec<IndexedDir>,
+    /// the names of every command in `dirs`, once they've been asked for
+    names: Option<Arc<BTreeSet<String>>>,
+}
+
+impl PathIndex {
+    /// brings the index up to date with `path_var` and the directories in it
+    fn refresh(&mut self, path_var: OsString) {
+        if path_var != self.path_var || self.dirs.is_empty() {
+            self.dirs = std::env::split_paths(&path_var)
+                .map(IndexedDir::new)
+                .collect();
+            self.path_var = path_var;
+            self.names = None;
+        }
+        for dir in &mut self.dirs {
+            if dir.refresh() {
+                self.names = None;
+            }
+        }
+    }
+
+    /// the names of every command in `path_var`, only reading its directories if they haven't
+    /// been collected since it last changed
+    fn names(&mut self, path_var: OsString) -> Arc<BTreeSet<String>> {
+        if path_var != self.path_var {
+            self.names = None;
+        }
+        if let Some(names) = &self.names {
+            return names.clone();
+        }
+        self.refresh(path_var);
+        let names = self
+            .dirs
+            .iter()
+            .flat_map(|x| &x.commands)
+            .filter_map(|x| x.to_str().map(str::to_owned))
+            .collect::<BTreeSet<_>>();
+        self.names.insert(Arc::new(names)).clone()
+    }
+}
+
+fn index() -> &'static Mutex<PathIndex> {
+    static INDEX: OnceLock<Mutex<PathIndex>> = OnceLock::new();
+    INDEX.get_or_init(|| Mutex::new(PathIndex::default()))
+}
+
+fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(x) => x.is_file() && x.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_hits() {
+        let path = std::env::temp_dir().join(format!("rs-shell-hash-{}", std::process::id()));
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let name = OsStr::new("cmd");
+        let mut table = HashTable::default();
+        let searches = std::cell::Cell::new(0);
+        let locate = |_: &OsStr| {
+            searches.set(searches.get() + 1);
+            Some(path.clone())
+        };
+
+        for _ in 0..3 {
+            assert_eq!(table.lookup(name, locate), Some(path.clone()));
+        }
+        // only the first lookup has to search
+        assert_eq!(searches.get(), 1);
+        assert_eq!(table.entries[name].hits, 3);
+
+        // an executable that's gone is searched for again
+        fs::remove_file(&path).unwrap();
+        assert_eq!(table.lookup(name, |_| None), None);
+        assert!(table.entries.is_empty());
+    }
+
+    #[test]
+    fn index_follows_changes() {
+        let dir = std::env::temp_dir().join(format!("rs-shell-path-{}", std::process::id()));
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::create_dir_all(&a).unwrap();
+        fs::create_dir_all(&b).unwrap();
+        let add = |dir: &Path, name: &str, mode: u32| {
+            let path = dir.join(name);
+            fs::write(&path, "").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        add(&a, "one", 0o755);
+        add(&a, "data", 0o644);
+
+        let mut index = PathIndex::default();
+        let names = |index: &PathIndex| {
+            let names = index.dirs.iter().flat_map(|x| &x.commands);
+            names
+                .map(|x| x.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        index.refresh(a.clone().into_os_string());
+        assert_eq!(names(&index), ["one"]);
+
+        // the directory's modification time is what's checked, which may not have moved on yet
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        add(&a, "two", 0o755);
+        index.refresh(a.clone().into_os_string());
+        assert_eq!(names(&index), ["one", "two"]);
+
+        add(&b, "three", 0o700);
+        index.refresh(std::env::join_paths([&b, &a]).unwrap());
+        assert_eq!(names(&index), ["three", "one", "two"]);
+
+        // the names are collected once, until `$PATH` or a directory in it changes
+        let path_var = b.clone().into_os_string();
+        let names = index.names(path_var.clone());
+        assert_eq!(*names, BTreeSet::from(["three".to_owned()]));
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        add(&b, "four", 0o755);
+        assert!(Arc::ptr_eq(&index.names(path_var.clone()), &names));
+        index.refresh(path_var.clone());
+        assert_eq!(index.names(path_var).len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+//! a fuzzy finder built into the line editor, like fzf's key bindings but without needing fzf
+//! installed, ctrl-t picks a file under the working directory to insert at the cursor, and alt-r
+//! picks a line from the history to replace the one being edited
+//!
+//! typing narrows the list down to the entries that contain what's been typed, in order but not
+//! necessarily together, best matches first, up/down (or ctrl-p/ctrl-n) move the selection, enter
+//! picks it, and escape or ctrl-c give up, the picker is drawn on the terminal's alternate screen,
+//! so whatever was on the screen before is left as it was
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use log::warn;
+use nix::{errno::Errno, libc, unistd};
+use parking_lot::Mutex;
+use rustyline::{Cmd, ConditionalEventHandler, Event, EventContext, Movement, RepeatCount};
+
+use cs128h_project::{proc_manager, terminal};
+
+/// the most files that are looked at, so that opening the picker in `/` doesn't take forever,
+/// directories are walked breadth first so it's the deepest ones that are left out
+const MAX_FILES: usize = 20_000;
+
+/// what a [`Picker`] picks from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// files under the working directory, inserted at the cursor
+    Files,
+    /// lines from the history, which replace the line being edited
+    History,
+}
+
+/// opens the picker when its key is pressed, see the [module docs](self)
+#[derive(Debug)]
+pub struct Picker {
+    source: Source,
+    /// every line in the history, oldest first, shared with the frontend that adds to it
+    history: Arc<Mutex<Vec<String>>>,
+}
+
+impl Picker {
+    pub fn new(source: Source, history: Arc<Mutex<Vec<String>>>) -> Self {
+        Self { source, history }
+    }
+
+    fn candidates(&self) -> Vec<String> {
+        match self.source {
+            Source::Files => files(Path::new("."), MAX_FILES),
+            Source::History => {
+                // newest first, and only the most recent of any line that's been run more than once
+                let mut seen = HashSet::new();
+                let history = self.history.lock();
+                history
+                    .iter()
+                    .rev()
+                    .filter(|x| seen.insert(x.as_str()))
+                    .cloned()
+                    .collect()
+            }
+        }
+    }
+}
+
+impl ConditionalEventHandler for Picker {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        // the history is searched for what's already been typed, like fzf does
+        let query = match self.source {
+            Source::Files => String::new(),
+            Source::History => ctx.line().to_owned(),
+        };
+        let picked = match pick(&self.candidates(), query) {
+            Ok(Some(x)) => x,
+            Ok(None) => return Some(Cmd::Noop),
+            Err(e) => {
+                warn!("unable to open the picker: {}", e);
+                return Some(Cmd::Noop);
+            }
+        };
+        Some(match self.source {
+            Source::Files => Cmd::Insert(1, proc_manager::quote(picked.as_ref())),
+            Source::History => Cmd::Replace(Movement::WholeBuffer, Some(picked)),
+        })
+    }
+}
+
+/// the paths of the files and directories under `root`, relative to it, directories end in `/`,
+/// hidden ones (and everything in them) are left out, as are any past the first `limit`
+fn files(root: &Path, limit: usize) -> Vec<String> {
+    let mut found = Vec::new();
+    let mut pending = vec![String::new()];
+    while !pending.is_empty() && found.len() < limit {
+        let mut next = Vec::new();
+        for dir in pending {
+            let Ok(entries) = fs::read_dir(root.join(&dir)) else {
+                continue;
+            };
+            let mut entries = entries
+                .filter_map(|x| x.ok())
+                .filter_map(|x| Some((x.file_name().into_string().ok()?, x.file_type().ok()?)))
+                .filter(|(name, _)| !name.starts_with('.'))
+                .collect::<Vec<_>>();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, kind) in entries {
+                let path = format!("{}{}", dir, name);
+                // symlinks aren't followed, so there's no way to end up going round in circles
+                if kind.is_dir() {
+                    found.push(format!("{}/", path));
+                    next.push(format!("{}/", path));
+                } else {
+                    found.push(path);
+                }
+            }
+        }
+        pending = next;
+    }
+    found.truncate(limit);
+    found
+}
+
+/// how well `query` matches `candidate`, `None` if it doesn't, i.e. if its characters don't all
+/// appear in `candidate` in order
+///
+/// like fzf, matching ignores case unless the query has uppercase letters in it, and characters
+/// that match next to each other or at the start of a word count for more than scattered ones
+pub fn score(query: &str, candidate: &str) -> Option<i64> {
+    let fold = !query.chars().any(char::is_uppercase);
+    let norm = |c: char| {
+        if fold {
+            c.to_lowercase().next().unwrap_or(c)
+        } else {
+            c
+        }
+    };
+    let query = query.chars().map(norm).collect::<Vec<_>>();
+    let text = candidate.chars().map(norm).collect::<Vec<_>>();
+    let Some(&first) = query.first() else {
+        return Some(0);
+    };
+
+    // the best of the matches starting at each place the first character appears, each matching
+    // the rest of the query as early as it can
+    let mut best = None;
+    'starts: for start in (0..text.len()).filter(|&i| text[i] == first) {
+        let mut total = 0;
+        let mut last: Option<usize> = None;
+        let mut rest = start..text.len();
+        for &c in &query {
+            let Some(i) = rest.by_ref().find(|&i| text[i] == c) else {
+                // if it can't be matched from here, it can't be from anywhere later either
+                break 'starts;
+            };
+            total += 16;
+            if i == 0 || "/_-. ".contains(text[i - 1]) {
+                total += 10;
+            }
+            match last {
+                Some(x) if x + 1 == i => total += 8,
+                Some(x) => total -= (i - x - 1) as i64,
+                None => (),
+            }
+            last = Some(i);
+        }
+        best = best.max(Some(total));
+    }
+    best
+}
+
+/// the candidates that match `query`, best first, shorter ones first when they match as well, and
+/// otherwise in the order they were given
+pub fn filter<'a>(query: &str, candidates: &'a [String]) -> Vec<&'a str> {
+    let mut found = candidates
+        .iter()
+        .filter_map(|x| Some((score(query, x)?, x.as_str())))
+        .collect::<Vec<_>>();
+    found.sort_by_key(|(score, x)| (-score, x.chars().count()));
+    found.into_iter().map(|(_, x)| x).collect()
+}
+
+/// a key pressed in the picker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Backspace,
+    ClearQuery,
+    Up,
+    Down,
+    Accept,
+    Cancel,
+}
+
+/// the keys in what was read from the terminal, anything that isn't understood is skipped
+fn keys(input: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let input = String::from_utf8_lossy(input);
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        keys.push(match c {
+            '\r' | '\n' => Key::Accept,
+            '\x03' | '\x07' => Key::Cancel,
+            '\x7f' | '\x08' => Key::Backspace,
+            '\x15' => Key::ClearQuery,
+            '\x10' => Key::Up,
+            '\x0e' | '\t' => Key::Down,
+            '\x1b' => match chars.peek() {
+                // a lone escape is the escape key, otherwise it's the start of a sequence like an
+                // arrow key, only the arrows mean anything here
+                None => Key::Cancel,
+                Some('[' | 'O') => {
+                    chars.next();
+                    let mut last = None;
+                    for c in chars.by_ref() {
+                        last = Some(c);
+                        if c.is_ascii_alphabetic() || c == '~' {
+                            break;
+                        }
+                    }
+                    match last {
+                        Some('A') => Key::Up,
+                        Some('B') => Key::Down,
+                        _ => continue,
+                    }
+                }
+                Some(_) => continue,
+            },
+            c if c.is_control() => continue,
+            c => Key::Char(c),
+        });
+    }
+    keys
+}
+
+/// switches to the alternate screen for as long as it's around
+struct AlternateScreen;
+
+impl AlternateScreen {
+    fn enter() -> io::Result<Self> {
+        let mut stdout = io::stdout();
+        stdout.write_all(b"\x1b[?1049h")?;
+        stdout.flush()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for AlternateScreen {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = stdout
+            .write_all(b"\x1b[?1049l")
+            .and_then(|_| stdout.flush());
+    }
+}
+
+/// lets the user pick one of `candidates`, starting with `query` already typed, `None` if they
+/// gave up
+///
+/// this is only called from a key binding, so the terminal is already in raw mode
+fn pick(candidates: &[String], mut query: String) -> io::Result<Option<String>> {
+    let _screen = AlternateScreen::enter()?;
+    let mut selected = 0;
+    let mut input = [0; 64];
+    loop {
+        let found = filter(&query, candidates);
+        selected = selected.min(found.len().saturating_sub(1));
+        draw(&query, &found, candidates.len(), selected)?;
+
+        let read = match unistd::read(libc::STDIN_FILENO, &mut input) {
+            Ok(0) => return Ok(None),
+            Ok(x) => x,
+            // e.g. the terminal being resized, which the next draw picks up anyway
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for key in keys(&input[..read]) {
+            match key {
+                Key::Char(c) => {
+                    query.push(c);
+                    selected = 0;
+                }
+                Key::Backspace => {
+                    query.pop();
+                    selected = 0;
+                }
+                Key::ClearQuery => {
+                    query.clear();
+                    selected = 0;
+                }
+                Key::Up => selected = selected.saturating_sub(1),
+                Key::Down => selected += 1,
+                Key::Accept => return Ok(found.get(selected).map(|x| x.to_string())),
+                Key::Cancel => return Ok(None),
+            }
+        }
+    }
+}
+
+/// redraws the picker, the query on the first line, how many candidates match it on the second,
+/// and then as many of the matches as fit, scrolled so that the selected one is showing
+fn draw(query: &str, found: &[&str], total: usize, selected: usize) -> io::Result<()> {
+    let (rows, cols) = terminal::size();
+    let shown = rows.saturating_sub(2).max(1);
+    let skip = (selected + 1).saturating_sub(shown);
+
+    let mut screen = format!("\x1b[H\x1b[2J> {}\r\n  {}/{}", query, found.len(), total);
+    for (i, candidate) in found.iter().enumerate().skip(skip).take(shown) {
+        let line = candidate
+            .chars()
+            .take(cols.saturating_sub(2))
+            .collect::<String>();
+        match i == selected {
+            true => screen.push_str(&format!("\r\n\x1b[7m> {}\x1b[0m", line)),
+            false => screen.push_str(&format!("\r\n  {}", line)),
+        }
+    }
+    screen.push_str(&format!("\x1b[1;{}H", query.chars().count() + 3));
+
+    let mut stdout = io::stdout();
+    stdout.write_all(screen.as_bytes())?;
+    stdout.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_matching() {
+        assert_eq!(score("", "anything"), Some(0));
+        assert_eq!(score("mn", "main.rs"), Some(16 + 10 + 16 - 2));
+        assert_eq!(score("nm", "main.rs"), None);
+        // lowercase matches either case, but uppercase only matches itself
+        assert!(score("readme", "README.md").is_some());
+        assert!(score("README", "readme.md").is_none());
+
+        let candidates = [
+            "src/frontend.rs",
+            "src/main.rs",
+            "target/",
+            "src/",
+            "README.md",
+        ]
+        .map(String::from);
+        assert_eq!(filter("src", &candidates)[0], "src/");
+        // consecutive characters at the start of a word beat scattered ones
+        assert_eq!(filter("main", &candidates), ["src/main.rs"]);
+        assert_eq!(filter("fr", &candidates)[0], "src/frontend.rs");
+        assert_eq!(filter("", &candidates).len(), candidates.len());
+    }
+
+    #[test]
+    fn key_parsing() {
+        assert_eq!(
+            keys("ab\x7f\x1b[A\x1b[B\x1b[1;5C\r".as_bytes()),
+            [
+                Key::Char('a'),
+                Key::Char('b'),
+                Key::Backspace,
+                Key::Up,
+                Key::Down,
+                Key::Accept
+            ]
+        );
+        assert_eq!(keys(b"\x1b"), [Key::Cancel]);
+        assert_eq!(keys(b"\x03"), [Key::Cancel]);
+    }
+}
@@ -0,0 +1,90 @@
+//! scheduling priority of the commands the shell runs, set with the `nice` builtin
+//!
+//! the priority only applies to commands started from the thread that set it, rather than being a
+//! global option, so that `parallel` running `nice ...` on one worker doesn't affect what the
+//! other workers start
+
+use std::cell::Cell;
+
+use nix::{errno::Errno, libc};
+
+/// `IOPRIO_CLASS_IDLE` from `linux/ioprio.h`
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+/// `IOPRIO_CLASS_SHIFT` from `linux/ioprio.h`
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// `IOPRIO_WHO_PROCESS` from `linux/ioprio.h`
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// how a command is to be scheduled, relative to the shell
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Priority {
+    /// added to the niceness the command inherits from the shell, like `nice -n`
+    pub niceness: i32,
+    /// only give the command disk time when nothing else wants it, like `ionice -c 3`
+    pub idle_io: bool,
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<Priority>> = const { Cell::new(None) };
+}
+
+/// the priority commands started from this thread should be run with, if it's been changed
+pub fn current() -> Option<Priority> {
+    CURRENT.get()
+}
+
+/// runs `f` with commands started from this thread (while it runs) using `priority`
+pub fn with<T>(priority: Option<Priority>, f: impl FnOnce() -> T) -> T {
+    let old = CURRENT.replace(priority);
+    let result = f();
+    CURRENT.set(old);
+    result
+}
+
+impl Priority {
+    /// applies the priority to the calling process, this only makes async-signal-safe calls so it
+    /// can be used in a freshly forked child
+    pub fn apply(self) -> Result<(), Errno> {
+        if self.niceness != 0 {
+            // `nice` can legitimately return -1, so errors have to be told apart with errno
+            Errno::clear();
+            // SAFETY: only affects the calling process
+            if unsafe { libc::nice(self.niceness) } == -1 && Errno::last_raw() != 0 {
+                return Err(Errno::last());
+            }
+        }
+        if self.idle_io {
+            set_idle_io()?;
+        }
+        Ok(())
+    }
+}
+
+/// puts the calling process in the idle I/O scheduling class
+#[cfg(target_os = "linux")]
+fn set_idle_io() -> Result<(), Errno> {
+    // SAFETY: only affects the calling process
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if result == -1 {
+        return Err(Errno::last());
+    }
+    Ok(())
+}
+
+/// I/O scheduling classes are linux only, so `nice -i` fails elsewhere
+#[cfg(not(target_os = "linux"))]
+fn set_idle_io() -> Result<(), Errno> {
+    Err(Errno::ENOSYS)
+}
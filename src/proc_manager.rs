@@ -1,267 +1,1711 @@
 use std::{
-    collections::VecDeque, ffi::{OsStr, OsString}, fs::{self, File}, io, string::FromUtf8Error
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{self, File},
+    io::{self, IsTerminal, Read, Write},
+    os::fd::{AsFd, OwnedFd},
+    os::unix::{ffi::OsStringExt, fs::OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    thread::{self, JoinHandle},
 };
 
-use subprocess::{CaptureData, Exec, ExitStatus, Pipeline, PopenError};
+use log::warn;
+use nix::{
+    libc,
+    sys::{
+        signal::{self, Signal},
+        stat::Mode,
+        wait::waitpid,
+    },
+    unistd::{self, Pid},
+};
+use subprocess::ExitStatus;
 
 use crate::{
-    ast::{RedirectFd, RedirectOp, Separator},
-    builtins::Builtin,
-    env,
+    ast::{RedirectFd, RedirectOp, RedirectType, Separator, Span},
+    audit,
+    builtins::{self, Builtin, BuiltinCheck},
+    diagnostic::{Diagnostic, Label},
+    env::{self, EnvError},
     evaluator::FlattenedCmdline,
+    i18n, jobs,
+    last_output::{self, Recorder},
+    metrics,
+    options::{self, ShellOption},
+    pager::{self, Pager},
+    path, priority,
+    redirect::Target,
+    sandbox::{self, Sandbox},
+    spawn, suggest,
 };
 
+/// the variable that sets the most output, in bytes, a command substitution may produce, `0`
+/// lifts the limit entirely
+const SUBSTITUTION_LIMIT_VAR: &str = "RS_SHELL_SUBST_LIMIT";
+
+/// the substitution output limit used when `RS_SHELL_SUBST_LIMIT` is unset
+const DEFAULT_SUBSTITUTION_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// the variable set to what traced commands are prefixed with
+const XTRACE_PREFIX_VAR: &str = "PS4";
+
+/// the prefix for traced commands used when `PS4` is unset
+const DEFAULT_XTRACE_PREFIX: &str = "+ ";
+
+/// the variable naming a command to run in place of commands that can't be found
+const NOT_FOUND_HANDLER_VAR: &str = "command_not_found_handler";
+
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
 pub enum ProcError {
     /// this error indicates that a redirect could not be successfully created this could be
     /// because you're trying to do a stdin error from a file that does not exist, but it could
     /// also indicate that an output redirect file is not writable or could not be created.
-    #[error("unable to redirecd '{path}': {internal}")]
-    RedirectError { path: String, internal: io::Error },
+    #[error("{}", i18n::format(
+        "unable to redirect '{path}': {error}",
+        &[("path", .path), ("error", .internal)],
+    ))]
+    RedirectError {
+        path: String,
+        internal: io::Error,
+        /// where the path was given, if it's a redirect's
+        span: Option<Span>,
+    },
+
+    /// a `>` redirect would have overwritten an existing file while `noclobber` is enabled
+    #[error("{}", i18n::format(
+        "cannot overwrite existing file '{path}' (noclobber is set)",
+        &[("path", .path)],
+    ))]
+    FileExists { path: String, span: Option<Span> },
+
+    /// a redirect, `cd` or `source` names a path outside the directory the sandbox confines the
+    /// shell to, see [`crate::sandbox`]
+    #[error("{}", i18n::format("'{path}' is outside the sandbox", &[("path", .path)]))]
+    OutsideSandbox { path: String, span: Option<Span> },
 
-    #[error("invalid mixture of type and file descriptors")]
+    /// a redirect names an fd other than stdin, stdout, or stderr
+    #[error("{}", i18n::format(
+        "redirecting fd {fd} isn't supported, only 0, 1, and 2 can be redirected",
+        &[("fd", .fd)],
+    ))]
+    UnsupportedFd { fd: u32, span: Option<Span> },
+
+    #[error("{}", i18n::text("invalid mixture of type and file descriptors"))]
     InvalidRedirect { op: RedirectOp },
 
-    #[error("error while creating subprocess: {internal}")]
-    SubprocessError { internal: PopenError },
+    #[error("{}", i18n::format(
+        "unable to run '{command}' ({path}): {error}",
+        &[("command", .command), ("path", &.path.display()), ("error", &spawn_failure(.internal))],
+    ))]
+    SubprocessError {
+        /// the command word as it was typed
+        command: String,
+        /// the executable it resolved to
+        path: PathBuf,
+        internal: io::Error,
+        span: Option<Span>,
+    },
+
+    #[error("{}", i18n::format(
+        "substitution output too large, the limit is {limit} bytes (see $RS_SHELL_SUBST_LIMIT)",
+        &[("limit", .limit)],
+    ))]
+    SubstitutionTooLarge { limit: u64 },
 
-    #[error("text output of shell substitution not valid UTF-8")]
-    SubstitutionError { internal: FromUtf8Error },
+    /// the command couldn't be found as a builtin or in `$PATH`, `suggestion` is the closest known
+    /// command if there's one that's a plausible typo
+    #[error("{}", not_found(.name, .suggestion.as_deref()))]
+    CommandNotFound {
+        name: String,
+        suggestion: Option<String>,
+        span: Option<Span>,
+    },
 
-    #[error("final command in seqence had dangling pipe")]
-    DanglingPipe,
+    /// a variable couldn't be assigned, e.g. because it's readonly
+    #[error("{internal}")]
+    Env { internal: EnvError },
 
-    #[error("feature '{feature}' has not yet been implemented")]
+    #[error("{}", i18n::format(
+        "feature '{feature}' has not yet been implemented",
+        &[("feature", .feature)],
+    ))]
     NotImplemented { feature: &'static str },
 }
 
+impl ProcError {
+    /// the status a command that failed with this error is treated as having exited with, like
+    /// other shells 127 if it couldn't be found, 126 if it couldn't be executed, and otherwise 1
+    pub fn exit_status(&self) -> ExitStatus {
+        match self {
+            ProcError::CommandNotFound { .. } => ExitStatus::Exited(127),
+            ProcError::SubprocessError { internal, .. } => match internal.kind() {
+                io::ErrorKind::PermissionDenied => ExitStatus::Exited(126),
+                io::ErrorKind::NotFound => ExitStatus::Exited(127),
+                _ => ExitStatus::Exited(1),
+            },
+            _ => ExitStatus::Exited(1),
+        }
+    }
+
+    /// points the error at `span` if it's one that can be, and isn't already
+    pub(crate) fn with_span(mut self, at: Span) -> Self {
+        if let ProcError::RedirectError { span, .. }
+        | ProcError::FileExists { span, .. }
+        | ProcError::OutsideSandbox { span, .. }
+        | ProcError::UnsupportedFd { span, .. }
+        | ProcError::SubprocessError { span, .. }
+        | ProcError::CommandNotFound { span, .. } = &mut self
+        {
+            span.get_or_insert(at);
+        }
+        self
+    }
+}
+
+impl Diagnostic for ProcError {
+    fn labels(&self) -> Vec<Label> {
+        let (span, message) = match self {
+            ProcError::RedirectError { span, .. } => (*span, "couldn't be opened"),
+            ProcError::FileExists { span, .. } => (*span, "already exists"),
+            ProcError::OutsideSandbox { span, .. } => (*span, "outside the sandbox"),
+            ProcError::UnsupportedFd { span, .. } => (*span, "unsupported fd"),
+            ProcError::InvalidRedirect { op } => (Some(op.span), "can't be used with this fd"),
+            ProcError::SubprocessError { span, .. } => (*span, "couldn't be run"),
+            ProcError::CommandNotFound { span, .. } => (*span, "not found"),
+            _ => (None, ""),
+        };
+        let label = |x| Label::new(x, i18n::text(message));
+        span.map(label).into_iter().collect()
+    }
+}
+
+/// a pipeline of external commands to be run as a single job
 #[derive(Debug)]
-pub struct ProcManager {}
+pub struct ExternalJob {
+    /// the commands making up the pipeline, in order, none of them have a `next`
+    pub commands: Vec<FlattenedCmdline>,
+    /// what the start of the pipeline reads from, the shell's own stdin if not given
+    pub stdin: Option<File>,
+    /// what the end of the pipeline writes to, the shell's own stdout if not given
+    pub stdout: Option<File>,
+    /// what every command of the pipeline writes its stderr to, the shell's own if not given
+    pub stderr: Option<File>,
+    pub mode: JobMode,
+}
+
+/// how a job is run relative to the shell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobMode {
+    /// handed the terminal and waited for
+    Foreground,
+    /// left running in the background, like with `&`
+    Background,
+    /// left running in the background without being announced or showing up in `jobs`, used for
+    /// jobs the shell starts as part of running another command like process substitutions
+    Detached,
+    /// waited for, but alongside other jobs so it isn't handed the terminal, it stays in the
+    /// shell's process group instead so that `^C` still reaches it, used by `parallel`
+    Concurrent,
+}
+
+/// how a [`ProcManager`] runs external commands
+///
+/// everything else (builtins, command chains, dry-run mode) is handled by the [`ProcManager`]
+/// itself, so swapping this out lets the evaluator be tested without spawning any processes
+pub trait ProcessBackend: fmt::Debug {
+    /// runs `job`, returning its exit status once it's done, or straight away if it's not run in
+    /// the foreground
+    fn run(&mut self, job: ExternalJob) -> Result<ExitStatus, ProcError>;
+
+    /// another backend that runs jobs the same way, for running them from another thread
+    fn fork(&self) -> Box<dyn ProcessBackend + Send>;
+
+    /// gives every job run from now on `fifos` to hold on to until it's been reaped, in place of
+    /// whatever was given before
+    fn hold(&mut self, fifos: SharedFifos);
+}
+
+#[derive(Debug)]
+pub struct ProcManager {
+    backend: Box<dyn ProcessBackend>,
+}
+
+impl Default for ProcManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl ProcManager {
     pub fn new() -> Self {
-        Self {}
+        Self::with_backend(NativeBackend::default())
     }
 
-    pub fn dispatch(&mut self, cmd: FlattenedCmdline) -> Result<ExitStatus, ProcError> {
-        let execs = self.build_exec(cmd)?;
-        let mut exit = ExitStatus::Undetermined;
-
-        for exec in execs {
-            exit = exec.join()?;
+    /// creates a [`ProcManager`] that runs external commands with `backend`
+    pub fn with_backend(backend: impl ProcessBackend + 'static) -> Self {
+        Self {
+            backend: Box::new(backend),
         }
+    }
+
+    /// keeps `fifos` around while the next command dispatched runs, and after that for as long as
+    /// any job it left running does, so whatever reads them doesn't find them gone
+    pub fn hold(&mut self, fifos: Vec<Fifo>) {
+        self.backend.hold(Arc::new(fifos));
+    }
 
-        Ok(exit)
+    pub fn dispatch(&mut self, cmd: FlattenedCmdline) -> Result<ExitStatus, ProcError> {
+        self.run_chain(cmd, None, None, None)
+    }
+
+    /// starts `cmd` as a process substitution, returning the FIFO its output can be read from
+    ///
+    /// the command is detached so it's never waited for, it's left to exit (or be killed by
+    /// `SIGPIPE`) on its own once whatever reads the FIFO is done with it
+    pub fn dispatch_fifo(&mut self, cmd: FlattenedCmdline) -> Result<Fifo, ProcError> {
+        let (fifo, writer) = Fifo::new().map_err(|e| ProcError::RedirectError {
+            path: "fifo".to_owned(),
+            internal: e,
+            span: None,
+        })?;
+        self.run_chain(cmd, Some(&writer), None, Some(JobMode::Detached))?;
+        Ok(fifo)
     }
 
+    /// runs `cmd` like [`ProcManager::dispatch`] but collects everything it writes to stdout, and
+    /// to stderr as well if `include_stderr` is set
+    ///
+    /// every stage writes into the same pipe, which is drained by a background thread while the
+    /// stages run, so output is never buffered per stage, stderr that isn't collected is left
+    /// attached to the terminal where it interleaves with everything else naturally
+    ///
+    /// output beyond the limit set by `RS_SHELL_SUBST_LIMIT` isn't collected, instead the pipe is
+    /// closed so the command doesn't keep running for nothing, and an error is returned
     pub fn dispatch_capture(
         &mut self,
         cmd: FlattenedCmdline,
+        include_stderr: bool,
     ) -> Result<(ExitStatus, OsString), ProcError> {
-        let execs = self.build_exec(cmd)?;
-        let mut buf = Vec::new();
-        let mut exit = ExitStatus::Undetermined;
+        let (reader, writer) = io::pipe().map_err(|e| ProcError::RedirectError {
+            path: "pipe".to_owned(),
+            internal: e,
+            span: None,
+        })?;
+        let limit = substitution_limit();
+        let reader = thread::spawn(move || {
+            let mut buf = Vec::new();
+            // reading one byte past the limit is enough to tell whether it's been exceeded
+            reader
+                .take(limit.saturating_add(1))
+                .read_to_end(&mut buf)
+                .map(|_| buf)
+        });
 
-        for exec in execs {
-            let cap = exec.capture()?;
-            exit = cap.exit_status;
-            buf.extend(cap.stdout);
+        let writer = File::from(OwnedFd::from(writer));
+        let exit = self.run_chain(cmd, Some(&writer), include_stderr.then_some(&writer), None);
+        // the reader only finishes once every copy of the write end has been closed
+        drop(writer);
+        let buf = reader
+            .join()
+            .expect("substitution reader thread must not panic")
+            .map_err(|e| ProcError::RedirectError {
+                path: "pipe".to_owned(),
+                internal: e,
+                span: None,
+            })?;
+        if buf.len() as u64 > limit {
+            return Err(ProcError::SubstitutionTooLarge { limit });
         }
+        // like in other shells, the output is passed along as is even if it isn't valid UTF-8
+        Ok((exit?, OsString::from_vec(buf)))
+    }
 
-        let output =
-            String::from_utf8(buf).map_err(|e| ProcError::SubstitutionError { internal: e })?;
-        Ok((exit, output.into()))
+    /// see [`run_chain`]
+    fn run_chain(
+        &mut self,
+        cmd: FlattenedCmdline,
+        stdout: Option<&File>,
+        stderr: Option<&File>,
+        mode: Option<JobMode>,
+    ) -> Result<ExitStatus, ProcError> {
+        let exit = run_chain(self.backend.as_mut(), cmd, stdout, stderr, mode);
+        // every job that needs the held FIFOs has been given them by now
+        self.backend.hold(SharedFifos::default());
+        exit
     }
 
-    fn build_exec(&self, cmd: FlattenedCmdline) -> Result<Vec<Execable>, ProcError> {
-        let execs = self.build_exec_internal(cmd)?;
+    /// builds the first stage of a command chain, that is, everything up to the first `;` or `&`,
+    /// and returns it along with whether it should be run in the background and the rest of the
+    /// chain
+    ///
+    /// stages are built one at a time right before they're run so that builtins like `cd` or
+    /// `shopt` affect how the commands after them are resolved
+    fn build_stage(
+        mut cmd: FlattenedCmdline,
+    ) -> Result<(Execable, bool, Option<FlattenedCmdline>), ProcError> {
+        let mut next = cmd.next.take();
+        let mut background = cmd.background;
+        let mut stage = if cmd.is_assignment() {
+            Execable::Assignment(cmd.envs)
+        } else {
+            match builtin_check(cmd) {
+                BuiltinCheck::Yes(builtin) => builtin.into(),
+                BuiltinCheck::No(cmd) => Execable::External(vec![cmd]),
+            }
+        };
 
-        let mut ret: Vec<Execable> = Vec::new();
+        loop {
+            match next {
+                Some((Separator::Pipe, next_cmd)) => {
+                    let mut next_cmd = *next_cmd;
+                    next = next_cmd.next.take();
+                    background = next_cmd.background;
+                    if next_cmd.is_assignment() {
+                        return Err(ProcError::NotImplemented {
+                            feature: "piping into assignments",
+                        });
+                    }
+                    stage = match builtin_check(next_cmd) {
+                        BuiltinCheck::No(next_cmd) => stage.pipe(next_cmd),
+                        BuiltinCheck::Yes(_) => {
+                            return Err(ProcError::NotImplemented {
+                                feature: "piping into builtins",
+                            })
+                        }
+                    };
+                }
+                Some((Separator::Semicolon, next_cmd)) => {
+                    return Ok((stage, background, Some(*next_cmd)))
+                }
+                Some((Separator::Fork, next_cmd)) => return Ok((stage, true, Some(*next_cmd))),
+                None => return Ok((stage, background, None)),
+            }
+        }
+    }
+}
 
-        for (sep, exec) in execs {
-            match sep {
-                Separator::Semicolon => ret.push(exec.into()),
-                Separator::Pipe => match ret.pop() {
-                    Some(x) => ret.push(x.pipe(exec)),
-                    None => return Err(ProcError::DanglingPipe),
-                },
-                Separator::Fork => return Err(ProcError::NotImplemented { feature: "fork" }),
+/// runs every stage of a command chain in order with `backend`, sending the stdout and stderr of
+/// each to `stdout` and `stderr` (unless they've been redirected elsewhere) if given, if `mode` is
+/// given every stage is run that way, rather than in the foreground or background as written
+///
+/// in dry-run mode stages are printed rather than run
+pub(crate) fn run_chain(
+    backend: &mut dyn ProcessBackend,
+    cmd: FlattenedCmdline,
+    stdout: Option<&File>,
+    stderr: Option<&File>,
+    mode: Option<JobMode>,
+) -> Result<ExitStatus, ProcError> {
+    let mut exit = ExitStatus::Undetermined;
+    let mut rest = Some(cmd);
+
+    while let Some(cmd) = rest {
+        // `set` is still run so that dry-run mode can be turned back off
+        if options::enabled(ShellOption::DryRun) && cmd.command != "set" {
+            let (line, next) = describe_stage(&cmd);
+            let line = format!("{}\n", line);
+            let written = match stdout {
+                Some(mut file) => file.write_all(line.as_bytes()),
+                None => io::stdout().write_all(line.as_bytes()),
+            };
+            written.map_err(|e| ProcError::RedirectError {
+                path: "pipe".to_owned(),
+                internal: e,
+                span: None,
+            })?;
+            exit = ExitStatus::Exited(0);
+            rest = next.cloned();
+            continue;
+        }
+        if options::enabled(ShellOption::Xtrace) {
+            eprint!("{}", trace(&cmd));
+        }
+
+        // `copyout` is left out so that it copies the output of the command before it, rather
+        // than its own
+        let keep_output = options::enabled(ShellOption::KeepOutput)
+            && stdout.is_none()
+            && cmd.command != last_output::COPY_BUILTIN;
+        let audit = audit::Entry::start(&cmd);
+        let (exec, background, next) = match ProcManager::build_stage(cmd) {
+            Ok(x) => x,
+            Err(e) => {
+                let result = Err(e);
+                if let Some(audit) = audit {
+                    audit.finish(&result);
+                }
+                return result;
+            }
+        };
+        let [stdout, stderr] = [stdout, stderr].map(|x| {
+            x.map(File::try_clone)
+                .transpose()
+                .map_err(|e| ProcError::RedirectError {
+                    path: "pipe".to_owned(),
+                    internal: e,
+                    span: None,
+                })
+        });
+        let (mut stdout, stderr) = (stdout?, stderr?);
+        let mode = if let Some(mode) = mode {
+            mode
+        } else if background {
+            JobMode::Background
+        } else {
+            JobMode::Foreground
+        };
+        // output that's kept is passed on to the pager, if it's being paged
+        let (mut recorder, mut pager) = (None, None);
+        if mode == JobMode::Foreground && stdout.is_none() {
+            let pipe_error = |e| ProcError::RedirectError {
+                path: "pipe".to_owned(),
+                internal: e,
+                span: None,
+            };
+            if options::enabled(ShellOption::AutoPage)
+                && io::stdout().is_terminal()
+                && exec.commands().iter().all(|(x, _)| pager::pages(x))
+            {
+                let (started, file) = Pager::start().map_err(pipe_error)?;
+                pager = Some(started);
+                stdout = Some(file);
+            }
+            if keep_output {
+                let (started, file) = Recorder::start(stdout.take()).map_err(pipe_error)?;
+                recorder = Some(started);
+                stdout = Some(file);
+            }
+        }
+        let started = metrics::start();
+        let commands = started.map(|_| exec.commands());
+        let result = exec.run(backend, stdout, stderr, mode);
+        if let Some(recorder) = recorder {
+            recorder.finish();
+        }
+        if let Some(output) = pager.and_then(Pager::finish) {
+            page(backend, output);
+        }
+        if let (Some(started), Some(commands), Ok(_)) = (started, commands, &result) {
+            let waited = matches!(mode, JobMode::Foreground | JobMode::Concurrent);
+            metrics::record(commands, waited.then(|| started.elapsed()));
+        }
+        if let Some(audit) = audit {
+            audit.finish(&result);
+        }
+        exit = result?;
+        rest = next;
+    }
+
+    Ok(exit)
+}
+
+/// shows `output` in the pager, or if there isn't one, writes it out as it is
+fn page(backend: &mut dyn ProcessBackend, output: Vec<u8>) {
+    let cmd = pager::command();
+    if path::search(&cmd.command).is_none() {
+        warn!("pager not found: {}", cmd.command.to_string_lossy());
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&output).and_then(|_| stdout.flush());
+        return;
+    }
+    let result = match feed(output) {
+        Ok(stdin) => backend.run(ExternalJob {
+            commands: vec![cmd],
+            stdin: Some(stdin),
+            stdout: None,
+            stderr: None,
+            mode: JobMode::Foreground,
+        }),
+        Err(e) => Err(ProcError::RedirectError {
+            path: "pipe".to_owned(),
+            internal: e,
+            span: None,
+        }),
+    };
+    if let Err(e) = result {
+        warn!("unable to run the pager: {}", e);
+    }
+}
+
+/// the [`ProcessBackend`] that actually spawns processes, as jobs under the shell's job control
+#[derive(Debug, Default)]
+pub struct NativeBackend {
+    /// see [`ProcessBackend::hold`]
+    fifos: SharedFifos,
+}
+
+impl ProcessBackend for NativeBackend {
+    fn run(&mut self, job: ExternalJob) -> Result<ExitStatus, ProcError> {
+        // every command is resolved before any of them are spawned, so a typo anywhere in a
+        // pipeline doesn't leave the rest of it running
+        let mut procs = job
+            .commands
+            .into_iter()
+            .map(|cmd| {
+                let (cmd, executable) = self.resolve_command(cmd)?;
+                self.build_process(cmd, executable)
+            })
+            .collect::<Result<Vec<_>, ProcError>>()?;
+        let tees = procs
+            .iter_mut()
+            .flat_map(|x| x.tees.drain(..))
+            .collect::<Vec<_>>();
+
+        if job.mode == JobMode::Foreground {
+            let id = spawn_job(
+                procs,
+                job.stdin,
+                job.stdout,
+                job.stderr,
+                job.mode,
+                &self.fifos,
+            )?;
+            let status = jobs::foreground(id, false);
+            // so that everything's been written by the time the next command runs, a job that was
+            // stopped instead still has the pipes open, so its tees are left to finish on their own
+            if !jobs::list().iter().any(|x| x.id == id) {
+                for tee in tees {
+                    let _ = tee.join();
+                }
+            }
+            return Ok(status);
+        }
+        let id = spawn_job(
+            procs,
+            job.stdin,
+            job.stdout,
+            job.stderr,
+            job.mode,
+            &self.fifos,
+        )?;
+        if job.mode == JobMode::Concurrent {
+            let status = jobs::wait(id);
+            for tee in tees {
+                let _ = tee.join();
             }
+            return Ok(status);
+        }
+        if job.mode == JobMode::Detached {
+            jobs::detach(id);
+            return Ok(ExitStatus::Exited(0));
         }
+        // makes it the current job
+        jobs::background(id);
+        if jobs::enabled() {
+            if let Some(job) = jobs::list().into_iter().find(|x| x.id == id) {
+                if let Some((pid, _)) = job.procs.last() {
+                    eprintln!("[{}] {}", id, pid);
+                }
+            }
+        }
+        Ok(ExitStatus::Exited(0))
+    }
 
-        Ok(ret)
+    fn fork(&self) -> Box<dyn ProcessBackend + Send> {
+        Box::new(NativeBackend {
+            fifos: self.fifos.clone(),
+        })
     }
 
-    fn build_exec_internal(
+    fn hold(&mut self, fifos: SharedFifos) {
+        self.fifos = fifos;
+    }
+}
+
+impl NativeBackend {
+    /// finds the executable the command of `cmd` refers to before trying to spawn it, producing a
+    /// [`ProcError::CommandNotFound`] with a typo suggestion if there isn't one
+    ///
+    /// if `command_not_found_handler` names a command, that's run in its place with the original
+    /// command line as its arguments, the `correct` option is handled before this, see [`correct`]
+    fn resolve_command(
         &self,
-        cmd: FlattenedCmdline,
-    ) -> Result<VecDeque<(Separator, Exec)>, ProcError> {
-        let mut exec = subprocess::Exec::cmd(cmd.command)
-            .args(cmd.arguments.as_slice())
-            .env_extend(env::pairs().as_slice())
-            .env_extend(cmd.envs.as_slice());
-
-        for (op, path) in cmd.redirects.into_iter() {
-            match op.r#type {
-                crate::ast::RedirectType::Out => match op.fd {
-                    RedirectFd::All => {
-                        let file = file_write(&path)?;
-                        let file2 = file.try_clone().map_err(|e| ProcError::RedirectError {
-                            path: path.to_string_lossy().to_string(),
-                            internal: e,
-                        })?;
-
-                        exec = exec.stdout(file).stderr(file2)
-                    }
-                    RedirectFd::Default | RedirectFd::Stdout => {
-                        let file = file_write(&path)?;
+        mut cmd: FlattenedCmdline,
+    ) -> Result<(FlattenedCmdline, PathBuf), ProcError> {
+        if let Some(executable) = path::search(&cmd.command) {
+            return Ok((cmd, executable));
+        }
 
-                        exec = exec.stdout(file)
-                    }
-                    RedirectFd::Stdin => return Err(ProcError::InvalidRedirect { op }),
-                    RedirectFd::Stderr => {
-                        let file = file_write(&path)?;
+        let name = cmd.command.to_string_lossy().to_string();
+        let suggestion = suggestion(&name);
 
-                        exec = exec.stderr(file)
-                    }
-                },
-                crate::ast::RedirectType::OutAppend => match op.fd {
-                    RedirectFd::All => {
-                        let file = file_append(&path)?;
-                        let file2 = file.try_clone().map_err(|e| ProcError::RedirectError {
-                            path: path.to_string_lossy().to_string(),
-                            internal: e,
-                        })?;
-
-                        exec = exec.stdout(file).stderr(file2)
-                    }
-                    RedirectFd::Default | RedirectFd::Stdout => {
-                        let file = file_append(&path)?;
+        let handler = env::get(NOT_FOUND_HANDLER_VAR);
+        if !handler.is_empty() {
+            if let Some(executable) = path::search(&handler) {
+                let command = std::mem::replace(&mut cmd.command, handler);
+                cmd.arguments.insert(0, command);
+                return Ok((cmd, executable));
+            }
+        }
 
-                        exec = exec.stdout(file)
-                    }
-                    RedirectFd::Stdin => return Err(ProcError::InvalidRedirect { op }),
-                    RedirectFd::Stderr => {
-                        let file = file_append(&path)?;
+        Err(ProcError::CommandNotFound {
+            name,
+            suggestion,
+            span: cmd.command_span,
+        })
+    }
 
-                        exec = exec.stderr(file)
-                    }
+    fn build_process(
+        &self,
+        cmd: FlattenedCmdline,
+        executable: PathBuf,
+    ) -> Result<Process, ProcError> {
+        let mut tees = Vec::new();
+        let stdio = crate::redirect::apply(&cmd.redirects, true)?;
+        let mut streams = Vec::with_capacity(3);
+        for target in stdio {
+            streams.push(match target {
+                Target::Inherited(x) => Stream::Inherited(x),
+                Target::Files(files) => match fan_out(files, &mut tees)? {
+                    Some(file) => Stream::File(file),
+                    None => Stream::Closed,
                 },
-                crate::ast::RedirectType::In => match op.fd {
-                    RedirectFd::All => return Err(ProcError::InvalidRedirect { op }),
-                    RedirectFd::Default | RedirectFd::Stdin => {
-                        let file = file_read(&path)?;
+                Target::Closed => Stream::Closed,
+            });
+        }
+        let process = Process {
+            executable,
+            command_span: cmd.command_span,
+            argv: std::iter::once(cmd.command).chain(cmd.arguments).collect(),
+            envs: cmd.envs,
+            stdio: streams.try_into().expect("there are always 3 streams"),
+            tees,
+        };
+        Ok(process)
+    }
+}
 
-                        exec = exec.stdin(file)
-                    }
-                    RedirectFd::Stdout => return Err(ProcError::InvalidRedirect { op }),
-                    RedirectFd::Stderr => return Err(ProcError::InvalidRedirect { op }),
-                },
-            };
+/// describes the first stage of a command chain as a fully expanded command line, returning it
+/// along with the rest of the chain
+fn describe_stage(mut cmd: &FlattenedCmdline) -> (String, Option<&FlattenedCmdline>) {
+    let mut line = String::new();
+    loop {
+        let next = cmd.next.as_ref();
+        let words = cmd
+            .envs
+            .iter()
+            .map(|(name, value)| format!("{}={}", name.to_string_lossy(), quote(value)))
+            .chain((!cmd.is_assignment()).then(|| quote(&cmd.command)))
+            .chain(cmd.arguments.iter().map(|x| quote(x)))
+            .chain(cmd.redirects.iter().map(|(op, path, _)| match op.r#type {
+                crate::ast::RedirectType::Close | crate::ast::RedirectType::Duplicate(_) => {
+                    op.to_string()
+                }
+                _ => format!("{}{}", op, quote(path)),
+            }));
+        line.push_str(&words.collect::<Vec<_>>().join(" "));
+
+        match next {
+            Some((Separator::Pipe, next)) => {
+                line.push_str(" | ");
+                cmd = next;
+            }
+            Some((Separator::Semicolon, next)) => return (line, Some(next)),
+            Some((Separator::Fork, next)) => {
+                line.push_str(" &");
+                return (line, Some(next));
+            }
+            None => {
+                if cmd.background {
+                    line.push_str(" &");
+                }
+                return (line, None);
+            }
+        }
+    }
+}
+
+/// says why a command couldn't be started, calling out the two most common reasons
+fn spawn_failure(e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => {
+            i18n::format("permission denied ({error})", &[("error", e)])
+        }
+        // exec reports a missing interpreter as the command itself not existing
+        io::ErrorKind::NotFound => i18n::format(
+            "not found, or neither is its interpreter ({error})",
+            &[("error", e)],
+        ),
+        _ => e.to_string(),
+    }
+}
+
+/// the message for [`ProcError::CommandNotFound`]
+fn not_found(name: &str, suggestion: Option<&str>) -> String {
+    match suggestion {
+        Some(x) => i18n::format(
+            "command not found: {name} — did you mean '{suggestion}'?",
+            &[("name", &name), ("suggestion", &x)],
+        ),
+        None => i18n::format("command not found: {name}", &[("name", &name)]),
+    }
+}
+
+/// the line the first stage of `cmd` is traced with when xtrace is enabled, the stage as a fully
+/// expanded command line prefixed with `PS4`
+fn trace(cmd: &FlattenedCmdline) -> String {
+    let prefix = env::get(XTRACE_PREFIX_VAR);
+    let prefix = if prefix.is_empty() {
+        DEFAULT_XTRACE_PREFIX.into()
+    } else {
+        prefix.to_string_lossy()
+    };
+    format!("{}{}\n", prefix, describe_stage(cmd).0)
+}
+
+/// quotes a word so that it reads back as the same single word, words that don't need quoting
+/// are left alone
+pub fn quote(word: &OsStr) -> String {
+    let word = word.to_string_lossy();
+    let plain = |c: char| c.is_alphanumeric() || "_-./=:,+@%^".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        return word.into_owned();
+    }
+    if !word.contains('\'') {
+        return format!("'{}'", word);
+    }
+    // a single quoted word can't have a `'` in it, so it's double quoted instead, with everything
+    // that's special there escaped
+    let mut quoted = String::from("\"");
+    for c in word.chars() {
+        if matches!(c, '"' | '$' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// the most output a command substitution may produce, from `RS_SHELL_SUBST_LIMIT`
+fn substitution_limit() -> u64 {
+    let value = env::get(SUBSTITUTION_LIMIT_VAR);
+    if value.is_empty() {
+        return DEFAULT_SUBSTITUTION_LIMIT;
+    }
+    match value.to_string_lossy().parse() {
+        Ok(0) => u64::MAX,
+        Ok(x) => x,
+        Err(_) => {
+            warn!(
+                "ignoring invalid {}: '{}'",
+                SUBSTITUTION_LIMIT_VAR,
+                value.to_string_lossy()
+            );
+            DEFAULT_SUBSTITUTION_LIMIT
         }
+    }
+}
+
+/// the builtin or command in `$PATH` closest to `name`, which is suggested when it can't be found
+fn suggestion(name: &str) -> Option<String> {
+    let commands = path::commands();
+    suggest::closest(
+        name,
+        commands
+            .iter()
+            .map(String::as_str)
+            .chain(builtins::names().map(|x| x as &str)),
+    )
+    .map(str::to_owned)
+}
+
+/// checks whether `cmd` is a builtin, with the `correct` option enabled a command that can't be
+/// found is offered a correction first, which might be a builtin itself
+fn builtin_check(cmd: FlattenedCmdline) -> BuiltinCheck {
+    match Builtin::maybe_new(cmd) {
+        BuiltinCheck::No(cmd) => Builtin::maybe_new(correct(cmd, confirm_correction)),
+        x => x,
+    }
+}
+
+/// with the `correct` option enabled, replaces the command of `cmd` with its [`suggestion`] if it
+/// can't be found and `confirm` agrees to it
+fn correct(
+    mut cmd: FlattenedCmdline,
+    confirm: impl FnOnce(&str, &str) -> bool,
+) -> FlattenedCmdline {
+    if !options::enabled(ShellOption::Correct) || path::search(&cmd.command).is_some() {
+        return cmd;
+    }
+    let name = cmd.command.to_string_lossy().into_owned();
+    if let Some(correction) = suggestion(&name) {
+        if confirm(&name, &correction) {
+            cmd.command = correction.into();
+        }
+    }
+    cmd
+}
+
+/// asks the user on the terminal whether `name` should be corrected to `correction`
+fn confirm_correction(name: &str, correction: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        return false;
+    }
+    eprint!("rs-shell: correct '{}' to '{}' [y/N]? ", name, correction);
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// creates a pipe that is fed `data` from a background thread and returns its read end
+///
+/// this is how in-memory data like here-strings or the output of a builtin that's been piped into
+/// an external command is given to a process as its stdin, the thread means the data doesn't have
+/// to fit in the pipe's buffer
+pub(crate) fn feed(data: Vec<u8>) -> io::Result<File> {
+    let (reader, mut writer) = io::pipe()?;
+    thread::spawn(move || {
+        // the reader closing early (e.g. `cmd | head`) isn't an error worth reporting
+        let _ = writer.write_all(&data);
+    });
+    Ok(File::from(OwnedFd::from(reader)))
+}
 
-        match cmd.next {
-            Some((separator, next_cmd)) => {
-                let mut seq = self.build_exec_internal(*next_cmd)?;
-                seq.front_mut()
-                    .expect("build_exec() must return non empty collection")
-                    .0 = separator;
-                seq.push_front((Separator::Semicolon, exec));
-                Ok(seq)
+/// combines the files a stream has been redirected to into the one the process is given, with more
+/// than one (e.g. `cmd > a > b`) that's a pipe which a thread copies everything written to it from
+/// to each of them, the thread is added to `tees`
+pub(crate) fn fan_out(
+    mut files: Vec<File>,
+    tees: &mut Vec<JoinHandle<()>>,
+) -> Result<Option<File>, ProcError> {
+    if files.len() <= 1 {
+        return Ok(files.pop());
+    }
+    let (mut reader, writer) = io::pipe().map_err(|e| ProcError::RedirectError {
+        path: "pipe".to_owned(),
+        internal: e,
+        span: None,
+    })?;
+    tees.push(thread::spawn(move || {
+        let mut buf = [0u8; 8192];
+        loop {
+            let len = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(x) => x,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            // a file that can't be written to (e.g. a full disk) doesn't stop the others
+            files.retain_mut(|file| file.write_all(&buf[..len]).is_ok());
+            if files.is_empty() {
+                break;
             }
-            None => Ok(VecDeque::from([(Separator::Semicolon, exec)])),
+        }
+    }));
+    Ok(Some(File::from(OwnedFd::from(writer))))
+}
+
+/// the path redirects are most often to, see [`null_file`]
+const NULL_PATH: &str = "/dev/null";
+
+/// a new handle to `/dev/null`, which is only opened once and then duplicated, since redirecting
+/// to it is common enough that it's worth not looking the device up every time
+fn null_file() -> io::Result<File> {
+    static NULL_FILE: OnceLock<File> = OnceLock::new();
+    match NULL_FILE.get() {
+        Some(x) => x.try_clone(),
+        None => {
+            let file = File::options().read(true).write(true).open(NULL_PATH)?;
+            NULL_FILE.get_or_init(|| file).try_clone()
         }
     }
 }
 
-fn file_read(path: &OsStr) -> Result<File, ProcError> {
+pub(crate) fn file_read(path: &OsStr) -> Result<File, ProcError> {
+    if path == NULL_PATH {
+        return null_file().map_err(|e| ProcError::RedirectError {
+            path: NULL_PATH.to_owned(),
+            internal: e,
+            span: None,
+        });
+    }
     fs::File::options()
         .read(true)
         .write(false)
-        .open(&path)
+        .open(path)
         .map_err(|e| ProcError::RedirectError {
             path: path.to_string_lossy().to_string(),
             internal: e,
+            span: None,
         })
 }
-fn file_write(path: &OsStr) -> Result<File, ProcError> {
+
+/// opens `path` for a `>` redirect, truncating it, or with `noclobber` enabled only creating it
+///
+/// like other shells, `noclobber` only protects regular files, so redirects to things like
+/// `/dev/null` or a FIFO still work when it's enabled
+pub(crate) fn file_write(path: &OsStr) -> Result<File, ProcError> {
+    let redirect_error = |e| ProcError::RedirectError {
+        path: path.to_string_lossy().to_string(),
+        internal: e,
+        span: None,
+    };
+    if path == NULL_PATH {
+        return null_file().map_err(redirect_error);
+    }
+    confined(path)?;
+    if !options::enabled(ShellOption::Noclobber) {
+        return fs::File::options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .map_err(redirect_error);
+    }
+
+    match fs::File::options().create_new(true).write(true).open(path) {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            if fs::metadata(path).map_err(redirect_error)?.is_file() {
+                return Err(ProcError::FileExists {
+                    path: path.to_string_lossy().to_string(),
+                    span: None,
+                });
+            }
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .map_err(redirect_error)
+        }
+        x => x.map_err(redirect_error),
+    }
+}
+
+/// opens `path` for a `>>` redirect, creating it if needed; unlike `>` this never clobbers
+pub(crate) fn file_append(path: &OsStr) -> Result<File, ProcError> {
+    if path == NULL_PATH {
+        return null_file().map_err(|e| ProcError::RedirectError {
+            path: NULL_PATH.to_owned(),
+            internal: e,
+            span: None,
+        });
+    }
+    confined(path)?;
     fs::File::options()
         .create(true)
-        .write(true)
-        .append(false)
-        .open(&path)
+        .append(true)
+        .open(path)
         .map_err(|e| ProcError::RedirectError {
             path: path.to_string_lossy().to_string(),
             internal: e,
+            span: None,
         })
 }
-fn file_append(path: &OsStr) -> Result<File, ProcError> {
-    fs::File::options()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open(&path)
-        .map_err(|e| ProcError::RedirectError {
+
+/// refuses to write to `path` if it's outside the sandbox, see [`sandbox::allows`]
+fn confined(path: &OsStr) -> Result<(), ProcError> {
+    match sandbox::allows(Path::new(path)) {
+        true => Ok(()),
+        false => Err(ProcError::OutsideSandbox {
             path: path.to_string_lossy().to_string(),
-            internal: e,
+            span: None,
+        }),
+    }
+}
+
+/// a FIFO created for a process substitution, which is removed when this is dropped
+#[derive(Debug)]
+pub struct Fifo {
+    path: PathBuf,
+}
+
+impl Fifo {
+    /// creates a new FIFO in the temporary directory, returning it along with a pipe whose
+    /// contents are forwarded into it once something opens it for reading
+    ///
+    /// the forwarding is done from a background thread since opening a FIFO blocks until both
+    /// ends have been opened, which also means nothing written is lost if the command writing
+    /// finishes before whatever reads it has started
+    ///
+    /// the FIFO is made in a directory of its own that only the user can get into, so its path
+    /// can't be guessed ahead of time and nothing else can open it first
+    fn new() -> io::Result<(Self, File)> {
+        let dir = unistd::mkdtemp(&std::env::temp_dir().join("rs-shell-XXXXXX"))?;
+        let path = dir.join("fifo");
+        if let Err(e) = unistd::mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR) {
+            let _ = fs::remove_dir(&dir);
+            return Err(e.into());
+        }
+
+        let (mut reader, writer) = io::pipe()?;
+        let fifo = path.clone();
+        thread::spawn(move || {
+            if let Ok(mut fifo) = File::options().write(true).open(fifo) {
+                // the reader closing early isn't an error worth reporting
+                let _ = io::copy(&mut reader, &mut fifo);
+            }
+        });
+        Ok((Self { path }, File::from(OwnedFd::from(writer))))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Fifo {
+    fn drop(&mut self) {
+        // if nothing ever opened the FIFO, this lets the forwarding thread's open go through so
+        // it can give up, and with it the command writing into the pipe
+        let _ = File::options()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&self.path);
+        let _ = fs::remove_file(&self.path);
+        if let Some(dir) = self.path.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+/// the FIFOs of the process substitutions in a command, shared between every job started for it,
+/// so that they're only removed once the command's finished and each of those jobs has been reaped
+pub type SharedFifos = Arc<Vec<Fifo>>;
+
+/// an external command that's ready to be spawned
+#[derive(Debug)]
+pub struct Process {
+    /// the resolved path of the command, so `$PATH` isn't searched a second time when spawning
+    executable: PathBuf,
+    /// see [`FlattenedCmdline::command_span`]
+    command_span: Option<Span>,
+    /// the command followed by its arguments
+    argv: Vec<OsString>,
+    /// one-shot environment variables, set on top of the shell's environment
+    envs: Vec<(env::Name, OsString)>,
+    /// where stdin, stdout, and stderr go once the command's redirects have been applied
+    stdio: [Stream; 3],
+    /// the threads copying the output of streams redirected to more than one file, see [`fan_out`]
+    tees: Vec<JoinHandle<()>>,
+}
+
+impl Process {
+    /// the command line this process was built from, as shown in job listings
+    fn describe(&self) -> String {
+        self.argv
+            .iter()
+            .map(|x| x.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// starts the process in the process group `pgid`, `stdin` and `stdout` are the pipes
+    /// connecting it to the rest of its pipeline and `stderr` is where its job's stderr goes,
+    /// explicit redirects take precedence over all of them
+    fn spawn(
+        &self,
+        stdin: Option<&File>,
+        stdout: Option<&File>,
+        stderr: Option<&File>,
+        pgid: Option<Pid>,
+        foreground: bool,
+    ) -> io::Result<Pid> {
+        let pipeline = [stdin, stdout, stderr];
+        // a stream duplicated from one of the shell's own (e.g. `2>&1` outside of a pipeline)
+        // needs its own handle to it
+        let mut shell = [None, None, None];
+        for (i, stream) in self.stdio.iter().enumerate() {
+            if let Stream::Inherited(x) = *stream {
+                if x != i && pipeline[x].is_none() {
+                    shell[i] = Some(shell_stream(x)?);
+                }
+            }
+        }
+        let environ = env::environ();
+        let sandbox = Sandbox::current().map(Sandbox::prepare);
+        spawn::spawn(&spawn::Command {
+            executable: &self.executable,
+            argv: &self.argv,
+            env: &environ,
+            env_overrides: &self.envs,
+            stdio: std::array::from_fn(|i| match &self.stdio[i] {
+                Stream::Inherited(x) => pipeline[*x].or(shell[i].as_ref()),
+                Stream::File(file) => Some(file),
+                Stream::Closed => None,
+            }),
+            closed: self.stdio.each_ref().map(|x| matches!(x, Stream::Closed)),
+            pgid,
+            foreground,
+            sandbox: sandbox.as_ref(),
+            priority: priority::current(),
         })
+    }
+}
+
+/// where one of a [`Process`]'s standard streams goes, see [`Target`]
+#[derive(Debug)]
+enum Stream {
+    /// fd `n` of the pipeline, or of the shell if it isn't connected to anything
+    Inherited(usize),
+    File(File),
+    /// closed with `>&-`, which also keeps it from being connected to the rest of the pipeline
+    Closed,
+}
+
+/// a new handle to one of the shell's own stdin, stdout, or stderr
+pub(crate) fn shell_stream(fd: usize) -> io::Result<File> {
+    let owned = match fd {
+        0 => io::stdin().as_fd().try_clone_to_owned(),
+        1 => io::stdout().as_fd().try_clone_to_owned(),
+        _ => io::stderr().as_fd().try_clone_to_owned(),
+    }?;
+    Ok(File::from(owned))
+}
+
+/// spawns every process of a pipeline, connecting each one's stdout to the next one's stdin, and
+/// adds them to the job table as a single job, returning its id
+///
+/// `stdin` and `stdout` are what the start and end of the pipeline are connected to and `stderr` is
+/// what all of it writes errors to, they default to the shell's own
+///
+/// with job control enabled the first process becomes the leader of a new process group that the
+/// rest of the pipeline joins (unless it's [`JobMode::Concurrent`]), which is given the terminal if
+/// the job is started in the foreground
+fn spawn_job(
+    procs: Vec<Process>,
+    mut stdin: Option<File>,
+    mut stdout: Option<File>,
+    stderr: Option<File>,
+    mode: JobMode,
+    fifos: &SharedFifos,
+) -> Result<usize, ProcError> {
+    let foreground = mode == JobMode::Foreground;
+    let command = procs
+        .iter()
+        .map(Process::describe)
+        .collect::<Vec<_>>()
+        .join(" | ");
+    let count = procs.len();
+    let mut pids = Vec::with_capacity(count);
+    let mut group = ProcessGroup::for_job(mode, jobs::enabled());
+
+    for (i, process) in procs.into_iter().enumerate() {
+        let (stdout, next_stdin) = if i + 1 < count {
+            let (reader, writer) = io::pipe().map_err(|e| ProcError::RedirectError {
+                path: "pipe".to_owned(),
+                internal: e,
+                span: None,
+            })?;
+            (
+                Some(File::from(OwnedFd::from(writer))),
+                Some(File::from(OwnedFd::from(reader))),
+            )
+        } else {
+            (stdout.take(), None)
+        };
+
+        let pid = match process.spawn(
+            stdin.as_ref(),
+            stdout.as_ref(),
+            stderr.as_ref(),
+            group.pgid(),
+            foreground,
+        ) {
+            Ok(x) => x,
+            Err(e) => {
+                // don't leave the start of a half spawned pipeline running
+                for pid in pids {
+                    let _ = signal::kill(pid, Signal::SIGKILL);
+                    let _ = waitpid(pid, None);
+                }
+                return Err(ProcError::SubprocessError {
+                    command: process
+                        .argv
+                        .first()
+                        .map(|x| x.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    path: process.executable,
+                    internal: e,
+                    span: process.command_span,
+                });
+            }
+        };
+        group.started(pid);
+        pids.push(pid);
+        stdin = next_stdin;
+    }
+
+    Ok(jobs::add(command, pids, group.leader(), fifos.clone()))
+}
+
+/// the process group the processes of a job are started in, see [`spawn_job`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ProcessGroup {
+    /// the shell's own, when job control is disabled or the job is [`JobMode::Concurrent`]
+    Shell,
+    /// a new group, which the next process started becomes the leader of
+    New,
+    /// the group led by the job's first process, which the rest of the pipeline joins
+    Joined(Pid),
+}
+
+impl ProcessGroup {
+    pub(crate) fn for_job(mode: JobMode, job_control: bool) -> Self {
+        match job_control && mode != JobMode::Concurrent {
+            true => ProcessGroup::New,
+            false => ProcessGroup::Shell,
+        }
+    }
+
+    /// the pgid to start the next process with, see [`spawn::Command::pgid`]
+    pub(crate) fn pgid(self) -> Option<Pid> {
+        match self {
+            ProcessGroup::Shell => None,
+            ProcessGroup::New => Some(Pid::from_raw(0)),
+            ProcessGroup::Joined(x) => Some(x),
+        }
+    }
+
+    /// records that `pid` was started with [`ProcessGroup::pgid`]
+    pub(crate) fn started(&mut self, pid: Pid) {
+        if *self == ProcessGroup::New {
+            *self = ProcessGroup::Joined(pid);
+        }
+    }
+
+    /// the job's process group, once its first process has been started, `None` if it shares the
+    /// shell's
+    pub(crate) fn leader(self) -> Option<Pid> {
+        match self {
+            ProcessGroup::Joined(x) => Some(x),
+            _ => None,
+        }
+    }
 }
 
 pub enum Execable {
-    Exec(Exec),
-    Pipeline(Pipeline),
+    /// a single external command, or a pipeline of them
+    External(Vec<FlattenedCmdline>),
     Builtin(Builtin),
+    /// a command line that only assigns variables, e.g. `NAME=value`, which are set in the shell
+    /// itself
+    Assignment(Vec<(env::Name, OsString)>),
 }
 
 impl Execable {
-    fn join(self) -> Result<ExitStatus, ProcError> {
+    /// runs this with `backend`, waiting for it to finish or be stopped if it's started in the
+    /// foreground
+    ///
+    /// builtins always run to completion, since they run inside the shell itself
+    fn run(
+        self,
+        backend: &mut dyn ProcessBackend,
+        stdout: Option<File>,
+        stderr: Option<File>,
+        mode: JobMode,
+    ) -> Result<ExitStatus, ProcError> {
         match self {
-            Execable::Exec(x) => x
-                .join()
-                .map_err(|e| ProcError::SubprocessError { internal: e }),
-            Execable::Pipeline(x) => x
-                .join()
-                .map_err(|e| ProcError::SubprocessError { internal: e }),
-            Execable::Builtin(x) => Ok(x.execute()),
+            Execable::External(commands) => backend.run(ExternalJob {
+                commands,
+                stdin: None,
+                stdout,
+                stderr,
+                mode,
+            }),
+            Execable::Builtin(x) => {
+                let status = x.execute(backend, stdout, stderr)?;
+                Ok(match mode {
+                    JobMode::Foreground | JobMode::Concurrent => status,
+                    _ => ExitStatus::Exited(0),
+                })
+            }
+            Execable::Assignment(envs) => {
+                for (name, value) in envs {
+                    env::set(name, value).map_err(|e| ProcError::Env { internal: e })?;
+                }
+                Ok(ExitStatus::Exited(0))
+            }
         }
     }
 
-    fn capture(self) -> Result<CaptureData, ProcError> {
+    /// the name and kind of each command this runs, assignments don't run any
+    fn commands(&self) -> Vec<(String, metrics::Kind)> {
+        let name = |x: &FlattenedCmdline| x.command.to_string_lossy().into_owned();
         match self {
-            Execable::Exec(x) => x
-                .capture()
-                .map_err(|e| ProcError::SubprocessError { internal: e }),
-            Execable::Pipeline(x) => x
-                .capture()
-                .map_err(|e| ProcError::SubprocessError { internal: e }),
-            Execable::Builtin(x) => Ok(x.capture()),
+            Execable::External(x) => x
+                .iter()
+                .map(|x| (name(x), metrics::Kind::External))
+                .collect(),
+            Execable::Builtin(x) => x
+                .commands()
+                .enumerate()
+                .map(|(i, x)| match i {
+                    0 => (name(x), metrics::Kind::Builtin),
+                    _ => (name(x), metrics::Kind::External),
+                })
+                .collect(),
+            Execable::Assignment(_) => Vec::new(),
         }
     }
 
-    fn pipe(self, into: Exec) -> Execable {
+    fn pipe(self, mut into: FlattenedCmdline) -> Execable {
         match self {
-            Execable::Exec(x) => (x | into).into(),
-            Execable::Pipeline(x) => (x | into).into(),
+            Execable::External(mut x) => {
+                x.push(into);
+                Execable::External(x)
+            }
             Execable::Builtin(x) => x.pipe(into).into(),
+            // like any other command in a pipeline, the assignment is made in a subshell, so it's
+            // a no-op that exits with 0 without writing anything, leaving nothing to read
+            Execable::Assignment(_) => {
+                let op = RedirectOp {
+                    fd: RedirectFd::Default,
+                    r#type: RedirectType::In,
+                    span: Span::default(),
+                };
+                into.redirects.insert(0, (op, NULL_PATH.into(), None));
+                Execable::External(vec![into])
+            }
         }
     }
 }
 
-impl From<Exec> for Execable {
-    fn from(value: Exec) -> Self {
-        Self::Exec(value)
+impl From<Builtin> for Execable {
+    fn from(value: Builtin) -> Self {
+        Self::Builtin(value)
     }
 }
 
-impl From<Pipeline> for Execable {
-    fn from(value: Pipeline) -> Self {
-        Self::Pipeline(value)
+/// a [`ProcessBackend`] for tests that records what it's asked to run rather than running it, and
+/// macros to go with it, so a test can check that some input leads to exactly the jobs expected
+/// without touching the system
+///
+/// ```ignore
+/// let (backend, status) = record!("A=1 a x > f | b; c &", Exited(3) => "");
+/// assert_spawns!(backend, ["A=1 a x >f | b", "c &"]);
+/// ```
+#[cfg(test)]
+pub(crate) mod recording {
+    use std::{
+        collections::VecDeque,
+        sync::{
+            atomic::{AtomicI32, Ordering},
+            Arc,
+        },
+    };
+
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    /// the exit status and stdout of each job, or the error it fails with, in the order they're run
+    type Script = VecDeque<Result<(ExitStatus, Vec<u8>), ProcError>>;
+
+    /// a job that a [`RecordingBackend`] was asked to run
+    #[derive(Debug, Clone)]
+    pub struct RecordedJob {
+        /// the commands making up the pipeline, see [`ExternalJob::commands`]
+        pub commands: Vec<FlattenedCmdline>,
+        pub mode: JobMode,
+        /// the process group each command would've been started in with job control enabled,
+        /// each command being given the next of a sequence of made up pids
+        pub pgids: Vec<Option<Pid>>,
+        /// the FIFOs the job was given to hold on to, which it keeps unless it's run in the
+        /// foreground since nothing here is ever reaped
+        pub fifos: SharedFifos,
+    }
+
+    impl RecordedJob {
+        /// the job written as a fully expanded command line, the way `set -x` shows it, followed
+        /// by `&` unless it's run in the foreground, and `&!` if it's detached
+        pub fn line(&self) -> String {
+            // whether it's in the background is up to the mode rather than how it was written
+            let stages = self.commands.iter().map(|x| {
+                let cmd = FlattenedCmdline {
+                    background: false,
+                    ..x.clone()
+                };
+                describe_stage(&cmd).0
+            });
+            let mut line = stages.collect::<Vec<_>>().join(" | ");
+            match self.mode {
+                JobMode::Foreground | JobMode::Concurrent => (),
+                JobMode::Background => line.push_str(" &"),
+                JobMode::Detached => line.push_str(" &!"),
+            }
+            line
+        }
+    }
+
+    /// a [`ProcessBackend`] for tests, which records every job it's asked to run rather than
+    /// running it and answers with scripted results
+    #[derive(Debug, Default, Clone)]
+    pub struct RecordingBackend {
+        /// every job run so far, shared between clones so they can still be looked at once the
+        /// backend has been handed to a [`ProcManager`]
+        pub jobs: Arc<Mutex<Vec<RecordedJob>>>,
+        /// jobs run after this runs out exit successfully without any output
+        script: Arc<Mutex<Script>>,
+        /// the last pid made up for a process, see [`RecordedJob::pgids`]
+        last_pid: Arc<AtomicI32>,
+        /// see [`ProcessBackend::hold`]
+        fifos: SharedFifos,
+    }
+
+    impl RecordingBackend {
+        /// adds the result of the next job to the script
+        pub fn then(self, status: ExitStatus, stdout: &str) -> Self {
+            self.script.lock().push_back(Ok((status, stdout.into())));
+            self
+        }
+
+        /// makes the next job in the script fail with `error` rather than run, e.g. as if its
+        /// command couldn't be found
+        pub fn fail(self, error: ProcError) -> Self {
+            self.script.lock().push_back(Err(error));
+            self
+        }
+
+        /// every job run so far, see [`RecordedJob::line`]
+        pub fn lines(&self) -> Vec<String> {
+            self.jobs.lock().iter().map(RecordedJob::line).collect()
+        }
+    }
+
+    impl ProcessBackend for RecordingBackend {
+        fn run(&mut self, job: ExternalJob) -> Result<ExitStatus, ProcError> {
+            let mut group = ProcessGroup::for_job(job.mode, true);
+            let pgids = job
+                .commands
+                .iter()
+                .map(|_| {
+                    let pid = Pid::from_raw(self.last_pid.fetch_add(1, Ordering::Relaxed) + 1);
+                    let pgid = group.pgid().map(|x| if x.as_raw() == 0 { pid } else { x });
+                    group.started(pid);
+                    pgid
+                })
+                .collect();
+            self.jobs.lock().push(RecordedJob {
+                commands: job.commands,
+                mode: job.mode,
+                pgids,
+                fifos: match job.mode {
+                    JobMode::Foreground | JobMode::Concurrent => SharedFifos::default(),
+                    _ => self.fifos.clone(),
+                },
+            });
+            let (status, output) = self
+                .script
+                .lock()
+                .pop_front()
+                .unwrap_or(Ok((ExitStatus::Exited(0), Vec::new())))?;
+            if let Some(mut stdout) = job.stdout {
+                stdout
+                    .write_all(&output)
+                    .map_err(|e| ProcError::RedirectError {
+                        path: "pipe".to_owned(),
+                        internal: e,
+                        span: None,
+                    })?;
+            }
+            Ok(match job.mode {
+                JobMode::Foreground | JobMode::Concurrent => status,
+                _ => ExitStatus::Exited(0),
+            })
+        }
+
+        fn fork(&self) -> Box<dyn ProcessBackend + Send> {
+            Box::new(self.clone())
+        }
+
+        fn hold(&mut self, fifos: SharedFifos) {
+            self.fifos = fifos;
+        }
+    }
+
+    /// parses and runs `input` with a new [`Evaluator`](crate::Evaluator) on a
+    /// [`RecordingBackend`], which answers each job with the next `status => stdout` given,
+    /// returning the backend along with the result
+    macro_rules! record {
+        ($input:expr $(, $status:expr => $stdout:expr)* $(,)?) => {{
+            let backend = $crate::proc_manager::recording::RecordingBackend::default()
+                $(.then($status, $stdout))*;
+            let mut evaluator = $crate::Evaluator::with_backend(backend.clone());
+            let ast = $crate::ast::generate_ast($input).expect("test input must parse");
+            let result = evaluator.eval(ast);
+            (backend, result)
+        }};
     }
+
+    /// asserts that a [`RecordingBackend`] was asked to run exactly the jobs given, in order, each
+    /// written as a command line, see [`RecordedJob::line`]
+    macro_rules! assert_spawns {
+        ($backend:expr, [$($line:expr),* $(,)?] $(,)?) => {{
+            let expected: Vec<&str> = vec![$($line),*];
+            assert_eq!($backend.lines(), expected);
+        }};
+    }
+
+    pub(crate) use {assert_spawns, record};
 }
 
-impl From<Builtin> for Execable {
-    fn from(value: Builtin) -> Self {
-        Self::Builtin(value)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Evaluator;
+
+    fn flatten(input: &str) -> FlattenedCmdline {
+        let ast = crate::ast::generate_ast(input).expect("test input must parse");
+        Evaluator::with_backend(recording::RecordingBackend::default())
+            .flatten(ast)
+            .unwrap()
+    }
+
+    #[test]
+    fn quote_reads_back() {
+        let words = [
+            "plain",
+            "",
+            "a b",
+            "it's",
+            "a;b|c&d",
+            "\"$HOME\" \\n",
+            "'$x' \\\"y\"",
+        ];
+        for word in words {
+            let cmd = flatten(&format!("a {}", quote(OsStr::new(word))));
+            assert_eq!(cmd.arguments, [OsString::from(word)], "{}", word);
+        }
+    }
+
+    #[test]
+    fn dry_run_output() {
+        let backend = recording::RecordingBackend::default();
+        let mut manager = ProcManager::with_backend(backend.clone());
+        let cmd = flatten("a \"it's\" 'x;y' | b > f");
+        let (status, output) = options::with(ShellOption::DryRun, true, || {
+            manager.dispatch_capture(cmd, false)
+        })
+        .unwrap();
+        assert!(status.success());
+        assert_eq!(output, "a \"it's\" 'x;y' | b >f\n");
+        assert!(backend.lines().is_empty());
+    }
+
+    #[test]
+    fn process_groups() {
+        use recording::{assert_spawns, record};
+
+        let (backend, _) = record!("a | b | c; d & e | f; parallel 'g | h'");
+        assert_spawns!(backend, ["a | b | c", "d &", "e | f", "g | h"]);
+        let pgids = backend
+            .jobs
+            .lock()
+            .iter()
+            .map(|x| x.pgids.iter().map(|x| x.map(Pid::as_raw)).collect())
+            .collect::<Vec<Vec<_>>>();
+        // every process of a pipeline joins the group of its first, except when it's run
+        // concurrently with others by a builtin
+        assert_eq!(
+            pgids,
+            [
+                vec![Some(1), Some(1), Some(1)],
+                vec![Some(4)],
+                vec![Some(5), Some(5)],
+                vec![None, None],
+            ]
+        );
+    }
+
+    #[test]
+    fn piped_assignments() {
+        use recording::{assert_spawns, record};
+
+        env::make_readonly("RS_TEST_PIPED".into(), Some("x".into())).unwrap();
+        // the assignment is made in a subshell, so it's a no-op that can't fail
+        let (backend, status) = record!("RS_TEST_PIPED=y | a; RS_TEST_PIPED_TOO=1 | b | c");
+        assert!(status.unwrap().success());
+        assert_spawns!(backend, ["a </dev/null", "b </dev/null | c"]);
+        assert_eq!(env::get("RS_TEST_PIPED"), "x");
+        assert_eq!(env::lookup("RS_TEST_PIPED_TOO"), None);
+
+        let (_, status) = record!("a | RS_TEST_PIPED_TOO=1");
+        assert!(matches!(
+            status,
+            Err(crate::EvalError::DispatchError {
+                internal: ProcError::NotImplemented { .. }
+            })
+        ));
+    }
+
+    #[test]
+    fn xtrace_lines() {
+        let cmd = flatten("A=\"x y\" a 'b c' > f | b; c");
+        assert_eq!(trace(&cmd), "+ A='x y' a 'b c' >f | b\n");
+        env::set(XTRACE_PREFIX_VAR.into(), ">> ".into()).unwrap();
+        let traced = trace(&cmd);
+        env::unset(XTRACE_PREFIX_VAR).unwrap();
+        assert_eq!(traced, ">> A='x y' a 'b c' >f | b\n");
+    }
+
+    #[test]
+    fn command_not_found() {
+        let backend = NativeBackend::default();
+        let name = "rs-shell-no-such-command";
+        let missing = flatten(&format!("{} x", name));
+        let e = backend.resolve_command(missing.clone()).unwrap_err();
+        assert!(matches!(&e, ProcError::CommandNotFound { name: x, .. } if x == name));
+        assert_eq!(e.exit_status(), ExitStatus::Exited(127));
+        // which is what `$?` is for the rest of the line
+        let mut evaluator = Evaluator::new();
+        let line = format!("{} x; RS_TEST_NOT_FOUND=$?", name);
+        evaluator
+            .eval(crate::ast::generate_ast(&line).unwrap())
+            .unwrap();
+        assert_eq!(env::get("RS_TEST_NOT_FOUND"), "127");
+
+        // the handler is run in its place, with the original command line as its arguments
+        env::set(NOT_FOUND_HANDLER_VAR.into(), "sh".into()).unwrap();
+        let resolved = backend.resolve_command(missing);
+        env::unset(NOT_FOUND_HANDLER_VAR).unwrap();
+        let (cmd, executable) = resolved.unwrap();
+        assert_eq!(cmd.command, "sh");
+        assert_eq!(cmd.arguments, [name, "x"]);
+        assert_eq!(Some(executable), path::search(OsStr::new("sh")));
+    }
+
+    #[test]
+    fn corrections() {
+        let cmd = flatten("shoptt -s autocd");
+        assert_eq!(correct(cmd.clone(), |_, _| true).command, "shoptt");
+        options::with(ShellOption::Correct, true, || {
+            assert_eq!(correct(cmd.clone(), |_, _| false).command, "shoptt");
+            // a correction to a builtin is run as one
+            let corrected = correct(cmd, |name, x| name == "shoptt" && x == "shopt");
+            assert!(matches!(
+                Builtin::maybe_new(corrected),
+                BuiltinCheck::Yes(_)
+            ));
+        });
+    }
+
+    #[test]
+    fn fan_out_copies_to_every_file() {
+        let paths = (0..2)
+            .map(|i| {
+                std::env::temp_dir().join(format!("rs-shell-test-{}-{}", std::process::id(), i))
+            })
+            .collect::<Vec<_>>();
+        let files = paths
+            .iter()
+            .map(|x| File::create(x).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut tees = Vec::new();
+        let mut writer = fan_out(files, &mut tees).unwrap().unwrap();
+        writer.write_all(b"hello\n").unwrap();
+        drop(writer);
+        for tee in tees {
+            tee.join().unwrap();
+        }
+
+        for path in paths {
+            assert_eq!(fs::read(&path).unwrap(), b"hello\n");
+            fs::remove_file(path).unwrap();
+        }
     }
 }
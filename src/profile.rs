@@ -0,0 +1,108 @@
+//! timing of the phases each command goes through, reported after it's run when the shell is
+//! started with `--profile`, to help track down where a slow command line spends its time
+
+use std::{
+    fmt::{self, Display},
+    ops::AddAssign,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// a phase of running a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// turning the text of a command line into an AST, see [`crate::ast::generate_ast`]
+    Parse,
+    /// expanding everything in the AST, which includes running any substitutions in it
+    Flatten,
+    /// actually running the commands
+    Dispatch,
+}
+
+/// how long was spent in each [`Phase`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    pub parse: Duration,
+    pub flatten: Duration,
+    pub dispatch: Duration,
+}
+
+impl Timings {
+    fn get_mut(&mut self, phase: Phase) -> &mut Duration {
+        match phase {
+            Phase::Parse => &mut self.parse,
+            Phase::Flatten => &mut self.flatten,
+            Phase::Dispatch => &mut self.dispatch,
+        }
+    }
+}
+
+impl AddAssign for Timings {
+    fn add_assign(&mut self, rhs: Self) {
+        self.parse += rhs.parse;
+        self.flatten += rhs.flatten;
+        self.dispatch += rhs.dispatch;
+    }
+}
+
+impl Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "parse {:?}, flatten {:?}, dispatch {:?}",
+            self.parse, self.flatten, self.dispatch
+        )
+    }
+}
+
+/// the timings of the command currently being run, `None` unless profiling's been enabled
+fn current() -> &'static Mutex<Option<Timings>> {
+    static CURRENT: OnceLock<Mutex<Option<Timings>>> = OnceLock::new();
+    CURRENT.get_or_init(|| Mutex::new(None))
+}
+
+/// starts timing commands, this can't be undone
+pub fn enable() {
+    current().lock().get_or_insert_with(Timings::default);
+}
+
+pub fn enabled() -> bool {
+    current().lock().is_some()
+}
+
+/// measures the time until the returned guard is dropped as spent in `phase`
+pub fn start(phase: Phase) -> Timer {
+    Timer {
+        phase,
+        start: Instant::now(),
+    }
+}
+
+/// see [`start`]
+#[must_use]
+pub struct Timer {
+    phase: Phase,
+    start: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(timings) = current().lock().as_mut() {
+            *timings.get_mut(self.phase) += self.start.elapsed();
+        }
+    }
+}
+
+/// prints how long the command that just finished, described by `what`, spent in each phase, and
+/// starts timing the next one afresh, returning the timings so they can be totalled up
+///
+/// does nothing (and returns zero for everything) if profiling isn't enabled
+pub fn report(what: impl Display) -> Timings {
+    let Some(timings) = current().lock().as_mut().map(std::mem::take) else {
+        return Timings::default();
+    };
+    eprintln!("profile: {}: {}", what, timings);
+    timings
+}
@@ -0,0 +1,420 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use subprocess::{Exec, NullFile, Redirection};
+
+use cs128h_project::env;
+
+use crate::{config, theme};
+
+/// the prompt template used when `PS1` is unset
+pub const DEFAULT_TEMPLATE: &str = "rs-shell $ ";
+
+/// text shown in place of an async segment that has never finished computing in this directory
+const PLACEHOLDER: &str = "…";
+
+/// command run by the `\g` escape to find the current git branch
+const GIT_BRANCH_CMD: &str = "git rev-parse --abbrev-ref HEAD";
+
+/// information about the previously executed command that prompt escapes can refer to
+pub use cs128h_project::repl::Context;
+
+/// a single parsed piece of a prompt template
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// text that is printed verbatim
+    Literal(String),
+    /// the current working directory with `$HOME` abbreviated to `~` (`\w`)
+    Cwd,
+    /// the final component of the current working directory (`\W`)
+    CwdBase,
+    /// the value of `$USER` (`\u`)
+    User,
+    /// the exit code of the last command (`\?`)
+    Status,
+    /// the wall-clock duration of the last command (`\D`)
+    Duration,
+    /// the output of a shell command (`\(cmd)`, `\g`), computed on a background thread so a slow
+    /// command never blocks the prompt from being printed
+    Async(String),
+}
+
+/// the cached result of an async segment for a specific working directory
+#[derive(Debug, Default)]
+struct AsyncState {
+    /// the output of the most recent completed run, if any
+    value: Option<String>,
+    /// whether a background thread is currently recomputing this segment
+    running: bool,
+}
+
+type Hook = Box<dyn FnMut() + Send>;
+
+/// called whenever an async segment finishes, see [`Prompt::on_update`]
+#[derive(Clone, Default)]
+struct UpdateHook(Arc<Mutex<Option<Hook>>>);
+
+impl UpdateHook {
+    fn call(&self) {
+        if let Some(hook) = &mut *self.0.lock() {
+            hook();
+        }
+    }
+}
+
+impl fmt::Debug for UpdateHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UpdateHook")
+    }
+}
+
+/// renders `PS1`-style prompt templates
+///
+/// async segments are rendered stale-while-revalidate: every render shows the last completed value
+/// (or a placeholder) and kicks off a fresh computation in the background, once it's done the
+/// [`Prompt::on_update`] hook is called so the frontend can repaint with [`Prompt::rerender`],
+/// otherwise it's picked up by the next prompt.
+#[derive(Debug, Clone, Default)]
+pub struct Prompt {
+    cache: Arc<Mutex<HashMap<(PathBuf, String), AsyncState>>>,
+    /// incremented every time a background computation finishes, or the prompt is invalidated
+    generation: Arc<AtomicU64>,
+    on_update: UpdateHook,
+}
+
+impl Prompt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// renders `template`, starting background computations for any async segments
+    ///
+    /// this never waits on an async segment, it will always return immediately
+    pub fn render(&self, template: &str, ctx: &Context) -> String {
+        self.render_internal(template, ctx, true)
+    }
+
+    /// renders `template` using whatever async results are available right now, without starting
+    /// any new background work
+    pub fn rerender(&self, template: &str, ctx: &Context) -> String {
+        self.render_internal(template, ctx, false)
+    }
+
+    /// a counter that changes whenever an async segment finishes (or [`Prompt::invalidate`] is
+    /// called), used by the frontend to decide when a repaint is worthwhile
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// makes the frontend repaint the prompt, e.g. because the template has changed
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// sets the hook called from the background thread once an async segment finishes, which
+    /// replaces any set before, [`Prompt::invalidate`] doesn't call it since the prompt can only
+    /// change in between prompts then
+    pub fn on_update(&self, hook: impl FnMut() + Send + 'static) {
+        *self.on_update.0.lock() = Some(Box::new(hook));
+    }
+
+    fn render_internal(&self, template: &str, ctx: &Context, spawn: bool) -> String {
+        let cwd = std::env::current_dir().unwrap_or_default();
+        let mut out = String::new();
+        for segment in parse_template(template) {
+            match segment {
+                Segment::Literal(x) => out.push_str(&x),
+                Segment::Cwd => out.push_str(&abbreviate_home(&cwd)),
+                Segment::CwdBase => out.push_str(
+                    &cwd.file_name()
+                        .map(|x| x.to_string_lossy().to_string())
+                        .unwrap_or_else(|| cwd.to_string_lossy().to_string()),
+                ),
+                Segment::User => out.push_str(&env::get("USER").to_string_lossy()),
+                Segment::Status => out.push_str(&ctx.status.to_string()),
+                Segment::Duration => out.push_str(&format_duration(ctx.duration)),
+                Segment::Async(cmd) => out.push_str(&self.async_segment(&cwd, cmd, spawn)),
+            }
+        }
+        match ctx.status {
+            0 => out,
+            _ => theme::paint(&config::get().theme.prompt_failure, &out),
+        }
+    }
+
+    fn async_segment(&self, cwd: &Path, cmd: String, spawn: bool) -> String {
+        let key = (cwd.to_path_buf(), cmd);
+        let mut cache = self.cache.lock();
+        let state = cache.entry(key.clone()).or_default();
+        let value = state
+            .value
+            .clone()
+            .unwrap_or_else(|| PLACEHOLDER.to_owned());
+
+        if spawn && !state.running {
+            state.running = true;
+            let cache = Arc::clone(&self.cache);
+            let generation = Arc::clone(&self.generation);
+            let on_update = self.on_update.clone();
+            thread::spawn(move || {
+                let output = run_segment(&key.0, &key.1);
+                if let Some(state) = cache.lock().get_mut(&key) {
+                    state.value = Some(output);
+                    state.running = false;
+                }
+                generation.fetch_add(1, Ordering::AcqRel);
+                on_update.call();
+            });
+        }
+
+        value
+    }
+}
+
+/// runs an async segment's command and returns the first line of its stdout, failures render as an
+/// empty segment
+fn run_segment(cwd: &Path, cmd: &str) -> String {
+    // stdout is only captured by default if stderr isn't redirected either
+    match Exec::shell(cmd)
+        .cwd(cwd)
+        .stdin(NullFile)
+        .stdout(Redirection::Pipe)
+        .stderr(NullFile)
+        .capture()
+    {
+        Ok(x) if x.success() => x
+            .stdout_str()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim_end()
+            .to_owned(),
+        _ => String::new(),
+    }
+}
+
+fn abbreviate_home(path: &Path) -> String {
+    let home = PathBuf::from(env::get("HOME"));
+    match path.strip_prefix(&home) {
+        Ok(rest) if !home.as_os_str().is_empty() => {
+            if rest.as_os_str().is_empty() {
+                "~".to_owned()
+            } else {
+                format!("~/{}", rest.to_string_lossy())
+            }
+        }
+        _ => path.to_string_lossy().to_string(),
+    }
+}
+
+/// formats a duration compactly for the prompt, e.g. `320ms`, `4.2s`, or `3m07s`
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    if millis < 1000 {
+        format!("{}ms", millis)
+    } else if millis < 60_000 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else {
+        let secs = duration.as_secs();
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// parses a prompt template into its segments
+///
+/// unrecognized escapes are kept verbatim so a typo in `PS1` is visible rather than silently eaten
+fn parse_template(template: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            literal.push(c);
+            continue;
+        }
+        let segment = match chars.next() {
+            Some('w') => Segment::Cwd,
+            Some('W') => Segment::CwdBase,
+            Some('u') => Segment::User,
+            Some('?') => Segment::Status,
+            Some('D') => Segment::Duration,
+            Some('g') => Segment::Async(GIT_BRANCH_CMD.to_owned()),
+            Some('(') => {
+                let mut depth = 1;
+                let mut cmd = String::new();
+                for c in chars.by_ref() {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => (),
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                    cmd.push(c);
+                }
+                Segment::Async(cmd)
+            }
+            Some('n') => {
+                literal.push('\n');
+                continue;
+            }
+            Some('$') => {
+                literal.push('$');
+                continue;
+            }
+            Some('\\') => {
+                literal.push('\\');
+                continue;
+            }
+            Some(x) => {
+                literal.push('\\');
+                literal.push(x);
+                continue;
+            }
+            None => {
+                literal.push('\\');
+                continue;
+            }
+        };
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(std::mem::take(&mut literal)));
+        }
+        segments.push(segment);
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+
+    segments
+}
+
+/// the number of terminal columns `s` occupies, ignoring ANSI escape sequences
+pub fn display_width(s: &str) -> usize {
+    let mut width = 0;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
+
+/// `fresh` with its last line padded with spaces, or cut short at the start with an ellipsis, to
+/// the width of the last line of `shown`, so it can be drawn in place of `shown` without moving
+/// where the input starts
+///
+/// escape sequences are kept as they are, so colors still apply to whatever's left
+pub fn fit(fresh: &str, shown: &str) -> String {
+    let last_line = |s: &'_ str| s.rfind('\n').map_or(0, |i| i + 1);
+    let (head, tail) = fresh.split_at(last_line(fresh));
+    let width = display_width(&shown[last_line(shown)..]);
+    let current = display_width(tail);
+    let mut out = head.to_owned();
+    if width == 0 {
+        return out;
+    }
+    if current <= width {
+        out.push_str(tail);
+        out.extend(std::iter::repeat_n(' ', width - current));
+        return out;
+    }
+    // the ellipsis takes one of the columns
+    let mut skip = current - width + 1;
+    out.push_str(PLACEHOLDER);
+    let mut chars = tail.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            out.push(c);
+            for c in chars.by_ref() {
+                out.push(c);
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else if skip > 0 {
+            skip -= 1;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_parsing() {
+        assert_eq!(
+            parse_template("\\u:\\w \\(echo hi)\\$ "),
+            vec![
+                Segment::User,
+                Segment::Literal(":".to_owned()),
+                Segment::Cwd,
+                Segment::Literal(" ".to_owned()),
+                Segment::Async("echo hi".to_owned()),
+                Segment::Literal("$ ".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn async_segment_never_blocks() {
+        let prompt = Prompt::new();
+        assert_eq!(
+            prompt.render("\\(sleep 1; echo done)", &Context::default()),
+            PLACEHOLDER
+        );
+    }
+
+    #[test]
+    fn async_segment_update() {
+        let prompt = Prompt::new();
+        let (sender, updated) = std::sync::mpsc::channel();
+        prompt.on_update(move || sender.send(()).unwrap());
+        let ctx = Context::default();
+        assert_eq!(prompt.render("[\\(echo hi)] ", &ctx), "[…] ");
+        updated.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(prompt.rerender("[\\(echo hi)] ", &ctx), "[hi] ");
+    }
+
+    #[test]
+    fn fitting_repaints() {
+        assert_eq!(fit("[hi] ", "[…] "), "…i] ");
+        assert_eq!(fit("[] ", "[…] "), "[]  ");
+        assert_eq!(fit("\x1b[1mmain\x1b[0m $ ", "…… $ "), "…\x1b[1mn\x1b[0m $ ");
+        // only the line the input is on has to stay the same width
+        assert_eq!(fit("~/a (main)\n$ ", "~/a (…)\n$ "), "~/a (main)\n$ ");
+    }
+
+    #[test]
+    fn status_and_duration() {
+        let prompt = Prompt::new();
+        let ctx = Context {
+            status: 2,
+            duration: Duration::from_millis(4300),
+            prompt: None,
+        };
+        assert_eq!(prompt.render("[\\?] \\D", &ctx), "\x1b[31m[2] 4.3s\x1b[0m");
+    }
+}
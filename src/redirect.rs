@@ -0,0 +1,201 @@
+//! works out where each of a command's standard streams ends up by applying its redirects one at a
+//! time from left to right, the same as POSIX shells do, so `cmd > f 2>&1` sends both stdout and
+//! stderr to `f` while `cmd 2>&1 > f` sends stderr to wherever stdout was before `> f`
+
+use std::ffi::OsString;
+use std::fs::File;
+
+use crate::ast::{RedirectFd, RedirectOp, RedirectType, Span};
+use crate::proc_manager::{self, ProcError};
+
+/// where one of stdin, stdout, or stderr is connected to
+#[derive(Debug)]
+pub(crate) enum Target {
+    /// whatever fd `n` was connected to before any redirects, i.e. the pipeline or the shell's own
+    Inherited(usize),
+    /// every file the stream has been redirected to, output is copied to all of them
+    Files(Vec<File>),
+    /// closed with `>&-`
+    Closed,
+}
+
+impl Target {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(match self {
+            Target::Inherited(x) => Target::Inherited(*x),
+            Target::Files(files) => Target::Files(
+                files
+                    .iter()
+                    .map(File::try_clone)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Target::Closed => Target::Closed,
+        })
+    }
+}
+
+/// applies `redirects` in order, starting from every stream being inherited
+///
+/// with `read_input` false redirects of stdin are skipped entirely, for builtins which never read
+/// it
+pub(crate) fn apply(
+    redirects: &[(RedirectOp, OsString, Option<Span>)],
+    read_input: bool,
+) -> Result<[Target; 3], ProcError> {
+    let mut stdio = [0, 1, 2].map(Target::Inherited);
+
+    for (op, path, span) in redirects {
+        let at = |e: ProcError| match span {
+            Some(span) => e.with_span(*span),
+            None => e,
+        };
+        let fds: &[usize] = match op.fd {
+            RedirectFd::All => &[1, 2],
+            RedirectFd::Default => match op.r#type {
+                RedirectType::In | RedirectType::HereString => &[0],
+                _ => &[1],
+            },
+            RedirectFd::Stdin => &[0],
+            RedirectFd::Stdout => &[1],
+            RedirectFd::Stderr => &[2],
+            RedirectFd::Fd(fd) => {
+                return Err(ProcError::UnsupportedFd {
+                    fd,
+                    span: span.map(|_| op.span),
+                })
+            }
+        };
+
+        match &op.r#type {
+            RedirectType::Out | RedirectType::OutAppend => {
+                if fds == [0] {
+                    return Err(ProcError::InvalidRedirect { op: op.clone() });
+                }
+                // `&>` shares one open file between both, so neither overwrites the other
+                let file = match op.r#type {
+                    RedirectType::Out => proc_manager::file_write(path).map_err(at)?,
+                    _ => proc_manager::file_append(path).map_err(at)?,
+                };
+                for &fd in fds {
+                    let file = file.try_clone().map_err(|e| ProcError::RedirectError {
+                        path: path.to_string_lossy().to_string(),
+                        internal: e,
+                        span: *span,
+                    })?;
+                    match &mut stdio[fd] {
+                        Target::Files(files) => files.push(file),
+                        x => *x = Target::Files(vec![file]),
+                    }
+                }
+            }
+            RedirectType::In | RedirectType::HereString if !read_input => {}
+            RedirectType::In => {
+                if fds != [0] {
+                    return Err(ProcError::InvalidRedirect { op: op.clone() });
+                }
+                stdio[0] = Target::Files(vec![proc_manager::file_read(path).map_err(at)?]);
+            }
+            // the "path" of a here-string is the data itself
+            RedirectType::HereString => {
+                if fds != [0] {
+                    return Err(ProcError::InvalidRedirect { op: op.clone() });
+                }
+                let mut data = path.as_encoded_bytes().to_vec();
+                data.push(b'\n');
+                let file = proc_manager::feed(data).map_err(|e| ProcError::RedirectError {
+                    path: "pipe".to_owned(),
+                    internal: e,
+                    span: None,
+                })?;
+                stdio[0] = Target::Files(vec![file]);
+            }
+            RedirectType::Close => {
+                for &fd in fds {
+                    stdio[fd] = Target::Closed;
+                }
+            }
+            RedirectType::Duplicate(target) => {
+                let target = match target.number() {
+                    Some(x @ 0..=2) => x as usize,
+                    Some(fd) => {
+                        return Err(ProcError::UnsupportedFd {
+                            fd,
+                            span: span.map(|_| op.span),
+                        })
+                    }
+                    None => return Err(ProcError::InvalidRedirect { op: op.clone() }),
+                };
+                for &fd in fds {
+                    stdio[fd] =
+                        stdio[target]
+                            .try_clone()
+                            .map_err(|e| ProcError::RedirectError {
+                                path: op.to_string(),
+                                internal: e,
+                                span: *span,
+                            })?;
+                }
+            }
+        }
+    }
+
+    Ok(stdio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evaluator::CommandBuilder;
+
+    fn files(target: &Target) -> usize {
+        match target {
+            Target::Files(x) => x.len(),
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn left_to_right() {
+        let dir = std::env::temp_dir().join(format!("rs-shell-redirect-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out");
+
+        // `> f 2>&1` sends both to the file
+        let cmd = CommandBuilder::new("true")
+            .redirect(RedirectFd::Default, RedirectType::Out, &path)
+            .duplicate(RedirectFd::Stderr, RedirectFd::Stdout)
+            .build();
+        let stdio = apply(&cmd.redirects, true).unwrap();
+        assert_eq!((files(&stdio[1]), files(&stdio[2])), (1, 1));
+
+        // `2>&1 > f` leaves stderr where stdout was
+        let cmd = CommandBuilder::new("true")
+            .duplicate(RedirectFd::Stderr, RedirectFd::Stdout)
+            .redirect(RedirectFd::Default, RedirectType::Out, &path)
+            .build();
+        let stdio = apply(&cmd.redirects, true).unwrap();
+        assert_eq!(files(&stdio[1]), 1);
+        assert!(matches!(stdio[2], Target::Inherited(1)));
+
+        // duplicating a closed fd closes the copy too
+        let cmd = CommandBuilder::new("true")
+            .close(RedirectFd::Stdout)
+            .duplicate(RedirectFd::Stderr, RedirectFd::Stdout)
+            .build();
+        let stdio = apply(&cmd.redirects, true).unwrap();
+        assert!(matches!(
+            stdio,
+            [Target::Inherited(0), Target::Closed, Target::Closed]
+        ));
+
+        let cmd = CommandBuilder::new("true")
+            .duplicate(RedirectFd::Stderr, RedirectFd::Fd(5))
+            .build();
+        assert!(matches!(
+            apply(&cmd.redirects, true),
+            Err(ProcError::UnsupportedFd { fd: 5, .. })
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+//! the interactive read-eval-print loop, with where lines come from and where the shell's own
+//! messages go left up to whatever's running it, so it can be driven by a line editor in a
+//! terminal, a TUI pane, a web terminal, or a test
+//!
+//! only what the REPL itself has to say (errors, job notifications, warnings about unfinished
+//! jobs, per-directory environments being loaded) is written to its output, the commands it runs still write to the shell's stdout and
+//! stderr, so those need pointing at e.g. a pty too for their output to end up in the same place
+
+use std::{io, io::Write, time::Duration};
+
+use log::{debug, warn};
+use subprocess::ExitStatus;
+
+use crate::{
+    direnv, env,
+    evaluator::{self, Evaluator},
+    i18n,
+    jobs::{self, JobState},
+    options::{self, ShellOption},
+    parse_cache, profile,
+};
+
+/// how many seconds a command line has to run for before the [`LineSource`] is told it's finished,
+/// see [`LineSource::long_command_finished`]
+const NOTIFY_AFTER_VAR: &str = "RS_SHELL_NOTIFY_AFTER";
+
+/// information about the previously run command, e.g. for the prompt
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    /// the exit code of the last command
+    pub status: u8,
+    /// how long the last command took to run
+    pub duration: Duration,
+    /// the prompt to show as it is, from `$RS_SHELL_PROMPT_CMD`, rather than one rendered from a
+    /// template, see [`Evaluator::prompt_command`]
+    pub prompt: Option<String>,
+}
+
+/// what a [`LineSource`] read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// a complete command line
+    Line(String),
+    /// the line being typed was abandoned, e.g. with ^C, so the REPL starts over
+    Interrupted,
+    /// there are no more lines, e.g. ^D was typed, so the REPL exits
+    Eof,
+}
+
+/// where a [`Repl`] gets its lines from, and is told about each command line as it's run
+pub trait LineSource {
+    /// reads the next command line, `ctx` being about the one before it
+    fn read_line(&mut self, ctx: &Context) -> io::Result<Input>;
+
+    /// the line that was just read is about to be run, or reported as a syntax error
+    fn command_started(&mut self) {}
+
+    /// the line that was just read has finished, with exit code `status`
+    fn command_finished(&mut self, _status: u8) {}
+
+    /// `line` has finished, with exit code `status`, after running for longer than
+    /// `$RS_SHELL_NOTIFY_AFTER` says is worth mentioning, e.g. with a desktop notification for
+    /// when the user's gone off to do something else while waiting for it
+    fn long_command_finished(&mut self, _line: &str, _status: u8, _duration: Duration) {}
+
+    /// the REPL is exiting normally, e.g. to save history
+    fn finish(&mut self) {}
+}
+
+/// the REPL, see the [module docs](self)
+pub struct Repl {
+    source: Box<dyn LineSource>,
+    output: Box<dyn Write>,
+    /// how many lines have been read, for `LINENO`
+    lines: usize,
+    /// whether the user's been warned about unfinished jobs since the last command, if so exiting
+    /// again (with ^D or `exit`) goes through with it
+    warned_jobs: bool,
+}
+
+impl Repl {
+    pub fn new(source: Box<dyn LineSource>, output: Box<dyn Write>) -> Self {
+        Self {
+            source,
+            output,
+            lines: 0,
+            warned_jobs: false,
+        }
+    }
+
+    /// reads and runs lines with `evaluator` until the source runs out or `exit` is run, returning
+    /// the status of the last command
+    ///
+    /// an error is only returned if the source or output fail, in which case
+    /// [`LineSource::finish`] isn't called
+    pub fn run(&mut self, evaluator: &mut Evaluator) -> io::Result<ExitStatus> {
+        loop {
+            for line in jobs::take_notifications() {
+                writeln!(self.output, "{}", line)?;
+            }
+            // the directory can change other than with `cd`, e.g. the one the shell started in
+            if options::enabled(ShellOption::Direnv) {
+                for line in direnv::update(false) {
+                    writeln!(self.output, "{}", line)?;
+                }
+            }
+            evaluator.precmd();
+            let ctx = Context {
+                status: evaluator::exit_code(evaluator.last_status()),
+                duration: evaluator.last_duration(),
+                prompt: evaluator.prompt_command(),
+            };
+            let input = match self.source.read_line(&ctx)? {
+                Input::Line(x) => x,
+                // ^C at the prompt discards the line and starts over, like other shells
+                Input::Interrupted => continue,
+                Input::Eof => match self.exit()? {
+                    true => break,
+                    false => continue,
+                },
+            };
+            debug!("read line from user: '{}'", input);
+            self.lines += 1;
+            env::set_line(self.lines);
+            let was_warned = std::mem::replace(&mut self.warned_jobs, false);
+            self.source.command_started();
+            let ast = match parse_cache::parse(&input) {
+                Ok(x) => x,
+                Err(e) => {
+                    writeln!(self.output, "rs-shell: {}", e)?;
+                    profile::report(format_args!("line {}", self.lines));
+                    evaluator.set_last_status(ExitStatus::Exited(1));
+                    self.source
+                        .command_finished(evaluator::exit_code(evaluator.last_status()));
+                    continue;
+                }
+            };
+            debug!("successful AST generation");
+            evaluator.preexec(&input);
+            let mut written = Ok(());
+            let result = evaluator.eval_reporting(ast, &mut |e| {
+                if written.is_ok() {
+                    written = evaluator::write_error(&mut self.output, e, &input, self.lines);
+                }
+            });
+            written?;
+            if let Err(e) = result {
+                evaluator::write_error(&mut self.output, e, &input, self.lines)?;
+            }
+            profile::report(format_args!("line {}", self.lines));
+            let status = evaluator::exit_code(evaluator.last_status());
+            self.source.command_finished(status);
+            let duration = evaluator.last_duration();
+            if notify_after().is_some_and(|x| duration >= x) {
+                self.source.long_command_finished(&input, status, duration);
+            }
+            // like ^D, the first `exit` with jobs still around only warns about them
+            if evaluator.exit_requested() {
+                self.warned_jobs = was_warned;
+                if self.exit()? {
+                    break;
+                }
+            }
+        }
+        self.output.flush()?;
+        self.source.finish();
+        Ok(evaluator.last_status())
+    }
+
+    /// whether to go through with exiting, the first time there are unfinished jobs the user's
+    /// only warned about them
+    fn exit(&mut self) -> io::Result<bool> {
+        match jobs::unfinished().filter(|_| !self.warned_jobs) {
+            Some(state) => {
+                let message = match state {
+                    JobState::Stopped => i18n::text("There are stopped jobs."),
+                    _ => i18n::text("There are running jobs."),
+                };
+                writeln!(self.output, "{}", message)?;
+                self.warned_jobs = true;
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+}
+
+/// how long `$RS_SHELL_NOTIFY_AFTER` says a command has to run for, `None` if it's unset, zero, or
+/// invalid, in which case no command is long enough
+fn notify_after() -> Option<Duration> {
+    let value = env::lookup(NOTIFY_AFTER_VAR)?;
+    let value = value.to_string_lossy();
+    match value.trim().parse::<u64>() {
+        Ok(0) => None,
+        Ok(x) => Some(Duration::from_secs(x)),
+        Err(_) => {
+            warn!("ignoring invalid {}: '{}'", NOTIFY_AFTER_VAR, value);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Arc};
+
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::proc_manager::recording::{assert_spawns, RecordingBackend};
+
+    /// plays back lines, and writes down the status of each one
+    struct Script {
+        lines: VecDeque<Input>,
+        statuses: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl LineSource for Script {
+        fn read_line(&mut self, _ctx: &Context) -> io::Result<Input> {
+            Ok(self.lines.pop_front().unwrap_or(Input::Eof))
+        }
+
+        fn command_finished(&mut self, status: u8) {
+            self.statuses.lock().push(status);
+        }
+    }
+
+    /// output that can still be looked at once it's been handed to the REPL
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn scripted_session() {
+        let backend = RecordingBackend::default()
+            .then(ExitStatus::Exited(0), "")
+            .then(ExitStatus::Exited(4), "");
+        let mut evaluator = Evaluator::with_backend(backend.clone());
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let script = Script {
+            lines: VecDeque::from([
+                Input::Line("a x".to_owned()),
+                Input::Interrupted,
+                Input::Line("b (".to_owned()),
+                Input::Line("b $?".to_owned()),
+            ]),
+            statuses: statuses.clone(),
+        };
+        let output = Output::default();
+
+        let mut repl = Repl::new(Box::new(script), Box::new(output.clone()));
+        assert_eq!(repl.run(&mut evaluator).unwrap(), ExitStatus::Exited(4));
+
+        assert_eq!(*statuses.lock(), [0, 1, 4]);
+        // a line that doesn't parse still sets `$?`
+        assert_spawns!(backend, ["a x", "b 1"]);
+        let output = String::from_utf8(output.0.lock().clone()).unwrap();
+        assert!(output.starts_with("rs-shell: parser error"), "{}", output);
+    }
+}
@@ -0,0 +1,388 @@
+//! property tests for the parser, arbitrary ASTs are rendered with their `Display` impls and
+//! parsed back, which has to give the same AST, and flattening has to give the same command line
+//! however the AST was arrived at
+//!
+//! the generators only make ASTs the grammar can actually produce, e.g. literal characters are
+//! kept as the raw source text the parser would store, and never two in a row (which would be
+//! parsed as one), positions are left out since they're the one thing rendering doesn't keep
+
+use std::borrow::Cow;
+
+use proptest::{collection::vec, option, prelude::*};
+
+use crate::{
+    ast::*,
+    evaluator::{Evaluator, FlattenedCmdline},
+};
+
+/// how deeply generated substitutions are nested
+const MAX_DEPTH: u32 = 2;
+
+/// a position for every node, parsed ASTs have theirs replaced with it by [`forget_positions`]
+const NOWHERE: Span = Span { start: 0, end: 0 };
+const LOCATION: Location = Location::START;
+
+/// escapes a [`RawChars`] may contain, a [`Chars`] can have the same apart from those that are an
+/// [`Escape`] in double quotes
+const ESCAPES: &str = r#"\\["\\/bfnrt]|\\u[0-9a-f]{4}"#;
+const CHARS_ESCAPES: &str = r#"\\[/bfnrt]|\\u[0-9a-f]{4}"#;
+
+fn raw_chars(command: bool) -> impl Strategy<Value = String> {
+    // `=` would make a command into an assignment
+    let plain = match command {
+        true => "[a-zA-Z0-9_.,:+%@/~^{}é-]",
+        false => "[a-zA-Z0-9_.,:+%@/~^{}é=-]",
+    };
+    proptest::string::string_regex(&format!("({}|{}){{1,6}}", plain, ESCAPES)).expect("valid regex")
+}
+
+fn chars() -> impl Strategy<Value = String> {
+    proptest::string::string_regex(&format!(
+        r#"([a-zA-Z0-9 \t\n'(){{}}<>=#;|&é_.-]|{}){{1,6}}"#,
+        CHARS_ESCAPES
+    ))
+    .expect("valid regex")
+}
+
+fn env_literal() -> impl Strategy<Value = EnvLiteral<'static>> {
+    "[a-zA-Z_][a-zA-Z0-9_]{0,5}".prop_map(|x| EnvLiteral(Cow::Owned(x.into()), NOWHERE))
+}
+
+fn dollar_env() -> impl Strategy<Value = DollarEnv<'static>> {
+    let name = prop_oneof![
+        env_literal(),
+        "[0-9]{1,2}".prop_map(|x| EnvLiteral(Cow::Owned(x.into()), NOWHERE)),
+    ];
+    let modifier = option::of(prop_oneof![
+        Just(CaseModifier::UpperFirst),
+        Just(CaseModifier::UpperAll),
+        Just(CaseModifier::LowerFirst),
+        Just(CaseModifier::LowerAll),
+    ]);
+    (name, modifier).prop_map(|(name, modifier)| DollarEnv(name, LOCATION, modifier, NOWHERE))
+}
+
+fn string_literal(command: bool) -> impl Strategy<Value = StringLiteral<'static>> {
+    let component = prop_oneof![
+        raw_chars(command).prop_map(|x| StringLiteralComponent::RawChars(RawChars(
+            Cow::Owned(x.into()),
+            NOWHERE
+        ))),
+        dollar_env().prop_map(StringLiteralComponent::DollarEnv),
+        Just(StringLiteralComponent::DollarStatus(NOWHERE)),
+    ];
+    vec(component, 1..4).prop_map(|components| {
+        let mut merged: Vec<StringLiteralComponent> = Vec::new();
+        for component in components {
+            match (merged.last_mut(), component) {
+                (
+                    Some(StringLiteralComponent::RawChars(last)),
+                    StringLiteralComponent::RawChars(x),
+                ) => last.0.to_mut().push(&*x.0),
+                (_, component) => merged.push(component),
+            }
+        }
+        StringLiteral(merged, NOWHERE)
+    })
+}
+
+fn single_quote_string() -> impl Strategy<Value = SingleQuoteString<'static>> {
+    // `\r\n` is read as `\n`, see [`crate::ast::normalize_line_endings`]
+    "[^']{0,6}"
+        .prop_filter("no CRLF", |x| !x.contains("\r\n"))
+        .prop_map(|x| SingleQuoteString(Cow::Owned(x.into()), NOWHERE))
+}
+
+fn double_quote_string(depth: u32) -> BoxedStrategy<DoubleQuoteString<'static>> {
+    let mut components = vec![
+        chars()
+            .prop_map(|x| DoubleQuoteComponent::Chars(Chars(Cow::Owned(x.into()), NOWHERE)))
+            .boxed(),
+        prop_oneof![Just('"'), Just('$'), Just('\\')]
+            .prop_map(|x| DoubleQuoteComponent::Escape(Escape(x, NOWHERE)))
+            .boxed(),
+        dollar_env()
+            .prop_map(DoubleQuoteComponent::DollarEnv)
+            .boxed(),
+        Just(DoubleQuoteComponent::DollarStatus(NOWHERE)).boxed(),
+    ];
+    if depth > 0 {
+        components.push(
+            command_line(depth - 1)
+                .prop_map(|x| DoubleQuoteComponent::DollarShell(DollarShell(x, NOWHERE)))
+                .boxed(),
+        );
+    }
+    vec(proptest::strategy::Union::new(components), 0..4)
+        .prop_map(|components| {
+            let mut merged: Vec<DoubleQuoteComponent> = Vec::new();
+            for component in components {
+                match (merged.last_mut(), component) {
+                    (Some(DoubleQuoteComponent::Chars(last)), DoubleQuoteComponent::Chars(x)) => {
+                        last.0.to_mut().push(&*x.0)
+                    }
+                    (_, component) => merged.push(component),
+                }
+            }
+            DoubleQuoteString(merged, NOWHERE)
+        })
+        .boxed()
+}
+
+fn command(depth: u32) -> impl Strategy<Value = Command<'static>> {
+    prop_oneof![
+        string_literal(true).prop_map(Command::StringLiteral),
+        single_quote_string().prop_map(Command::SingleQuoteString),
+        double_quote_string(depth).prop_map(Command::DoubleQuoteString),
+    ]
+}
+
+fn argument(depth: u32) -> BoxedStrategy<Argument<'static>> {
+    let mut arguments = vec![
+        string_literal(false)
+            .prop_map(Argument::StringLiteral)
+            .boxed(),
+        single_quote_string()
+            .prop_map(Argument::SingleQuoteString)
+            .boxed(),
+        double_quote_string(depth)
+            .prop_map(Argument::DoubleQuoteString)
+            .boxed(),
+    ];
+    if depth > 0 {
+        arguments.push(
+            command_line(depth - 1)
+                .prop_map(|x| Argument::ShellSubstitution(ShellSubstitution(x, NOWHERE)))
+                .boxed(),
+        );
+        arguments.push(
+            command_line(depth - 1)
+                .prop_map(|x| Argument::ProcessSubstitution(ProcessSubstitution(x, NOWHERE)))
+                .boxed(),
+        );
+    }
+    proptest::strategy::Union::new(arguments).boxed()
+}
+
+fn command_env(depth: u32) -> impl Strategy<Value = CommandEnv<'static>> {
+    // substitutions can't be assigned since the whole assignment is one word, which can't have
+    // spaces in it
+    let value = prop_oneof![
+        string_literal(false).prop_map(Argument::StringLiteral),
+        single_quote_string().prop_map(Argument::SingleQuoteString),
+        double_quote_string(depth).prop_map(Argument::DoubleQuoteString),
+        // `NAME=` on its own
+        Just(Argument::StringLiteral(StringLiteral(Vec::new(), NOWHERE))),
+    ];
+    (env_literal(), value).prop_map(|(name, value)| CommandEnv {
+        name,
+        value,
+        span: NOWHERE,
+    })
+}
+
+fn redirection(depth: u32) -> impl Strategy<Value = Redirection<'static>> {
+    let fd = prop_oneof![
+        Just(RedirectFd::All),
+        Just(RedirectFd::Default),
+        Just(RedirectFd::Stdin),
+        Just(RedirectFd::Stdout),
+        Just(RedirectFd::Stderr),
+        (3..1000u32).prop_map(RedirectFd::Fd),
+    ];
+    let r#type = prop_oneof![
+        Just(RedirectType::Out),
+        Just(RedirectType::OutAppend),
+        Just(RedirectType::In),
+        Just(RedirectType::HereString),
+        Just(RedirectType::Close),
+        prop_oneof![
+            Just(RedirectFd::Stdin),
+            Just(RedirectFd::Stdout),
+            Just(RedirectFd::Stderr),
+            (3..1000u32).prop_map(RedirectFd::Fd),
+        ]
+        .prop_map(RedirectType::Duplicate),
+    ];
+    (fd, r#type, argument(depth)).prop_map(|(fd, r#type, arg)| Redirection {
+        arg: (!matches!(r#type, RedirectType::Close | RedirectType::Duplicate(_))).then_some(arg),
+        op: RedirectOp {
+            fd,
+            r#type,
+            span: NOWHERE,
+        },
+        span: NOWHERE,
+    })
+}
+
+/// a single command, without anything chained after it
+fn stage(depth: u32) -> impl Strategy<Value = CommandLine<'static>> {
+    let full = (
+        vec(command_env(depth), 0..2),
+        command(depth),
+        vec(argument(depth), 0..3),
+        vec(redirection(depth), 0..2),
+    )
+        .prop_map(|(envs, command, arguments, redirects)| CommandLine {
+            envs,
+            command: Some(command),
+            arguments,
+            redirects,
+            next: None,
+            background: false,
+            span: NOWHERE,
+        });
+    let assignment = vec(command_env(depth), 1..3).prop_map(|envs| CommandLine {
+        envs,
+        command: None,
+        arguments: Vec::new(),
+        redirects: Vec::new(),
+        next: None,
+        background: false,
+        span: NOWHERE,
+    });
+    prop_oneof![4 => full, 1 => assignment]
+}
+
+fn command_line(depth: u32) -> impl Strategy<Value = CommandLine<'static>> {
+    let separator = prop_oneof![
+        Just(Separator::Semicolon),
+        Just(Separator::Pipe),
+        Just(Separator::Fork),
+    ];
+    (
+        stage(depth),
+        vec((separator, stage(depth)), 0..3),
+        any::<bool>(),
+    )
+        .prop_map(|(first, rest, background)| {
+            let mut stages = std::iter::once(first)
+                .chain(rest.iter().map(|(_, x)| x.clone()))
+                .collect::<Vec<_>>();
+            let separators = rest.into_iter().map(|(x, _)| x);
+            let mut last = stages.pop().expect("there's always a first stage");
+            last.background = background;
+            for (mut stage, separator) in stages.into_iter().rev().zip(separators.rev()) {
+                stage.next = Some((separator, Box::new(last)));
+                last = stage;
+            }
+            last
+        })
+}
+
+fn main_ast(depth: u32) -> impl Strategy<Value = Main<'static>> {
+    command_line(depth).prop_map(Main)
+}
+
+/// replaces every span and location in `ast` with the ones the generators use
+fn forget_positions(ast: &mut Main) {
+    fn line(x: &mut CommandLine) {
+        x.span = NOWHERE;
+        x.envs.iter_mut().for_each(|x| {
+            x.span = NOWHERE;
+            x.name.1 = NOWHERE;
+            argument(&mut x.value);
+        });
+        match &mut x.command {
+            Some(Command::StringLiteral(x)) => string_literal(x),
+            Some(Command::SingleQuoteString(x)) => x.1 = NOWHERE,
+            Some(Command::DoubleQuoteString(x)) => double_quote_string(x),
+            None => (),
+        }
+        x.arguments.iter_mut().for_each(argument);
+        x.redirects.iter_mut().for_each(|x| {
+            x.span = NOWHERE;
+            x.op.span = NOWHERE;
+            x.arg.iter_mut().for_each(argument);
+        });
+        if let Some((_, next)) = &mut x.next {
+            line(next);
+        }
+    }
+    fn argument(x: &mut Argument) {
+        match x {
+            Argument::StringLiteral(x) => string_literal(x),
+            Argument::SingleQuoteString(x) => x.1 = NOWHERE,
+            Argument::DoubleQuoteString(x) => double_quote_string(x),
+            Argument::ShellSubstitution(x) => {
+                x.1 = NOWHERE;
+                line(&mut x.0);
+            }
+            Argument::ProcessSubstitution(x) => {
+                x.1 = NOWHERE;
+                line(&mut x.0);
+            }
+        }
+    }
+    fn dollar_env(x: &mut DollarEnv) {
+        x.0 .1 = NOWHERE;
+        x.1 = LOCATION;
+        x.3 = NOWHERE;
+    }
+    fn string_literal(x: &mut StringLiteral) {
+        x.1 = NOWHERE;
+        for component in &mut x.0 {
+            match component {
+                StringLiteralComponent::RawChars(x) => x.1 = NOWHERE,
+                StringLiteralComponent::DollarEnv(x) => dollar_env(x),
+                StringLiteralComponent::DollarStatus(x) => *x = NOWHERE,
+            }
+        }
+    }
+    fn double_quote_string(x: &mut DoubleQuoteString) {
+        x.1 = NOWHERE;
+        for component in &mut x.0 {
+            match component {
+                DoubleQuoteComponent::Chars(x) => x.1 = NOWHERE,
+                DoubleQuoteComponent::Escape(x) => x.1 = NOWHERE,
+                DoubleQuoteComponent::DollarEnv(x) => dollar_env(x),
+                DoubleQuoteComponent::DollarStatus(x) => *x = NOWHERE,
+                DoubleQuoteComponent::DollarShell(x) => {
+                    x.1 = NOWHERE;
+                    line(&mut x.0);
+                }
+            }
+        }
+    }
+    line(&mut ast.0);
+}
+
+/// like [`forget_positions`] for a flattened command line
+fn forget_spans(cmd: &mut FlattenedCmdline) {
+    cmd.command_span = None;
+    for (op, _, span) in &mut cmd.redirects {
+        op.span = NOWHERE;
+        *span = None;
+    }
+    if let Some((_, next)) = &mut cmd.next {
+        forget_spans(next);
+    }
+}
+
+proptest! {
+    #[test]
+    fn render_then_parse(ast in main_ast(MAX_DEPTH)) {
+        let rendered = ast.to_string();
+        let mut parsed = generate_ast(&rendered)
+            .map_err(|e| TestCaseError::fail(format!("{:?} didn't parse: {}", rendered, e)))?;
+        // what's rendered is already canonical, so rendering it again changes nothing
+        prop_assert_eq!(&parsed.to_string(), &rendered);
+        forget_positions(&mut parsed);
+        prop_assert_eq!(parsed, ast);
+    }
+
+    // substitutions are left out since flattening runs them
+    #[test]
+    fn flatten_is_deterministic(ast in main_ast(0)) {
+        let rendered = ast.to_string();
+        let parsed = generate_ast(&rendered).expect("checked by render_then_parse");
+        let mut evaluator = Evaluator::new();
+        let mut flattened = evaluator.flatten(parsed.clone()).expect("nothing can fail");
+        prop_assert_eq!(&evaluator.flatten(parsed).expect("nothing can fail"), &flattened);
+        // and it doesn't matter whether the AST was parsed or made some other way
+        let mut direct = evaluator.flatten(ast).expect("nothing can fail");
+        forget_spans(&mut flattened);
+        forget_spans(&mut direct);
+        prop_assert_eq!(direct, flattened);
+    }
+}
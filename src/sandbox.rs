@@ -0,0 +1,318 @@
+//! opt-in sandboxing of the commands the shell runs
+//!
+//! with the `sandbox` option enabled every command is started in a user namespace of its own,
+//! along with a network namespace that has nothing but a loopback interface that's down, and/or
+//! a mount namespace in which every filesystem is read-only, which of the two is set by
+//! `RS_SANDBOX`
+//!
+//! redirects are opened by the shell before the command is started, so something like
+//! `curl ... > file` can still be sandboxed, but with `fs` the shell itself is confined to the
+//! directory the option was turned on in: redirects can only write to files under it, and `cd`
+//! and `source` can't leave it
+//!
+//! the option can't be turned off again once it's on, and the restrictions are read from
+//! `RS_SANDBOX` when it's turned on, so nothing run in the sandbox can loosen it
+//!
+//! namespaces only exist on linux, elsewhere entering the sandbox always fails, so commands aren't
+//! run at all with the option enabled rather than being run without it
+
+#[cfg(target_os = "linux")]
+use std::{
+    ffi::{CStr, CString},
+    ptr,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use log::warn;
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::{libc, unistd};
+
+use crate::{
+    env,
+    options::{self, ShellOption},
+};
+
+/// the variable listing the restrictions the sandbox applies, as a comma separated list of `net`
+/// (no network access) and `fs` (a read-only filesystem), all of them apply when it's unset
+const SANDBOX_VAR: &str = "RS_SANDBOX";
+
+/// the path that can always be written to, even from a read-only sandbox
+const NULL_PATH: &str = "/dev/null";
+
+/// `MOUNT_ATTR_RDONLY` from `linux/mount.h`
+#[cfg(target_os = "linux")]
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+/// `struct mount_attr` from `linux/mount.h`, the argument to `mount_setattr`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// the restrictions commands are run under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sandbox {
+    /// cut the command off from every network, including the host's loopback interface
+    pub no_network: bool,
+    /// make every filesystem read-only
+    pub read_only: bool,
+}
+
+impl Sandbox {
+    /// the sandbox used when `RS_SANDBOX` is unset, with every restriction applied
+    const DEFAULT: Sandbox = Sandbox {
+        no_network: true,
+        read_only: true,
+    };
+
+    /// the sandbox commands should be run in, if the `sandbox` option is enabled
+    pub fn current() -> Option<Self> {
+        if !options::enabled(ShellOption::Sandbox) {
+            return None;
+        }
+        Some(lock().sandbox)
+    }
+
+    /// the sandbox described by `RS_SANDBOX`
+    fn from_env() -> Self {
+        let value = env::get(SANDBOX_VAR);
+        if value.is_empty() {
+            return Self::DEFAULT;
+        }
+        match Self::parse(&value.to_string_lossy()) {
+            Some(x) => x,
+            None => {
+                warn!(
+                    "ignoring invalid {}: '{}'",
+                    SANDBOX_VAR,
+                    value.to_string_lossy()
+                );
+                Self::DEFAULT
+            }
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let mut sandbox = Self {
+            no_network: false,
+            read_only: false,
+        };
+        for restriction in value.split(',').map(str::trim) {
+            match restriction {
+                "net" => sandbox.no_network = true,
+                "fs" => sandbox.read_only = true,
+                _ => return None,
+            }
+        }
+        Some(sandbox)
+    }
+
+    /// prepares everything needed to enter the sandbox from a child process between `fork` and
+    /// `exec`, where nothing can be allocated
+    #[cfg(target_os = "linux")]
+    pub fn prepare(self) -> Prepared {
+        let map = |id: u32| CString::new(format!("{} {} 1", id, id)).expect("ids contain no nul");
+        let mut flags = libc::CLONE_NEWUSER;
+        if self.no_network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        if self.read_only {
+            flags |= libc::CLONE_NEWNS;
+        }
+        Prepared {
+            flags,
+            // the command keeps its own ids inside the namespace, so files it creates (or fails
+            // to) are still owned by the user
+            uid_map: map(unistd::getuid().as_raw()),
+            gid_map: map(unistd::getgid().as_raw()),
+            read_only: self.read_only,
+        }
+    }
+
+    /// see the linux version, there's nothing to prepare for a sandbox that can't be entered
+    #[cfg(not(target_os = "linux"))]
+    pub fn prepare(self) -> Prepared {
+        Prepared
+    }
+}
+
+/// the sandbox as it was when the option was turned on
+#[derive(Debug)]
+pub(crate) struct Locked {
+    sandbox: Sandbox,
+    /// the directory the shell is confined to with `fs`
+    root: PathBuf,
+}
+
+/// fixes the sandbox's restrictions, and the directory the shell is confined to, the first time
+/// it's called, this is called when the option is turned on
+pub(crate) fn lock() -> &'static Locked {
+    static LOCKED: OnceLock<Locked> = OnceLock::new();
+    LOCKED.get_or_init(|| Locked {
+        sandbox: Sandbox::from_env(),
+        root: std::env::current_dir()
+            .and_then(fs::canonicalize)
+            .unwrap_or_else(|_| PathBuf::from("/")),
+    })
+}
+
+/// whether the shell itself may use `path`, for a redirect, `cd` or `source`, which is always the
+/// case unless the sandbox is on with `fs`, see the module docs
+pub fn allows(path: &Path) -> bool {
+    if !Sandbox::current().is_some_and(|x| x.read_only) || path == Path::new(NULL_PATH) {
+        return true;
+    }
+    // symlinks and `..` are resolved first so they can't be used to get out, a path that doesn't
+    // exist yet is checked by where it would be created
+    let resolved = fs::canonicalize(path).or_else(|e| match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = match parent.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => parent,
+            };
+            fs::canonicalize(parent).map(|x| x.join(name))
+        }
+        _ => Err(e),
+    });
+    resolved.is_ok_and(|x| x.starts_with(&lock().root))
+}
+
+/// a [`Sandbox`] that's ready to be entered, see [`Sandbox::prepare`]
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct Prepared {
+    flags: libc::c_int,
+    uid_map: CString,
+    gid_map: CString,
+    read_only: bool,
+}
+
+/// see the linux version
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug)]
+pub struct Prepared;
+
+#[cfg(target_os = "linux")]
+impl Prepared {
+    /// moves the calling process into the sandbox, this only makes async-signal-safe calls so
+    /// it can be used in a freshly forked child
+    pub fn enter(&self) -> Result<(), Errno> {
+        // SAFETY: unshare only affects the calling process
+        if unsafe { libc::unshare(self.flags) } == -1 {
+            return Err(Errno::last());
+        }
+        // the gid map can't be written by an unprivileged process until setgroups is denied
+        write_file(c"/proc/self/setgroups", b"deny")?;
+        write_file(c"/proc/self/uid_map", self.uid_map.as_bytes())?;
+        write_file(c"/proc/self/gid_map", self.gid_map.as_bytes())?;
+
+        if !self.read_only {
+            return Ok(());
+        }
+        // keeps the change from propagating back out of the namespace
+        let root = c"/".as_ptr();
+        let flags = libc::MS_REC | libc::MS_PRIVATE;
+        let attr = MountAttr {
+            attr_set: MOUNT_ATTR_RDONLY,
+            attr_clr: 0,
+            propagation: 0,
+            userns_fd: 0,
+        };
+        // SAFETY: both calls only get pointers to memory that outlives them, and the mounts they
+        // change are the ones in the namespace that was just created
+        unsafe {
+            if libc::mount(ptr::null(), root, ptr::null(), flags, ptr::null()) == -1 {
+                return Err(Errno::last());
+            }
+            let result = libc::syscall(
+                libc::SYS_mount_setattr,
+                libc::AT_FDCWD,
+                root,
+                libc::AT_RECURSIVE,
+                &attr as *const MountAttr,
+                size_of::<MountAttr>(),
+            );
+            if result == -1 {
+                return Err(Errno::last());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Prepared {
+    /// always fails, see the module docs
+    pub fn enter(&self) -> Result<(), Errno> {
+        Err(Errno::ENOSYS)
+    }
+}
+
+/// writes `data` to the file at `path` using nothing but async-signal-safe calls
+#[cfg(target_os = "linux")]
+fn write_file(path: &CStr, data: &[u8]) -> Result<(), Errno> {
+    // SAFETY: the pointers are valid for the given lengths, and the fd is closed before returning
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd == -1 {
+            return Err(Errno::last());
+        }
+        let written = libc::write(fd, data.as_ptr().cast(), data.len());
+        let errno = Errno::last();
+        libc::close(fd);
+        if written != data.len() as isize {
+            return Err(errno);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restrictions_parsing() {
+        assert_eq!(
+            Sandbox::parse("net"),
+            Some(Sandbox {
+                no_network: true,
+                read_only: false
+            })
+        );
+        assert_eq!(
+            Sandbox::parse("fs, net"),
+            Some(Sandbox {
+                no_network: true,
+                read_only: true
+            })
+        );
+        assert_eq!(Sandbox::parse("net,disk"), None);
+    }
+
+    #[test]
+    fn confined_paths() {
+        assert!(allows(Path::new("/etc/passwd")));
+        options::with(ShellOption::Sandbox, true, || {
+            if !lock().sandbox.read_only {
+                return;
+            }
+            let root = &lock().root;
+            assert!(allows(&root.join("Cargo.toml")));
+            assert!(allows(&root.join("new-file")));
+            assert!(allows(Path::new("/dev/null")));
+            assert!(!allows(Path::new("/etc/passwd")));
+            assert!(!allows(&root.join("../outside")));
+            assert!(!allows(Path::new("/nonexistent/file")));
+        });
+    }
+}
@@ -0,0 +1,269 @@
+//! running scripts, i.e. files of commands, one line at a time, whether that's one given on the
+//! command line, one loaded with `source`, or the rc file
+
+use std::{fs, io, path::Path};
+
+use log::debug;
+use subprocess::ExitStatus;
+
+use crate::{
+    ast::{self, AstError},
+    env,
+    evaluator::{self, Evaluator},
+    options::{self, ShellOption},
+    profile::{self, Timings},
+};
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum ScriptError {
+    /// the script couldn't be read at all
+    #[error("{path}: {internal}")]
+    Read { path: String, internal: io::Error },
+
+    /// a line of the script couldn't be parsed, this only stops the script if `errexit` is
+    /// enabled, see [`run`]
+    #[error("{path}: line {line}: {internal}")]
+    Parse {
+        path: String,
+        line: usize,
+        internal: AstError,
+    },
+}
+
+impl ScriptError {
+    /// the status to exit with because of the error, like other shells do
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ScriptError::Read { .. } => 127,
+            ScriptError::Parse { .. } => 2,
+        }
+    }
+}
+
+/// runs the script at `path` with `evaluator`, returning the status of the last command it runs
+///
+/// errors parsing or evaluating a line are reported and the script carries on with the next one,
+/// a line that can't be parsed counting as a command that exited with status 2, unless `errexit`
+/// is enabled, in which case the first line to fail stops it (with an error, if it didn't parse),
+/// running `exit` stops it too, see [`Evaluator::exit_requested`]
+pub fn run(evaluator: &mut Evaluator, path: &Path) -> Result<ExitStatus, ScriptError> {
+    let script = read(path)?;
+
+    let mut status = ExitStatus::Exited(0);
+    let mut total = Timings::default();
+    for (line, statement) in statements(&script) {
+        env::set_line(line);
+        debug!("read line {} from script: '{}'", line, statement);
+        let ast = match ast::generate_ast(&statement) {
+            Ok(x) => x,
+            Err(e) => {
+                let e = ScriptError::Parse {
+                    path: path.display().to_string(),
+                    line,
+                    internal: e,
+                };
+                if options::enabled(ShellOption::Errexit) {
+                    return Err(e);
+                }
+                eprintln!("rs-shell: {}", e);
+                evaluator.set_last_status(ExitStatus::Exited(2));
+                status = evaluator.last_status();
+                continue;
+            }
+        };
+        let report = &mut |e| evaluator::report_error(e, &statement, line);
+        if let Err(e) = evaluator.eval_reporting(ast, report) {
+            report(e);
+        }
+        status = evaluator.last_status();
+        total += profile::report(format_args!("{}:{}", path.display(), line));
+        if evaluator.exit_requested() {
+            break;
+        }
+        if options::enabled(ShellOption::Errexit) && !status.success() {
+            break;
+        }
+    }
+    if profile::enabled() {
+        eprintln!("profile: {}: total: {}", path.display(), total);
+    }
+    Ok(status)
+}
+
+/// parses the script at `path` without running any of it, returning an error for every line that
+/// fails to, so they can all be fixed in one go
+pub fn check(path: &Path) -> Vec<ScriptError> {
+    let script = match read(path) {
+        Ok(x) => x,
+        Err(e) => return vec![e],
+    };
+    statements(&script)
+        .filter_map(|(line, statement)| {
+            let e = ast::generate_ast(&statement).err()?;
+            Some(ScriptError::Parse {
+                path: path.display().to_string(),
+                line,
+                internal: e,
+            })
+        })
+        .collect()
+}
+
+/// reads the script at `path`, without running it
+pub fn read(path: &Path) -> Result<String, ScriptError> {
+    fs::read_to_string(path).map_err(|e| ScriptError::Read {
+        path: path.display().to_string(),
+        internal: e,
+    })
+}
+
+/// formats `script` consistently, each statement is written the way [`ast`] renders it (one space
+/// between words, redirects last, and so on) on a line of its own, without indentation, comments
+/// are kept but unindented too, runs of blank lines are squashed into one, and a `#!` line is left
+/// alone
+///
+/// statements continued over several lines with a backslash are joined into one, since there's
+/// no telling where the breaks would go now, comments after a statement on the same line are
+/// arguments as far as the shell's concerned, so they're formatted as such
+///
+/// `name` is only used for errors, which are for the first statement that can't be parsed
+pub fn format(name: &str, script: &str) -> Result<String, ScriptError> {
+    let mut out = String::new();
+    if let Some(shebang) = script.lines().next().filter(|x| x.starts_with("#!")) {
+        out.push_str(shebang);
+        out.push('\n');
+    }
+    let mut blank = false;
+    for (line, chunk) in chunks(script) {
+        match chunk {
+            Chunk::Blank => {
+                blank = !out.is_empty();
+            }
+            Chunk::Comment(lines) => {
+                if std::mem::take(&mut blank) {
+                    out.push('\n');
+                }
+                for x in lines {
+                    out.push_str(x.trim_start());
+                    out.push('\n');
+                }
+            }
+            Chunk::Statement(statement) => {
+                let ast = ast::generate_ast(&statement).map_err(|e| ScriptError::Parse {
+                    path: name.to_owned(),
+                    line,
+                    internal: e,
+                })?;
+                if std::mem::take(&mut blank) {
+                    out.push('\n');
+                }
+                out.push_str(&ast.to_string());
+                out.push('\n');
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// a line of a script, or several joined with trailing backslashes, see [`chunks`]
+#[derive(Debug, PartialEq, Eq)]
+enum Chunk<'a> {
+    Blank,
+    /// the lines of the comment as they were written
+    Comment(Vec<&'a str>),
+    Statement(String),
+}
+
+/// each statement in `script` along with the line it starts on, skipping blank lines and comments
+pub(crate) fn statements(script: &str) -> impl Iterator<Item = (usize, String)> + '_ {
+    chunks(script).filter_map(|(line, chunk)| match chunk {
+        Chunk::Statement(x) => Some((line, x)),
+        _ => None,
+    })
+}
+
+/// each chunk of `script` along with the line it starts on
+///
+/// a `#!` line at the very start is skipped as a whole, even if it ends in a backslash, since it's
+/// for the kernel rather than the shell
+fn chunks(script: &str) -> impl Iterator<Item = (usize, Chunk<'_>)> + '_ {
+    // `lines` already takes care of `\r\n`, but not a `\r` at the very end of the script
+    let mut lines = script
+        .lines()
+        .map(|x| x.strip_suffix('\r').unwrap_or(x))
+        .enumerate()
+        .peekable();
+    lines.next_if(|(_, line)| line.starts_with("#!"));
+    std::iter::from_fn(move || {
+        let (i, line) = lines.next()?;
+        let mut raw = vec![line];
+        let mut statement = line.to_owned();
+        // a trailing backslash continues the statement on the next line, like it does at the prompt
+        while statement.ends_with('\\') {
+            statement.pop();
+            match lines.next() {
+                Some((_, x)) => {
+                    raw.push(x);
+                    statement.push_str(x);
+                }
+                None => break,
+            }
+        }
+        let trimmed = statement.trim();
+        let chunk = match trimmed.is_empty() {
+            true => Chunk::Blank,
+            false if trimmed.starts_with('#') => Chunk::Comment(raw),
+            false => Chunk::Statement(statement),
+        };
+        Some((i + 1, chunk))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_statements() {
+        let script = "# comment\necho a\n\n  # indented comment\necho b \\\n  c\necho d\n";
+        let got: Vec<_> = statements(script).collect();
+        let expected = [(2, "echo a"), (5, "echo b   c"), (7, "echo d")];
+        assert_eq!(got, expected.map(|(i, x)| (i, x.to_owned())));
+
+        let script = "# comment\r\necho a\r\necho b \\\r\n  c\r\necho d\r";
+        let got: Vec<_> = statements(script).collect();
+        let expected = [(2, "echo a"), (3, "echo b   c"), (5, "echo d")];
+        assert_eq!(got, expected.map(|(i, x)| (i, x.to_owned())));
+
+        let script = "#!/usr/bin/env -S rs-shell -e \\\necho a\n";
+        let got: Vec<_> = statements(script).collect();
+        assert_eq!(got, [(2, "echo a".to_owned())]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let path = std::env::temp_dir().join(format!("rs-shell-script-{}", std::process::id()));
+        fs::write(&path, "RS_TEST_SCRIPT=1\nb (\nRS_TEST_SCRIPT=2\n").unwrap();
+        let status = run(&mut Evaluator::new(), &path).unwrap();
+        assert_eq!(status, ExitStatus::Exited(0));
+        assert_eq!(env::get("RS_TEST_SCRIPT"), "2");
+
+        fs::write(&path, "true\nb (\n").unwrap();
+        let status = run(&mut Evaluator::new(), &path).unwrap();
+        assert_eq!(status, ExitStatus::Exited(2));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn formatting() {
+        let script = "#!/bin/rs-shell\n\n  # a \\\n\techo\necho   'a'|cat>x;b\n\n\n  c \\\n d\n\n";
+        let expected = "#!/bin/rs-shell\n\n# a \\\necho\necho 'a' | cat > x; b\n\nc d\n";
+        let got = format("x", script).unwrap();
+        assert_eq!(got, expected);
+        assert_eq!(format("x", &got).unwrap(), got);
+
+        let e = format("x", "a\n\nb (\n").unwrap_err();
+        assert!(matches!(e, ScriptError::Parse { line: 3, .. }), "{:?}", e);
+    }
+}
@@ -0,0 +1,191 @@
+//! serde support for the `OsString`s in the AST and flattened command lines, for use with
+//! `#[serde(with = "...")]`
+//!
+//! serde's own impl writes them as `{"Unix": [bytes...]}`, which is no fun to read or write by
+//! hand, so instead they're written as strings, falling back to bytes only when they aren't valid
+//! UTF-8, either is accepted when reading them back
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+};
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{
+    ast::{RedirectOp, Span},
+    env::Name,
+};
+
+/// serializes as a string when it can, see the [module docs](self)
+struct Os<'a>(&'a OsStr);
+
+impl Serialize for Os<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.to_str() {
+            Some(x) => serializer.serialize_str(x),
+            None => serializer.serialize_bytes(self.0.as_bytes()),
+        }
+    }
+}
+
+/// deserializes from either a string or bytes
+struct OsBuf(OsString);
+
+impl<'de> Deserialize<'de> for OsBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OsVisitor;
+
+        impl<'de> Visitor<'de> for OsVisitor {
+            type Value = OsBuf;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a string or bytes")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(OsBuf(v.into()))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(OsBuf(OsStr::from_bytes(v).to_owned()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(OsBuf(OsString::from_vec(v)))
+            }
+
+            // formats without a bytes type, like JSON, write bytes as a list of numbers
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut bytes = Vec::new();
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(OsBuf(OsString::from_vec(bytes)))
+            }
+        }
+
+        deserializer.deserialize_any(OsVisitor)
+    }
+}
+
+/// for an `OsString`
+pub mod os_string {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(x: &OsStr, serializer: S) -> Result<S::Ok, S::Error> {
+        Os(x).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OsString, D::Error> {
+        Ok(OsBuf::deserialize(deserializer)?.0)
+    }
+}
+
+/// for a `Cow<OsStr>`, which is always owned once it's been deserialized
+pub mod os_cow {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    pub fn serialize<S: Serializer>(x: &OsStr, serializer: S) -> Result<S::Ok, S::Error> {
+        Os(x).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, 'a, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Cow<'a, OsStr>, D::Error> {
+        Ok(Cow::Owned(OsBuf::deserialize(deserializer)?.0))
+    }
+}
+
+/// for a `Vec<OsString>`, e.g. arguments
+pub mod os_strings {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(x: &[OsString], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(x.iter().map(|x| Os(x)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<OsString>, D::Error> {
+        let x = Vec::<OsBuf>::deserialize(deserializer)?;
+        Ok(x.into_iter().map(|x| x.0).collect())
+    }
+}
+
+/// for a `Vec<(Name, OsString)>`, i.e. one-shot variables
+pub mod os_pairs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        x: &[(Name, OsString)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(x.iter().map(|(k, v)| (Os(k), Os(v))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(Name, OsString)>, D::Error> {
+        let x = Vec::<(OsBuf, OsBuf)>::deserialize(deserializer)?;
+        Ok(x.into_iter().map(|(k, v)| (k.0.into(), v.0)).collect())
+    }
+}
+
+/// for the redirects of a [`crate::evaluator::FlattenedCmdline`]
+pub mod redirects {
+    use super::*;
+
+    type Redirect = (RedirectOp, OsString, Option<Span>);
+
+    pub fn serialize<S: Serializer>(x: &[Redirect], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(x.iter().map(|(op, path, span)| (op, Os(path), span)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Redirect>, D::Error> {
+        let x = Vec::<(RedirectOp, OsBuf, Option<Span>)>::deserialize(deserializer)?;
+        Ok(x.into_iter()
+            .map(|(op, path, span)| (op, path.0, span))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ast, evaluator::FlattenedCmdline};
+
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let ast = ast::generate_ast("A=1 echo 'a b' \"$x $(pwd)\" > out | wc -l &").unwrap();
+        let json = serde_json::to_string(&ast).unwrap();
+        assert_eq!(serde_json::from_str::<ast::Main>(&json).unwrap(), ast);
+
+        let cmd = FlattenedCmdline {
+            envs: vec![("A".into(), "1".into())],
+            command: "cat".into(),
+            command_span: None,
+            arguments: vec![OsString::from_vec(b"\xffinvalid".to_vec())],
+            redirects: Vec::new(),
+            next: None,
+            background: false,
+        };
+        let json = serde_json::to_value(&cmd).unwrap();
+        assert_eq!(json["command"], "cat");
+        assert_eq!(json["envs"], serde_json::json!([["A", "1"]]));
+        assert!(json["arguments"][0].is_array());
+        assert_eq!(
+            serde_json::from_value::<FlattenedCmdline>(json).unwrap(),
+            cmd
+        );
+    }
+}
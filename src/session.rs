@@ -0,0 +1,372 @@
+//! saving the state of the shell to a file and restoring it later, with `--save-session` and
+//! `--restore-session`, or the `session` builtin
+//!
+//! a session is the working directory, every variable along with its attributes, and every alias,
+//! each on a line of its own made up of a keyword followed by quoted fields, e.g.
+//!
+//! ```text
+//! cwd "/home/me/project"
+//! var "x" "EDITOR" "vim"
+//! var "" "UNSET_BUT_DECLARED"
+//! array "" "DIRS" "0" "src" "1" "docs"
+//! assoc "" "COLORS" "red" "#f00"
+//! alias "ll" "ls -l"
+//! ```
+//!
+//! where the first field of a variable is its attributes as `declare` flags, fields are quoted
+//! like JSON strings, `\\`, `\"`, `\n`, `\t`, `\r` and `\xHH` being the only escapes
+//!
+//! `PWD`, `OLDPWD` and `SHLVL` aren't saved, since they describe the shell they're in rather than
+//! the session
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    alias,
+    env::{self, Attributes, Value},
+};
+
+/// variables that are left out of a saved session
+const UNSAVED_VARS: &[&str] = &["PWD", "OLDPWD", "SHLVL"];
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum SessionError {
+    #[error("{path}: {internal}")]
+    Io { path: String, internal: io::Error },
+
+    #[error("{path}: line {line}: {reason}")]
+    Syntax {
+        path: String,
+        line: usize,
+        reason: &'static str,
+    },
+}
+
+/// the state of the shell, see the [module docs](self)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session {
+    cwd: Option<PathBuf>,
+    vars: Vec<(OsString, Attributes, Option<Value>)>,
+    aliases: Vec<(String, String)>,
+}
+
+impl Session {
+    /// the state the shell's in right now
+    pub fn capture() -> Self {
+        let vars = env::declared()
+            .into_iter()
+            .filter(|(name, _, _)| !UNSAVED_VARS.iter().any(|x| name == x))
+            .map(|(name, attrs, value)| (name.to_os_string(), attrs, value))
+            .collect();
+        Self {
+            cwd: std::env::current_dir().ok(),
+            vars,
+            aliases: alias::list(),
+        }
+    }
+
+    /// puts the shell in this state, anything that can't be restored (e.g. a variable that's
+    /// readonly in this shell) is skipped, and described in the returned list
+    pub fn restore(self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Some(cwd) = self.cwd {
+            match std::env::set_current_dir(&cwd) {
+                Ok(()) => env::update_pwd(),
+                Err(e) => problems.push(format!("unable to cd to '{}': {}", cwd.display(), e)),
+            }
+        }
+
+        let current = env::declared()
+            .into_iter()
+            .map(|(name, attrs, value)| (name, (attrs, value)))
+            .collect::<HashMap<_, _>>();
+        for (name, attrs, value) in self.vars {
+            // e.g. readonly variables set by the rc file in both shells
+            if current.get(name.as_os_str()) == Some(&(attrs, value.clone())) {
+                continue;
+            }
+            if let Err(e) = restore_var(name.clone().into(), attrs, value) {
+                problems.push(e.to_string());
+            }
+        }
+
+        for (name, value) in self.aliases {
+            if let Err(e) = alias::set(name, value) {
+                problems.push(e.to_string());
+            }
+        }
+        problems
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut out = String::from("# rs-shell session\n");
+        if let Some(cwd) = &self.cwd {
+            let _ = writeln!(out, "cwd {}", quote(&cwd.to_string_lossy()));
+        }
+        for (name, attrs, value) in &self.vars {
+            let (keyword, fields) = match value {
+                None => ("var", Vec::new()),
+                Some(Value::Scalar(x)) => ("var", vec![x.to_string_lossy().into_owned()]),
+                Some(Value::Indexed(x)) => (
+                    "array",
+                    x.iter()
+                        .flat_map(|(k, v)| [k.to_string(), v.to_string_lossy().into_owned()])
+                        .collect(),
+                ),
+                Some(Value::Associative(x)) => (
+                    "assoc",
+                    x.iter()
+                        .flat_map(|(k, v)| [k, v].map(|x| x.to_string_lossy().into_owned()))
+                        .collect(),
+                ),
+            };
+            let _ = write!(
+                out,
+                "{} {} {}",
+                keyword,
+                quote(&attrs.flags()),
+                quote(&name.to_string_lossy())
+            );
+            for field in fields {
+                let _ = write!(out, " {}", quote(&field));
+            }
+            out.push('\n');
+        }
+        for (name, value) in &self.aliases {
+            let _ = writeln!(out, "alias {} {}", quote(name), quote(value));
+        }
+        out
+    }
+
+    /// parses a session written by [`Session::serialize`], `Err` being the line that's wrong and
+    /// what's wrong with it
+    pub fn parse(input: &str) -> Result<Self, (usize, &'static str)> {
+        let mut session = Self::default();
+        for (i, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |reason| (i + 1, reason);
+            let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let fields = fields(rest).map_err(err)?;
+            match (keyword, fields.as_slice()) {
+                ("cwd", [cwd]) => session.cwd = Some(cwd.into()),
+                ("var", [attrs, name, value @ ..]) if value.len() <= 1 => {
+                    let value = value.first().map(|x| Value::Scalar(x.into()));
+                    session
+                        .vars
+                        .push((name.into(), parse_flags(attrs).map_err(err)?, value));
+                }
+                ("array" | "assoc", [attrs, name, elements @ ..]) if elements.len() % 2 == 0 => {
+                    let pairs = elements.chunks(2).map(|x| (x[0].clone(), x[1].clone()));
+                    let value = match keyword {
+                        "array" => Value::Indexed(
+                            pairs
+                                .map(|(k, v)| {
+                                    Ok((k.parse().map_err(|_| "invalid index")?, v.into()))
+                                })
+                                .collect::<Result<BTreeMap<_, _>, _>>()
+                                .map_err(err)?,
+                        ),
+                        _ => Value::Associative(pairs.map(|(k, v)| (k.into(), v.into())).collect()),
+                    };
+                    session
+                        .vars
+                        .push((name.into(), parse_flags(attrs).map_err(err)?, Some(value)));
+                }
+                ("alias", [name, value]) => session.aliases.push((name.clone(), value.clone())),
+                ("cwd" | "var" | "array" | "assoc" | "alias", _) => {
+                    return Err(err("wrong number of fields"))
+                }
+                _ => return Err(err("unknown keyword")),
+            }
+        }
+        Ok(session)
+    }
+}
+
+/// writes the current state of the shell to `path`
+pub fn save(path: &Path) -> Result<(), SessionError> {
+    fs::write(path, Session::capture().serialize()).map_err(|e| SessionError::Io {
+        path: path.display().to_string(),
+        internal: e,
+    })
+}
+
+/// restores the state saved in `path`, returning anything that couldn't be, see
+/// [`Session::restore`]
+pub fn restore(path: &Path) -> Result<Vec<String>, SessionError> {
+    let input = fs::read_to_string(path).map_err(|e| SessionError::Io {
+        path: path.display().to_string(),
+        internal: e,
+    })?;
+    let session = Session::parse(&input).map_err(|(line, reason)| SessionError::Syntax {
+        path: path.display().to_string(),
+        line,
+        reason,
+    })?;
+    Ok(session.restore())
+}
+
+fn restore_var(
+    name: env::Name,
+    attrs: Attributes,
+    value: Option<Value>,
+) -> Result<(), env::EnvError> {
+    let set_attrs = |x: &mut Attributes| *x = attrs;
+    match value {
+        // for a nameref, this sets the name it refers to rather than the variable it refers to
+        Some(Value::Scalar(x)) => env::declare_ref(name, Some(x), set_attrs),
+        Some(array) => {
+            // a readonly array can't have its value set, so that attribute comes after
+            let writable = Attributes {
+                readonly: false,
+                ..attrs
+            };
+            env::declare_ref(name.clone(), None, |x| *x = writable)?;
+            env::set_value(name.clone(), array)?;
+            env::declare_ref(name, None, set_attrs)
+        }
+        None => env::declare_ref(name, None, set_attrs),
+    }
+}
+
+fn parse_flags(flags: &str) -> Result<Attributes, &'static str> {
+    let mut attrs = Attributes::default();
+    for flag in flags.chars() {
+        let attr = match flag {
+            'i' => &mut attrs.integer,
+            'l' => &mut attrs.lowercase,
+            'n' => &mut attrs.nameref,
+            'r' => &mut attrs.readonly,
+            'u' => &mut attrs.uppercase,
+            'x' => &mut attrs.exported,
+            _ => return Err("unknown attribute"),
+        };
+        *attr = true;
+    }
+    Ok(attrs)
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\x{:02x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// the quoted fields making up the rest of a line
+fn fields(mut rest: &str) -> Result<Vec<String>, &'static str> {
+    let mut fields = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return Ok(fields);
+        }
+        let mut chars = rest
+            .strip_prefix('"')
+            .ok_or("expected '\"'")?
+            .char_indices();
+        let mut field = String::new();
+        let end = loop {
+            match chars.next().ok_or("unterminated field")? {
+                (i, '"') => break i + 2,
+                (_, '\\') => match chars.next().ok_or("unterminated field")?.1 {
+                    '"' => field.push('"'),
+                    '\\' => field.push('\\'),
+                    'n' => field.push('\n'),
+                    't' => field.push('\t'),
+                    'r' => field.push('\r'),
+                    'x' => {
+                        let hex: String = chars.by_ref().take(2).map(|(_, x)| x).collect();
+                        let byte = u8::from_str_radix(&hex, 16).map_err(|_| "invalid escape")?;
+                        field.push(byte.into());
+                    }
+                    _ => return Err("invalid escape"),
+                },
+                (_, c) => field.push(c),
+            }
+        };
+        fields.push(field);
+        rest = &rest[end..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_files() {
+        let session = Session {
+            cwd: Some(PathBuf::from("/tmp/a \"dir\"")),
+            vars: vec![
+                (
+                    "RS_TEST_EXPORTED".into(),
+                    Attributes {
+                        exported: true,
+                        ..Default::default()
+                    },
+                    Some(Value::Scalar("line\nbreak\x01".into())),
+                ),
+                ("RS_TEST_DECLARED".into(), Attributes::default(), None),
+                (
+                    "RS_TEST_ARRAY".into(),
+                    Attributes::default(),
+                    Some(Value::Indexed([(0, "a".into()), (3, "b".into())].into())),
+                ),
+                (
+                    "RS_TEST_ASSOC".into(),
+                    Attributes {
+                        readonly: true,
+                        ..Default::default()
+                    },
+                    Some(Value::Associative([("k".into(), "v".into())].into())),
+                ),
+            ],
+            aliases: vec![("ll".to_owned(), "ls -l".to_owned())],
+        };
+        let serialized = session.serialize();
+        assert!(serialized.contains("var \"x\" \"RS_TEST_EXPORTED\" \"line\\nbreak\\x01\"\n"));
+        assert_eq!(Session::parse(&serialized), Ok(session));
+
+        assert_eq!(
+            Session::parse("cwd \"/a\" \"/b\""),
+            Err((1, "wrong number of fields"))
+        );
+        assert_eq!(
+            Session::parse("\nvar \"q\" \"A\""),
+            Err((2, "unknown attribute"))
+        );
+        assert_eq!(
+            Session::parse("array \"\" \"A\" \"x\" \"y\""),
+            Err((1, "invalid index"))
+        );
+        assert_eq!(Session::parse("alias \"a"), Err((1, "unterminated field")));
+        assert_eq!(
+            Session::parse("function \"f\""),
+            Err((1, "unknown keyword"))
+        );
+    }
+}
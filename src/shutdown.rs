@@ -0,0 +1,50 @@
+//! things that have to be done before the shell exits, whether that's normally or because it
+//! panicked, e.g. saving the history, so that a crash loses as little of the session as possible
+
+use std::{panic, sync::OnceLock, thread, time::Duration};
+
+use parking_lot::Mutex;
+
+type Hook = Box<dyn FnOnce() + Send>;
+
+fn hooks() -> &'static Mutex<Vec<Hook>> {
+    static HOOKS: OnceLock<Mutex<Vec<Hook>>> = OnceLock::new();
+    HOOKS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// runs `f` when the shell shuts down, see [`run`]
+pub fn on_shutdown(f: impl FnOnce() + Send + 'static) {
+    hooks().lock().push(Box::new(f));
+}
+
+/// runs every hook registered with [`on_shutdown`], most recently registered first
+///
+/// each hook only ever runs once, so it's fine for this to be called again, e.g. by the panic hook
+/// after a normal shutdown's already started
+pub fn run() {
+    loop {
+        // a hook that panics while this is held would otherwise deadlock the panic hook
+        let Some(mut hooks) = hooks().try_lock_for(Duration::from_secs(1)) else {
+            return;
+        };
+        let Some(hook) = hooks.pop() else {
+            return;
+        };
+        drop(hooks);
+        hook();
+    }
+}
+
+/// makes a panic on the main thread run the shutdown hooks, after whatever panic hook was already
+/// installed has reported it
+///
+/// panics on other threads are left alone, since the shell carries on without them
+pub fn install_panic_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        if thread::current().name() == Some("main") {
+            run();
+        }
+    }));
+}
@@ -0,0 +1,287 @@
+//! native fork/exec process spawning
+//!
+//! this is used instead of the subprocess crate for anything that runs as a job, since job control
+//! needs every process of a pipeline to join the same process group and to have the signal
+//! dispositions the shell changed for itself reset before it starts
+
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr, OsString},
+    fs::File,
+    io::{self, Read},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+    ptr,
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    libc,
+    sys::wait::waitpid,
+    unistd::{self, ForkResult, Pid},
+};
+
+use crate::{
+    env::{Environ, Name},
+    priority::Priority,
+    sandbox,
+};
+
+/// the signals the shell may have changed the disposition of, which are reset to their defaults in
+/// every child
+const RESET_SIGNALS: &[libc::c_int] = &[
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+    libc::SIGCHLD,
+    libc::SIGPIPE,
+];
+
+/// everything needed to start a single process
+#[derive(Debug)]
+pub struct Command<'a> {
+    pub executable: &'a Path,
+    pub argv: &'a [OsString],
+    /// the environment of the process
+    pub env: &'a Environ,
+    /// variables to add to `env`, replacing any with the same name, like the assignments in
+    /// `NAME=value command`
+    pub env_overrides: &'a [(Name, OsString)],
+    /// the files to use as stdin, stdout and stderr, `None` inherits the shell's own
+    pub stdio: [Option<&'a File>; 3],
+    /// which of stdin, stdout and stderr to close in the process, if it isn't also given a file
+    /// for them in `stdio`
+    pub closed: [bool; 3],
+    /// the process group to put the process in, `Some(0)` makes it the leader of a new one and
+    /// `None` leaves it in the shell's
+    pub pgid: Option<Pid>,
+    /// whether to hand the terminal to the process's group before it execs, this is also done by
+    /// the shell but a process that reads from the terminal straight away could otherwise be
+    /// stopped by `SIGTTIN` before the shell gets to it
+    pub foreground: bool,
+    /// the sandbox to run the process in, if any
+    pub sandbox: Option<&'a sandbox::Prepared>,
+    /// the priority to run the process with, if not the shell's own
+    pub priority: Option<Priority>,
+}
+
+/// starts `cmd`, returning its pid once it's successfully exec'd
+///
+/// failures in the child (e.g. exec failing) are sent back to the parent over a close-on-exec
+/// pipe, so they're reported here rather than as the child exiting
+pub fn spawn(cmd: &Command) -> io::Result<Pid> {
+    // everything the child needs is prepared up front, since allocating between fork and exec
+    // isn't safe in a multithreaded process
+    let executable = cstring(cmd.executable.as_os_str().as_bytes())?;
+    let argv = cmd
+        .argv
+        .iter()
+        .map(|x| cstring(x.as_bytes()))
+        .collect::<io::Result<Vec<_>>>()?;
+    // the last assignment to each variable, since a later one wins
+    let last = cmd
+        .env_overrides
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| (&**name, i))
+        .collect::<HashMap<&OsStr, usize>>();
+    let overrides = cmd
+        .env_overrides
+        .iter()
+        .enumerate()
+        .filter(|(i, (name, _))| last[&**name] == *i)
+        .map(|(_, (name, value))| cstring(&[name.as_bytes(), b"=", value.as_bytes()].concat()))
+        .collect::<io::Result<Vec<_>>>()?;
+    let argv_ptrs = null_terminated(&argv);
+    // the shared environment is only pointed to, rather than copied
+    let env_ptrs = cmd
+        .env
+        .iter()
+        .filter(|(name, _)| !last.contains_key(name))
+        .map(|(_, x)| x.as_ptr())
+        .chain(overrides.iter().map(|x| x.as_ptr()))
+        .chain(std::iter::once(ptr::null()))
+        .collect::<Vec<_>>();
+    let stdio = cmd.stdio.map(|x| x.map(AsRawFd::as_raw_fd));
+    let (reader, writer) = unistd::pipe2(OFlag::O_CLOEXEC)?;
+
+    // SAFETY: the child only makes async-signal-safe calls before it execs or exits
+    match unsafe { unistd::fork() }? {
+        ForkResult::Child => {
+            let errno = exec_child(&executable, &argv_ptrs, &env_ptrs, stdio, cmd);
+            // SAFETY: writing to a pipe and exiting are both async-signal-safe
+            unsafe {
+                libc::write(
+                    writer.as_raw_fd(),
+                    errno.to_ne_bytes().as_ptr().cast(),
+                    size_of::<i32>(),
+                );
+                libc::_exit(127)
+            }
+        }
+        ForkResult::Parent { child } => {
+            drop(writer);
+            if let Some(pgid) = cmd.pgid {
+                // also done in the parent so the group exists by the time this returns no matter
+                // which of the two runs first, it fails harmlessly if the child has already exec'd
+                let pgid = if pgid.as_raw() == 0 { child } else { pgid };
+                let _ = unistd::setpgid(child, pgid);
+            }
+
+            let mut buf = [0u8; size_of::<i32>()];
+            match File::from(reader).read_exact(&mut buf) {
+                // the pipe was closed by exec without anything being written
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(child),
+                Err(e) => Err(e),
+                Ok(()) => {
+                    let _ = waitpid(child, None);
+                    Err(io::Error::from_raw_os_error(i32::from_ne_bytes(buf)))
+                }
+            }
+        }
+    }
+}
+
+/// sets up the child's process group, signals, and stdio, and then execs it, only returning (with
+/// the errno of whatever went wrong) on failure
+fn exec_child(
+    executable: &CString,
+    argv: &[*const libc::c_char],
+    env: &[*const libc::c_char],
+    stdio: [Option<RawFd>; 3],
+    cmd: &Command,
+) -> i32 {
+    if let Some(pgid) = cmd.pgid {
+        if let Err(e) = unistd::setpgid(Pid::from_raw(0), pgid) {
+            return e as i32;
+        }
+    }
+
+    // SAFETY: these only affect the child, and are all async-signal-safe
+    unsafe {
+        if cmd.foreground && cmd.pgid.is_some() {
+            // SIGTTOU is blocked for the same reason as in `jobs::give_terminal`, the mask is
+            // cleared again below, and the stdin this is done on is still the shell's
+            let mut block = std::mem::zeroed::<libc::sigset_t>();
+            libc::sigemptyset(&mut block);
+            libc::sigaddset(&mut block, libc::SIGTTOU);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &block, ptr::null_mut());
+            libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+        }
+    }
+
+    // before entering the sandbox, where the process might no longer be allowed to
+    if let Some(priority) = cmd.priority {
+        if let Err(e) = priority.apply() {
+            return e as i32;
+        }
+    }
+
+    if let Some(sandbox) = cmd.sandbox {
+        if let Err(e) = sandbox.enter() {
+            return e as i32;
+        }
+    }
+
+    // SAFETY: these only affect the child, and are all async-signal-safe
+    unsafe {
+        for sig in RESET_SIGNALS {
+            libc::signal(*sig, libc::SIG_DFL);
+        }
+        let mut empty = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(&mut empty);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &empty, ptr::null_mut());
+    }
+
+    for (target, fd) in stdio.into_iter().enumerate() {
+        if let Some(fd) = fd {
+            if let Err(e) = unistd::dup2(fd, target as RawFd) {
+                return e as i32;
+            }
+        }
+    }
+    for (target, closed) in cmd.closed.into_iter().enumerate() {
+        if closed && stdio[target].is_none() {
+            // SAFETY: closing one of the child's own stdio fds doesn't affect the parent
+            unsafe { libc::close(target as RawFd) };
+        }
+    }
+
+    // SAFETY: all three arrays are null terminated and outlive the call
+    unsafe { libc::execve(executable.as_ptr(), argv.as_ptr(), env.as_ptr()) };
+    Errno::last_raw()
+}
+
+fn cstring(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn null_terminated(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings
+        .iter()
+        .map(|x| x.as_ptr())
+        .chain(std::iter::once(ptr::null()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env;
+
+    #[test]
+    fn environment_and_failures() {
+        let environ = env::environ();
+        let (mut reader, writer) = io::pipe().unwrap();
+        let writer = File::from(std::os::fd::OwnedFd::from(writer));
+        let argv = [
+            "sh",
+            "-c",
+            "printf %s \"$RS_TEST_SPAWN-$RS_TEST_SPAWN_TOO\"",
+        ]
+        .map(OsString::from);
+        let overrides = [
+            ("RS_TEST_SPAWN", "1"),
+            ("RS_TEST_SPAWN_TOO", "2"),
+            ("RS_TEST_SPAWN", "3"),
+        ]
+        .map(|(name, value)| (Name::from(OsStr::new(name)), OsString::from(value)));
+        let cmd = Command {
+            executable: Path::new("/bin/sh"),
+            argv: &argv,
+            env: &environ,
+            env_overrides: &overrides,
+            stdio: [None, Some(&writer), None],
+            closed: [false; 3],
+            pgid: None,
+            foreground: false,
+            sandbox: None,
+            priority: None,
+        };
+        let pid = spawn(&cmd).unwrap();
+        assert!(matches!(
+            waitpid(pid, None),
+            Ok(nix::sys::wait::WaitStatus::Exited(_, 0))
+        ));
+
+        // exec failing is reported by the parent rather than as the child exiting
+        let missing = Command {
+            executable: Path::new("/nonexistent/rs-shell-test"),
+            ..cmd
+        };
+        let e = spawn(&missing).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        drop(writer);
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        // a later assignment to the same variable wins
+        assert_eq!(output, "3-2");
+    }
+}
@@ -0,0 +1,68 @@
+/// the largest edit distance at which a candidate is still considered a plausible typo
+const MAX_DISTANCE: usize = 2;
+
+/// finds the candidate closest to `name`, if any is close enough to plausibly be what was meant
+///
+/// ties are broken in favor of whichever candidate comes first
+pub fn closest<'a, I>(name: &str, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    // very short names are too ambiguous to correct, everything is one or two edits away
+    let max = MAX_DISTANCE.min(name.chars().count().saturating_sub(1));
+    candidates
+        .into_iter()
+        .filter(|x| *x != name)
+        .map(|x| (distance(name, x), x))
+        .filter(|(dist, _)| *dist <= max)
+        .min_by_key(|(dist, _)| *dist)
+        .map(|(_, x)| x)
+}
+
+/// optimal string alignment distance, that is, levenshtein distance where swapping two adjacent
+/// characters counts as a single edit, so that `gti` is one edit away from `git`
+pub fn distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let mut dist = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in dist.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in dist[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            dist[i][j] = (dist[i - 1][j] + 1)
+                .min(dist[i][j - 1] + 1)
+                .min(dist[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                dist[i][j] = dist[i][j].min(dist[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    dist[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transposition_is_one_edit() {
+        assert_eq!(distance("gti", "git"), 1);
+        assert_eq!(distance("sl", "ls"), 1);
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn closest_candidate() {
+        let candidates = ["grep", "git", "gitk", "cd"];
+        assert_eq!(closest("gti", candidates), Some("git"));
+        assert_eq!(closest("cargo", candidates), None);
+    }
+}
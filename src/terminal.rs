@@ -0,0 +1,20 @@
+//! the terminal the shell's running in
+
+use nix::libc;
+
+/// the size of the terminal stdout is connected to, in rows and columns, or 24 by 80 if it isn't
+/// connected to one
+pub fn size() -> (usize, usize) {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ only writes a `winsize` through the pointer it's given, which `size` is
+    let res = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if res != 0 || size.ws_row == 0 || size.ws_col == 0 {
+        return (24, 80);
+    }
+    (size.ws_row.into(), size.ws_col.into())
+}
@@ -0,0 +1,134 @@
+//! the colors the shell uses for its own output, which come from a built-in theme, picked with
+//! `colors.theme` in the config file, with any of the other `colors` settings overriding it, e.g.
+//!
+//! ```toml
+//! [colors]
+//! theme = "bright"
+//! diagnostic = "1;35"
+//! ```
+//!
+//! each color is a list of SGR parameters (e.g. `1;31` for bold red), or empty for none, apart
+//! from `ls`, which is in the format of `LS_COLORS` (and only used when that isn't set)
+
+use cs128h_project::lexer::TokenKind;
+
+/// the names of the built-in themes, the first being the default
+pub const NAMES: &[&str] = &["default", "plain", "bright"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Theme {
+    /// the prompt after a command fails
+    pub prompt_failure: String,
+    /// the underlines pointing at what's wrong in error messages
+    pub diagnostic: String,
+    /// completions that aren't files, e.g. flags from a completion spec
+    pub completion: String,
+    /// files being completed, in the format of `LS_COLORS`
+    pub ls: String,
+    /// the command line being typed, by what each part of it is, see [`cs128h_project::lexer`]
+    pub syntax_command: String,
+    pub syntax_variable: String,
+    pub syntax_string: String,
+    pub syntax_operator: String,
+    pub syntax_comment: String,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            prompt_failure: "31".to_owned(),
+            diagnostic: "1;31".to_owned(),
+            completion: String::new(),
+            // the GNU `dircolors` defaults
+            ls: "di=01;34:ln=01;36:so=01;35:pi=40;33:ex=01;32".to_owned(),
+            syntax_command: "1".to_owned(),
+            syntax_variable: "36".to_owned(),
+            syntax_string: "33".to_owned(),
+            syntax_operator: "35".to_owned(),
+            syntax_comment: "2".to_owned(),
+        }
+    }
+}
+
+impl Theme {
+    /// the built-in theme called `name`, see [`NAMES`]
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(Self::default()),
+            "plain" => Some(Self {
+                prompt_failure: String::new(),
+                diagnostic: String::new(),
+                completion: String::new(),
+                ls: String::new(),
+                syntax_command: String::new(),
+                syntax_variable: String::new(),
+                syntax_string: String::new(),
+                syntax_operator: String::new(),
+                syntax_comment: String::new(),
+            }),
+            "bright" => Some(Self {
+                prompt_failure: "1;91".to_owned(),
+                diagnostic: "1;91".to_owned(),
+                completion: "96".to_owned(),
+                ls: "di=01;94:ln=01;96:so=01;95:pi=01;93:ex=01;92".to_owned(),
+                syntax_command: "1;92".to_owned(),
+                syntax_variable: "96".to_owned(),
+                syntax_string: "93".to_owned(),
+                syntax_operator: "95".to_owned(),
+                syntax_comment: "90".to_owned(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// the color of a token of the command line being typed
+    pub fn syntax(&self, kind: TokenKind) -> &str {
+        match kind {
+            TokenKind::Command => &self.syntax_command,
+            TokenKind::Variable | TokenKind::Assignment => &self.syntax_variable,
+            TokenKind::String => &self.syntax_string,
+            TokenKind::Operator => &self.syntax_operator,
+            TokenKind::Comment => &self.syntax_comment,
+            TokenKind::Word => "",
+        }
+    }
+
+    /// whether the command line is colored at all
+    pub fn highlights_syntax(&self) -> bool {
+        [
+            &self.syntax_command,
+            &self.syntax_variable,
+            &self.syntax_string,
+            &self.syntax_operator,
+            &self.syntax_comment,
+        ]
+        .iter()
+        .any(|x| !x.is_empty())
+    }
+
+    /// overrides the color called `name` (as it's called in the config file) with `value`
+    pub fn set(&mut self, name: &str, value: String) -> Result<(), String> {
+        let color = match name {
+            "prompt_failure" => &mut self.prompt_failure,
+            "diagnostic" => &mut self.diagnostic,
+            "completion" => &mut self.completion,
+            "ls" => &mut self.ls,
+            "syntax_command" => &mut self.syntax_command,
+            "syntax_variable" => &mut self.syntax_variable,
+            "syntax_string" => &mut self.syntax_string,
+            "syntax_operator" => &mut self.syntax_operator,
+            "syntax_comment" => &mut self.syntax_comment,
+            _ => return Err("unknown setting".to_owned()),
+        };
+        *color = value;
+        Ok(())
+    }
+}
+
+/// `text` colored with `style`, or left as it is if that's empty
+pub fn paint(style: &str, text: &str) -> String {
+    match style.is_empty() {
+        true => text.to_owned(),
+        false => format!("\x1b[{}m{}\x1b[0m", style, text),
+    }
+}
@@ -0,0 +1,296 @@
+//! traversal of the AST, so anything that needs to look at every node of a given kind (every
+//! variable, every nested command line, ...) doesn't have to match its way down through all of
+//! them itself
+//!
+//! each `visit_*` method of [`Visitor`] is called on a node of that type and by default carries on
+//! into its children by calling the matching `walk_*` function, so an implementation overrides the
+//! methods for the nodes it cares about, and calls the `walk_*` function from them if it still
+//! wants to see what's inside, e.g. to find every variable that's substituted
+//!
+//! ```
+//! use cs128h_project::{ast::DollarEnv, parse, visit::Visitor};
+//!
+//! struct Variables(Vec<String>);
+//!
+//! impl<'ast> Visitor<'ast> for Variables {
+//!     fn visit_dollar_env(&mut self, node: &'ast DollarEnv) {
+//!         self.0.push(node.0 .0.to_string_lossy().into_owned());
+//!     }
+//! }
+//!
+//! let mut variables = Variables(Vec::new());
+//! variables.visit_main(&parse("echo $HOME (echo \"$USER\")").unwrap());
+//! assert_eq!(variables.0, ["HOME", "USER"]);
+//! ```
+//!
+//! the children of a command line are visited in the order of its fields, envs, command,
+//! arguments, redirects, then the next command line, which isn't always the order they were
+//! written in, since redirects can come between arguments, so sort by [`Span`] if that matters
+
+use crate::ast::{
+    Argument, Chars, Command, CommandEnv, CommandLine, DollarEnv, DollarShell,
+    DoubleQuoteComponent, DoubleQuoteString, EnvLiteral, Escape, Main, ProcessSubstitution,
+    RawChars, RedirectOp, Redirection, Separator, ShellSubstitution, SingleQuoteString, Span,
+    StringLiteral, StringLiteralComponent,
+};
+
+/// visits the nodes of an AST, see the [module docs](self)
+pub trait Visitor<'ast> {
+    fn visit_main(&mut self, node: &'ast Main) {
+        walk_main(self, node);
+    }
+
+    /// called on every command line, including the ones chained after another and the ones
+    /// inside substitutions
+    fn visit_command_line(&mut self, node: &'ast CommandLine) {
+        walk_command_line(self, node);
+    }
+
+    fn visit_command_env(&mut self, node: &'ast CommandEnv) {
+        walk_command_env(self, node);
+    }
+
+    fn visit_command(&mut self, node: &'ast Command) {
+        walk_command(self, node);
+    }
+
+    fn visit_argument(&mut self, node: &'ast Argument) {
+        walk_argument(self, node);
+    }
+
+    fn visit_redirection(&mut self, node: &'ast Redirection) {
+        walk_redirection(self, node);
+    }
+
+    fn visit_redirect_op(&mut self, _node: &'ast RedirectOp) {}
+
+    fn visit_separator(&mut self, _node: &'ast Separator) {}
+
+    /// called on the name of a one-shot variable and of a substituted one
+    fn visit_env_literal(&mut self, _node: &'ast EnvLiteral) {}
+
+    fn visit_shell_substitution(&mut self, node: &'ast ShellSubstitution) {
+        walk_shell_substitution(self, node);
+    }
+
+    fn visit_process_substitution(&mut self, node: &'ast ProcessSubstitution) {
+        walk_process_substitution(self, node);
+    }
+
+    fn visit_double_quote_string(&mut self, node: &'ast DoubleQuoteString) {
+        walk_double_quote_string(self, node);
+    }
+
+    fn visit_single_quote_string(&mut self, _node: &'ast SingleQuoteString) {}
+
+    fn visit_string_literal(&mut self, node: &'ast StringLiteral) {
+        walk_string_literal(self, node);
+    }
+
+    fn visit_double_quote_component(&mut self, node: &'ast DoubleQuoteComponent) {
+        walk_double_quote_component(self, node);
+    }
+
+    fn visit_string_literal_component(&mut self, node: &'ast StringLiteralComponent) {
+        walk_string_literal_component(self, node);
+    }
+
+    fn visit_dollar_env(&mut self, node: &'ast DollarEnv) {
+        walk_dollar_env(self, node);
+    }
+
+    /// `$?`, which has nothing but its span
+    fn visit_dollar_status(&mut self, _span: Span) {}
+
+    fn visit_dollar_shell(&mut self, node: &'ast DollarShell) {
+        walk_dollar_shell(self, node);
+    }
+
+    fn visit_chars(&mut self, _node: &'ast Chars) {}
+
+    fn visit_escape(&mut self, _node: &'ast Escape) {}
+
+    fn visit_raw_chars(&mut self, _node: &'ast RawChars) {}
+}
+
+pub fn walk_main<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, node: &'ast Main) {
+    visitor.visit_command_line(&node.0);
+}
+
+pub fn walk_command_line<'ast, V: Visitor<'ast> + ?Sized>(
+    visitor: &mut V,
+    node: &'ast CommandLine,
+) {
+    for env in &node.envs {
+        visitor.visit_command_env(env);
+    }
+    if let Some(command) = &node.command {
+        visitor.visit_command(command);
+    }
+    for argument in &node.arguments {
+        visitor.visit_argument(argument);
+    }
+    for redirect in &node.redirects {
+        visitor.visit_redirection(redirect);
+    }
+    if let Some((separator, next)) = &node.next {
+        visitor.visit_separator(separator);
+        visitor.visit_command_line(next);
+    }
+}
+
+pub fn walk_command_env<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, node: &'ast CommandEnv) {
+    visitor.visit_env_literal(&node.name);
+    visitor.visit_argument(&node.value);
+}
+
+pub fn walk_command<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, node: &'ast Command) {
+    match node {
+        Command::StringLiteral(x) => visitor.visit_string_literal(x),
+        Command::SingleQuoteString(x) => visitor.visit_single_quote_string(x),
+        Command::DoubleQuoteString(x) => visitor.visit_double_quote_string(x),
+    }
+}
+
+pub fn walk_argument<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, node: &'ast Argument) {
+    match node {
+        Argument::StringLiteral(x) => visitor.visit_string_literal(x),
+        Argument::SingleQuoteString(x) => visitor.visit_single_quote_string(x),
+        Argument::DoubleQuoteString(x) => visitor.visit_double_quote_string(x),
+        Argument::ShellSubstitution(x) => visitor.visit_shell_substitution(x),
+        Argument::ProcessSubstitution(x) => visitor.visit_process_substitution(x),
+    }
+}
+
+pub fn walk_redirection<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, node: &'ast Redirection) {
+    visitor.visit_redirect_op(&node.op);
+    if let Some(arg) = &node.arg {
+        visitor.visit_argument(arg);
+    }
+}
+
+pub fn walk_shell_substitution<'ast, V: Visitor<'ast> + ?Sized>(
+    visitor: &mut V,
+    node: &'ast ShellSubstitution,
+) {
+    visitor.visit_command_line(&node.0);
+}
+
+pub fn walk_process_substitution<'ast, V: Visitor<'ast> + ?Sized>(
+    visitor: &mut V,
+    node: &'ast ProcessSubstitution,
+) {
+    visitor.visit_command_line(&node.0);
+}
+
+pub fn walk_double_quote_string<'ast, V: Visitor<'ast> + ?Sized>(
+    visitor: &mut V,
+    node: &'ast DoubleQuoteString,
+) {
+    for component in &node.0 {
+        visitor.visit_double_quote_component(component);
+    }
+}
+
+pub fn walk_string_literal<'ast, V: Visitor<'ast> + ?Sized>(
+    visitor: &mut V,
+    node: &'ast StringLiteral,
+) {
+    for component in &node.0 {
+        visitor.visit_string_literal_component(component);
+    }
+}
+
+pub fn walk_double_quote_component<'ast, V: Visitor<'ast> + ?Sized>(
+    visitor: &mut V,
+    node: &'ast DoubleQuoteComponent,
+) {
+    match node {
+        DoubleQuoteComponent::Chars(x) => visitor.visit_chars(x),
+        DoubleQuoteComponent::Escape(x) => visitor.visit_escape(x),
+        DoubleQuoteComponent::DollarEnv(x) => visitor.visit_dollar_env(x),
+        DoubleQuoteComponent::DollarStatus(x) => visitor.visit_dollar_status(*x),
+        DoubleQuoteComponent::DollarShell(x) => visitor.visit_dollar_shell(x),
+    }
+}
+
+pub fn walk_string_literal_component<'ast, V: Visitor<'ast> + ?Sized>(
+    visitor: &mut V,
+    node: &'ast StringLiteralComponent,
+) {
+    match node {
+        StringLiteralComponent::RawChars(x) => visitor.visit_raw_chars(x),
+        StringLiteralComponent::DollarEnv(x) => visitor.visit_dollar_env(x),
+        StringLiteralComponent::DollarStatus(x) => visitor.visit_dollar_status(*x),
+    }
+}
+
+pub fn walk_dollar_env<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, node: &'ast DollarEnv) {
+    visitor.visit_env_literal(&node.0);
+}
+
+pub fn walk_dollar_shell<'ast, V: Visitor<'ast> + ?Sized>(
+    visitor: &mut V,
+    node: &'ast DollarShell,
+) {
+    visitor.visit_command_line(&node.0);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::generate_ast;
+
+    use super::*;
+
+    /// writes down the nodes it's called on, and skips what's inside single quotes and process
+    /// substitutions to check that not walking a node stops there
+    #[derive(Default)]
+    struct Trace(Vec<String>);
+
+    impl<'ast> Visitor<'ast> for Trace {
+        fn visit_command_line(&mut self, node: &'ast CommandLine) {
+            self.0.push("line".to_owned());
+            walk_command_line(self, node);
+        }
+
+        fn visit_separator(&mut self, node: &'ast Separator) {
+            self.0.push(format!("{:?}", node));
+        }
+
+        fn visit_redirect_op(&mut self, node: &'ast RedirectOp) {
+            self.0.push(node.to_string());
+        }
+
+        fn visit_env_literal(&mut self, node: &'ast EnvLiteral) {
+            self.0.push(format!("${}", node.0.to_string_lossy()));
+        }
+
+        fn visit_dollar_status(&mut self, _span: Span) {
+            self.0.push("$?".to_owned());
+        }
+
+        fn visit_process_substitution(&mut self, _node: &'ast ProcessSubstitution) {
+            self.0.push("<()".to_owned());
+        }
+
+        fn visit_chars(&mut self, node: &'ast Chars) {
+            self.0.push(node.0.to_string_lossy().into_owned());
+        }
+
+        fn visit_raw_chars(&mut self, node: &'ast RawChars) {
+            self.0.push(node.0.to_string_lossy().into_owned());
+        }
+    }
+
+    #[test]
+    fn visiting() {
+        let ast = generate_ast("A=$B cmd > out \"x$(y $?)\" <(z) | w").unwrap();
+        let mut trace = Trace::default();
+        trace.visit_main(&ast);
+        let expected = [
+            "line", "$A", "$B", "cmd", "x", "line", "y", "$?", "<()", ">", "out", "Pipe", "line",
+            "w",
+        ];
+        assert_eq!(trace.0, expected);
+    }
+}